@@ -0,0 +1,59 @@
+//! Instruction dispatch benchmarks, run with `cargo bench --bench dispatch`.
+//! Each script is compiled once and only running it is timed.
+
+use dynamix::engine::Engine;
+
+use std::{sync::Arc, time::Instant};
+
+const ITERATIONS: u32 = 5;
+
+const BENCHMARKS: [(&str, &str); 3] = [
+    (
+        "fib",
+        "
+fun fib(n) {
+    if n < 2 {
+        return n;
+    }
+    return fib(n - 1) + fib(n - 2);
+}
+fib(25);
+",
+    ),
+    (
+        "string append",
+        "
+let text = \"\";
+for (let i = 0; i < 20000; i = i + 1) {
+    text = text + \"x\";
+}
+",
+    ),
+    (
+        "tight loop",
+        "
+let total = 0;
+for (let i = 0; i < 1000000; i = i + 1) {
+    total = total + i;
+}
+",
+    ),
+];
+
+fn main() {
+    for (name, source) in BENCHMARKS {
+        let block = Arc::new(Engine::new().compile(source).unwrap());
+        let mut total = 0.0;
+
+        for i in 0..ITERATIONS {
+            let mut engine = Engine::new();
+            let start = Instant::now();
+            engine.eval_block(block.clone()).unwrap();
+            let elapsed = start.elapsed().as_secs_f64() * 1000.0;
+            eprintln!("{name} run {i}: {elapsed:.2}ms");
+            total += elapsed;
+        }
+
+        eprintln!("{name}: {:.2}ms avg", total / ITERATIONS as f64);
+    }
+}
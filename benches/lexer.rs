@@ -0,0 +1,51 @@
+//! Lexes a large generated script, run with `cargo bench --bench lexer`.
+
+use dynamix::lexer::{Lexer, TokenType};
+
+use std::time::Instant;
+
+const ITERATIONS: u32 = 5;
+
+// identifiers, keywords, operators and literals in roughly the mix of a real
+// script
+const CHUNK: &str = r#"
+fun update(entity, delta) {
+    let speed = entity_speed * delta;
+    if speed > max_speed {
+        speed = max_speed;
+    }
+    while remaining_steps > 0 && !finished || forced {
+        remaining_steps = remaining_steps - 1;
+    }
+    print "moved " + speed;
+    return (speed, 'x', true, null);
+}
+"#;
+
+const CHUNKS: usize = 20_000;
+
+fn main() {
+    // an error token would time error recovery instead of lexing
+    assert!(Lexer::new(CHUNK)
+        .take_while(|token| token.typ3 != TokenType::Eof)
+        .all(|token| token.typ3 != TokenType::Error));
+
+    let source = CHUNK.repeat(CHUNKS);
+    let mut total = 0.0;
+
+    for i in 0..ITERATIONS {
+        let start = Instant::now();
+        let tokens = Lexer::new(&source)
+            .take_while(|token| token.typ3 != TokenType::Eof)
+            .count();
+        let elapsed = start.elapsed().as_secs_f64() * 1000.0;
+        eprintln!("run {i}: {tokens} tokens in {elapsed:.2}ms");
+        total += elapsed;
+    }
+
+    eprintln!(
+        "lexing {}KB: {:.2}ms avg",
+        source.len() / 1024,
+        total / ITERATIONS as f64
+    );
+}
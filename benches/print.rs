@@ -0,0 +1,26 @@
+//! Print-heavy benchmark, run with `cargo bench --bench print > /dev/null`
+//! so only the timings (written to stderr) are shown.
+
+use std::time::Instant;
+
+const ITERATIONS: u32 = 5;
+
+const SOURCE: &str = "
+for (let i = 0; i < 100000; i = i + 1) {
+    print i;
+}
+";
+
+fn main() {
+    let mut total = 0.0;
+
+    for i in 0..ITERATIONS {
+        let start = Instant::now();
+        dynamix::run(SOURCE).unwrap();
+        let elapsed = start.elapsed().as_secs_f64() * 1000.0;
+        eprintln!("run {i}: {elapsed:.2}ms");
+        total += elapsed;
+    }
+
+    eprintln!("print loop: {:.2}ms avg", total / ITERATIONS as f64);
+}
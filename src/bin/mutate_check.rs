@@ -0,0 +1,316 @@
+//! `cargo run --bin mutate-check [--threshold percent]`
+//!
+//! Checks that the spec suite notices when the bytecode is wrong. Every
+//! script under `tests/spec/` is compiled, then each instruction that has a
+//! plausible mistake is changed in turn, e.g. a conditional jump made
+//! unconditional or `+` made `-`. The mutant is saved as a `.dynb` file and
+//! run in a child process like a spec script would be. It's killed if the
+//! script's `// expect:` and `// error:` directives no longer hold.
+//!
+//! Survivors are printed, each one is behavior no spec script covers. Exits
+//! with a failure if less than `threshold` percent of the mutants were
+//! killed.
+
+use dynamix::{
+    byte_block::{ByteBlock, OpCode},
+    constant::{Constant, Function},
+    engine::Engine,
+};
+
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, ExitCode, Stdio},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+const SPEC_DIR: &str = "tests/spec";
+
+const SUCCESS: &str = "program exited successfully...";
+
+// a mutant that hangs is caught, the spec suite would never finish
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+const DEFAULT_THRESHOLD: f64 = 75.0;
+
+/// The opcode each opcode is swapped for.
+const SWAPS: &[(OpCode, OpCode)] = &[
+    (OpCode::Jz, OpCode::Jmp),
+    (OpCode::Jmp, OpCode::Jz),
+    (OpCode::JzLong, OpCode::JmpLong),
+    (OpCode::JmpLong, OpCode::JzLong),
+    (OpCode::Add, OpCode::Sub),
+    (OpCode::Sub, OpCode::Add),
+    (OpCode::Mul, OpCode::Div),
+    (OpCode::Div, OpCode::Mul),
+    (OpCode::Greater, OpCode::Less),
+    (OpCode::Less, OpCode::Greater),
+    (OpCode::True, OpCode::False),
+    (OpCode::False, OpCode::True),
+];
+
+struct Mutant {
+    /// e.g. `OP_ADD -> OP_SUB in greet at line: 3`
+    description: String,
+    block: ByteBlock,
+}
+
+struct Directives {
+    expected: Vec<String>,
+    error: Option<String>,
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+
+    // the child process a mutant runs in
+    if let Some("--run") = args.next().as_deref() {
+        let path = args.next().unwrap_or_default();
+        dynamix::print_result(&dynamix::run_file(&path), &path);
+        return ExitCode::SUCCESS;
+    }
+
+    let threshold = match threshold() {
+        Some(threshold) => threshold,
+        None => {
+            println!("Usage: mutate-check [--threshold percent]");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut scripts = Vec::new();
+    collect_scripts(Path::new(SPEC_DIR), &mut scripts);
+    scripts.sort();
+
+    let mut jobs = Vec::new();
+    for script in scripts.iter() {
+        let path = script.display().to_string();
+        let Ok(block) = Engine::new().compile_file(&path) else {
+            // scripts that test compile errors have no bytecode to mutate
+            continue;
+        };
+
+        let directives = Arc::new(parse_directives(&fs::read_to_string(script).unwrap()));
+        for mutant in mutants(&block) {
+            jobs.push((path.clone(), mutant, Arc::clone(&directives)));
+        }
+    }
+
+    let total = jobs.len();
+    let mut survivors = run_jobs(jobs);
+    survivors.sort();
+
+    for survivor in survivors.iter() {
+        println!("survived: {survivor}");
+    }
+
+    let killed = total - survivors.len();
+    let score = if total == 0 {
+        100.0
+    } else {
+        killed as f64 * 100.0 / total as f64
+    };
+    println!("{killed} of {total} mutants killed ({score:.1}%), threshold {threshold}%");
+
+    if score < threshold {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Runs every mutant on as many threads as there are cores, returning the
+/// ones that survived.
+fn run_jobs(jobs: Vec<(String, Mutant, Arc<Directives>)>) -> Vec<String> {
+    let jobs = Arc::new(Mutex::new(jobs.into_iter().enumerate()));
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+
+    let handles: Vec<_> = (0..workers)
+        .map(|_| {
+            let jobs = Arc::clone(&jobs);
+            thread::spawn(move || {
+                let mut survivors = Vec::new();
+                loop {
+                    let Some((id, (path, mutant, directives))) = jobs.lock().unwrap().next() else {
+                        break survivors;
+                    };
+
+                    if !is_killed(id, &mutant, &directives) {
+                        survivors.push(format!("{path}: {}", mutant.description));
+                    }
+                }
+            })
+        })
+        .collect();
+
+    handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect()
+}
+
+fn threshold() -> Option<f64> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => Some(DEFAULT_THRESHOLD),
+        [flag, percent] if flag == "--threshold" => percent.parse().ok(),
+        _ => None,
+    }
+}
+
+fn collect_scripts(dir: &Path, scripts: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            collect_scripts(&path, scripts);
+        } else if path.extension().is_some_and(|ext| ext == "dyn") {
+            scripts.push(path);
+        }
+    }
+}
+
+/// Every single instruction change to `block` and the functions declared
+/// in it.
+fn mutants(block: &ByteBlock) -> Vec<Mutant> {
+    let mut mutants = Vec::new();
+
+    let mut offset = 0;
+    while offset < block.bytes.len() {
+        let Ok(opcode) = OpCode::from(block.bytes[offset]) else {
+            break;
+        };
+
+        if let Some((_, swap)) = SWAPS.iter().find(|(from, _)| *from == opcode) {
+            let mut mutant = block.clone();
+            mutant.bytes[offset] = *swap as u8;
+            mutants.push(Mutant {
+                description: format!(
+                    "{} -> {} in {} at {}",
+                    opcode.name(),
+                    swap.name(),
+                    block.name(),
+                    block.location(offset)
+                ),
+                block: mutant,
+            });
+        }
+
+        offset += opcode.width();
+    }
+
+    for (index, constant) in block.constants() {
+        let Constant::Function(function) = constant else {
+            continue;
+        };
+
+        for inner in mutants_of(function) {
+            let mut mutant = block.clone();
+            Arc::make_mut(&mut mutant.constants.constants)[index] =
+                Constant::Function(Arc::new(inner.1));
+            mutants.push(Mutant {
+                description: inner.0,
+                block: mutant,
+            });
+        }
+    }
+
+    mutants
+}
+
+fn mutants_of(function: &Function) -> Vec<(String, Function)> {
+    mutants(&function.block)
+        .into_iter()
+        .map(|mutant| {
+            let function = Function {
+                name: function.name.clone(),
+                arity: function.arity,
+                block: Arc::new(mutant.block),
+            };
+            (mutant.description, function)
+        })
+        .collect()
+}
+
+/// Runs the mutant in a child process, returning true if its output breaks
+/// the script's directives.
+fn is_killed(id: usize, mutant: &Mutant, directives: &Directives) -> bool {
+    let name = format!("dynamix-mutant-{}-{id}.dynb", std::process::id());
+    let path = std::env::temp_dir().join(name);
+    fs::write(&path, mutant.block.serialize().unwrap()).unwrap();
+
+    let mut child = Command::new(std::env::current_exe().unwrap())
+        .arg("--run")
+        .arg(&path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // read on other threads so a chatty mutant can't fill a pipe and stall
+    let stdout = read_all(child.stdout.take().unwrap());
+    let stderr = read_all(child.stderr.take().unwrap());
+
+    let started = Instant::now();
+    let finished = loop {
+        if child.try_wait().unwrap().is_some() {
+            break true;
+        }
+        if started.elapsed() > TIMEOUT {
+            child.kill().ok();
+            child.wait().ok();
+            break false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    };
+
+    let stdout = stdout.join().unwrap();
+    let stderr = stderr.join().unwrap();
+    fs::remove_file(&path).ok();
+
+    // a mutant can make the VM panic, which counts as caught
+    !finished || !passes(directives, &stdout, &stderr)
+}
+
+fn read_all(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut output = String::new();
+        pipe.read_to_string(&mut output).ok();
+        output
+    })
+}
+
+fn parse_directives(source: &str) -> Directives {
+    let mut directives = Directives {
+        expected: Vec::new(),
+        error: None,
+    };
+
+    for line in source.lines() {
+        if let Some(expect) = line.split("// expect: ").nth(1) {
+            directives.expected.push(expect.trim_end().to_string());
+        } else if let Some(error) = line.split("// error: ").nth(1) {
+            directives.error = Some(error.trim_end().to_string());
+        }
+    }
+
+    directives
+}
+
+/// The same checks `tests/spec.rs` makes.
+fn passes(directives: &Directives, stdout: &str, stderr: &str) -> bool {
+    let mut printed: Vec<&str> = stdout.lines().collect();
+    let succeeded = printed.last() == Some(&SUCCESS);
+    if succeeded {
+        printed.pop();
+    }
+
+    let status_ok = match &directives.error {
+        Some(error) => !succeeded && stderr.contains(error.as_str()),
+        None => succeeded,
+    };
+
+    status_ok && printed == directives.expected
+}
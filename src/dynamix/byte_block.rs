@@ -1,4 +1,6 @@
-use crate::constant::{Constant, ConstantPool};
+use crate::constant::{Constant, ConstantPool, Function};
+
+use std::sync::Arc;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OpCode {
@@ -13,10 +15,13 @@ pub enum OpCode {
     Jmp,
     Loop,
     Constant,
+    ConstantLong,
     True,
     False,
     Char,
     Null,
+    Tuple,
+    Unpack,
     Equal,
     Greater,
     Less,
@@ -26,7 +31,68 @@ pub enum OpCode {
     Sub,
     Mul,
     Div,
+    Pow,
+    Call,
+    Invoke,
     Return,
+    Assert,
+    JzLong,
+    JmpLong,
+    LoopLong,
+    DefineGlobalLong,
+    GetGlobalLong,
+    SetGlobalLong,
+    InvokeLong,
+}
+
+/// The operands that follow an opcode byte.
+///
+/// Two byte operands (jump distances, long constant indices) are stored
+/// big-endian, `encode_short` and `decode_short` are the only places that
+/// know that. The same goes for the four byte distances of long jumps and
+/// `encode_long` and `decode_long`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operands {
+    None,
+    Byte,
+    Short,
+    Long,
+    /// A constant index followed by an argument count.
+    ByteByte,
+    /// A long constant index followed by an argument count.
+    ShortByte,
+}
+
+impl Operands {
+    pub fn len(self) -> usize {
+        match self {
+            Operands::None => 0,
+            Operands::Byte => 1,
+            Operands::Short | Operands::ByteByte => 2,
+            Operands::ShortByte => 3,
+            Operands::Long => 4,
+        }
+    }
+
+    pub fn is_empty(self) -> bool {
+        self == Operands::None
+    }
+}
+
+pub fn encode_short(value: u16) -> [u8; 2] {
+    value.to_be_bytes()
+}
+
+pub fn decode_short(bytes: [u8; 2]) -> u16 {
+    u16::from_be_bytes(bytes)
+}
+
+pub fn encode_long(value: u32) -> [u8; 4] {
+    value.to_be_bytes()
+}
+
+pub fn decode_long(bytes: [u8; 4]) -> u32 {
+    u32::from_be_bytes(bytes)
 }
 
 pub enum OpError {
@@ -47,10 +113,13 @@ impl OpCode {
             value if value == OpCode::Jmp as u8 => Ok(OpCode::Jmp),
             value if value == OpCode::Loop as u8 => Ok(OpCode::Loop),
             value if value == OpCode::Constant as u8 => Ok(OpCode::Constant),
+            value if value == OpCode::ConstantLong as u8 => Ok(OpCode::ConstantLong),
             value if value == OpCode::True as u8 => Ok(OpCode::True),
             value if value == OpCode::False as u8 => Ok(OpCode::False),
             value if value == OpCode::Char as u8 => Ok(OpCode::Char),
             value if value == OpCode::Null as u8 => Ok(OpCode::Null),
+            value if value == OpCode::Tuple as u8 => Ok(OpCode::Tuple),
+            value if value == OpCode::Unpack as u8 => Ok(OpCode::Unpack),
             value if value == OpCode::Equal as u8 => Ok(OpCode::Equal),
             value if value == OpCode::Greater as u8 => Ok(OpCode::Greater),
             value if value == OpCode::Less as u8 => Ok(OpCode::Less),
@@ -60,41 +129,276 @@ impl OpCode {
             value if value == OpCode::Sub as u8 => Ok(OpCode::Sub),
             value if value == OpCode::Mul as u8 => Ok(OpCode::Mul),
             value if value == OpCode::Div as u8 => Ok(OpCode::Div),
+            value if value == OpCode::Pow as u8 => Ok(OpCode::Pow),
+            value if value == OpCode::Call as u8 => Ok(OpCode::Call),
+            value if value == OpCode::Invoke as u8 => Ok(OpCode::Invoke),
             value if value == OpCode::Return as u8 => Ok(OpCode::Return),
+            value if value == OpCode::Assert as u8 => Ok(OpCode::Assert),
+            value if value == OpCode::JzLong as u8 => Ok(OpCode::JzLong),
+            value if value == OpCode::JmpLong as u8 => Ok(OpCode::JmpLong),
+            value if value == OpCode::LoopLong as u8 => Ok(OpCode::LoopLong),
+            value if value == OpCode::DefineGlobalLong as u8 => Ok(OpCode::DefineGlobalLong),
+            value if value == OpCode::GetGlobalLong as u8 => Ok(OpCode::GetGlobalLong),
+            value if value == OpCode::SetGlobalLong as u8 => Ok(OpCode::SetGlobalLong),
+            value if value == OpCode::InvokeLong as u8 => Ok(OpCode::InvokeLong),
             _ => Err(OpError::UnknownOperation),
         }
     }
 }
 
+impl OpCode {
+    pub fn operands(self) -> Operands {
+        use OpCode::*;
+        match self {
+            DefineGlobal | GetGlobal | SetGlobal | GetLocal | SetLocal | Constant | Char
+            | Tuple | Unpack | Call => Operands::Byte,
+            Jz | Jmp | Loop | ConstantLong | DefineGlobalLong | GetGlobalLong | SetGlobalLong => {
+                Operands::Short
+            }
+            JzLong | JmpLong | LoopLong => Operands::Long,
+            Invoke => Operands::ByteByte,
+            InvokeLong => Operands::ShortByte,
+            Print | Pop | True | False | Null | Equal | Greater | Less | Negate | Not | Add
+            | Sub | Mul | Div | Pow | Return | Assert => Operands::None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        use OpCode::*;
+        match self {
+            Print => "OP_PRINT",
+            Pop => "OP_POP",
+            DefineGlobal => "OP_DEFINE_GLOBAL",
+            GetGlobal => "OP_GET_GLOBAL",
+            SetGlobal => "OP_SET_GLOBAL",
+            GetLocal => "OP_GET_LOCAL",
+            SetLocal => "OP_SET_LOCAL",
+            Jz => "OP_JUMP_IF_FALSE",
+            Jmp => "OP_JUMP",
+            Loop => "OP_LOOP",
+            Constant => "OP_CONSTANT",
+            ConstantLong => "OP_CONSTANT_LONG",
+            True => "OP_TRUE",
+            False => "OP_FALSE",
+            Char => "OP_CHAR",
+            Null => "OP_NULL",
+            Tuple => "OP_TUPLE",
+            Unpack => "OP_UNPACK",
+            Equal => "OP_EQUAL",
+            Greater => "OP_GREATER",
+            Less => "OP_LESS",
+            Negate => "OP_NEGATE",
+            Not => "OP_NOT",
+            Add => "OP_ADD",
+            Sub => "OP_SUB",
+            Mul => "OP_MUL",
+            Div => "OP_DIV",
+            Pow => "OP_POW",
+            Call => "OP_CALL",
+            Invoke => "OP_INVOKE",
+            Return => "OP_RETURN",
+            Assert => "OP_ASSERT",
+            JzLong => "OP_JUMP_IF_FALSE_LONG",
+            JmpLong => "OP_JUMP_LONG",
+            LoopLong => "OP_LOOP_LONG",
+            DefineGlobalLong => "OP_DEFINE_GLOBAL_LONG",
+            GetGlobalLong => "OP_GET_GLOBAL_LONG",
+            SetGlobalLong => "OP_SET_GLOBAL_LONG",
+            InvokeLong => "OP_INVOKE_LONG",
+        }
+    }
+
+    /// Size of the whole instruction in bytes, opcode included.
+    pub fn width(self) -> usize {
+        1 + self.operands().len()
+    }
+
+    /// Whether the instruction's operand is a jump distance, and in which
+    /// direction it jumps.
+    pub fn jump_direction(self) -> Option<isize> {
+        match self {
+            OpCode::Jz | OpCode::Jmp | OpCode::JzLong | OpCode::JmpLong => Some(1),
+            OpCode::Loop | OpCode::LoopLong => Some(-1),
+            _ => None,
+        }
+    }
+
+    /// The jump that takes a four byte distance, for jumps too long for
+    /// two bytes.
+    pub fn widened(self) -> Self {
+        match self {
+            OpCode::Jz => OpCode::JzLong,
+            OpCode::Jmp => OpCode::JmpLong,
+            OpCode::Loop => OpCode::LoopLong,
+            opcode => opcode,
+        }
+    }
+
+    /// The jump that takes a two byte distance, the opposite of `widened`.
+    pub fn narrowed(self) -> Self {
+        match self {
+            OpCode::JzLong => OpCode::Jz,
+            OpCode::JmpLong => OpCode::Jmp,
+            OpCode::LoopLong => OpCode::Loop,
+            opcode => opcode,
+        }
+    }
+}
+
+/// Debug information about a local variable, it lives in `slot` while the
+/// instruction offset is in `start..end`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalInfo {
+    pub name: String,
+    pub slot: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A `test "name" { ... }` block, compiled into a function that takes no
+/// arguments.
+#[derive(Debug, Clone)]
+pub struct TestCase {
+    pub name: String,
+    pub function: Arc<Function>,
+}
+
 #[derive(Debug, Clone)]
 pub struct ByteBlock {
+    /// What the block is called in errors and traces, usually the path of
+    /// the script it was compiled from.
+    pub name: Option<String>,
     pub bytes: Vec<u8>,
     pub constants: ConstantPool,
     pub lines: Vec<u32>,
+    /// Source file of every byte, 0 is the main file and `n` is `includes[n - 1]`.
+    pub files: Vec<u16>,
+    pub includes: Vec<String>,
+    pub locals: Vec<LocalInfo>,
+    /// `test` blocks declared at the top level, in source order.
+    pub tests: Vec<TestCase>,
 }
 
 impl ByteBlock {
     pub fn new() -> Self {
         Self {
+            name: None,
             bytes: Vec::new(),
             constants: ConstantPool::new(),
             lines: Vec::new(),
+            files: Vec::new(),
+            includes: Vec::new(),
+            locals: Vec::new(),
+            tests: Vec::new(),
         }
     }
 
+    /// The block's name, or `script` for anonymous top level code.
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or("script")
+    }
+
     pub fn push(&mut self, byte: u8, line: u32) {
+        self.push_in(byte, line, 0);
+    }
+
+    /// Pushes a byte that was compiled from the source file `file`.
+    pub fn push_in(&mut self, byte: u8, line: u32, file: u16) {
         self.bytes.push(byte);
         self.lines.push(line);
+        self.files.push(file);
+    }
+
+    /// The included file the byte at `offset` came from, `None` if it's
+    /// from the main file.
+    pub fn include(&self, offset: usize) -> Option<&str> {
+        let file = self.files.get(offset).copied().unwrap_or(0) as usize;
+        let include = self.includes.get(file.checked_sub(1)?)?;
+        Some(include)
+    }
+
+    /// Where the byte at `offset` came from, e.g. `line: 3` or `common.dyn line: 3`.
+    pub fn location(&self, offset: usize) -> String {
+        let line = self.lines.get(offset).copied().unwrap_or(0);
+        match self.include(offset) {
+            Some(include) => format!("{include} line:{line:2}"),
+            None => format!("line:{line:2}"),
+        }
     }
 
     pub fn write_constant(&mut self, value: Constant, line: u32) {
         let constant = self.push_constant(value);
-        self.push(constant, line);
+        self.push(constant as u8, line);
+    }
+
+    /// Reads the big-endian operand at `offset`.
+    pub fn read_short(&self, offset: usize) -> Option<u16> {
+        let bytes = self.bytes.get(offset..offset + 2)?;
+        Some(decode_short([bytes[0], bytes[1]]))
+    }
+
+    /// Reads the big-endian four byte operand at `offset`.
+    pub fn read_long(&self, offset: usize) -> Option<u32> {
+        let bytes = self.bytes.get(offset..offset + 4)?;
+        Some(decode_long([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Where the jump instruction at `offset` lands, jumps are relative to
+    /// the end of the instruction.
+    pub fn jump_target(&self, offset: usize) -> Option<usize> {
+        let opcode = OpCode::from(*self.bytes.get(offset)?).ok()?;
+        let sign = opcode.jump_direction()?;
+        let distance = match opcode.operands() {
+            Operands::Long => self.read_long(offset + 1)? as isize,
+            _ => self.read_short(offset + 1)? as isize,
+        };
+        (offset + opcode.width()).checked_add_signed(sign * distance)
+    }
+
+    /// Every constant in the pool along with its index.
+    pub fn constants(&self) -> impl Iterator<Item = (usize, &Constant)> {
+        self.constants.constants.iter().enumerate()
+    }
+
+    /// The pool index used by the instruction at `offset`, if it has one.
+    pub fn constant_operand(&self, offset: usize) -> Option<usize> {
+        match OpCode::from(*self.bytes.get(offset)?).ok()? {
+            OpCode::Constant
+            | OpCode::Char
+            | OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::Invoke => self.bytes.get(offset + 1).map(|&index| index as usize),
+            OpCode::ConstantLong
+            | OpCode::DefineGlobalLong
+            | OpCode::GetGlobalLong
+            | OpCode::SetGlobalLong
+            | OpCode::InvokeLong => self.read_short(offset + 1).map(|index| index as usize),
+            _ => None,
+        }
+    }
+
+    /// For every constant, the offsets of the instructions that use it.
+    pub fn constant_references(&self) -> Vec<Vec<usize>> {
+        let mut references = vec![Vec::new(); self.constants.len()];
+
+        let mut offset = 0;
+        while let Some(Ok(opcode)) = self.bytes.get(offset).map(|&byte| OpCode::from(byte)) {
+            if let Some(index) = self.constant_operand(offset) {
+                if let Some(references) = references.get_mut(index) {
+                    references.push(offset);
+                }
+            }
+            offset += opcode.width();
+        }
+
+        references
     }
 
-    pub fn push_constant(&mut self, value: Constant) -> u8 {
+    /// Adds `value` to the pool and returns its index.
+    pub fn push_constant(&mut self, value: Constant) -> usize {
         self.constants.push(value);
-        self.constants.len() as u8 - 1
+        self.constants.len() - 1
     }
 }
 
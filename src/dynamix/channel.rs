@@ -0,0 +1,123 @@
+//! Unbounded queues of values shared between script tasks and the host.
+//!
+//! ```
+//! use dynamix::{channel, constant::Constant, engine::Engine};
+//!
+//! let (sender, receiver) = channel::channel();
+//! let mut engine = Engine::new();
+//! engine.vm_mut().set_global("inbox", sender.value());
+//!
+//! sender.send(Constant::Number(20.0));
+//! engine.eval("send(inbox, recv(inbox) + 1);").unwrap();
+//! assert_eq!(receiver.try_recv(), Some(Constant::Number(21.0)));
+//! ```
+
+use crate::constant::Constant;
+
+use std::{
+    cmp::Ordering,
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+/// Scripts see both ends of a channel as the same value.
+#[derive(Debug, Clone, Default)]
+pub struct Channel {
+    queue: Arc<Mutex<VecDeque<Constant>>>,
+}
+
+impl Channel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn send(&self, value: Constant) {
+        self.queue.lock().unwrap().push_back(value);
+    }
+
+    /// The oldest value sent, `None` if the channel is empty.
+    pub fn try_recv(&self) -> Option<Constant> {
+        self.queue.lock().unwrap().pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// What's queued, oldest first, without taking it off the queue.
+    pub(crate) fn values(&self) -> Vec<Constant> {
+        self.queue.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// The same for every copy of the channel.
+    pub(crate) fn id(&self) -> usize {
+        Arc::as_ptr(&self.queue) as usize
+    }
+}
+
+// channels are compared by identity, not by what's queued in them
+impl PartialEq for Channel {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.queue, &other.queue)
+    }
+}
+
+impl PartialOrd for Channel {
+    fn partial_cmp(&self, _other: &Self) -> Option<Ordering> {
+        None
+    }
+}
+
+/// The host's end for values going into a script.
+#[derive(Debug, Clone)]
+pub struct Sender {
+    channel: Channel,
+}
+
+impl Sender {
+    pub fn send(&self, value: Constant) {
+        self.channel.send(value);
+    }
+
+    /// The channel as a value to hand to a script, e.g. with `set_global`.
+    pub fn value(&self) -> Constant {
+        Constant::Channel(self.channel.clone())
+    }
+}
+
+/// The host's end for values coming out of a script.
+#[derive(Debug, Clone)]
+pub struct Receiver {
+    channel: Channel,
+}
+
+impl Receiver {
+    pub fn try_recv(&self) -> Option<Constant> {
+        self.channel.try_recv()
+    }
+
+    /// Every value sent so far, oldest first.
+    pub fn drain(&self) -> Vec<Constant> {
+        std::iter::from_fn(|| self.channel.try_recv()).collect()
+    }
+
+    /// The channel as a value to hand to a script, e.g. with `set_global`.
+    pub fn value(&self) -> Constant {
+        Constant::Channel(self.channel.clone())
+    }
+}
+
+/// Creates a channel along with host side handles to both of its ends.
+pub fn channel() -> (Sender, Receiver) {
+    let channel = Channel::new();
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
@@ -0,0 +1,151 @@
+//! Arguments of the `dynamix` binary, other than the `dap` and `graph`
+//! subcommands which parse their own.
+
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Command {
+    /// Runs the script, or starts the REPL if there isn't one.
+    #[default]
+    Run,
+    /// `dynamix test`, runs the script's `test` blocks.
+    Test,
+    /// `dynamix compile`, saves the script's bytecode.
+    Compile,
+}
+
+/// Where the script comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    Path(String),
+    /// Source passed with `-e`.
+    Source(String),
+    /// `-`, the source is read from stdin.
+    Stdin,
+}
+
+impl Input {
+    /// The path, or what errors call source that isn't in a file.
+    pub fn name(&self) -> &str {
+        match self {
+            Input::Path(path) => path,
+            Input::Source(..) => "<eval>",
+            Input::Stdin => "<stdin>",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    pub command: Command,
+    pub input: Option<Input>,
+    pub defines: Vec<String>,
+    /// `-o`, where `compile` writes the bytecode.
+    pub output: Option<String>,
+    /// `--profile`, where the folded call stacks of the run are written.
+    pub profile: Option<String>,
+    pub disassemble: bool,
+    pub tokens: bool,
+    /// `--optimize`, runs the optimizer over the compiled bytecode.
+    pub optimize: bool,
+}
+
+impl Options {
+    /// Where `compile` writes the bytecode, next to the script unless `-o`
+    /// says otherwise.
+    pub fn output_path(&self) -> Option<String> {
+        match (&self.output, &self.input) {
+            (Some(output), ..) => Some(output.clone()),
+            (None, Some(Input::Path(path))) => {
+                Some(Path::new(path).with_extension("dynb").display().to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Parses the arguments after the binary's name, the error says which one
+/// was wrong.
+pub fn parse(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+
+    let mut args = args.iter().peekable();
+    options.command = match args.peek().map(|arg| arg.as_str()) {
+        Some("test") => Command::Test,
+        Some("compile") => Command::Compile,
+        _ => Command::Run,
+    };
+    if options.command != Command::Run {
+        args.next();
+    }
+
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| {
+            args.next()
+                .cloned()
+                .ok_or(format!("Expected a value after '{flag}'"))
+        };
+
+        match arg.as_str() {
+            "-e" | "--eval" => set_input(&mut options, Input::Source(value(arg)?))?,
+            "-o" => options.output = Some(value(arg)?),
+            "--profile" => options.profile = Some(value(arg)?),
+            "-D" => options.defines.push(value(arg)?),
+            "--disassemble" => options.disassemble = true,
+            "--tokens" => options.tokens = true,
+            "--optimize" => options.optimize = true,
+            "-" => set_input(&mut options, Input::Stdin)?,
+            _ => match arg.strip_prefix("-D") {
+                Some(name) => options.defines.push(name.to_string()),
+                None if arg.starts_with('-') => return Err(format!("Unknown flag '{arg}'")),
+                None => set_input(&mut options, Input::Path(arg.clone()))?,
+            },
+        }
+    }
+
+    validate(&options)?;
+    Ok(options)
+}
+
+fn set_input(options: &mut Options, input: Input) -> Result<(), String> {
+    if options.input.is_some() {
+        return Err("Expected a single script or '-e' source".to_string());
+    }
+
+    options.input = Some(input);
+    Ok(())
+}
+
+fn validate(options: &Options) -> Result<(), String> {
+    let is_run = options.command == Command::Run;
+
+    if options.output.is_some() && options.command != Command::Compile {
+        return Err("'-o' only applies to 'compile'".to_string());
+    }
+    if options.profile.is_some() && (!is_run || options.disassemble || options.tokens) {
+        return Err("'--profile' only applies to running a script".to_string());
+    }
+    if (options.disassemble || options.tokens) && !is_run {
+        return Err(
+            "'--disassemble' and '--tokens' can't be combined with a subcommand".to_string(),
+        );
+    }
+    if options.disassemble && options.tokens {
+        return Err("'--disassemble' and '--tokens' can't be used together".to_string());
+    }
+
+    // with nothing to run the REPL starts, it has no use for flags
+    let flags = !options.defines.is_empty()
+        || options.disassemble
+        || options.tokens
+        || options.optimize
+        || options.profile.is_some();
+    if options.input.is_none() && (!is_run || flags) {
+        return Err("Expected a script or '-e' source".to_string());
+    }
+    if options.command == Command::Compile && options.output_path().is_none() {
+        return Err("'compile' needs '-o' to know where to write '-e' source".to_string());
+    }
+
+    Ok(())
+}
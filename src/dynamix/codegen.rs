@@ -0,0 +1,988 @@
+//! Lowers the tree `parser` produces to bytecode.
+//!
+//! Variables are resolved here, so the errors about them are reported while
+//! generating code. An instruction is placed on the line of the token that
+//! completes what it's generated for, e.g. an `OP_ADD` on the last token of
+//! its right operand and a block's pops on its '}'.
+
+use crate::{
+    byte_block::{encode_short, ByteBlock, LocalInfo, OpCode, TestCase},
+    compile_output::{Symbol, SymbolKind},
+    constant::{Constant, Function, Interner},
+    diagnostic::{Diagnostic, Severity, Span},
+    disassembler::Disassembler,
+    lexer::{Token, TokenType},
+    optimizer,
+    parser::{self, Expr, Literal, Program, Stmt},
+    stack::Stack,
+};
+
+use std::{collections::HashMap, sync::Arc};
+
+#[derive(Debug)]
+struct Local {
+    name: Token,
+    depth: isize,
+    kind: SymbolKind,
+    // whether the local's value is ever read, assigning doesn't count
+    used: bool,
+}
+
+const LOCALS_MAX_SIZE: usize = 256;
+
+/// The state of the enclosing function, set aside while a nested function
+/// body is compiled into a block of its own.
+struct Enclosing {
+    block: ByteBlock,
+    locals: Stack<Local>,
+    scope_depth: usize,
+    in_function: bool,
+    long_jumps: HashMap<usize, usize>,
+    string_slots: HashMap<String, usize>,
+}
+
+pub struct CodeGen {
+    block: ByteBlock,
+    locals: Stack<Local>,
+    scope_depth: usize,
+    strings: Interner,
+    // names of the included files, for diagnostics and symbols
+    includes: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
+    // set by an error and cleared after the statement it's in, so a
+    // statement reports one error
+    panic_mode: bool,
+    had_error: bool,
+    // false at the top level, where 'return' isn't allowed
+    in_function: bool,
+    // prints top level expression statements instead of popping them
+    echo: bool,
+    symbols: Vec<Symbol>,
+    // jumps too long for their operand, by offset, with where they land
+    long_jumps: HashMap<usize, usize>,
+    // pool index of every string constant in the block, so each is added once
+    string_slots: HashMap<String, usize>,
+}
+
+impl CodeGen {
+    pub fn new() -> Self {
+        Self {
+            block: ByteBlock::new(),
+            locals: Stack::new(LOCALS_MAX_SIZE),
+            scope_depth: 0,
+            strings: Interner::new(),
+            includes: Vec::new(),
+            diagnostics: Vec::new(),
+            panic_mode: false,
+            had_error: false,
+            in_function: false,
+            echo: false,
+            symbols: Vec::new(),
+            long_jumps: HashMap::new(),
+            string_slots: HashMap::new(),
+        }
+    }
+
+    /// See `Compiler::with_echo`.
+    pub fn with_echo(mut self, enabled: bool) -> Self {
+        self.echo = enabled;
+        self
+    }
+
+    /// Names the generated block, see `ByteBlock::name`.
+    pub fn with_name(mut self, name: Option<String>) -> Self {
+        self.block.name = name;
+        self
+    }
+
+    /// Generates the code of `program` into the block.
+    pub fn generate(&mut self, program: &Program) {
+        self.includes = program.includes.clone();
+        self.block.includes = program.includes.clone();
+
+        self.statements(&program.statements);
+
+        self.emit_byte(OpCode::Return as u8, &program.eof);
+        self.widen_long_jumps(&program.eof);
+    }
+
+    pub fn byte_code(&self) -> &ByteBlock {
+        &self.block
+    }
+
+    pub fn into_byte_code(self) -> ByteBlock {
+        self.block
+    }
+
+    /// The errors found by `generate`, in the order they were found.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Every variable, parameter and function declared, in source order.
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    fn statements(&mut self, statements: &[Stmt]) {
+        let mut returned = false;
+
+        for statement in statements {
+            // only the first statement that can't run is reported
+            if returned {
+                if let Some(start) = statement.start() {
+                    self.warn_at(start, "Unreachable code");
+                    returned = false;
+                }
+            }
+
+            self.statement(statement);
+            self.panic_mode = false;
+
+            returned |= always_returns(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Stmt) {
+        match statement {
+            Stmt::Let {
+                name, value, end, ..
+            } => self.let_declaration(name, value.as_ref(), end),
+            Stmt::LetTuple {
+                names, value, end, ..
+            } => self.destructuring_let_declaration(names, value, end),
+            Stmt::Fun { name, function } => self.fun_declaration(name, function),
+            Stmt::Test {
+                keyword,
+                name,
+                body,
+            } => self.test_declaration(keyword, name, body),
+            Stmt::Print { value, end, .. } => {
+                self.expression(value);
+                self.emit_byte(OpCode::Print as u8, end);
+            }
+            Stmt::Assert {
+                condition,
+                message,
+                end,
+                ..
+            } => {
+                self.expression(condition);
+                match message {
+                    Some(message) => self.expression(message),
+                    None => self.emit_byte(OpCode::Null as u8, condition.end()),
+                }
+                self.emit_byte(OpCode::Assert as u8, end);
+            }
+            Stmt::Expression { expr, end } => self.expression_statement(expr, end),
+            Stmt::Return {
+                keyword,
+                value,
+                end,
+            } => self.return_statement(keyword, value.as_ref(), end),
+            Stmt::If {
+                condition,
+                then,
+                otherwise,
+                ..
+            } => self.if_statement(condition, then, otherwise.as_ref()),
+            Stmt::While {
+                condition, body, ..
+            } => self.while_statement(condition, body),
+            Stmt::For {
+                initializer,
+                condition,
+                semicolon,
+                increment,
+                close,
+                body,
+                ..
+            } => self.for_statement(
+                initializer.as_deref(),
+                condition.as_ref(),
+                semicolon,
+                increment.as_ref(),
+                close,
+                body,
+            ),
+            Stmt::Block(block) => {
+                self.begin_scope();
+                self.statements(&block.statements);
+                self.end_scope(&block.close);
+            }
+            Stmt::Error => (),
+        }
+    }
+
+    fn let_declaration(&mut self, name: &Token, value: Option<&Expr>, end: &Token) {
+        let global = self.declare_variable(name, self.variable_kind());
+
+        match value {
+            Some(value) => self.expression(value),
+            None => self.emit_byte(OpCode::Null as u8, name),
+        }
+
+        self.define_variable(global, end);
+    }
+
+    fn destructuring_let_declaration(&mut self, names: &[Token], value: &Expr, end: &Token) {
+        // a global can be redefined, but not by the pattern that defines
+        // it, locals report it as a redefinition when they're declared
+        if self.scope_depth == 0 {
+            for (i, name) in names.iter().enumerate() {
+                if names[..i].iter().any(|other| other.lexeme == name.lexeme) {
+                    let err = format!("Variable '{}' appears twice in the pattern", name.lexeme);
+                    self.error_at(name, &err);
+                }
+            }
+        }
+
+        let kind = self.variable_kind();
+        let globals: Vec<usize> = names
+            .iter()
+            .map(|name| self.declare_variable(name, kind))
+            .collect();
+
+        self.expression(value);
+
+        // unpacking leaves every element on the stack in order, which lines
+        // up with the slots of the locals declared above
+        self.emit_bytes(&[OpCode::Unpack as u8, globals.len() as u8], end);
+
+        if self.scope_depth > 0 {
+            let first = self.locals.len().saturating_sub(globals.len());
+            for i in first..self.locals.len() {
+                self.mark_initialized_at(i);
+            }
+            return;
+        }
+
+        // globals are defined from the top of the stack down
+        for global in globals.into_iter().rev() {
+            self.define_variable(global, end);
+        }
+    }
+
+    fn fun_declaration(&mut self, name: &Token, function: &parser::Function) {
+        let global = self.declare_variable(name, SymbolKind::Function);
+
+        // initialized before the body is compiled so it can call itself
+        if self.scope_depth > 0 {
+            self.mark_initialized();
+        }
+
+        self.function(function, &name.lexeme);
+
+        if self.scope_depth == 0 {
+            self.define_variable(global, &function.body.close);
+        }
+    }
+
+    /// Compiles a parameter list and body, leaving the function on the stack.
+    fn function(&mut self, function: &parser::Function, name: &str) {
+        let enclosing = self.begin_function(name);
+        self.begin_scope();
+
+        for param in function.params.iter() {
+            let global = self.declare_variable(param, SymbolKind::Parameter);
+            self.define_variable(global, param);
+        }
+
+        self.statements(&function.body.statements);
+
+        let close = &function.body.close;
+        let compiled = self.end_function(enclosing, name, function.params.len(), close);
+        let index = self.add_constant(Constant::Function(Arc::new(compiled)), close);
+        self.emit_constant_index(index, close);
+    }
+
+    /// `test "name" { body }` compiles the body into a function that only
+    /// `dynamix test` runs, a normal run skips it.
+    fn test_declaration(&mut self, keyword: &Token, name: &Token, body: &parser::Block) {
+        if self.in_function || self.scope_depth > 0 {
+            self.error_at(keyword, "Tests can only be declared at the top level");
+        }
+
+        let test = name.lexeme.trim_matches('"').to_string();
+        if self.block.tests.iter().any(|other| other.name == test) {
+            self.error_at(name, &format!("Duplicate test '{test}'"));
+        }
+
+        let enclosing = self.begin_function(&test);
+        self.begin_scope();
+
+        self.statements(&body.statements);
+
+        let function = self.end_function(enclosing, &test, 0, &body.close);
+        self.block.tests.push(TestCase {
+            name: test,
+            function: Arc::new(function),
+        });
+    }
+
+    fn begin_function(&mut self, name: &str) -> Enclosing {
+        let mut block = ByteBlock::new();
+        block.name = Some(name.to_string());
+        block.includes = self.block.includes.clone();
+
+        let enclosing = Enclosing {
+            block: std::mem::replace(&mut self.block, block),
+            locals: std::mem::replace(&mut self.locals, Stack::new(LOCALS_MAX_SIZE)),
+            scope_depth: std::mem::replace(&mut self.scope_depth, 0),
+            in_function: std::mem::replace(&mut self.in_function, true),
+            long_jumps: std::mem::take(&mut self.long_jumps),
+            string_slots: std::mem::take(&mut self.string_slots),
+        };
+
+        // slot 0 holds the function being called, it has no name to resolve
+        self.locals.push(Local {
+            name: Token {
+                lexeme: String::new(),
+                ..Token::new()
+            },
+            depth: 0,
+            kind: SymbolKind::Local,
+            used: true,
+        });
+
+        enclosing
+    }
+
+    fn end_function(
+        &mut self,
+        enclosing: Enclosing,
+        name: &str,
+        arity: usize,
+        close: &Token,
+    ) -> Function {
+        // falling off the end of the body returns null
+        self.emit_bytes(&[OpCode::Null as u8, OpCode::Return as u8], close);
+        self.widen_long_jumps(close);
+
+        // the body's scope isn't ended, its locals go with the frame
+        let locals = std::mem::replace(&mut self.locals, enclosing.locals);
+        self.warn_unused(locals.iter());
+
+        let block = std::mem::replace(&mut self.block, enclosing.block);
+        self.long_jumps = enclosing.long_jumps;
+        self.string_slots = enclosing.string_slots;
+        self.scope_depth = enclosing.scope_depth;
+        self.in_function = enclosing.in_function;
+
+        if !self.had_error && cfg!(debug_assertions) && cfg!(feature = "debug-print") {
+            Disassembler::disassemble(&block, block.name());
+        }
+
+        Function {
+            name: name.to_string(),
+            arity,
+            block: Arc::new(block),
+        }
+    }
+
+    fn expression_statement(&mut self, expr: &Expr, end: &Token) {
+        self.expression(expr);
+
+        let top_level = !self.in_function && self.scope_depth == 0;
+        if self.echo && top_level && !ends_in_assignment(expr) {
+            self.emit_byte(OpCode::Print as u8, end);
+        } else {
+            self.emit_byte(OpCode::Pop as u8, end);
+        }
+    }
+
+    fn return_statement(&mut self, keyword: &Token, value: Option<&Expr>, end: &Token) {
+        if !self.in_function {
+            self.error_at(keyword, "Can't return from top-level code");
+        }
+
+        match value {
+            Some(value) => self.expression(value),
+            None => self.emit_byte(OpCode::Null as u8, end),
+        }
+
+        self.emit_byte(OpCode::Return as u8, end);
+    }
+
+    fn if_statement(
+        &mut self,
+        condition: &Expr,
+        then: &parser::Block,
+        otherwise: Option<&parser::Block>,
+    ) {
+        self.expression(condition);
+
+        let then_jump = self.emit_jump(OpCode::Jz as u8, condition.end());
+        self.emit_byte(OpCode::Pop as u8, condition.end());
+
+        self.begin_scope();
+        self.statements(&then.statements);
+        self.end_scope(&then.close);
+
+        let else_jump = self.emit_jump(OpCode::Jmp as u8, &then.close);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop as u8, &then.close);
+
+        if let Some(otherwise) = otherwise {
+            self.begin_scope();
+            self.statements(&otherwise.statements);
+            self.end_scope(&otherwise.close);
+        }
+
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self, condition: &Expr, body: &parser::Block) {
+        let loop_start = self.block.bytes.len();
+        self.expression(condition);
+
+        let exit_jump = self.emit_jump(OpCode::Jz as u8, condition.end());
+        self.emit_byte(OpCode::Pop as u8, condition.end());
+
+        self.begin_scope();
+        self.statements(&body.statements);
+        self.end_scope(&body.close);
+
+        self.emit_loop(loop_start, &body.close);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop as u8, &body.close);
+    }
+
+    fn for_statement(
+        &mut self,
+        initializer: Option<&Stmt>,
+        condition: Option<&Expr>,
+        semicolon: &Token,
+        increment: Option<&Expr>,
+        close: &Token,
+        body: &parser::Block,
+    ) {
+        // begin scope so we can include the inline variable
+        self.begin_scope();
+        if let Some(initializer) = initializer {
+            self.statement(initializer);
+        }
+
+        let mut loop_start = self.block.bytes.len();
+        let mut exit_jump = None;
+        if let Some(condition) = condition {
+            self.expression(condition);
+
+            exit_jump = Some(self.emit_jump(OpCode::Jz as u8, semicolon));
+            self.emit_byte(OpCode::Pop as u8, semicolon);
+        }
+
+        if let Some(increment) = increment {
+            let body_jump = self.emit_jump(OpCode::Jmp as u8, semicolon);
+            let increment_start = self.block.bytes.len();
+            self.expression(increment);
+            self.emit_byte(OpCode::Pop as u8, increment.end());
+
+            self.emit_loop(loop_start, close);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        // the body gets a scope of its own, its locals are popped every
+        // iteration instead of piling up until the loop ends
+        self.begin_scope();
+        self.statements(&body.statements);
+        self.end_scope(&body.close);
+
+        self.emit_loop(loop_start, &body.close);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::Pop as u8, &body.close);
+        }
+
+        self.end_scope(&body.close);
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal { value, token } => self.literal(value, token),
+            Expr::Variable(name) => self.named_variable(name, None),
+            Expr::Assign { name, value } => self.named_variable(name, Some(value)),
+            Expr::Unary { operator, operand } => {
+                self.expression(operand);
+
+                let opcode = match operator.typ3 {
+                    TokenType::Minus => OpCode::Negate,
+                    TokenType::Bang => OpCode::Not,
+                    _ => unreachable!(),
+                };
+                self.emit_byte(opcode as u8, operand.end());
+            }
+            Expr::Binary { .. } | Expr::Logical { .. } => {
+                let (first, operations) = expr.chain();
+                self.expression(first);
+                for (operator, right) in operations {
+                    match operator.typ3 {
+                        TokenType::And => self.and(operator, right),
+                        TokenType::Or => self.or(operator, right),
+                        _ => {
+                            self.expression(right);
+                            self.binary(operator, right.end());
+                        }
+                    }
+                }
+            }
+            Expr::Grouping { expr, .. } => self.expression(expr),
+            Expr::Tuple {
+                elements, close, ..
+            } => {
+                for element in elements.iter() {
+                    self.expression(element);
+                }
+                self.emit_bytes(&[OpCode::Tuple as u8, elements.len() as u8], close);
+            }
+            Expr::Call {
+                callee,
+                args,
+                close,
+            } => {
+                self.expression(callee);
+                for arg in args.iter() {
+                    self.expression(arg);
+                }
+                self.emit_bytes(&[OpCode::Call as u8, args.len() as u8], close);
+            }
+            Expr::Invoke {
+                receiver,
+                name,
+                args,
+                close,
+            } => {
+                self.expression(receiver);
+                let index = self.identifier_constant(name);
+                for arg in args.iter() {
+                    self.expression(arg);
+                }
+                self.emit_indexed(OpCode::Invoke, OpCode::InvokeLong, index, close);
+                self.emit_byte(args.len() as u8, close);
+            }
+            Expr::Function(function) => self.function(function, "<anonymous>"),
+            Expr::Error(..) => (),
+        }
+    }
+
+    fn literal(&mut self, value: &Literal, token: &Token) {
+        match value {
+            Literal::Bool(false) => self.emit_byte(OpCode::False as u8, token),
+            Literal::Bool(true) => self.emit_byte(OpCode::True as u8, token),
+            Literal::Null => self.emit_byte(OpCode::Null as u8, token),
+            Literal::Number(value) => self.emit_constant(Constant::Number(*value), token),
+            Literal::Char(value) => self.emit_constant(Constant::Char(*value), token),
+            Literal::String(value) => {
+                let index = self.string_constant(value, token);
+                self.emit_constant_index(index, token);
+            }
+        }
+    }
+
+    fn binary(&mut self, operator: &Token, at: &Token) {
+        let bytes: &[OpCode] = match operator.typ3 {
+            TokenType::Plus => &[OpCode::Add],
+            TokenType::Minus => &[OpCode::Sub],
+            TokenType::Star => &[OpCode::Mul],
+            TokenType::StarStar => &[OpCode::Pow],
+            TokenType::Slash => &[OpCode::Div],
+            TokenType::BangEq => &[OpCode::Equal, OpCode::Not],
+            TokenType::EqEq => &[OpCode::Equal],
+            TokenType::Gt => &[OpCode::Greater],
+            TokenType::Gte => &[OpCode::Less, OpCode::Not],
+            TokenType::Lt => &[OpCode::Less],
+            TokenType::Lte => &[OpCode::Greater, OpCode::Not],
+            _ => unreachable!(),
+        };
+
+        for opcode in bytes {
+            self.emit_byte(*opcode as u8, at);
+        }
+    }
+
+    fn and(&mut self, operator: &Token, right: &Expr) {
+        let end_jump = self.emit_jump(OpCode::Jz as u8, operator);
+
+        self.emit_byte(OpCode::Pop as u8, operator);
+        self.expression(right);
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self, operator: &Token, right: &Expr) {
+        let else_jump = self.emit_jump(OpCode::Jz as u8, operator);
+        let end_jump = self.emit_jump(OpCode::Jmp as u8, operator);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop as u8, operator);
+
+        self.expression(right);
+        self.patch_jump(end_jump);
+    }
+
+    fn named_variable(&mut self, name: &Token, value: Option<&Expr>) {
+        let (get_op, set_op, arg) = match self.resolve_local(name, value.is_none()) {
+            // slots always fit in a byte, there are at most 256 locals
+            Some(slot) => (
+                (OpCode::GetLocal, OpCode::GetLocal),
+                (OpCode::SetLocal, OpCode::SetLocal),
+                slot,
+            ),
+            None => (
+                (OpCode::GetGlobal, OpCode::GetGlobalLong),
+                (OpCode::SetGlobal, OpCode::SetGlobalLong),
+                self.identifier_constant(name),
+            ),
+        };
+
+        match value {
+            Some(value) => {
+                self.expression(value);
+                self.emit_indexed(set_op.0, set_op.1, arg, value.end());
+            }
+            None => self.emit_indexed(get_op.0, get_op.1, arg, name),
+        }
+    }
+
+    fn begin_scope(&mut self) -> usize {
+        self.scope_depth += 1;
+        self.scope_depth
+    }
+
+    /// Pops the locals of the scope, on the line of `close`.
+    fn end_scope(&mut self, close: &Token) -> usize {
+        self.scope_depth -= 1;
+
+        let mut ended = Vec::new();
+        while !self.locals.is_empty()
+            && self.locals[self.locals.len() - 1].depth > self.scope_depth as isize
+        {
+            let slot = self.locals.len() - 1;
+            let end = self.block.bytes.len();
+            if let Some(info) = self
+                .block
+                .locals
+                .iter_mut()
+                .rev()
+                .find(|info| info.slot == slot && info.end == usize::MAX)
+            {
+                info.end = end;
+            }
+
+            ended.push(self.locals.remove(slot));
+            self.emit_byte(OpCode::Pop as u8, close);
+        }
+
+        self.warn_unused(ended.iter().rev());
+
+        self.scope_depth
+    }
+
+    fn identifier_constant(&mut self, name: &Token) -> usize {
+        self.string_constant(&name.lexeme, name)
+    }
+
+    /// Interns `value` and reuses its slot if the pool already holds it.
+    fn string_constant(&mut self, value: &str, at: &Token) -> usize {
+        if let Some(&index) = self.string_slots.get(value) {
+            return index;
+        }
+
+        let string = self.strings.string(value);
+        let index = self.add_constant(string, at);
+        self.string_slots.insert(value.to_string(), index);
+        index
+    }
+
+    /// The slot of the local called `name`, `read` marks it used.
+    fn resolve_local(&mut self, name: &Token, read: bool) -> Option<usize> {
+        // search from the innermost scope outwards so shadowing resolves
+        // to the most recent declaration
+        let (slot, depth) = self
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(.., local)| local.name.lexeme == name.lexeme)
+            .map(|(i, local)| (i, local.depth))?;
+
+        if depth == -1 {
+            let err = format!("variable name '{}' not allowed in initializer", name.lexeme);
+            self.error_at(name, &err);
+        }
+
+        if read {
+            self.locals[slot].used = true;
+        }
+
+        Some(slot)
+    }
+
+    fn add_local(&mut self, name: &Token, kind: SymbolKind) {
+        if self.locals.len() == u8::MAX as usize {
+            self.error_at(name, "Too many local variables in function");
+            return;
+        }
+
+        // only locals of the innermost scope can clash
+        let redefined = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth == -1 || local.depth >= self.scope_depth as isize)
+            .any(|local| local.name.lexeme == name.lexeme);
+
+        let shadowed = self
+            .locals
+            .iter()
+            .rev()
+            .find(|local| local.name.lexeme == name.lexeme)
+            .map(|local| local.name.line);
+
+        if redefined {
+            let err = format!("Redefined variable '{}' in the same scope", name.lexeme);
+            self.error_at(name, &err);
+        } else if let Some(line) = shadowed {
+            let warning = format!(
+                "Variable '{}' shadows the one declared on line {line}",
+                name.lexeme
+            );
+            self.warn_at(name, &warning);
+        }
+
+        self.locals.push(Local {
+            name: name.clone(),
+            depth: -1,
+            kind,
+            used: false,
+        });
+    }
+
+    fn variable_kind(&self) -> SymbolKind {
+        match self.scope_depth {
+            0 => SymbolKind::Global,
+            _ => SymbolKind::Local,
+        }
+    }
+
+    /// Declares `name` in the current scope, returning the constant that
+    /// names it if it's a global.
+    fn declare_variable(&mut self, name: &Token, kind: SymbolKind) -> usize {
+        self.symbols.push(Symbol {
+            name: name.lexeme.clone(),
+            kind,
+            span: Span::of(name, &self.includes),
+            depth: self.scope_depth,
+        });
+
+        if self.scope_depth == 0 {
+            return self.identifier_constant(name);
+        }
+
+        self.add_local(name, kind);
+        0
+    }
+
+    fn mark_initialized(&mut self) {
+        self.mark_initialized_at(self.locals.len() - 1);
+    }
+
+    fn mark_initialized_at(&mut self, index: usize) {
+        self.locals[index].depth = self.scope_depth as isize;
+
+        // the local becomes visible to debuggers from here until its scope ends
+        self.block.locals.push(LocalInfo {
+            name: self.locals[index].name.lexeme.clone(),
+            slot: index,
+            start: self.block.bytes.len(),
+            end: usize::MAX,
+        });
+    }
+
+    fn define_variable(&mut self, global: usize, at: &Token) {
+        if self.scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_indexed(OpCode::DefineGlobal, OpCode::DefineGlobalLong, global, at);
+    }
+
+    fn emit_byte(&mut self, byte: u8, at: &Token) {
+        self.block.push_in(byte, at.line as u32, at.file);
+    }
+
+    fn emit_bytes(&mut self, bytes: &[u8], at: &Token) {
+        for byte in bytes.iter() {
+            self.emit_byte(*byte, at);
+        }
+    }
+
+    fn emit_loop(&mut self, loop_start: usize, at: &Token) {
+        let jump = self.block.bytes.len();
+        self.emit_byte(OpCode::Loop as u8, at);
+
+        let offset = (self.block.bytes.len() - loop_start) + 2;
+        if offset > u16::MAX as usize {
+            self.long_jumps.insert(jump, loop_start);
+        }
+
+        self.emit_bytes(&encode_short(offset.min(u16::MAX as usize) as u16), at);
+    }
+
+    fn emit_jump(&mut self, instruction: u8, at: &Token) -> usize {
+        self.emit_bytes(&[instruction, 0xff, 0xff], at);
+        self.block.bytes.len() - 2
+    }
+
+    /// Adds `constant` to the pool, operands of `OP_CONSTANT_LONG` address
+    /// up to 65536 constants.
+    fn add_constant(&mut self, constant: Constant, at: &Token) -> usize {
+        let index = self.block.push_constant(constant);
+
+        if index > u16::MAX as usize {
+            self.error_at(at, "Too many constants in one block");
+            return 0;
+        }
+
+        index
+    }
+
+    fn emit_constant(&mut self, constant: Constant, at: &Token) {
+        let index = self.add_constant(constant, at);
+        self.emit_constant_index(index, at);
+    }
+
+    fn emit_constant_index(&mut self, index: usize, at: &Token) {
+        self.emit_indexed(OpCode::Constant, OpCode::ConstantLong, index, at);
+    }
+
+    /// Emits `short` with a one byte operand, or `long` with a two byte one
+    /// when `index` doesn't fit in a byte.
+    fn emit_indexed(&mut self, short: OpCode, long: OpCode, index: usize, at: &Token) {
+        match u8::try_from(index) {
+            Ok(index) => self.emit_bytes(&[short as u8, index], at),
+            Err(..) => {
+                self.emit_byte(long as u8, at);
+                self.emit_bytes(&encode_short(index as u16), at);
+            }
+        }
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        let jump = self.block.bytes.len() - offset - 2;
+
+        // the operand is left as it is, `widen_long_jumps` makes room
+        if jump > u16::MAX as usize {
+            self.long_jumps.insert(offset - 1, self.block.bytes.len());
+            return;
+        }
+
+        let [high, low] = encode_short(jump as u16);
+        self.block.bytes[offset] = high;
+        self.block.bytes[offset + 1] = low;
+    }
+
+    /// Lays the block out again with long jumps for the jumps that didn't
+    /// fit their two byte operand, once the block is complete and nothing
+    /// holds on to its offsets anymore.
+    fn widen_long_jumps(&mut self, end: &Token) {
+        if self.long_jumps.is_empty() || self.had_error {
+            return;
+        }
+
+        let long_jumps = std::mem::take(&mut self.long_jumps);
+        match optimizer::widen_jumps(&self.block, &long_jumps) {
+            Some(block) => self.block = block,
+            None => self.error_at(
+                end,
+                "Too much code to jump over, extract it into a function",
+            ),
+        }
+    }
+
+    /// Reports the locals in `locals` that were never read, parameters and
+    /// names starting with '_' are left alone.
+    fn warn_unused<'l>(&mut self, locals: impl Iterator<Item = &'l Local>) {
+        for local in locals {
+            let name = &local.name.lexeme;
+            // macros rename the locals they declare to 'name@id'
+            if local.used
+                || local.kind == SymbolKind::Parameter
+                || name.starts_with('_')
+                || name.contains('@')
+            {
+                continue;
+            }
+
+            let warning = match local.kind {
+                SymbolKind::Function => format!("Unused function '{name}'"),
+                _ => format!("Unused variable '{name}'"),
+            };
+            self.warn_at(&local.name, &warning);
+        }
+    }
+
+    /// Reports something that compiles but is likely a mistake, warnings
+    /// don't fail the compilation.
+    fn warn_at(&mut self, token: &Token, msg: &str) {
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            message: msg.to_string(),
+            span: Span::of(token, &self.includes),
+        });
+    }
+
+    fn error_at(&mut self, token: &Token, msg: &str) {
+        if self.panic_mode {
+            return;
+        } else {
+            self.panic_mode = true;
+        }
+
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message: msg.to_string(),
+            span: Span::of(token, &self.includes),
+        });
+
+        self.had_error = true;
+    }
+}
+
+impl Default for CodeGen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether the value of `expr` is the value of an assignment, which echoing
+/// leaves out like the REPL does.
+fn ends_in_assignment(expr: &Expr) -> bool {
+    match expr {
+        Expr::Assign { .. } => true,
+        Expr::Grouping { expr, .. } => ends_in_assignment(expr),
+        Expr::Logical { right, .. } => ends_in_assignment(right),
+        _ => false,
+    }
+}
+
+/// Whether running `statement` returns on every path through it.
+fn always_returns(statement: &Stmt) -> bool {
+    match statement {
+        Stmt::Return { .. } => true,
+        Stmt::Block(block) => block.statements.iter().any(always_returns),
+        Stmt::If {
+            then,
+            otherwise: Some(otherwise),
+            ..
+        } => {
+            then.statements.iter().any(always_returns)
+                && otherwise.statements.iter().any(always_returns)
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,112 @@
+//! What `Compiler::compile` hands back: the bytecode and everything tools
+//! like the debugger or an editor integration need to make sense of it.
+
+use crate::{
+    byte_block::{ByteBlock, OpCode},
+    constant::Constant,
+    diagnostic::{Diagnostic, Span},
+};
+
+#[derive(Debug, Clone)]
+pub struct CompileOutput {
+    /// Only worth running if `succeeded`.
+    pub block: ByteBlock,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Every variable, parameter and function declared, in source order.
+    pub symbols: Vec<Symbol>,
+    /// Where each line's code starts, in the script and every function in it.
+    pub line_map: Vec<LineEntry>,
+}
+
+impl CompileOutput {
+    /// Whether compiling reported no errors, warnings don't count.
+    pub fn succeeded(&self) -> bool {
+        !self.diagnostics.iter().any(Diagnostic::is_error)
+    }
+
+    /// The diagnostics that don't stop the block from running.
+    pub fn warnings(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.diagnostics
+            .iter()
+            .filter(|diagnostic| !diagnostic.is_error())
+    }
+
+    /// The entries for `line` of the main file, one per block with code on it.
+    pub fn offsets_of(&self, line: u32) -> impl Iterator<Item = &LineEntry> {
+        self.line_map
+            .iter()
+            .filter(move |entry| entry.file.is_none() && entry.line == line)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Global,
+    Local,
+    Parameter,
+    Function,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// Where it's declared, `near` is the name.
+    pub span: Span,
+    /// 0 at the top level, locals count the blocks they're nested in.
+    pub depth: usize,
+}
+
+/// The first instruction compiled from a line, in the block named `block`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineEntry {
+    pub block: String,
+    /// The included file the line is in, `None` for the main file.
+    pub file: Option<String>,
+    pub line: u32,
+    pub offset: usize,
+}
+
+/// The line map of `block` followed by those of the functions and tests
+/// declared in it, depth first like `Disassembler::disassemble_all`.
+pub fn line_map(block: &ByteBlock) -> Vec<LineEntry> {
+    let mut entries = Vec::new();
+    map_lines(block, &mut entries);
+    entries
+}
+
+fn map_lines(block: &ByteBlock, entries: &mut Vec<LineEntry>) {
+    let mut previous = None;
+    let mut offset = 0;
+    while offset < block.bytes.len() {
+        let location = (block.files[offset], block.lines[offset]);
+        if previous != Some(location) {
+            let (file, line) = location;
+            entries.push(LineEntry {
+                block: block.name().to_string(),
+                file: (file as usize)
+                    .checked_sub(1)
+                    .and_then(|file| block.includes.get(file))
+                    .cloned(),
+                line,
+                offset,
+            });
+            previous = Some(location);
+        }
+
+        offset += match OpCode::from(block.bytes[offset]) {
+            Ok(opcode) => opcode.width(),
+            Err(..) => 1,
+        };
+    }
+
+    for (_, constant) in block.constants() {
+        if let Constant::Function(function) = constant {
+            map_lines(&function.block, entries);
+        }
+    }
+
+    for test in block.tests.iter() {
+        map_lines(&test.function.block, entries);
+    }
+}
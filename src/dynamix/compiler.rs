@@ -1,1064 +1,186 @@
+//! Compiles source to bytecode, the `parser` turns it into a syntax tree
+//! which `codegen` lowers to a `ByteBlock`, optionally `optimizer`d.
+
 use crate::{
-    byte_block::{ByteBlock, OpCode},
-    constant::{Constant, Object, ObjectType},
+    byte_block::ByteBlock,
+    codegen::CodeGen,
+    compile_output::{self, CompileOutput, Symbol},
+    diagnostic::Diagnostic,
     disassembler::Disassembler,
-    lexer::{Lexer, Token, TokenType},
-    stack::Stack,
+    macros::Macro,
+    optimizer,
+    parser::{Parser, Program},
 };
 
-use std::collections::HashMap;
-
-struct Parser {
-    cursor: Token,
-    previous: Token,
-    had_error: bool,
-    panic_mode: bool,
-}
+use std::{collections::HashMap, sync::Arc};
 
-#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq)]
-enum Precedence {
-    None,
-    Assignment,
-    Or,
-    And,
-    Equality,
-    Comparison,
-    Term,
-    Factor,
-    Unary,
-    Call,
-    Atom,
+/// Rewrites the syntax tree between parsing and codegen, e.g. to
+/// instrument a script or lower a DSL built on its syntax. Any
+/// `Fn(&mut Program)` is a pass.
+pub trait CompilerPass: Send + Sync {
+    fn run(&self, program: &mut Program);
 }
 
-impl Precedence {
-    fn from_u32(value: u32) -> Self {
-        use Precedence::*;
-        match value {
-            0 => None,
-            1 => Assignment,
-            2 => Or,
-            3 => And,
-            4 => Equality,
-            5 => Comparison,
-            6 => Term,
-            7 => Factor,
-            8 => Unary,
-            9 => Call,
-            10 => Atom,
-            _ => panic!("Unknown value: {value}"),
-        }
+impl<F: Fn(&mut Program) + Send + Sync> CompilerPass for F {
+    fn run(&self, program: &mut Program) {
+        self(program)
     }
 }
 
-type ParseFn<'a> = fn(&mut Compiler<'a>, bool);
-
-struct ParseRule<'a> {
-    prefix: Option<Box<ParseFn<'a>>>,
-    infix: Option<Box<ParseFn<'a>>>,
-    precedence: Precedence,
-}
-
-#[derive(Debug, Clone)]
-struct Local {
-    name: Token,
-    depth: isize,
-}
-
-const LOCALS_MAX_SIZE: usize = 256;
-
 pub struct Compiler<'a> {
-    lexer: Lexer<'a>,
-    parser: Parser,
+    source: &'a str,
+    defines: Vec<String>,
     block: ByteBlock,
-    locals: Stack<Local>,
-    scope_depth: usize,
-    parse_rules: HashMap<u32, ParseRule<'a>>,
+    macros: HashMap<String, Macro>,
+    // path of the main file, includes are resolved relative to it
+    path: Option<String>,
+    diagnostics: Vec<Diagnostic>,
+    // prints top level expression statements instead of popping them
+    echo: bool,
+    // lets the last statement leave out its ';', for REPL lines
+    optional_final_semicolon: bool,
+    symbols: Vec<Symbol>,
+    optimize: bool,
+    passes: Vec<Arc<dyn CompilerPass>>,
 }
 
 impl<'a> Compiler<'a> {
     pub fn new(source: &'a str) -> Self {
         Self {
-            lexer: Lexer::new(source),
-            parser: Parser {
-                cursor: Token::new(),
-                previous: Token::new(),
-                had_error: false,
-                panic_mode: false,
-            },
+            source,
+            defines: Vec::new(),
             block: ByteBlock::new(),
-            locals: Stack::new(LOCALS_MAX_SIZE),
-            scope_depth: 0,
-            parse_rules: vec![
-                (
-                    TokenType::LParen,
-                    ParseRule {
-                        prefix: Some(Box::new(Compiler::grouping)),
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::RParen,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::LCurly,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::RCurly,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::Comma,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::Dot,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::Minus,
-                    ParseRule {
-                        prefix: Some(Box::new(Compiler::unary)),
-                        infix: Some(Box::new(Compiler::binary)),
-                        precedence: Precedence::Term,
-                    },
-                ),
-                (
-                    TokenType::Plus,
-                    ParseRule {
-                        prefix: None,
-                        infix: Some(Box::new(Compiler::binary)),
-                        precedence: Precedence::Term,
-                    },
-                ),
-                (
-                    TokenType::Semicolon,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::Slash,
-                    ParseRule {
-                        prefix: None,
-                        infix: Some(Box::new(Compiler::binary)),
-                        precedence: Precedence::Factor,
-                    },
-                ),
-                (
-                    TokenType::Star,
-                    ParseRule {
-                        prefix: None,
-                        infix: Some(Box::new(Compiler::binary)),
-                        precedence: Precedence::Factor,
-                    },
-                ),
-                (
-                    TokenType::Bang,
-                    ParseRule {
-                        prefix: Some(Box::new(Compiler::unary)),
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::BangEq,
-                    ParseRule {
-                        prefix: None,
-                        infix: Some(Box::new(Compiler::binary)),
-                        precedence: Precedence::Equality,
-                    },
-                ),
-                (
-                    TokenType::Eq,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::EqEq,
-                    ParseRule {
-                        prefix: None,
-                        infix: Some(Box::new(Compiler::binary)),
-                        precedence: Precedence::Equality,
-                    },
-                ),
-                (
-                    TokenType::Gt,
-                    ParseRule {
-                        prefix: None,
-                        infix: Some(Box::new(Compiler::binary)),
-                        precedence: Precedence::Comparison,
-                    },
-                ),
-                (
-                    TokenType::Gte,
-                    ParseRule {
-                        prefix: None,
-                        infix: Some(Box::new(Compiler::binary)),
-                        precedence: Precedence::Comparison,
-                    },
-                ),
-                (
-                    TokenType::Lt,
-                    ParseRule {
-                        prefix: None,
-                        infix: Some(Box::new(Compiler::binary)),
-                        precedence: Precedence::Comparison,
-                    },
-                ),
-                (
-                    TokenType::Lte,
-                    ParseRule {
-                        prefix: None,
-                        infix: Some(Box::new(Compiler::binary)),
-                        precedence: Precedence::Comparison,
-                    },
-                ),
-                (
-                    TokenType::Ident,
-                    ParseRule {
-                        prefix: Some(Box::new(Compiler::variable)),
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::String,
-                    ParseRule {
-                        prefix: Some(Box::new(Compiler::string)),
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::Number,
-                    ParseRule {
-                        prefix: Some(Box::new(Compiler::number)),
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::Char,
-                    ParseRule {
-                        prefix: Some(Box::new(Compiler::character)),
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::And,
-                    ParseRule {
-                        prefix: None,
-                        infix: Some(Box::new(Compiler::and)),
-                        precedence: Precedence::And,
-                    },
-                ),
-                (
-                    TokenType::Struct,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::Else,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::False,
-                    ParseRule {
-                        prefix: Some(Box::new(Compiler::literal)),
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::For,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::Fun,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::If,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::Null,
-                    ParseRule {
-                        prefix: Some(Box::new(Compiler::literal)),
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::Or,
-                    ParseRule {
-                        prefix: None,
-                        infix: Some(Box::new(Compiler::or)),
-                        precedence: Precedence::Or,
-                    },
-                ),
-                (
-                    TokenType::Print,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::Return,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::Super,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::SSelf,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::True,
-                    ParseRule {
-                        prefix: Some(Box::new(Compiler::literal)),
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::Let,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::While,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::Error,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-                (
-                    TokenType::Eof,
-                    ParseRule {
-                        prefix: None,
-                        infix: None,
-                        precedence: Precedence::None,
-                    },
-                ),
-            ]
-            .into_iter()
-            .map(|(k, v)| (k as u32, v))
-            .collect(),
-        }
-    }
-
-    pub fn compile(&mut self) -> bool {
-        self.advance();
-
-        while !self.matches(TokenType::Eof) {
-            self.declaration();
-        }
-
-        self.emit_return();
-        self.consume(TokenType::Eof, "Expected end of expression".to_string());
-
-        if !self.parser.had_error && cfg!(debug_assertions) && cfg!(feature = "debug-print") {
-            Disassembler::disassemble(&self.block, "code");
-        }
-
-        !self.parser.had_error
-    }
-
-    pub fn byte_code(&self) -> &ByteBlock {
-        &self.block
-    }
-
-    fn advance(&mut self) {
-        self.parser.previous = self.parser.cursor.clone();
-
-        loop {
-            self.parser.cursor = self.lexer.next().unwrap();
-            if self.parser.cursor.typ3 != TokenType::Error {
-                break;
-            }
-
-            self.error_at_cursor(&self.parser.cursor.lexeme.clone());
-        }
-    }
-
-    fn expression(&mut self) {
-        self.parse_precedence(Precedence::Assignment)
-    }
-
-    fn block(&mut self) {
-        while !self.check(TokenType::RCurly) && !self.check(TokenType::Eof) {
-            self.declaration();
-        }
-
-        self.consume(TokenType::RCurly, "Expected '}' after block".to_string());
-    }
-
-    fn let_declaration(&mut self) {
-        let global = self.parse_variable("Expected variable name".to_string());
-
-        if self.matches(TokenType::Eq) {
-            self.expression();
-        } else {
-            self.emit_byte(OpCode::Null as u8);
-        }
-
-        self.consume(
-            TokenType::Semicolon,
-            "Expected ';' after expression".to_string(),
-        );
-
-        self.define_variable(global);
-    }
-
-    fn print_statement(&mut self) {
-        self.expression();
-        self.consume(
-            TokenType::Semicolon,
-            "Expected ';' after expression".to_string(),
-        );
-
-        self.emit_byte(OpCode::Print as u8);
-    }
-
-    fn expression_statement(&mut self) {
-        self.expression();
-        self.consume(
-            TokenType::Semicolon,
-            "Expected ';' after expression".to_string(),
-        );
-
-        self.emit_byte(OpCode::Pop as u8);
-    }
-
-    fn if_statement(&mut self) {
-        self.expression();
-
-        let then_jump = self.emit_jump(OpCode::Jz as u8);
-        self.emit_byte(OpCode::Pop as u8);
-
-        self.begin_scope();
-        self.consume(TokenType::LCurly, "Expected '{' after if".to_string());
-        self.block();
-        self.end_scope();
-
-        let else_jump = self.emit_jump(OpCode::Jmp as u8);
-
-        self.patch_jump(then_jump);
-        self.emit_byte(OpCode::Pop as u8);
-
-        if self.matches(TokenType::Else) {
-            self.consume(TokenType::LCurly, "Expected '}' after block".to_string());
-            self.block();
-        }
-
-        self.patch_jump(else_jump);
-    }
-
-    fn while_statement(&mut self) {
-        let loop_start = self.block.bytes.len();
-        self.expression();
-
-        let exit_jump = self.emit_jump(OpCode::Jz as u8);
-        self.emit_byte(OpCode::Pop as u8);
-
-        self.begin_scope();
-        self.consume(TokenType::LCurly, "Expected '{' after while".to_string());
-        self.block();
-        self.end_scope();
-
-        self.emit_loop(loop_start as u8);
-
-        self.patch_jump(exit_jump);
-        self.emit_byte(OpCode::Pop as u8);
-    }
-
-    fn for_statement(&mut self) {
-        // begin scope so we can include the inline variable
-        self.begin_scope();
-        self.consume(TokenType::LParen, "Expected '(' after for".to_string());
-        if self.matches(TokenType::Semicolon) {
-            // no initializer
-        } else if self.matches(TokenType::Let) {
-            self.let_declaration();
-        } else {
-            self.expression_statement();
-        }
-        
-        let mut loop_start = self.block.bytes.len();
-        let mut exit_jump = -1;
-        if !self.matches(TokenType::Semicolon) {
-            self.expression();
-            self.consume(TokenType::Semicolon, "Expected ';' after expression".to_string());
-
-            exit_jump = self.emit_jump(OpCode::Jz as u8) as isize;
-            self.emit_byte(OpCode::Pop as u8);
-        }
-
-        if !self.matches(TokenType::RParen) {
-            let body_jump = self.emit_jump(OpCode::Jmp as u8);
-            let increment_start = self.block.bytes.len();
-            self.expression();
-            self.emit_byte(OpCode::Pop as u8);
-            self.consume(TokenType::RParen, "Expected ')' after for statement".to_string());
-        
-            self.emit_loop(loop_start as u8);
-            loop_start = increment_start;
-            self.patch_jump(body_jump);
-        }
-
-        self.consume(TokenType::LCurly, "Expected '{' after for statement".to_string());
-        self.block();
-        
-        self.emit_loop(loop_start as u8);
-        
-        if exit_jump != -1 {
-            self.patch_jump(exit_jump as usize);
-            self.emit_byte(OpCode::Pop as u8);
-        }
-
-        self.end_scope();
-    }
-
-    fn declaration(&mut self) {
-        if self.matches(TokenType::Let) {
-            self.let_declaration();
-        } else {
-            self.statement();
-        }
-
-        if self.parser.panic_mode {
-            self.synchronize();
-        }
-    }
-
-    fn statement(&mut self) {
-        if self.matches(TokenType::Print) {
-            self.print_statement();
-        } else if self.matches(TokenType::If) {
-            self.if_statement();
-        } else if self.matches(TokenType::While) {
-            self.while_statement();
-        } else if self.matches(TokenType::For) {
-            self.for_statement();
-        } else if self.matches(TokenType::LCurly) {
-            self.begin_scope();
-            self.block();
-            self.end_scope();
-        } else {
-            self.expression_statement();
-        }
-    }
-
-    fn synchronize(&mut self) {
-        self.parser.panic_mode = false;
-
-        while self.parser.cursor.typ3 != TokenType::Eof {
-            if let TokenType::Semicolon = self.parser.previous.typ3 {
-                break;
-            }
-
-            use TokenType::*;
-            match self.parser.cursor.typ3 {
-                Struct | Fun | For | If | While | Let | Print | Return => break,
-                _ => (),
-            }
-
-            self.advance();
-        }
-    }
-
-    fn grouping(&mut self, _can_assign: bool) {
-        self.expression();
-        self.consume(
-            TokenType::RParen,
-            "Expected ')' after expression".to_string(),
-        );
-    }
-
-    fn binary(&mut self, _can_assign: bool) {
-        match self.get_operator_precedence() {
-            Some((precedence, operator)) => {
-                self.parse_precedence(Precedence::from_u32(precedence as u32 + 1));
-
-                match operator {
-                    TokenType::Plus => self.emit_byte(OpCode::Add as u8),
-                    TokenType::Minus => self.emit_byte(OpCode::Sub as u8),
-                    TokenType::Star => self.emit_byte(OpCode::Mul as u8),
-                    TokenType::Slash => self.emit_byte(OpCode::Div as u8),
-                    TokenType::BangEq => {
-                        self.emit_bytes(vec![OpCode::Equal as u8, OpCode::Not as u8])
-                    }
-                    TokenType::EqEq => self.emit_byte(OpCode::Equal as u8),
-                    TokenType::Gt => self.emit_byte(OpCode::Greater as u8),
-                    TokenType::Gte => self.emit_bytes(vec![OpCode::Less as u8, OpCode::Not as u8]),
-                    TokenType::Lt => self.emit_byte(OpCode::Less as u8),
-                    TokenType::Lte => {
-                        self.emit_bytes(vec![OpCode::Greater as u8, OpCode::Not as u8])
-                    }
-                    _ => {
-                        let err = format!(
-                            "Expected operator '+-*/' found '{}'",
-                            self.parser.previous.lexeme
-                        );
-
-                        self.error(&err);
-                    }
-                }
-            }
-            None => {
-                let err = format!(
-                    "Expected operator '+-*/' found '{}'",
-                    self.parser.previous.lexeme
-                );
-
-                self.error(&err);
-            }
-        }
-    }
-
-    fn literal(&mut self, _can_assign: bool) {
-        match self.parser.previous.typ3 {
-            TokenType::False => self.emit_byte(OpCode::False as u8),
-            TokenType::True => self.emit_byte(OpCode::True as u8),
-            TokenType::Null => self.emit_byte(OpCode::Null as u8),
-            _ => unreachable!(),
+            macros: HashMap::new(),
+            path: None,
+            diagnostics: Vec::new(),
+            echo: false,
+            optional_final_semicolon: false,
+            symbols: Vec::new(),
+            optimize: false,
+            passes: Vec::new(),
         }
     }
 
-    fn begin_scope(&mut self) -> usize {
-        self.scope_depth += 1;
-        self.scope_depth
+    /// Names that `#if NAME` regions test for.
+    pub fn with_defines(mut self, defines: &[String]) -> Self {
+        self.defines = defines.to_vec();
+        self
     }
 
-    fn end_scope(&mut self) -> usize {
-        self.scope_depth -= 1;
-
-        while !self.locals.is_empty()
-            && self.locals[self.locals.len() - 1].depth > self.scope_depth as isize
-        {
-            self.locals.remove(self.locals.len() - 1);
-            self.emit_byte(OpCode::Pop as u8);
-        }
-
-        self.scope_depth
+    /// Sets the path of the source being compiled, `#include` paths are
+    /// resolved relative to the directory it's in.
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
     }
 
-    fn named_variable(&mut self, name: Token, can_assign: bool) {
-        let get_op;
-        let set_op;
-        let mut arg = self.resolve_local(&name);
-
-        if arg != -1 {
-            get_op = OpCode::GetLocal;
-            set_op = OpCode::SetLocal;
-        } else {
-            arg = self.identifier_constant(&name) as i32;
-            get_op = OpCode::GetGlobal;
-            set_op = OpCode::SetGlobal;
-        }
-
-        if can_assign && self.matches(TokenType::Eq) {
-            self.expression();
-            self.emit_bytes(vec![set_op as u8, arg as u8]);
-        } else {
-            self.emit_bytes(vec![get_op as u8, arg as u8]);
-        }
+    /// Prints the value of every expression statement at the top level, like
+    /// the REPL does, instead of discarding it. Assignments aren't printed.
+    pub fn with_echo(mut self, enabled: bool) -> Self {
+        self.echo = enabled;
+        self
     }
 
-    fn variable(&mut self, can_assign: bool) {
-        self.named_variable(self.parser.previous.clone(), can_assign);
+    /// Runs the `optimizer` over the compiled block. Off by default so the
+    /// debugger sees the code as it was written.
+    pub fn with_optimizations(mut self, enabled: bool) -> Self {
+        self.optimize = enabled;
+        self
     }
 
-    fn string(&mut self, _can_assign: bool) {
-        let mut value = self.parser.previous.lexeme.as_bytes().to_owned();
-
-        // remove quotes from conversion
-        value.remove(0);
-        value.remove(value.len() - 1);
-
-        self.emit_constant(Constant::Obj(Object {
-            typ3: ObjectType::String,
-            bytes: value,
-        }));
+    /// Runs `passes` over the syntax tree in order before it's lowered.
+    /// Scripts with syntax errors are lowered as they were parsed.
+    pub fn with_passes(mut self, passes: &[Arc<dyn CompilerPass>]) -> Self {
+        self.passes = passes.to_vec();
+        self
     }
 
-    fn number(&mut self, _can_assign: bool) {
-        let mut lexeme = self.parser.previous.lexeme.clone();
-        if lexeme.contains('_') {
-            lexeme = lexeme.replace('_', "");
-        }
-        if lexeme.contains('\'') {
-            lexeme = lexeme.replace('\'', "");
-        }
-
-        let value = lexeme.parse::<f64>().unwrap();
-        self.emit_constant(Constant::Number(value));
-    }
-
-    fn character(&mut self, _can_assign: bool) {
-        let value = self.parser.previous.lexeme.parse::<char>().unwrap();
-        self.emit_constant(Constant::Char(value));
-    }
-
-    fn unary(&mut self, _can_assign: bool) {
-        let operator = self.parser.previous.typ3;
-
-        // compile operand
-        self.parse_precedence(Precedence::Unary);
-
-        if let TokenType::Minus = operator {
-            self.emit_byte(OpCode::Negate as u8);
-        } else if let TokenType::Bang = operator {
-            self.emit_byte(OpCode::Not as u8);
-        } else {
-            let err = format!(
-                "Expected unary operator '-' or '!' found '{}'",
-                self.parser.previous.lexeme
-            );
-
-            self.error(&err);
-        }
-    }
-
-    fn parse_precedence(&mut self, precedence: Precedence) {
-        self.advance();
-
-        let can_assign = precedence <= Precedence::Assignment;
-
-        if let Some(rule) = self.parse_rules.get(&(self.parser.previous.typ3 as u32)) {
-            match rule.prefix.clone() {
-                Some(func) => func(self, can_assign),
-                None => {
-                    let err = format!(
-                        "Expected expression found '{}'",
-                        self.parser.previous.lexeme
-                    );
-                    self.error(&err);
-                }
-            }
-        }
-
-        while let Some(rule) = self.parse_rules.get(&(self.parser.cursor.typ3 as u32)) {
-            if precedence <= rule.precedence {
-                self.advance();
-
-                if let Some(rule) = self.parse_rules.get(&(self.parser.previous.typ3 as u32)) {
-                    match rule.infix.clone() {
-                        Some(func) => func(self, can_assign),
-                        None => break,
-                    }
-                }
-            } else {
-                break;
-            }
-        }
-
-        if can_assign && self.matches(TokenType::Eq) {
-            self.error(&"Invalid assignment target".to_string());
-        }
+    /// Accepts a last statement without its ';', so `print 1 + 2` can be
+    /// typed at the prompt. Files are compiled without it.
+    pub fn with_optional_final_semicolon(mut self, enabled: bool) -> Self {
+        self.optional_final_semicolon = enabled;
+        self
     }
 
-    fn identifier_constant(&mut self, name: &Token) -> u8 {
-        self.make_constant(Constant::Obj(Object {
-            typ3: ObjectType::String,
-            bytes: name.lexeme.bytes().collect(),
-        }))
+    /// Starts with the macros an earlier compilation defined, e.g. on a
+    /// previous REPL line.
+    pub fn with_macros(mut self, macros: HashMap<String, Macro>) -> Self {
+        self.macros = macros;
+        self
     }
 
-    fn identifiers_equal(&self, name: &Token, token: &Token) -> bool {
-        if name.lexeme.len() != token.lexeme.len() {
-            return false;
-        }
-
-        name.lexeme == token.lexeme
+    /// Every macro defined so far, those passed to `with_macros` included.
+    pub fn macros(&self) -> &HashMap<String, Macro> {
+        &self.macros
     }
 
-    fn resolve_local(&mut self, name: &Token) -> i32 {
-        for (i, local) in self.locals.clone().enumerate() {
-            if self.identifiers_equal(name, &local.name) {
-                if local.depth == -1 {
-                    let err = format!("variable name '{}' not allowed in initializer", name.lexeme);
-                    self.error(&err);
-                }
-                return i as i32;
-            }
-        }
-
-        -1
+    /// Names the compiled block, see `ByteBlock::name`.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.block.name = Some(name.to_string());
+        self
     }
 
-    fn add_local(&mut self, name: &Token) {
-        if self.locals.len() == u8::MAX as usize {
-            self.error(&"Too many local variables in function".to_string());
-            return;
+    /// Compiles the source, `CompileOutput::succeeded` tells whether the
+    /// block can be run. The block and diagnostics stay available through
+    /// `byte_code` and `diagnostics` afterwards.
+    pub fn compile(&mut self) -> CompileOutput {
+        let mut parser = Parser::new(self.source)
+            .with_defines(&self.defines)
+            .with_optional_final_semicolon(self.optional_final_semicolon)
+            .with_macros(std::mem::take(&mut self.macros));
+        if let Some(path) = &self.path {
+            parser = parser.with_path(path);
         }
 
-        for local in self.locals.clone().by_ref() {
-            if local.depth != -1 && local.depth < self.scope_depth as isize {
-                break;
-            }
+        let mut program = parser.parse();
+        self.macros = parser.macros().clone();
 
-            if self.identifiers_equal(name, &local.name) {
-                let err = format!("Redefined variable '{}' in the same scope", name.lexeme);
-                self.error(&err);
+        if !parser.diagnostics().iter().any(Diagnostic::is_error) {
+            for pass in &self.passes {
+                pass.run(&mut program);
             }
         }
 
-        let local = Local {
-            name: name.clone(),
-            depth: -1,
-        };
-
-        self.locals.push(local);
-    }
-
-    fn declare_variable(&mut self) {
-        if self.scope_depth == 0 {
-            return;
-        }
-
-        let name = self.parser.previous.clone();
-        self.add_local(&name);
-    }
-
-    fn parse_variable(&mut self, error: String) -> u8 {
-        self.consume(TokenType::Ident, error);
-
-        self.declare_variable();
-        if self.scope_depth > 0 {
-            return 0;
-        }
-
-        self.identifier_constant(&self.parser.previous.clone())
-    }
-
-    fn mark_initialized(&mut self) {
-        let index = self.locals.len() - 1;
-        self.locals[index].depth = self.scope_depth as isize;
-    }
-
-    fn define_variable(&mut self, global: u8) {
-        if self.scope_depth > 0 {
-            self.mark_initialized();
-            return;
-        }
-
-        self.emit_bytes(vec![OpCode::DefineGlobal as u8, global]);
-    }
-
-    fn and(&mut self, _can_assign: bool) {
-        let end_jump = self.emit_jump(OpCode::Jz as u8);
-
-        self.emit_byte(OpCode::Pop as u8);
-        self.parse_precedence(Precedence::And);
-        self.patch_jump(end_jump);
-    }
-
-    fn or(&mut self, _can_assign: bool) {
-        let else_jump = self.emit_jump(OpCode::Jz as u8);
-        let end_jump = self.emit_jump(OpCode::Jmp as u8);
-
-        self.patch_jump(else_jump);
-        self.emit_byte(OpCode::Pop as u8);
-
-        self.parse_precedence(Precedence::Or);
-        self.patch_jump(end_jump);
-    }
-
-    fn consume(&mut self, typ3: TokenType, msg: String) {
-        if typ3 == self.parser.cursor.typ3 {
-            self.advance();
-            return;
-        }
-
-        self.error_at_cursor(&msg);
-    }
-
-    fn matches(&mut self, typ3: TokenType) -> bool {
-        if !self.check(typ3) {
-            return false;
-        }
-
-        self.advance();
-        true
-    }
-
-    fn check(&self, typ3: TokenType) -> bool {
-        self.parser.cursor.typ3 == typ3
-    }
-
-    fn get_operator_precedence(&self) -> Option<(Precedence, TokenType)> {
-        let operator = self.parser.previous.typ3;
-        self.parse_rules
-            .get(&(operator as u32))
-            .map(|rule| (rule.precedence, operator))
-    }
-
-    fn emit_byte(&mut self, byte: u8) {
-        self.block.push(byte, self.parser.previous.line as u32);
-    }
-
-    fn emit_bytes(&mut self, bytes: Vec<u8>) {
-        for byte in bytes.iter() {
-            self.emit_byte(*byte);
-        }
-    }
+        // names are resolved even in scripts that didn't parse, so their
+        // errors are reported along with the syntax errors
+        let mut codegen = CodeGen::new()
+            .with_echo(self.echo)
+            .with_name(self.block.name.clone());
+        codegen.generate(&program);
 
-    fn emit_loop(&mut self, loop_start: u8) {
-        self.emit_byte(OpCode::Loop as u8);
+        self.diagnostics = parser.diagnostics().to_vec();
+        self.diagnostics.extend_from_slice(codegen.diagnostics());
+        self.symbols = codegen.symbols().to_vec();
+        self.block = codegen.into_byte_code();
 
-        let offset = (self.block.bytes.len() - loop_start as usize) + 2;
-        if offset > u16::MAX as usize {
-            self.error(&"Loop body too large, extract it into a local function".to_string());
+        let succeeded = !self.diagnostics.iter().any(Diagnostic::is_error);
+        if self.optimize && succeeded {
+            self.block = optimizer::optimize(&self.block);
         }
 
-        self.emit_byte((offset >> 8) as u8 & 0xff);
-        self.emit_byte(offset as u8 & 0xff);
-    }
-
-    fn emit_jump(&mut self, instruction: u8) -> usize {
-        self.emit_byte(instruction);
-        self.emit_byte(0xff);
-        self.emit_byte(0xff);
-        self.block.bytes.len() - 2
-    }
-
-    fn emit_return(&mut self) {
-        self.emit_byte(OpCode::Return as u8)
-    }
-
-    fn make_constant(&mut self, constant: Constant) -> u8 {
-        let index = self.block.push_constant(constant);
-
-        if index == u8::MAX {
-            self.error(&"Too many constants in one block".to_string());
-            return 0;
+        if succeeded && cfg!(debug_assertions) && cfg!(feature = "debug-print") {
+            Disassembler::disassemble(&self.block, self.block.name());
         }
 
-        index
-    }
-
-    fn emit_constant(&mut self, constant: Constant) {
-        let index = self.make_constant(constant);
-        self.emit_bytes(vec![OpCode::Constant as u8, index]);
+        self.output()
     }
 
-    fn patch_jump(&mut self, offset: usize) {
-        let jump = self.block.bytes.len() - offset - 2;
-
-        if jump > u16::MAX as usize {
-            self.error(&"Too much code to jump over, extract it into a function".to_string());
+    fn output(&self) -> CompileOutput {
+        CompileOutput {
+            block: self.block.clone(),
+            diagnostics: self.diagnostics.clone(),
+            symbols: self.symbols.clone(),
+            line_map: compile_output::line_map(&self.block),
         }
-
-        self.block.bytes[offset] = (jump >> 8 & 0xff) as u8;
-        self.block.bytes[offset + 1] = (jump & 0xff) as u8;
-    }
-
-    fn error_at_cursor(&mut self, msg: &String) {
-        self.error_at(&self.parser.cursor.clone(), msg)
     }
 
-    fn error(&mut self, msg: &String) {
-        self.error_at(&self.parser.previous.clone(), msg)
+    pub fn byte_code(&self) -> &ByteBlock {
+        &self.block
     }
 
-    fn error_at(&mut self, token: &Token, msg: &String) {
-        if self.parser.panic_mode {
-            return;
-        } else {
-            self.parser.panic_mode = true;
-        }
-
-        let line = token.line;
-        print!("[line:{line:2}] Compiler Error:");
-
-        if let TokenType::Eof = token.typ3 {
-            print!(" at end:");
-        } else if let TokenType::Error = token.typ3 {
-            // Nothing
-        } else {
-            print!(" at '{}':", token.lexeme);
-        }
-
-        println!(" {msg}");
-
-        self.parser.had_error = true;
+    /// Everything reported by the last `compile`, the syntax errors first
+    /// and each kind in the order it was found.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
     }
 }
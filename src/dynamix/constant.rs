@@ -1,14 +1,169 @@
-use std::{fmt::Display, ops::Index};
+use crate::{byte_block::ByteBlock, channel::Channel, native::Native};
+
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    ops::Index,
+    sync::{Arc, Mutex},
+};
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, PartialOrd)]
 pub enum ObjectType {
     String,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Eq)]
 pub struct Object {
     pub typ3: ObjectType,
-    pub bytes: Vec<u8>,
+    pub bytes: Arc<[u8]>,
+}
+
+// interned strings share their bytes, so most comparisons stop at the handle
+impl PartialEq for Object {
+    fn eq(&self, other: &Self) -> bool {
+        self.typ3 == other.typ3
+            && (Arc::ptr_eq(&self.bytes, &other.bytes) || self.bytes == other.bytes)
+    }
+}
+
+impl PartialOrd for Object {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match self.typ3.partial_cmp(&other.typ3) {
+            Some(Ordering::Equal) => Some(self.bytes.cmp(&other.bytes)),
+            ordering => ordering,
+        }
+    }
+}
+
+impl Object {
+    /// Returns true if both objects are the same interned string.
+    pub fn same_handle(&self, other: &Object) -> bool {
+        Arc::ptr_eq(&self.bytes, &other.bytes)
+    }
+}
+
+/// Deduplicates strings so equal strings share a single allocation.
+#[derive(Debug, Clone, Default)]
+pub struct Interner {
+    strings: HashSet<Arc<[u8]>>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the shared handle for `bytes`, storing it on first use.
+    pub fn intern(&mut self, bytes: &[u8]) -> Arc<[u8]> {
+        if let Some(handle) = self.strings.get(bytes) {
+            return handle.clone();
+        }
+
+        let handle: Arc<[u8]> = Arc::from(bytes);
+        self.strings.insert(handle.clone());
+        handle
+    }
+
+    /// Adopts an existing handle so later lookups of the same bytes return it.
+    pub fn adopt(&mut self, handle: &Arc<[u8]>) {
+        if !self.strings.contains(handle) {
+            self.strings.insert(handle.clone());
+        }
+    }
+
+    pub fn string(&mut self, value: &str) -> Constant {
+        Constant::Obj(Object {
+            typ3: ObjectType::String,
+            bytes: self.intern(value.as_bytes()),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+/// A function declared in a script, its body runs in a call frame of its own.
+#[derive(Debug)]
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    pub block: Arc<ByteBlock>,
+}
+
+// every declaration creates a distinct function, so identity is equality
+impl PartialEq for Function {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialOrd for Function {
+    fn partial_cmp(&self, _other: &Self) -> Option<Ordering> {
+        None
+    }
+}
+
+/// A function wrapped by `memoize`, a call with arguments it has seen
+/// before returns the cached result instead of running the function again.
+#[derive(Debug, Clone)]
+pub struct Memoized {
+    pub function: Arc<Function>,
+    // shared by every copy, so a result cached through one is seen by all
+    cache: Arc<Mutex<HashMap<HashKey, Constant>>>,
+}
+
+impl Memoized {
+    pub fn new(function: Arc<Function>) -> Self {
+        Self {
+            function,
+            cache: Arc::default(),
+        }
+    }
+
+    pub fn get(&self, args: &HashKey) -> Option<Constant> {
+        self.cache.lock().unwrap().get(args).cloned()
+    }
+
+    pub fn insert(&self, args: HashKey, value: Constant) {
+        self.cache.lock().unwrap().insert(args, value);
+    }
+
+    /// How many results are cached.
+    pub fn len(&self) -> usize {
+        self.cache.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every cached result.
+    pub(crate) fn values(&self) -> Vec<Constant> {
+        self.cache.lock().unwrap().values().cloned().collect()
+    }
+
+    /// The same for every copy, they share the cache.
+    pub(crate) fn id(&self) -> usize {
+        Arc::as_ptr(&self.cache) as usize
+    }
+}
+
+impl PartialEq for Memoized {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.cache, &other.cache)
+    }
+}
+
+impl PartialOrd for Memoized {
+    fn partial_cmp(&self, _other: &Self) -> Option<Ordering> {
+        None
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
@@ -17,10 +172,43 @@ pub enum Constant {
     Bool(bool),
     Char(char),
     Obj(Object),
+    Tuple(Vec<Constant>),
+    // shared, a `Native` is several times the size of any other value and
+    // every stack slot would pay for it
+    Native(Arc<Native>),
+    Function(Arc<Function>),
+    Memoized(Memoized),
+    /// Handle of a task started with `spawn`.
+    Task(usize),
+    Channel(Channel),
     Null,
 }
 
 impl Constant {
+    pub fn new_string(value: &str) -> Self {
+        Constant::Obj(Object {
+            typ3: ObjectType::String,
+            bytes: Arc::from(value.as_bytes()),
+        })
+    }
+
+    /// `false`, `null`, `0`, `""` and `()` are falsey, everything else is truthy.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Constant::Number(x) => *x != 0.0,
+            Constant::Bool(x) => *x,
+            Constant::Obj(obj) => !obj.bytes.is_empty(),
+            Constant::Tuple(items) => !items.is_empty(),
+            Constant::Null => false,
+            Constant::Char(..)
+            | Constant::Native(..)
+            | Constant::Function(..)
+            | Constant::Memoized(..)
+            | Constant::Task(..)
+            | Constant::Channel(..) => true,
+        }
+    }
+
     pub fn type_to_string(&self) -> &str {
         match self {
             Constant::Number(..) => "number",
@@ -29,6 +217,12 @@ impl Constant {
             Constant::Obj(obj) => match obj.typ3 {
                 ObjectType::String => "String",
             },
+            Constant::Tuple(..) => "tuple",
+            Constant::Native(..) => "native fn",
+            Constant::Function(..) => "fn",
+            Constant::Memoized(..) => "memoized fn",
+            Constant::Task(..) => "task",
+            Constant::Channel(..) => "channel",
             Constant::Null => "null",
         }
     }
@@ -48,27 +242,233 @@ impl Constant {
             None
         }
     }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Constant::Obj(obj) if obj.typ3 == ObjectType::String => {
+                std::str::from_utf8(&obj.bytes).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<f64> for Constant {
+    fn from(x: f64) -> Self {
+        Constant::Number(x)
+    }
+}
+
+impl From<bool> for Constant {
+    fn from(x: bool) -> Self {
+        Constant::Bool(x)
+    }
+}
+
+impl From<char> for Constant {
+    fn from(c: char) -> Self {
+        Constant::Char(c)
+    }
+}
+
+impl From<&str> for Constant {
+    fn from(value: &str) -> Self {
+        Constant::new_string(value)
+    }
+}
+
+impl From<String> for Constant {
+    fn from(value: String) -> Self {
+        Constant::new_string(&value)
+    }
+}
+
+impl From<Vec<Constant>> for Constant {
+    fn from(items: Vec<Constant>) -> Self {
+        Constant::Tuple(items)
+    }
+}
+
+/// Implements `TryFrom<Constant>` for a type held by a single variant.
+macro_rules! try_from_constant {
+    ($type:ty, $variant:ident, $name:expr) => {
+        impl TryFrom<Constant> for $type {
+            type Error = String;
+
+            fn try_from(value: Constant) -> Result<Self, Self::Error> {
+                match value {
+                    Constant::$variant(x) => Ok(x),
+                    _ => Err(format!(
+                        "Expected {}, found '{}'",
+                        $name,
+                        value.type_to_string()
+                    )),
+                }
+            }
+        }
+    };
+}
+
+try_from_constant!(f64, Number, "number");
+try_from_constant!(bool, Bool, "bool");
+try_from_constant!(char, Char, "char");
+try_from_constant!(Vec<Constant>, Tuple, "tuple");
+
+impl TryFrom<Constant> for String {
+    type Error = String;
+
+    fn try_from(value: Constant) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            Some(string) => Ok(string.to_string()),
+            None => Err(format!(
+                "Expected string, found '{}'",
+                value.type_to_string()
+            )),
+        }
+    }
+}
+
+/// The subset of constants that can be used as map keys or switch cases.
+///
+/// Numbers are compared by their bits, with `-0` folded into `0` and every
+/// NaN folded into one canonical NaN so equal keys always hash equally.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum HashKey {
+    Number(u64),
+    Bool(bool),
+    Char(char),
+    String(Arc<[u8]>),
+    Tuple(Vec<HashKey>),
+    Null,
+}
+
+impl HashKey {
+    fn number(x: f64) -> Self {
+        let x = if x.is_nan() {
+            f64::NAN
+        } else if x == 0.0 {
+            0.0
+        } else {
+            x
+        };
+        HashKey::Number(x.to_bits())
+    }
+}
+
+impl TryFrom<&Constant> for HashKey {
+    type Error = String;
+
+    fn try_from(value: &Constant) -> Result<Self, Self::Error> {
+        match value {
+            Constant::Number(x) => Ok(HashKey::number(*x)),
+            Constant::Bool(x) => Ok(HashKey::Bool(*x)),
+            Constant::Char(c) => Ok(HashKey::Char(*c)),
+            Constant::Obj(obj) => match obj.typ3 {
+                ObjectType::String => Ok(HashKey::String(obj.bytes.clone())),
+            },
+            Constant::Tuple(items) => items
+                .iter()
+                .map(HashKey::try_from)
+                .collect::<Result<_, _>>()
+                .map(HashKey::Tuple),
+            Constant::Null => Ok(HashKey::Null),
+            Constant::Native(..)
+            | Constant::Function(..)
+            | Constant::Memoized(..)
+            | Constant::Task(..)
+            | Constant::Channel(..) => Err(format!(
+                "Value of type '{}' can't be used as a key",
+                value.type_to_string()
+            )),
+        }
+    }
+}
+
+impl From<&HashKey> for Constant {
+    fn from(key: &HashKey) -> Self {
+        match key {
+            HashKey::Number(bits) => Constant::Number(f64::from_bits(*bits)),
+            HashKey::Bool(x) => Constant::Bool(*x),
+            HashKey::Char(c) => Constant::Char(*c),
+            HashKey::String(bytes) => Constant::Obj(Object {
+                typ3: ObjectType::String,
+                bytes: bytes.clone(),
+            }),
+            HashKey::Tuple(items) => Constant::Tuple(items.iter().map(Constant::from).collect()),
+            HashKey::Null => Constant::Null,
+        }
+    }
+}
+
+/// Numbers this large and up are printed with an exponent, e.g. `1e21`.
+const EXPONENT_FROM: f64 = 1e21;
+
+/// So are numbers smaller than this, other than 0.
+const EXPONENT_BELOW: f64 = 1e-7;
+
+/// Writes `x` the way `print` shows it: whole numbers without a fraction,
+/// very large and very small numbers with an exponent and anything else in
+/// the fewest digits that read back as `x`. A precision, as in `{:.2}`,
+/// gives that many decimal places instead.
+fn fmt_number(x: f64, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if !x.is_finite() {
+        return write!(f, "{x}");
+    }
+
+    // adding 0 turns -0 into 0
+    let x = x + 0.0;
+    let magnitude = x.abs();
+    let exponent = magnitude >= EXPONENT_FROM || (magnitude < EXPONENT_BELOW && x != 0.0);
+    match (exponent, f.precision()) {
+        (true, Some(places)) => write!(f, "{x:.places$e}"),
+        (true, None) => write!(f, "{x:e}"),
+        (false, Some(places)) => write!(f, "{x:.places$}"),
+        (false, None) => write!(f, "{x}"),
+    }
 }
 
 impl Display for Constant {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Constant::Number(x) => write!(f, "{x}"),
+            Constant::Number(x) => fmt_number(*x, f),
             Constant::Bool(x) => write!(f, "{x}"),
             Constant::Char(c) => write!(f, "{c}"),
             Constant::Obj(obj) => match obj.typ3 {
                 ObjectType::String => {
-                    write!(f, "{}", String::from_utf8(obj.bytes.clone()).unwrap())
+                    write!(f, "{}", String::from_utf8_lossy(&obj.bytes))
                 }
             },
+            Constant::Tuple(items) => {
+                write!(f, "(")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    // a precision applies to the numbers inside too
+                    item.fmt(f)?;
+                }
+                // a single element tuple keeps its trailing comma
+                if items.len() == 1 {
+                    write!(f, ",")?;
+                }
+                write!(f, ")")
+            }
+            Constant::Native(native) => write!(f, "{native:?}"),
+            Constant::Function(function) => write!(f, "<fn {}>", function.name),
+            Constant::Memoized(memoized) => write!(f, "<memoized fn {}>", memoized.function.name),
+            Constant::Task(id) => write!(f, "<task {id}>"),
+            Constant::Channel(..) => write!(f, "<channel>"),
             Constant::Null => write!(f, "null"),
         }
     }
 }
 
+/// The pool is immutable once compilation finishes, so it is kept behind an
+/// `Arc` and cloning a block (e.g. to hand it to another VM) only bumps a
+/// reference count. Pushing into a shared pool copies it first.
 #[derive(Debug, Clone)]
 pub struct ConstantPool {
-    pub constants: Vec<Constant>,
+    pub constants: Arc<Vec<Constant>>,
 }
 
 impl Index<usize> for ConstantPool {
@@ -82,12 +482,17 @@ impl Index<usize> for ConstantPool {
 impl ConstantPool {
     pub fn new() -> Self {
         Self {
-            constants: Vec::new(),
+            constants: Arc::new(Vec::new()),
         }
     }
 
     pub fn push(&mut self, value: Constant) {
-        self.constants.push(value);
+        Arc::make_mut(&mut self.constants).push(value);
+    }
+
+    /// Returns true if both pools point at the same underlying storage.
+    pub fn shares_storage(&self, other: &ConstantPool) -> bool {
+        Arc::ptr_eq(&self.constants, &other.constants)
     }
 
     pub fn len(&self) -> usize {
@@ -0,0 +1,297 @@
+//! Just enough JSON to speak the debug adapter protocol.
+
+use std::fmt::Display;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, ..)| k == key).map(|(.., v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        if let Json::String(x) = self {
+            Some(x)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        if let Json::Number(x) = self {
+            Some(*x)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        if let Json::Bool(x) = self {
+            Some(*x)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        if let Json::Array(x) = self {
+            Some(x)
+        } else {
+            None
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self {
+        Json::String(value)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(value: f64) -> Self {
+        Json::Number(value)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Json::Bool(value)
+    }
+}
+
+/// Builds a `Json::Object` from key value pairs.
+pub fn object(fields: Vec<(&str, Json)>) -> Json {
+    Json::Object(
+        fields
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+    )
+}
+
+fn write_string(f: &mut std::fmt::Formatter<'_>, value: &str) -> std::fmt::Result {
+    write!(f, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(x) => write!(f, "{x}"),
+            Json::Number(x) => write!(f, "{x}"),
+            Json::String(x) => write_string(f, x),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+pub fn parse(source: &str) -> Result<Json, String> {
+    let mut parser = Parser {
+        chars: source.chars().collect(),
+        cursor: 0,
+    };
+
+    let value = parser.value()?;
+    parser.trim();
+
+    if parser.cursor != parser.chars.len() {
+        return Err(format!("Unexpected trailing input at {}", parser.cursor));
+    }
+
+    Ok(value)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    cursor: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> char {
+        self.chars.get(self.cursor).copied().unwrap_or('\0')
+    }
+
+    fn advance(&mut self) -> char {
+        let c = self.peek();
+        self.cursor += 1;
+        c
+    }
+
+    fn trim(&mut self) {
+        while self.peek().is_whitespace() {
+            self.cursor += 1;
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        self.trim();
+        if self.advance() != c {
+            return Err(format!("Expected '{c}' at {}", self.cursor - 1));
+        }
+        Ok(())
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        for c in word.chars() {
+            if self.advance() != c {
+                return Err(format!("Invalid literal at {}", self.cursor - 1));
+            }
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.trim();
+        match self.peek() {
+            '{' => self.object(),
+            '[' => self.array(),
+            '"' => Ok(Json::String(self.string()?)),
+            't' => self.keyword("true", Json::Bool(true)),
+            'f' => self.keyword("false", Json::Bool(false)),
+            'n' => self.keyword("null", Json::Null),
+            c if c == '-' || c.is_ascii_digit() => self.number(),
+            c => Err(format!("Unexpected character '{c}' at {}", self.cursor)),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+
+        self.trim();
+        if self.peek() == '}' {
+            self.advance();
+            return Ok(Json::Object(fields));
+        }
+
+        loop {
+            self.trim();
+            let key = self.string()?;
+            self.expect(':')?;
+            fields.push((key, self.value()?));
+
+            self.trim();
+            match self.advance() {
+                ',' => continue,
+                '}' => return Ok(Json::Object(fields)),
+                _ => return Err(format!("Expected ',' or '}}' at {}", self.cursor - 1)),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+
+        self.trim();
+        if self.peek() == ']' {
+            self.advance();
+            return Ok(Json::Array(items));
+        }
+
+        loop {
+            items.push(self.value()?);
+
+            self.trim();
+            match self.advance() {
+                ',' => continue,
+                ']' => return Ok(Json::Array(items)),
+                _ => return Err(format!("Expected ',' or ']' at {}", self.cursor - 1)),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.advance() != '"' {
+            return Err(format!("Expected string at {}", self.cursor - 1));
+        }
+
+        let mut value = String::new();
+        loop {
+            match self.advance() {
+                '"' => return Ok(value),
+                '\\' => match self.advance() {
+                    'n' => value.push('\n'),
+                    'r' => value.push('\r'),
+                    't' => value.push('\t'),
+                    'b' => value.push('\u{8}'),
+                    'f' => value.push('\u{c}'),
+                    'u' => {
+                        let hex: String = (0..4).map(|_| self.advance()).collect();
+                        let code = u32::from_str_radix(&hex, 16)
+                            .map_err(|_| format!("Invalid unicode escape '{hex}'"))?;
+                        value.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                    }
+                    c => value.push(c),
+                },
+                '\0' if self.cursor > self.chars.len() => {
+                    return Err("Unterminated string".to_string())
+                }
+                c => value.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.cursor;
+        while "+-.eE".contains(self.peek()) || self.peek().is_ascii_digit() {
+            self.advance();
+        }
+
+        let lexeme: String = self.chars[start..self.cursor].iter().collect();
+        lexeme
+            .parse::<f64>()
+            .map(Json::Number)
+            .map_err(|_| format!("Invalid number '{lexeme}'"))
+    }
+}
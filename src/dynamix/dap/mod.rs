@@ -0,0 +1,386 @@
+//! A debug adapter protocol server built on top of the `Debugger`, so
+//! editors like VS Code can debug `.dyn` scripts.
+//!
+//! The adapter talks to the client over TCP (`"debugServer": 4711` in a VS
+//! Code launch configuration) since the script itself writes to stdout.
+
+pub mod json;
+
+use crate::{
+    compiler::Compiler,
+    debugger::{DebugEvent, Debugger},
+    virtual_machine::{InterpretResult, VirtualMachine},
+};
+
+use json::{object, Json};
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::TcpListener,
+};
+
+pub const DEFAULT_PORT: u16 = 4711;
+
+const THREAD_ID: f64 = 1.0;
+const LOCALS_REFERENCE: f64 = 1.0;
+const GLOBALS_REFERENCE: f64 = 2.0;
+
+/// Accepts a single client on `port` and serves it until it disconnects.
+pub fn serve(port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("dynamix debug adapter listening on 127.0.0.1:{port}");
+
+    let (stream, ..) = listener.accept()?;
+    let reader = BufReader::new(stream.try_clone()?);
+
+    let mut vm = VirtualMachine::new();
+    let mut session = Session::new(Debugger::attach(&mut vm), reader, stream);
+    session.run()
+}
+
+pub struct Session<'a, R: BufRead, W: Write> {
+    debugger: Debugger<'a>,
+    reader: R,
+    writer: W,
+    seq: u64,
+    program: Option<String>,
+    configured: bool,
+    stop_on_entry: bool,
+    started: bool,
+}
+
+impl<'a, R: BufRead, W: Write> Session<'a, R, W> {
+    pub fn new(debugger: Debugger<'a>, reader: R, writer: W) -> Self {
+        Self {
+            debugger,
+            reader,
+            writer,
+            seq: 0,
+            program: None,
+            configured: false,
+            stop_on_entry: false,
+            started: false,
+        }
+    }
+
+    /// Handles requests until the client disconnects or the stream closes.
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Some(request) = self.read_message()? {
+            if !self.handle(&request)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_message(&mut self) -> io::Result<Option<Json>> {
+        let mut length = None;
+
+        loop {
+            let mut header = String::new();
+            if self.reader.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+
+            if let Some(value) = header.strip_prefix("Content-Length:") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+
+        let length = length.ok_or_else(|| invalid_data("Missing Content-Length header"))?;
+        let mut body = vec![0; length];
+        self.reader.read_exact(&mut body)?;
+
+        let body = String::from_utf8(body).map_err(|err| invalid_data(&err.to_string()))?;
+        json::parse(&body)
+            .map(Some)
+            .map_err(|err| invalid_data(&err))
+    }
+
+    fn send(&mut self, mut fields: Vec<(&str, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        fields.insert(0, ("seq", Json::Number(self.seq as f64)));
+
+        let body = object(fields).to_string();
+        write!(self.writer, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+        self.writer.flush()
+    }
+
+    fn respond(&mut self, request: &Json, success: bool, body: Json) -> io::Result<()> {
+        let request_seq = request.get("seq").cloned().unwrap_or(Json::Null);
+        let command = request.get("command").cloned().unwrap_or(Json::Null);
+
+        let mut fields = vec![
+            ("type", Json::from("response")),
+            ("request_seq", request_seq),
+            ("success", Json::Bool(success)),
+            ("command", command),
+        ];
+
+        if success {
+            fields.push(("body", body));
+        } else {
+            fields.push(("message", body));
+        }
+
+        self.send(fields)
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(vec![
+            ("type", Json::from("event")),
+            ("event", Json::from(event)),
+            ("body", body),
+        ])
+    }
+
+    /// Returns false once the session should end.
+    fn handle(&mut self, request: &Json) -> io::Result<bool> {
+        let empty = Json::Object(Vec::new());
+        let arguments = request.get("arguments").unwrap_or(&empty);
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+
+        match command {
+            "initialize" => {
+                let body = object(vec![("supportsConfigurationDoneRequest", true.into())]);
+                self.respond(request, true, body)?;
+                self.event("initialized", empty)?;
+            }
+            "launch" => {
+                let program = arguments.get("program").and_then(Json::as_str);
+                self.stop_on_entry = arguments
+                    .get("stopOnEntry")
+                    .and_then(Json::as_bool)
+                    .unwrap_or(false);
+
+                match program.map(|path| (path, self.load(path))) {
+                    Some((path, Ok(()))) => {
+                        self.program = Some(path.to_string());
+                        self.respond(request, true, empty)?;
+                        if self.configured {
+                            self.start()?;
+                        }
+                    }
+                    Some((.., Err(err))) => self.respond(request, false, err.into())?,
+                    None => self.respond(request, false, "Missing 'program'".into())?,
+                }
+            }
+            "setBreakpoints" => {
+                let path = arguments
+                    .get("source")
+                    .and_then(|source| source.get("path"))
+                    .and_then(Json::as_str)
+                    .unwrap_or("")
+                    .to_string();
+                let lines: Vec<u32> = arguments
+                    .get("breakpoints")
+                    .and_then(Json::as_array)
+                    .unwrap_or(&[])
+                    .iter()
+                    .filter_map(|bp| bp.get("line").and_then(Json::as_f64))
+                    .map(|line| line as u32)
+                    .collect();
+
+                self.debugger.clear_breakpoints(&path);
+                let breakpoints = lines
+                    .into_iter()
+                    .map(|line| {
+                        self.debugger.set_breakpoint(&path, line);
+                        object(vec![
+                            ("verified", true.into()),
+                            ("line", (line as f64).into()),
+                        ])
+                    })
+                    .collect();
+
+                let body = object(vec![("breakpoints", Json::Array(breakpoints))]);
+                self.respond(request, true, body)?;
+            }
+            "configurationDone" => {
+                self.configured = true;
+                self.respond(request, true, empty)?;
+                if self.program.is_some() {
+                    self.start()?;
+                }
+            }
+            "threads" => {
+                let thread = object(vec![("id", THREAD_ID.into()), ("name", "main".into())]);
+                let body = object(vec![("threads", Json::Array(vec![thread]))]);
+                self.respond(request, true, body)?;
+            }
+            "stackTrace" => {
+                let program = self.program.clone().unwrap_or_default();
+                let frames: Vec<Json> = self
+                    .debugger
+                    .stack_frames()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(id, frame)| {
+                        // frames in included code point at the included file
+                        let path = frame.file.unwrap_or_else(|| program.clone());
+                        object(vec![
+                            ("id", (id as f64).into()),
+                            ("name", frame.function.into()),
+                            ("line", (frame.line as f64).into()),
+                            ("column", 1.0.into()),
+                            ("source", object(vec![("path", path.as_str().into())])),
+                        ])
+                    })
+                    .collect();
+
+                let total = frames.len() as f64;
+                let body = object(vec![
+                    ("stackFrames", Json::Array(frames)),
+                    ("totalFrames", total.into()),
+                ]);
+                self.respond(request, true, body)?;
+            }
+            "scopes" => {
+                let scope = |name: &str, reference: f64| {
+                    object(vec![
+                        ("name", name.into()),
+                        ("variablesReference", reference.into()),
+                        ("expensive", false.into()),
+                    ])
+                };
+                let scopes = vec![
+                    scope("Locals", LOCALS_REFERENCE),
+                    scope("Globals", GLOBALS_REFERENCE),
+                ];
+                self.respond(request, true, object(vec![("scopes", Json::Array(scopes))]))?;
+            }
+            "variables" => {
+                let reference = arguments.get("variablesReference").and_then(Json::as_f64);
+                let variables = match reference {
+                    Some(LOCALS_REFERENCE) => self.debugger.read_locals(0).unwrap_or_default(),
+                    Some(GLOBALS_REFERENCE) => self.debugger.read_globals(),
+                    _ => Vec::new(),
+                };
+
+                let variables = variables
+                    .into_iter()
+                    .map(|(name, value)| {
+                        object(vec![
+                            ("name", name.into()),
+                            ("value", value.to_string().into()),
+                            ("type", value.type_to_string().into()),
+                            ("variablesReference", 0.0.into()),
+                        ])
+                    })
+                    .collect();
+
+                let body = object(vec![("variables", Json::Array(variables))]);
+                self.respond(request, true, body)?;
+            }
+            "continue" => {
+                let body = object(vec![("allThreadsContinued", true.into())]);
+                self.respond(request, true, body)?;
+                let event = self.debugger.resume();
+                self.report(event)?;
+            }
+            "next" | "stepIn" | "stepOut" => {
+                self.respond(request, true, empty)?;
+                let event = match command {
+                    "next" => self.debugger.step_over(),
+                    "stepIn" => self.debugger.step_line(),
+                    _ => self.debugger.step_out(),
+                };
+                self.report(event)?;
+            }
+            // requests are only read while the script is stopped, there's
+            // nothing running to pause
+            "pause" => {
+                let message = "Pausing a running script is not supported";
+                self.respond(request, false, message.into())?;
+            }
+            "disconnect" | "terminate" => {
+                self.respond(request, true, empty)?;
+                return Ok(false);
+            }
+            _ => {
+                let message = format!("Unsupported command '{command}'");
+                self.respond(request, false, message.into())?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn load(&mut self, path: &str) -> Result<(), String> {
+        let source = std::fs::read_to_string(path)
+            .map_err(|err| format!("Failed to open file from path: /{path} ({err})"))?;
+
+        let output = Compiler::new(&source)
+            .with_path(path)
+            .with_name(path)
+            .compile();
+        if !output.succeeded() {
+            let errors: Vec<String> = output
+                .diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic.to_string())
+                .collect();
+            return Err(format!("could not compile '{path}'\n{}", errors.join("\n")));
+        }
+
+        self.debugger.load(output.block, path);
+        Ok(())
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        if self.started {
+            return Ok(());
+        }
+        self.started = true;
+
+        if self.stop_on_entry {
+            return self.stopped("entry");
+        }
+
+        let event = self.debugger.resume();
+        self.report(event)
+    }
+
+    fn stopped(&mut self, reason: &str) -> io::Result<()> {
+        let body = object(vec![
+            ("reason", reason.into()),
+            ("threadId", THREAD_ID.into()),
+        ]);
+        self.event("stopped", body)
+    }
+
+    fn report(&mut self, event: DebugEvent) -> io::Result<()> {
+        match event {
+            DebugEvent::Stepped => self.stopped("step"),
+            DebugEvent::Breakpoint { .. } => self.stopped("breakpoint"),
+            DebugEvent::Finished(result) => {
+                if let InterpretResult::RuntimeError = result {
+                    let output = format!("{}\n", self.debugger.last_runtime_error());
+                    let body = object(vec![
+                        ("category", "stderr".into()),
+                        ("output", output.into()),
+                    ]);
+                    self.event("output", body)?;
+                }
+
+                let code = if result == InterpretResult::Ok {
+                    0.0
+                } else {
+                    1.0
+                };
+                self.event("exited", object(vec![("exitCode", code.into())]))?;
+                self.event("terminated", Json::Object(Vec::new()))
+            }
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
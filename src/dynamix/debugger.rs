@@ -0,0 +1,251 @@
+//! Runs a script under control of a tool: breakpoints, stepping by
+//! instruction, line or call frame, and reading the stack, locals and
+//! globals while it's paused.
+//!
+//! The `dap` server drives a `Debugger` for editors, embedders can attach
+//! one to their own `VirtualMachine`.
+
+use crate::{
+    byte_block::ByteBlock,
+    constant::Constant,
+    error::TraceFrame,
+    virtual_machine::{InterpretResult, VirtualMachine},
+};
+
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DebugEvent {
+    /// A single instruction was executed.
+    Stepped,
+    /// Execution paused before the first instruction of a line with a breakpoint.
+    Breakpoint { file: String, line: u32 },
+    /// The program ran to completion or stopped with an error.
+    Finished(InterpretResult),
+}
+
+/// Programmatic control over a `VirtualMachine`, meant to be driven by
+/// tooling (IDE integrations, a DAP server) rather than a terminal UI.
+///
+/// ```
+/// use dynamix::{compiler::Compiler, debugger::{Debugger, DebugEvent}, virtual_machine::VirtualMachine};
+///
+/// let output = Compiler::new("let a = 1;\nprint a;").compile();
+/// assert!(output.succeeded());
+///
+/// let mut vm = VirtualMachine::new();
+/// let mut debugger = Debugger::attach(&mut vm);
+/// debugger.load(output.block, "main.dyn");
+/// debugger.set_breakpoint("main.dyn", 2);
+///
+/// assert_eq!(
+///     debugger.resume(),
+///     DebugEvent::Breakpoint { file: "main.dyn".to_string(), line: 2 }
+/// );
+/// ```
+pub struct Debugger<'a> {
+    vm: &'a mut VirtualMachine,
+    file: String,
+    breakpoints: Vec<(String, u32)>,
+    finished: Option<InterpretResult>,
+    // set once a breakpoint at the current position has been reported, so
+    // resuming from it doesn't stop there again
+    reported: bool,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn attach(vm: &'a mut VirtualMachine) -> Self {
+        Self {
+            vm,
+            file: String::new(),
+            breakpoints: Vec::new(),
+            finished: Some(InterpretResult::Ok),
+            reported: false,
+        }
+    }
+
+    /// Loads `block` for execution, `file` is the name breakpoints refer to.
+    pub fn load(&mut self, block: impl Into<Arc<ByteBlock>>, file: &str) {
+        self.vm.load(block);
+        self.file = file.to_string();
+        self.finished = None;
+        self.reported = false;
+    }
+
+    pub fn set_breakpoint(&mut self, file: &str, line: u32) {
+        if !self.has_breakpoint(file, line) {
+            self.breakpoints.push((file.to_string(), line));
+        }
+    }
+
+    pub fn clear_breakpoint(&mut self, file: &str, line: u32) {
+        self.breakpoints.retain(|(f, l)| !(f == file && *l == line));
+    }
+
+    pub fn clear_breakpoints(&mut self, file: &str) {
+        self.breakpoints.retain(|(f, ..)| f != file);
+    }
+
+    pub fn has_breakpoint(&self, file: &str, line: u32) -> bool {
+        self.breakpoints
+            .iter()
+            .any(|(f, l)| f == file && *l == line)
+    }
+
+    /// Executes a single instruction.
+    pub fn step(&mut self) -> DebugEvent {
+        if let Some(result) = self.finished {
+            return DebugEvent::Finished(result);
+        }
+
+        self.reported = false;
+        match self.vm.step() {
+            Some(result) => {
+                let result = self.vm.flush_after(result);
+                self.finished = Some(result);
+                DebugEvent::Finished(result)
+            }
+            None => DebugEvent::Stepped,
+        }
+    }
+
+    /// Runs until execution reaches a different source line, stepping into
+    /// any function called on the way.
+    pub fn step_line(&mut self) -> DebugEvent {
+        let line = self.current_line();
+        let depth = self.vm.call_depth();
+
+        self.run_until(|debugger| {
+            debugger.vm.call_depth() != depth || debugger.current_line() != line
+        })
+    }
+
+    /// Runs until a different line of the running function, or its caller
+    /// once it returns. Calls made on the way run to completion unless they
+    /// reach a breakpoint.
+    pub fn step_over(&mut self) -> DebugEvent {
+        let line = self.current_line();
+        let depth = self.vm.call_depth();
+
+        self.run_until(|debugger| {
+            let now = debugger.vm.call_depth();
+            now < depth || (now == depth && debugger.current_line() != line)
+        })
+    }
+
+    /// Runs until the running function returns to its caller, or until a
+    /// breakpoint is reached.
+    pub fn step_out(&mut self) -> DebugEvent {
+        let depth = self.vm.call_depth();
+        self.run_until(|debugger| debugger.vm.call_depth() < depth)
+    }
+
+    /// Runs until a breakpoint is reached or the program finishes. A
+    /// breakpoint on the line execution is paused at counts, unless that's
+    /// where it already stopped.
+    pub fn resume(&mut self) -> DebugEvent {
+        if let Some(event) = self.breakpoint() {
+            return event;
+        }
+
+        self.run_until(|_| false)
+    }
+
+    fn run_until(&mut self, stop: impl Fn(&Self) -> bool) -> DebugEvent {
+        loop {
+            let line = self.current_line();
+
+            if let DebugEvent::Finished(result) = self.step() {
+                return DebugEvent::Finished(result);
+            }
+
+            if stop(self) {
+                return DebugEvent::Stepped;
+            }
+
+            if self.current_line() != line {
+                if let Some(event) = self.breakpoint() {
+                    return event;
+                }
+            }
+        }
+    }
+
+    /// The breakpoint on the line execution is at, if it has one that
+    /// hasn't been reported yet.
+    fn breakpoint(&mut self) -> Option<DebugEvent> {
+        let line = self.current_line()?;
+        if self.reported || !self.has_breakpoint(&self.file, line) {
+            return None;
+        }
+
+        self.reported = true;
+        Some(DebugEvent::Breakpoint {
+            file: self.file.clone(),
+            line,
+        })
+    }
+
+    /// The source line of the next instruction to execute.
+    pub fn current_line(&self) -> Option<u32> {
+        if self.finished.is_some() {
+            return None;
+        }
+
+        self.vm.block().lines.get(self.vm.offset()).copied()
+    }
+
+    /// The running functions innermost first, empty once the program has
+    /// finished.
+    pub fn stack_frames(&self) -> Vec<TraceFrame> {
+        if self.finished.is_some() {
+            return Vec::new();
+        }
+
+        self.vm.call_frames()
+    }
+
+    pub fn last_runtime_error(&self) -> String {
+        self.vm.last_runtime_error()
+    }
+
+    pub fn read_stack(&self) -> &[Constant] {
+        self.vm.stack()
+    }
+
+    /// Global variables sorted by name, natives are left out.
+    pub fn read_globals(&self) -> Vec<(String, Constant)> {
+        self.vm
+            .globals()
+            .iter()
+            .filter(|(.., value)| !matches!(value, Constant::Native(..)))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Local variables in scope for `frame`, only the running function's
+    /// frame 0 can be inspected.
+    pub fn read_locals(&self, frame: usize) -> Option<Vec<(String, Constant)>> {
+        if frame != 0 {
+            return None;
+        }
+
+        let offset = self.vm.offset();
+        let stack = &self.vm.stack()[self.vm.frame_base()..];
+
+        let locals = self
+            .vm
+            .block()
+            .locals
+            .iter()
+            .filter(|info| info.start <= offset && offset < info.end)
+            .filter_map(|info| {
+                stack
+                    .get(info.slot)
+                    .map(|value| (info.name.clone(), value.clone()))
+            })
+            .collect();
+
+        Some(locals)
+    }
+}
@@ -0,0 +1,97 @@
+//! Errors reported while compiling, collected so embedders and tests can
+//! inspect them instead of scraping stdout.
+
+use std::fmt::Display;
+
+use crate::lexer::{Token, TokenType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Where in the source a diagnostic points.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    /// Name of the included file, `None` for the file being compiled.
+    pub file: Option<String>,
+    pub line: usize,
+    pub near: Near,
+}
+
+impl Span {
+    /// Where `token` is, `includes` names the files of its `file` ids.
+    pub fn of(token: &Token, includes: &[String]) -> Self {
+        let file = (token.file as usize)
+            .checked_sub(1)
+            .and_then(|file| includes.get(file))
+            .cloned();
+
+        let near = match token.typ3 {
+            TokenType::Eof => Near::End,
+            TokenType::Error => Near::Nothing,
+            _ => Near::Lexeme(token.lexeme.clone()),
+        };
+
+        Span {
+            file,
+            line: token.line,
+            near,
+        }
+    }
+}
+
+/// The token a diagnostic was reported at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Near {
+    Lexeme(String),
+    End,
+    // errors from the lexer and preprocessor have no token to point at
+    Nothing,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+/// Renders the diagnostic the way the CLI prints it, e.g.
+/// `[line: 3] Compiler Error: at 'x': Undefined variable`.
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.severity {
+            Severity::Error => "Compiler Error",
+            Severity::Warning => "Compiler Warning",
+        };
+
+        let line = self.span.line;
+        match &self.span.file {
+            Some(file) => write!(f, "[{file} line:{line:2}] {kind}:")?,
+            None => write!(f, "[line:{line:2}] {kind}:")?,
+        }
+
+        match &self.span.near {
+            Near::Lexeme(lexeme) => write!(f, " at '{lexeme}':")?,
+            Near::End => write!(f, " at end:")?,
+            Near::Nothing => (),
+        }
+
+        write!(f, " {}", self.message)
+    }
+}
+
+/// Prints every diagnostic on its own line to stderr.
+pub fn print_diagnostics(diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        eprintln!("{diagnostic}");
+    }
+}
@@ -1,103 +1,325 @@
-use crate::byte_block::{ByteBlock, OpCode};
+//! Bytecode listings. The `write` functions format into any `fmt::Write`,
+//! e.g. a `String` a test compares, the others print to stdout.
+
+use crate::{
+    byte_block::{ByteBlock, OpCode, Operands},
+    constant::Constant,
+};
+
+use std::fmt::{self, Write};
 
 pub struct Disassembler {}
 
 impl Disassembler {
     pub fn disassemble(block: &ByteBlock, name: &str) {
-        println!("-- {name} --");
+        Disassembler::print(|out| Disassembler::write(out, block, name));
+    }
+
+    /// Disassembles `block` followed by every function and test declared in
+    /// it, depth first.
+    pub fn disassemble_all(block: &ByteBlock) {
+        Disassembler::print(|out| Disassembler::write_all(out, block));
+    }
+
+    /// Prints the constant pool with the offsets of the instructions that
+    /// reference each constant.
+    pub fn constants(block: &ByteBlock) {
+        Disassembler::print(|out| Disassembler::write_constants(out, block));
+    }
+
+    pub fn disassemble_instruction(block: &ByteBlock, offset: &mut usize) {
+        Disassembler::print(|out| Disassembler::write_instruction(out, block, offset));
+    }
+
+    /// The listing `disassemble_all` prints.
+    pub fn listing(block: &ByteBlock) -> String {
+        let mut listing = String::new();
+        // writing to a String can't fail
+        Disassembler::write_all(&mut listing, block).unwrap();
+        listing
+    }
+
+    fn print(write: impl FnOnce(&mut String) -> fmt::Result) {
+        let mut text = String::new();
+        write(&mut text).unwrap();
+        print!("{text}");
+    }
+
+    pub fn write(out: &mut dyn Write, block: &ByteBlock, name: &str) -> fmt::Result {
+        writeln!(out, "-- {name} --")?;
 
         let mut offset = 0;
         while offset < block.bytes.len() {
-            Disassembler::disassemble_instruction(block, &mut offset);
+            Disassembler::write_instruction(out, block, &mut offset)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_all(out: &mut dyn Write, block: &ByteBlock) -> fmt::Result {
+        Disassembler::write(out, block, block.name())?;
+
+        for (_, constant) in block.constants() {
+            if let Constant::Function(function) = constant {
+                writeln!(out)?;
+                Disassembler::write_all(out, &function.block)?;
+            }
+        }
+
+        for test in block.tests.iter() {
+            writeln!(out)?;
+            Disassembler::write_all(out, &test.function.block)?;
+        }
+        Ok(())
+    }
+
+    /// The operands of the instruction at `offset` as they appear in a
+    /// listing, e.g. `0003 count` or `-> 0027`.
+    pub fn operands(block: &ByteBlock, offset: usize) -> String {
+        let Some(Ok(opcode)) = block.bytes.get(offset).map(|&byte| OpCode::from(byte)) else {
+            return String::new();
+        };
+
+        if let Some(target) = block.jump_target(offset) {
+            return format!("-> {target:04}");
+        }
+
+        let constant = block
+            .constant_operand(offset)
+            .and_then(|index| Some((index, block.constants.constants.get(index)?)));
+
+        match (opcode.operands(), constant) {
+            (Operands::ByteByte, Some((index, value))) => {
+                let arg_count = block.bytes.get(offset + 2).copied().unwrap_or(0);
+                format!("({arg_count} args) {index:04} {value}")
+            }
+            (Operands::ShortByte, Some((index, value))) => {
+                let arg_count = block.bytes.get(offset + 3).copied().unwrap_or(0);
+                format!("({arg_count} args) {index:04} {value}")
+            }
+            (.., Some((index, value))) => format!("{index:04} {value}"),
+            (Operands::Byte, None) => match block.bytes.get(offset + 1) {
+                Some(operand) => format!("{operand:04}"),
+                None => String::new(),
+            },
+            _ => String::new(),
+        }
+    }
+
+    pub fn write_constants(out: &mut dyn Write, block: &ByteBlock) -> fmt::Result {
+        writeln!(out, "-- constants --")?;
+
+        let references = block.constant_references();
+        for (index, constant) in block.constants() {
+            let offsets: Vec<String> = references[index]
+                .iter()
+                .map(|offset| format!("{offset:04}"))
+                .collect();
+
+            writeln!(
+                out,
+                "{index:04} {:10} {:16} refs: {}",
+                constant.type_to_string(),
+                constant.to_string(),
+                offsets.join(" ")
+            )?;
         }
+        Ok(())
     }
 
-    fn write_block_instruction(block: &ByteBlock, name: &str, offset: &mut usize) {
+    fn constant_instruction(
+        out: &mut dyn Write,
+        block: &ByteBlock,
+        name: &str,
+        offset: &mut usize,
+    ) -> fmt::Result {
         let constant = block.bytes[*offset + 1];
-        print!("{name:16} {constant:04} ");
-        println!("{}", block.constants[constant as usize]);
         *offset += 2;
+        writeln!(
+            out,
+            "{name:16} {constant:04} {}",
+            block.constants[constant as usize]
+        )
     }
 
-    fn constant_instruction(block: &ByteBlock, name: &str, offset: &mut usize) {
-        Disassembler::write_block_instruction(block, name, offset)
+    fn constant_long_instruction(
+        out: &mut dyn Write,
+        block: &ByteBlock,
+        name: &str,
+        offset: &mut usize,
+    ) -> fmt::Result {
+        let constant = block.read_short(*offset + 1).unwrap();
+        *offset += 3;
+        writeln!(
+            out,
+            "{name:16} {constant:04} {}",
+            block.constants[constant as usize]
+        )
     }
 
-    fn simple_instruction(name: &str, offset: &mut usize) {
-        println!("{name}");
+    fn simple_instruction(out: &mut dyn Write, name: &str, offset: &mut usize) -> fmt::Result {
         *offset += 1;
+        writeln!(out, "{name}")
     }
 
-    fn byte_instruction(block: &ByteBlock, name: &str, offset: &mut usize) {
-        Disassembler::write_block_instruction(block, name, offset)
+    fn byte_instruction(
+        out: &mut dyn Write,
+        block: &ByteBlock,
+        name: &str,
+        offset: &mut usize,
+    ) -> fmt::Result {
+        let operand = block.bytes[*offset + 1];
+        *offset += 2;
+        writeln!(out, "{name:16} {operand:04}")
     }
 
-    fn jump_instruction(block: &ByteBlock, name: &str, sign: isize, offset: &mut usize) {
-        let mut jump = ((block.bytes[*offset + 1] as u8) as u16) << 8;
-        jump |= block.bytes[*offset + 2] as u16;
-        let to = *offset + 3 + (sign * jump as isize) as usize;
-        println!("{name:16} {offset:04} -> {}", to);
+    fn invoke_instruction(
+        out: &mut dyn Write,
+        block: &ByteBlock,
+        name: &str,
+        offset: &mut usize,
+    ) -> fmt::Result {
+        let constant = block.bytes[*offset + 1];
+        let arg_count = block.bytes[*offset + 2];
         *offset += 3;
+        writeln!(
+            out,
+            "{name:16} ({arg_count} args) {constant:04} {}",
+            block.constants[constant as usize]
+        )
     }
 
-    pub fn disassemble_instruction(block: &ByteBlock, offset: &mut usize) {
-        print!("{:04} ", *offset);
+    fn invoke_long_instruction(
+        out: &mut dyn Write,
+        block: &ByteBlock,
+        name: &str,
+        offset: &mut usize,
+    ) -> fmt::Result {
+        let constant = block.read_short(*offset + 1).unwrap();
+        let arg_count = block.bytes[*offset + 3];
+        *offset += 4;
+        writeln!(
+            out,
+            "{name:16} ({arg_count} args) {constant:04} {}",
+            block.constants[constant as usize]
+        )
+    }
+
+    fn jump_instruction(
+        out: &mut dyn Write,
+        block: &ByteBlock,
+        name: &str,
+        offset: &mut usize,
+    ) -> fmt::Result {
+        let to = block.jump_target(*offset).unwrap();
+        writeln!(out, "{name:16} {offset:04} -> {to}")?;
+        *offset += OpCode::from(block.bytes[*offset]).ok().unwrap().width();
+        Ok(())
+    }
+
+    pub fn write_instruction(
+        out: &mut dyn Write,
+        block: &ByteBlock,
+        offset: &mut usize,
+    ) -> fmt::Result {
+        write!(out, "{:04} ", *offset)?;
 
         let in_bounds = *offset < block.bytes.len();
         if !in_bounds {
-            return;
+            return Ok(());
         }
 
-        let same_line = || {
-            if *offset == 0 {
-                return false;
-            }
-
-            block.lines[*offset] == block.lines[*offset - 1]
-        };
-
-        if same_line() {
-            print!("   | ");
+        let same_line = *offset != 0 && block.lines[*offset] == block.lines[*offset - 1];
+        if same_line {
+            write!(out, "   | ")?;
         } else {
-            print!("{:04} ", block.lines[*offset]);
+            write!(out, "{:04} ", block.lines[*offset])?;
         }
 
         let instruction = block.bytes[*offset];
         match OpCode::from(instruction) {
             Ok(inst) => match inst {
-                OpCode::Print => Disassembler::simple_instruction("OP_PRINT", offset),
-                OpCode::Pop => Disassembler::simple_instruction("OP_POP", offset),
+                OpCode::Print => Disassembler::simple_instruction(out, "OP_PRINT", offset),
+                OpCode::Pop => Disassembler::simple_instruction(out, "OP_POP", offset),
                 OpCode::DefineGlobal => {
-                    Disassembler::simple_instruction("OP_DEFINE_GLOBAL", offset)
+                    Disassembler::constant_instruction(out, block, "OP_DEFINE_GLOBAL", offset)
+                }
+                OpCode::GetGlobal => {
+                    Disassembler::constant_instruction(out, block, "OP_GET_GLOBAL", offset)
+                }
+                OpCode::SetGlobal => {
+                    Disassembler::constant_instruction(out, block, "OP_SET_GLOBAL", offset)
+                }
+                OpCode::GetLocal => {
+                    Disassembler::byte_instruction(out, block, "OP_GET_LOCAL", offset)
+                }
+                OpCode::SetLocal => {
+                    Disassembler::byte_instruction(out, block, "OP_SET_LOCAL", offset)
+                }
+                OpCode::Jz => {
+                    Disassembler::jump_instruction(out, block, "OP_JUMP_IF_FALSE", offset)
+                }
+                OpCode::Jmp => Disassembler::jump_instruction(out, block, "OP_JUMP", offset),
+                OpCode::Loop => Disassembler::jump_instruction(out, block, "OP_LOOP", offset),
+                OpCode::JzLong => {
+                    Disassembler::jump_instruction(out, block, "OP_JUMP_IF_FALSE_LONG", offset)
+                }
+                OpCode::JmpLong => {
+                    Disassembler::jump_instruction(out, block, "OP_JUMP_LONG", offset)
+                }
+                OpCode::LoopLong => {
+                    Disassembler::jump_instruction(out, block, "OP_LOOP_LONG", offset)
                 }
-                OpCode::GetGlobal => Disassembler::simple_instruction("OP_GET_GLOBAL", offset),
-                OpCode::SetGlobal => Disassembler::simple_instruction("OP_SET_GLOBAL", offset),
-                OpCode::GetLocal => Disassembler::byte_instruction(block, "OP_GET_LOCAL", offset),
-                OpCode::SetLocal => Disassembler::byte_instruction(block, "OP_SET_LOCAL", offset),
-                OpCode::Jz => Disassembler::jump_instruction(block, "OP_JUMP_IF_FALSE", 1, offset),
-                OpCode::Jmp => Disassembler::jump_instruction(block, "OP_JUMP", 1, offset),
-                OpCode::Loop => Disassembler::jump_instruction(block, "OP_LOOP", -1, offset),
                 OpCode::Constant => {
-                    Disassembler::constant_instruction(block, "OP_CONSTANT", offset)
+                    Disassembler::constant_instruction(out, block, "OP_CONSTANT", offset)
+                }
+                OpCode::ConstantLong => {
+                    Disassembler::constant_long_instruction(out, block, "OP_CONSTANT_LONG", offset)
+                }
+                OpCode::True => Disassembler::simple_instruction(out, "OP_TRUE", offset),
+                OpCode::False => Disassembler::simple_instruction(out, "OP_FALSE", offset),
+                OpCode::Char => Disassembler::constant_instruction(out, block, "OP_CHAR", offset),
+                OpCode::Null => Disassembler::simple_instruction(out, "OP_NULL", offset),
+                OpCode::Tuple => Disassembler::byte_instruction(out, block, "OP_TUPLE", offset),
+                OpCode::Unpack => Disassembler::byte_instruction(out, block, "OP_UNPACK", offset),
+                OpCode::Equal => Disassembler::simple_instruction(out, "OP_EQUAL", offset),
+                OpCode::Greater => Disassembler::simple_instruction(out, "OP_GREATER", offset),
+                OpCode::Less => Disassembler::simple_instruction(out, "OP_LESS", offset),
+                OpCode::Negate => Disassembler::simple_instruction(out, "OP_NEGATE", offset),
+                OpCode::Not => Disassembler::simple_instruction(out, "OP_NOT", offset),
+                OpCode::Add => Disassembler::simple_instruction(out, "OP_ADD", offset),
+                OpCode::Sub => Disassembler::simple_instruction(out, "OP_SUB", offset),
+                OpCode::Mul => Disassembler::simple_instruction(out, "OP_MUL", offset),
+                OpCode::Div => Disassembler::simple_instruction(out, "OP_DIV", offset),
+                OpCode::Pow => Disassembler::simple_instruction(out, "OP_POW", offset),
+                OpCode::Call => Disassembler::byte_instruction(out, block, "OP_CALL", offset),
+                OpCode::Invoke => Disassembler::invoke_instruction(out, block, "OP_INVOKE", offset),
+                OpCode::DefineGlobalLong => Disassembler::constant_long_instruction(
+                    out,
+                    block,
+                    "OP_DEFINE_GLOBAL_LONG",
+                    offset,
+                ),
+                OpCode::GetGlobalLong => Disassembler::constant_long_instruction(
+                    out,
+                    block,
+                    "OP_GET_GLOBAL_LONG",
+                    offset,
+                ),
+                OpCode::SetGlobalLong => Disassembler::constant_long_instruction(
+                    out,
+                    block,
+                    "OP_SET_GLOBAL_LONG",
+                    offset,
+                ),
+                OpCode::InvokeLong => {
+                    Disassembler::invoke_long_instruction(out, block, "OP_INVOKE_LONG", offset)
                 }
-                OpCode::True => Disassembler::simple_instruction("OP_TRUE", offset),
-                OpCode::False => Disassembler::simple_instruction("OP_FALSE", offset),
-                OpCode::Char => Disassembler::simple_instruction("OP_CHAR", offset),
-                OpCode::Null => Disassembler::simple_instruction("OP_NULL", offset),
-                OpCode::Equal => Disassembler::simple_instruction("OP_EQUAL", offset),
-                OpCode::Greater => Disassembler::simple_instruction("OP_GREATER", offset),
-                OpCode::Less => Disassembler::simple_instruction("OP_LESS", offset),
-                OpCode::Negate => Disassembler::simple_instruction("OP_NEGATE", offset),
-                OpCode::Not => Disassembler::simple_instruction("OP_NOT", offset),
-                OpCode::Add => Disassembler::simple_instruction("OP_ADD", offset),
-                OpCode::Sub => Disassembler::simple_instruction("OP_SUB", offset),
-                OpCode::Mul => Disassembler::simple_instruction("OP_MUL", offset),
-                OpCode::Div => Disassembler::simple_instruction("OP_DIV", offset),
-                OpCode::Return => Disassembler::simple_instruction("OP_RETURN", offset),
+                OpCode::Return => Disassembler::simple_instruction(out, "OP_RETURN", offset),
+                OpCode::Assert => Disassembler::simple_instruction(out, "OP_ASSERT", offset),
             },
             Err(..) => {
-                eprintln!("Unknown opcode '{instruction:04}'");
                 *offset += 1;
+                writeln!(out, "Unknown opcode '{instruction:04}'")
             }
         }
     }
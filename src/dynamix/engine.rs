@@ -0,0 +1,497 @@
+//! An embeddable interpreter that keeps its state between evaluations.
+//!
+//! ```
+//! use dynamix::engine::Engine;
+//!
+//! let mut engine = Engine::builder().with_define("DEBUG").build();
+//! engine.eval("let total = 40;").unwrap();
+//! engine.eval("total = total + 2;").unwrap();
+//! ```
+
+use crate::{
+    byte_block::ByteBlock,
+    compiler::{Compiler, CompilerPass},
+    diagnostic::Diagnostic,
+    disassembler::Disassembler,
+    error::DynamixError,
+    native::Native,
+    serialize,
+    stdlib::Module,
+    testing::{self, TestOutcome},
+    virtual_machine::{
+        AngleMode, CancellationToken, InterpretResult, TraceSwitch, VirtualMachine,
+        VirtualMachineBuilder,
+    },
+    Value,
+};
+
+use std::{
+    io::{self, Write},
+    sync::Arc,
+    time::Duration,
+};
+
+/// Owns a `VirtualMachine`, so globals defined by one call to `eval` are
+/// visible to the next.
+pub struct Engine {
+    vm: VirtualMachine,
+    defines: Vec<String>,
+    disassemble: bool,
+    optimize: bool,
+    echo: bool,
+    passes: Vec<Arc<dyn CompilerPass>>,
+    error_sink: Option<ErrorSink>,
+    warning_sink: Option<WarningSink>,
+}
+
+/// Called with every error the engine returns, see
+/// `EngineBuilder::with_error_sink`.
+pub type ErrorSink = Box<dyn Fn(&DynamixError) + Send>;
+
+/// Called with every warning of a script that compiled, see
+/// `EngineBuilder::with_warning_sink`.
+pub type WarningSink = Box<dyn Fn(&Diagnostic) + Send>;
+
+pub struct EngineBuilder {
+    vm: VirtualMachineBuilder,
+    defines: Vec<String>,
+    disassemble: bool,
+    optimize: bool,
+    echo: bool,
+    passes: Vec<Arc<dyn CompilerPass>>,
+    error_sink: Option<ErrorSink>,
+    warning_sink: Option<WarningSink>,
+}
+
+impl EngineBuilder {
+    pub fn new() -> Self {
+        Self {
+            vm: VirtualMachineBuilder::new(),
+            defines: Vec::new(),
+            disassemble: false,
+            optimize: false,
+            echo: false,
+            passes: Vec::new(),
+            error_sink: None,
+            warning_sink: None,
+        }
+    }
+
+    /// An engine for evaluating expressions typed into an app: only the
+    /// `math` and `calc` natives exist, and every expression statement
+    /// prints its value like in the REPL.
+    ///
+    /// ```
+    /// use dynamix::engine::Engine;
+    /// use dynamix::virtual_machine::AngleMode;
+    ///
+    /// let mut engine = Engine::builder()
+    ///     .calculator()
+    ///     .with_angle_mode(AngleMode::Degrees)
+    ///     .build();
+    /// // prints 1 and 6
+    /// engine.eval("sin(90);\nfactorial(3)").unwrap();
+    /// assert!(engine.eval("flush()").is_err());
+    /// ```
+    #[cfg(feature = "stdlib")]
+    pub fn calculator(self) -> Self {
+        self.with_stdlib(false)
+            .with_module(&crate::stdlib::math::MODULE)
+            .with_module(&crate::stdlib::ranges::MODULE)
+            .with_module(&crate::stdlib::calc::MODULE)
+            .with_echo(true)
+    }
+
+    /// See `VirtualMachineBuilder::with_stdlib`.
+    pub fn with_stdlib(mut self, enabled: bool) -> Self {
+        self.vm = self.vm.with_stdlib(enabled);
+        self
+    }
+
+    /// See `VirtualMachineBuilder::enable_module`.
+    pub fn enable_module(mut self, name: &str) -> Result<Self, DynamixError> {
+        self.vm = self.vm.enable_module(name)?;
+        Ok(self)
+    }
+
+    /// See `VirtualMachineBuilder::with_module`.
+    pub fn with_module(mut self, module: &'static Module) -> Self {
+        self.vm = self.vm.with_module(module);
+        self
+    }
+
+    /// See `VirtualMachineBuilder::with_checked_division`.
+    pub fn with_checked_division(mut self, enabled: bool) -> Self {
+        self.vm = self.vm.with_checked_division(enabled);
+        self
+    }
+
+    /// See `VirtualMachineBuilder::with_stack_capacity`.
+    pub fn with_stack_capacity(mut self, capacity: usize) -> Self {
+        self.vm = self.vm.with_stack_capacity(capacity);
+        self
+    }
+
+    /// See `VirtualMachineBuilder::with_max_frames`.
+    pub fn with_max_frames(mut self, max_frames: usize) -> Self {
+        self.vm = self.vm.with_max_frames(max_frames);
+        self
+    }
+
+    /// See `VirtualMachineBuilder::with_max_stack`.
+    pub fn with_max_stack(mut self, max_stack: usize) -> Self {
+        self.vm = self.vm.with_max_stack(max_stack);
+        self
+    }
+
+    /// See `VirtualMachine::set_fuel`, running out is a
+    /// `DynamixError::OutOfFuel`.
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.vm = self.vm.with_fuel(fuel);
+        self
+    }
+
+    /// Fails every `eval`, `call` and the like that runs for longer than
+    /// `timeout` with `DynamixError::TimedOut`, see
+    /// `VirtualMachine::set_timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.vm = self.vm.with_timeout(timeout);
+        self
+    }
+
+    /// See `VirtualMachineBuilder::with_output`.
+    pub fn with_output(mut self, output: impl Write + Send + 'static) -> Self {
+        self.vm = self.vm.with_output(output);
+        self
+    }
+
+    /// See `VirtualMachineBuilder::with_trace_output`.
+    pub fn with_trace_output(mut self, output: impl Write + Send + 'static) -> Self {
+        self.vm = self.vm.with_trace_output(output);
+        self
+    }
+
+    /// See `VirtualMachineBuilder::with_profiling`.
+    pub fn with_profiling(mut self, enabled: bool) -> Self {
+        self.vm = self.vm.with_profiling(enabled);
+        self
+    }
+
+    /// See `VirtualMachineBuilder::with_random_seed`.
+    pub fn with_random_seed(mut self, seed: u64) -> Self {
+        self.vm = self.vm.with_random_seed(seed);
+        self
+    }
+
+    /// See `VirtualMachineBuilder::with_angle_mode`.
+    pub fn with_angle_mode(mut self, mode: AngleMode) -> Self {
+        self.vm = self.vm.with_angle_mode(mode);
+        self
+    }
+
+    /// Prints the value of every top level expression statement and lets
+    /// the last statement leave out its ';', see `Compiler::with_echo`.
+    pub fn with_echo(mut self, enabled: bool) -> Self {
+        self.echo = enabled;
+        self
+    }
+
+    /// See `VirtualMachineBuilder::with_tracing`.
+    pub fn with_tracing(mut self, enabled: bool) -> Self {
+        self.vm = self.vm.with_tracing(enabled);
+        self
+    }
+
+    /// Defines `name` for every `#if name` region the engine compiles.
+    pub fn with_define(mut self, name: &str) -> Self {
+        self.defines.push(name.to_string());
+        self
+    }
+
+    pub fn with_defines(mut self, names: &[String]) -> Self {
+        self.defines.extend_from_slice(names);
+        self
+    }
+
+    /// Prints the bytecode of everything the engine compiles before it runs,
+    /// functions included.
+    pub fn with_disassembly(mut self, enabled: bool) -> Self {
+        self.disassemble = enabled;
+        self
+    }
+
+    /// See `Compiler::with_optimizations`.
+    pub fn with_optimizations(mut self, enabled: bool) -> Self {
+        self.optimize = enabled;
+        self
+    }
+
+    /// Runs `pass` over the syntax tree of everything the engine compiles,
+    /// after the passes added before it.
+    ///
+    /// ```
+    /// use dynamix::{engine::Engine, parser::{Program, Stmt}};
+    ///
+    /// let mut engine = Engine::builder()
+    ///     .with_pass(|program: &mut Program| {
+    ///         program
+    ///             .statements
+    ///             .retain(|stmt| !matches!(stmt, Stmt::Assert { .. }))
+    ///     })
+    ///     .build();
+    ///
+    /// assert!(engine.eval("assert(false);").is_ok());
+    /// ```
+    pub fn with_pass(mut self, pass: impl CompilerPass + 'static) -> Self {
+        self.passes.push(Arc::new(pass));
+        self
+    }
+
+    /// Passes every compile, runtime and load error to `sink` as well as
+    /// returning it, so an embedder can collect them in one place.
+    ///
+    /// ```
+    /// use dynamix::engine::Engine;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let errors = Arc::new(Mutex::new(Vec::new()));
+    /// let sink = errors.clone();
+    /// let mut engine = Engine::builder()
+    ///     .with_error_sink(move |err| sink.lock().unwrap().push(err.to_string()))
+    ///     .build();
+    ///
+    /// assert!(engine.eval("print missing;").is_err());
+    /// assert_eq!(errors.lock().unwrap().len(), 1);
+    /// ```
+    pub fn with_error_sink(mut self, sink: impl Fn(&DynamixError) + Send + 'static) -> Self {
+        self.error_sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Passes the warnings of every script that compiles to `sink`, they
+    /// are otherwise dropped. A script that fails to compile returns its
+    /// warnings with the errors in `DynamixError::CompileError`.
+    ///
+    /// ```
+    /// use dynamix::engine::Engine;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let warnings = Arc::new(Mutex::new(Vec::new()));
+    /// let sink = warnings.clone();
+    /// let mut engine = Engine::builder()
+    ///     .with_warning_sink(move |warning| sink.lock().unwrap().push(warning.message.clone()))
+    ///     .build();
+    ///
+    /// assert!(engine.eval("{ let unused = 1; }").is_ok());
+    /// assert_eq!(*warnings.lock().unwrap(), ["Unused variable 'unused'"]);
+    /// ```
+    pub fn with_warning_sink(mut self, sink: impl Fn(&Diagnostic) + Send + 'static) -> Self {
+        self.warning_sink = Some(Box::new(sink));
+        self
+    }
+
+    pub fn build(self) -> Engine {
+        Engine {
+            vm: self.vm.build(),
+            defines: self.defines,
+            disassemble: self.disassemble,
+            optimize: self.optimize,
+            echo: self.echo,
+            passes: self.passes,
+            error_sink: self.error_sink,
+            warning_sink: self.warning_sink,
+        }
+    }
+}
+
+impl Default for EngineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        EngineBuilder::new().build()
+    }
+
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder::new()
+    }
+
+    pub fn eval(&mut self, source: &str) -> Result<Value, DynamixError> {
+        self.eval_at(source, None)
+    }
+
+    /// Runs the script at `path`, which may be bytecode saved by
+    /// `dynamix compile` instead of source.
+    pub fn eval_file(&mut self, path: &str) -> Result<Value, DynamixError> {
+        let block = self.load_file(path)?;
+        self.eval_block(block)
+    }
+
+    /// Compiles `source` without running it.
+    pub fn compile(&self, source: &str) -> Result<ByteBlock, DynamixError> {
+        self.compile_at(source, None)
+    }
+
+    /// Compiles the script at `path` without running it.
+    pub fn compile_file(&self, path: &str) -> Result<ByteBlock, DynamixError> {
+        let source = self.sink(std::fs::read_to_string(path).map_err(DynamixError::from))?;
+        self.compile_at(&source, Some(path))
+    }
+
+    /// Evaluates `source` as if it was read from `path`, so includes resolve
+    /// next to it.
+    pub(crate) fn eval_at(
+        &mut self,
+        source: &str,
+        path: Option<&str>,
+    ) -> Result<Value, DynamixError> {
+        let block = self.compile_at(source, path)?;
+        self.eval_block(block)
+    }
+
+    /// Runs the top level of `source`, then every `test` block it declares.
+    /// Errors only if the script doesn't compile or its top level fails.
+    pub fn test(&mut self, source: &str) -> Result<Vec<TestOutcome>, DynamixError> {
+        self.test_at(source, None)
+    }
+
+    pub fn test_file(&mut self, path: &str) -> Result<Vec<TestOutcome>, DynamixError> {
+        let block = self.load_file(path)?;
+        self.test_block(block)
+    }
+
+    fn test_at(
+        &mut self,
+        source: &str,
+        path: Option<&str>,
+    ) -> Result<Vec<TestOutcome>, DynamixError> {
+        let block = self.compile_at(source, path)?;
+        self.test_block(block)
+    }
+
+    /// Like `test`, for bytecode compiled earlier.
+    pub fn test_block(
+        &mut self,
+        block: impl Into<Arc<ByteBlock>>,
+    ) -> Result<Vec<TestOutcome>, DynamixError> {
+        let block = block.into();
+        self.eval_block(block.clone())?;
+        Ok(testing::run(&mut self.vm, &block))
+    }
+
+    /// Compiles the script at `path`, or loads it if it's bytecode.
+    pub fn load_file(&self, path: &str) -> Result<ByteBlock, DynamixError> {
+        let bytes = self.sink(std::fs::read(path).map_err(DynamixError::from))?;
+        if serialize::is_bytecode(&bytes) {
+            return self.sink(ByteBlock::deserialize(&bytes).map_err(DynamixError::from));
+        }
+
+        let source = self.sink(
+            String::from_utf8(bytes)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err).into()),
+        )?;
+        self.compile_at(&source, Some(path))
+    }
+
+    /// Compiles `source` as if it was read from `path`, so includes resolve
+    /// next to it and errors are reported in it.
+    pub fn compile_at(&self, source: &str, path: Option<&str>) -> Result<ByteBlock, DynamixError> {
+        let mut compiler = Compiler::new(source)
+            .with_defines(&self.defines)
+            .with_optimizations(self.optimize)
+            .with_echo(self.echo)
+            .with_optional_final_semicolon(self.echo)
+            .with_passes(&self.passes);
+        if let Some(path) = path {
+            compiler = compiler.with_path(path).with_name(path);
+        }
+
+        let output = compiler.compile();
+        if !output.succeeded() {
+            return self.sink(Err(DynamixError::CompileError(output.diagnostics)));
+        }
+
+        if let Some(sink) = &self.warning_sink {
+            output.warnings().for_each(sink);
+        }
+
+        let block = output.block;
+        if self.disassemble {
+            Disassembler::disassemble_all(&block);
+        }
+
+        Ok(block)
+    }
+
+    /// Runs bytecode compiled earlier, e.g. by `compile` or `load_file`.
+    /// Pass an `Arc` to run the same block again without copying it.
+    pub fn eval_block(&mut self, block: impl Into<Arc<ByteBlock>>) -> Result<Value, DynamixError> {
+        let result = match self.vm.interpret(block) {
+            InterpretResult::RuntimeError => Err(DynamixError::RuntimeError {
+                message: self.vm.last_runtime_error(),
+                line: self.vm.last_runtime_line(),
+                backtrace: self.vm.last_backtrace().to_vec(),
+            }),
+            InterpretResult::OutOfFuel => Err(DynamixError::OutOfFuel),
+            InterpretResult::Interrupted => Err(DynamixError::Interrupted),
+            InterpretResult::TimedOut => Err(DynamixError::TimedOut),
+            _ => Ok(self.vm.take_result()),
+        };
+        self.sink(result)
+    }
+
+    /// See `VirtualMachine::call`.
+    pub fn call(&mut self, name: &str, args: &[Value]) -> Result<Value, DynamixError> {
+        let result = self.vm.call(name, args);
+        self.sink(result)
+    }
+
+    /// Hands an error to the error sink on its way out. Only the places an
+    /// error starts call this, so each one is passed once.
+    fn sink<T>(&self, result: Result<T, DynamixError>) -> Result<T, DynamixError> {
+        if let (Err(err), Some(sink)) = (&result, &self.error_sink) {
+            sink(err);
+        }
+        result
+    }
+
+    /// See `VirtualMachine::set_tracing`.
+    pub fn set_tracing(&self, on: bool) {
+        self.vm.set_tracing(on);
+    }
+
+    /// A switch that traces only part of a long run, e.g. flipped by another
+    /// thread while `eval` runs. See `VirtualMachine::trace_switch`.
+    pub fn trace_switch(&self) -> TraceSwitch {
+        self.vm.trace_switch()
+    }
+
+    /// Stops `eval` and the other calls that run a script from another
+    /// thread, they return `DynamixError::Interrupted`. See
+    /// `VirtualMachine::cancellation_token`.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.vm.cancellation_token()
+    }
+
+    /// See `VirtualMachine::natives`.
+    pub fn natives(&self) -> Vec<Native> {
+        self.vm.natives()
+    }
+
+    pub fn vm(&self) -> &VirtualMachine {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VirtualMachine {
+        &mut self.vm
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
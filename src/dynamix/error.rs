@@ -0,0 +1,99 @@
+use crate::diagnostic::Diagnostic;
+
+use std::{fmt::Display, io};
+
+/// Why running a script through `run` or `run_file` failed.
+#[derive(Debug)]
+pub enum DynamixError {
+    /// Every diagnostic reported while compiling, at least one is an error.
+    CompileError(Vec<Diagnostic>),
+    /// `message` is the full report, location and backtrace included.
+    /// `backtrace` holds the calls that were running, innermost first.
+    RuntimeError {
+        message: String,
+        line: u32,
+        backtrace: Vec<TraceFrame>,
+    },
+    Io(io::Error),
+    /// The script used up the fuel it was given, see
+    /// `VirtualMachine::set_fuel`.
+    OutOfFuel,
+    /// The host cancelled the run, see `VirtualMachine::cancellation_token`.
+    Interrupted,
+    /// The run took longer than the timeout it was given, see
+    /// `VirtualMachine::set_timeout`.
+    TimedOut,
+    /// A module name passed to `enable_module` that isn't in
+    /// `stdlib::MODULES`.
+    UnknownModule(String),
+}
+
+// exit codes of the binary, from sysexits.h
+pub const EXIT_USAGE: u8 = 64;
+pub const EXIT_COMPILE_ERROR: u8 = 65;
+pub const EXIT_NO_INPUT: u8 = 66;
+pub const EXIT_RUNTIME_ERROR: u8 = 70;
+
+/// A call that was running when a runtime error was raised.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    /// Name of the function, or of the script for the top level.
+    pub function: String,
+    /// Line of the instruction it was at, the call for all but the
+    /// innermost frame.
+    pub line: u32,
+    /// The included file that line is in, `None` for the main file.
+    pub file: Option<String>,
+}
+
+impl DynamixError {
+    /// The status the binary exits with when a script fails this way.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            DynamixError::CompileError(..) => EXIT_COMPILE_ERROR,
+            DynamixError::RuntimeError { .. }
+            | DynamixError::OutOfFuel
+            | DynamixError::Interrupted
+            | DynamixError::TimedOut => EXIT_RUNTIME_ERROR,
+            DynamixError::Io(..) => EXIT_NO_INPUT,
+            DynamixError::UnknownModule(..) => EXIT_USAGE,
+        }
+    }
+}
+
+impl Display for DynamixError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DynamixError::CompileError(diagnostics) => {
+                for (i, diagnostic) in diagnostics.iter().enumerate() {
+                    if i != 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{diagnostic}")?;
+                }
+                Ok(())
+            }
+            DynamixError::RuntimeError { message, .. } => write!(f, "{message}"),
+            DynamixError::Io(err) => write!(f, "{err}"),
+            DynamixError::OutOfFuel => write!(f, "Out of fuel, the instruction budget is used up"),
+            DynamixError::Interrupted => write!(f, "Interrupted, the run was cancelled"),
+            DynamixError::TimedOut => write!(f, "Timed out, the run took too long"),
+            DynamixError::UnknownModule(name) => write!(f, "Unknown stdlib module '{name}'"),
+        }
+    }
+}
+
+impl std::error::Error for DynamixError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DynamixError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DynamixError {
+    fn from(err: io::Error) -> Self {
+        DynamixError::Io(err)
+    }
+}
@@ -0,0 +1,62 @@
+//! Keeps reference counted values from forming cycles.
+//!
+//! Values are freed as soon as nothing refers to them, which only works as
+//! long as no value can reach itself. Tuples are built from values that
+//! already exist so they can't, but channels and the caches of memoized fns
+//! are shared by every copy and filled in after they're made. Instead of a
+//! collector that finds cycles afterwards, the two places that fill them
+//! check with `reaches` first: `send` refuses a value that would close a
+//! cycle and a memoized fn doesn't cache one.
+
+use crate::constant::Constant;
+
+use std::collections::HashSet;
+
+/// Whether `container` is `value` or is held somewhere inside it, following
+/// tuples, the values queued in channels and the results memoized fns cached.
+/// `container` should be a channel or a memoized fn, which compare by
+/// identity.
+pub fn reaches(value: &Constant, container: &Constant) -> bool {
+    let mut found = false;
+    walk(std::iter::once(value), |value| {
+        found |= value == container;
+        !found
+    });
+    found
+}
+
+/// How many values `roots` keep alive, a channel or memoized fn shared by
+/// several of them is counted once along with what it holds.
+pub fn live_values<'a>(roots: impl Iterator<Item = &'a Constant>) -> usize {
+    let mut count = 0;
+    walk(roots, |_| {
+        count += 1;
+        true
+    });
+    count
+}
+
+/// Calls `visit` on every value reachable from `roots` until it returns
+/// false. Shared containers are entered once, so it ends even if there is
+/// a cycle after all.
+fn walk<'a>(roots: impl Iterator<Item = &'a Constant>, mut visit: impl FnMut(&Constant) -> bool) {
+    let mut pending: Vec<Constant> = roots.cloned().collect();
+    let mut entered = HashSet::new();
+
+    while let Some(value) = pending.pop() {
+        if !visit(&value) {
+            return;
+        }
+
+        match value {
+            Constant::Tuple(values) => pending.extend(values),
+            Constant::Channel(channel) if entered.insert(channel.id()) => {
+                pending.extend(channel.values())
+            }
+            Constant::Memoized(memoized) if entered.insert(memoized.id()) => {
+                pending.extend(memoized.values())
+            }
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! The `#include` dependency graph of a script, for `dynamix graph`.
+//!
+//! Includes are found by lexing each file, so every include is part of the
+//! graph whichever `#if` region it's in. Cycles are reported rather than
+//! rejected, the point is to find them before the compiler does.
+
+use crate::{
+    dap::json::{object, Json},
+    lexer::{Lexer, TokenType},
+};
+
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    Dot,
+    Json,
+}
+
+impl GraphFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dot" => Some(GraphFormat::Dot),
+            "json" => Some(GraphFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    /// The include leads back to a file that's still being included.
+    pub cyclic: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct DependencyGraph {
+    /// Every script in the order it was found, the entry script first.
+    pub files: Vec<String>,
+    /// Indices into `files` of includes that couldn't be read.
+    pub missing: Vec<usize>,
+    pub edges: Vec<Edge>,
+    // canonical path to index into 'files'
+    ids: HashMap<PathBuf, usize>,
+}
+
+impl DependencyGraph {
+    pub fn build(entry: &str) -> io::Result<Self> {
+        let source = std::fs::read_to_string(entry)?;
+
+        let mut graph = Self::default();
+        let id = graph.node(Path::new(entry));
+        graph.visit(id, &source, &mut vec![id]);
+        Ok(graph)
+    }
+
+    pub fn has_cycles(&self) -> bool {
+        self.edges.iter().any(|edge| edge.cyclic)
+    }
+
+    fn node(&mut self, path: &Path) -> usize {
+        let canonical = path.canonicalize().unwrap_or(path.to_path_buf());
+        if let Some(id) = self.ids.get(&canonical) {
+            return *id;
+        }
+
+        self.files.push(path.display().to_string());
+        self.ids.insert(canonical, self.files.len() - 1);
+        self.files.len() - 1
+    }
+
+    fn visit(&mut self, from: usize, source: &str, including: &mut Vec<usize>) {
+        let mut tokens = Lexer::new(source).take_while(|token| token.typ3 != TokenType::Eof);
+
+        let mut includes = Vec::new();
+        while let Some(token) = tokens.next() {
+            if token.typ3 != TokenType::Include {
+                continue;
+            }
+
+            if let Some(target) = tokens.next().filter(|t| t.typ3 == TokenType::String) {
+                includes.push(target.lexeme.trim_matches('"').to_string());
+            }
+        }
+
+        for name in includes {
+            let path = Path::new(&self.files[from]).with_file_name(name);
+            let known = self.files.len();
+            let to = self.node(&path);
+
+            self.edges.push(Edge {
+                from,
+                to,
+                cyclic: including.contains(&to),
+            });
+
+            // only follow files we haven't seen before
+            if to != known {
+                continue;
+            }
+
+            match std::fs::read_to_string(&path) {
+                Ok(source) => {
+                    including.push(to);
+                    self.visit(to, &source, including);
+                    including.pop();
+                }
+                Err(..) => self.missing.push(to),
+            }
+        }
+    }
+
+    pub fn render(&self, format: GraphFormat) -> String {
+        match format {
+            GraphFormat::Dot => self.to_dot(),
+            GraphFormat::Json => self.to_json().to_string(),
+        }
+    }
+
+    fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph dependencies {\n");
+
+        for (id, file) in self.files.iter().enumerate() {
+            let style = if self.missing.contains(&id) {
+                " [style=dashed]"
+            } else {
+                ""
+            };
+            dot.push_str(&format!("    {file:?}{style};\n"));
+        }
+
+        for edge in self.edges.iter() {
+            let style = if edge.cyclic { " [color=red]" } else { "" };
+            let (from, to) = (&self.files[edge.from], &self.files[edge.to]);
+            dot.push_str(&format!("    {from:?} -> {to:?}{style};\n"));
+        }
+
+        dot.push('}');
+        dot
+    }
+
+    fn to_json(&self) -> Json {
+        let files = self
+            .files
+            .iter()
+            .enumerate()
+            .map(|(id, file)| {
+                object(vec![
+                    ("path", file.as_str().into()),
+                    ("missing", self.missing.contains(&id).into()),
+                ])
+            })
+            .collect();
+
+        let edges = self
+            .edges
+            .iter()
+            .map(|edge| {
+                object(vec![
+                    ("from", self.files[edge.from].as_str().into()),
+                    ("to", self.files[edge.to].as_str().into()),
+                    ("cyclic", edge.cyclic.into()),
+                ])
+            })
+            .collect();
+
+        object(vec![
+            ("files", Json::Array(files)),
+            ("edges", Json::Array(edges)),
+            ("cycles", self.has_cycles().into()),
+        ])
+    }
+}
@@ -0,0 +1,125 @@
+//! `dynamix learn`, a tour of the language through short annotated scripts.
+//!
+//! Lessons run one after another in the same engine, so what one defines
+//! is still there in the next, the way lines typed into the REPL are.
+
+use std::{
+    io::{self, BufRead, Write},
+    sync::{Arc, Mutex},
+};
+
+use crate::engine::Engine;
+
+pub struct Lesson {
+    pub title: &'static str,
+    pub source: &'static str,
+}
+
+pub const LESSONS: &[Lesson] = &[
+    Lesson {
+        title: "Values",
+        source: include_str!("lessons/01_values.dyn"),
+    },
+    Lesson {
+        title: "Variables",
+        source: include_str!("lessons/02_variables.dyn"),
+    },
+    Lesson {
+        title: "Control flow",
+        source: include_str!("lessons/03_control_flow.dyn"),
+    },
+    Lesson {
+        title: "Functions",
+        source: include_str!("lessons/04_functions.dyn"),
+    },
+    Lesson {
+        title: "Tuples",
+        source: include_str!("lessons/05_tuples.dyn"),
+    },
+    Lesson {
+        title: "Assertions",
+        source: include_str!("lessons/06_assertions.dyn"),
+    },
+];
+
+/// What the engine prints, kept so it can be shown under the lesson.
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Captured {
+    fn take(&self) -> String {
+        String::from_utf8_lossy(&std::mem::take(&mut *self.0.lock().unwrap())).to_string()
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Answer {
+    Run,
+    Skip,
+    Quit,
+}
+
+/// Reads the answer to a prompt, running is the default and the end of
+/// `input` quits.
+fn ask(input: &mut impl BufRead, out: &mut impl Write, prompt: &str) -> io::Result<Answer> {
+    write!(out, "{prompt}")?;
+    out.flush()?;
+
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        writeln!(out)?;
+        return Ok(Answer::Quit);
+    }
+
+    Ok(match line.trim() {
+        "s" | "skip" => Answer::Skip,
+        "q" | "quit" => Answer::Quit,
+        _ => Answer::Run,
+    })
+}
+
+/// Steps through the lessons from the one at `start`, reading answers to
+/// its prompts from `input`.
+pub fn run(start: usize, input: &mut impl BufRead, out: &mut impl Write) -> io::Result<()> {
+    let captured = Captured::default();
+    let mut engine = Engine::builder().with_output(captured.clone()).build();
+
+    for (i, lesson) in LESSONS.iter().enumerate().skip(start) {
+        writeln!(
+            out,
+            "-- Lesson {}/{}: {} --",
+            i + 1,
+            LESSONS.len(),
+            lesson.title
+        )?;
+        writeln!(out, "{}", lesson.source.trim_end())?;
+        writeln!(out)?;
+
+        match ask(input, out, "[enter] run it, [s] skip, [q] quit: ")? {
+            Answer::Run => {}
+            Answer::Skip => continue,
+            Answer::Quit => return Ok(()),
+        }
+
+        let result = engine.eval(lesson.source);
+        write!(out, "{}", captured.take())?;
+        if let Err(err) = result {
+            writeln!(out, "error: {err}")?;
+        }
+        writeln!(out)?;
+    }
+
+    writeln!(
+        out,
+        "That's every lesson, run dynamix with no args to keep going in the REPL"
+    )
+}
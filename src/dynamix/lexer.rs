@@ -1,4 +1,36 @@
-use std::collections::HashMap;
+use std::marker::PhantomData;
+
+/// The char a char literal's lexeme stands for: the char itself, or for an
+/// escape one of `\n`, `\t`, `\r`, `\0`, `\\`, `\'`, `\"` or `\u{1F600}`
+/// with up to six hex digits. `None` if the escape isn't one of those.
+pub fn unescape_char(lexeme: &str) -> Option<char> {
+    let mut chars = lexeme.chars();
+    let c = match chars.next()? {
+        '\\' => match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\\' => '\\',
+            '\'' => '\'',
+            '"' => '"',
+            'u' => {
+                let digits = chars.as_str().strip_prefix('{')?.strip_suffix('}')?;
+                if digits.is_empty() || digits.len() > 6 {
+                    return None;
+                }
+                return char::from_u32(u32::from_str_radix(digits, 16).ok()?);
+            }
+            _ => return None,
+        },
+        c => c,
+    };
+
+    match chars.next() {
+        Some(..) => None,
+        None => Some(c),
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TokenType {
@@ -13,6 +45,7 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    StarStar,
 
     Bang,
     BangEq,
@@ -29,6 +62,7 @@ pub enum TokenType {
     Char,
 
     And,
+    Assert,
     Struct,
     Else,
     False,
@@ -43,8 +77,12 @@ pub enum TokenType {
     SSelf,
     True,
     Let,
+    Macro,
+    Test,
     While,
 
+    Include,
+
     Error,
     Eof,
 }
@@ -54,6 +92,11 @@ pub struct Token {
     pub typ3: TokenType,
     pub lexeme: String,
     pub line: usize,
+    /// Column of the token's first char counting from 1, 0 if the token
+    /// wasn't read from source.
+    pub column: usize,
+    /// Index of the source file the token came from, 0 is the main file.
+    pub file: u16,
 }
 
 impl Token {
@@ -62,6 +105,8 @@ impl Token {
             typ3: TokenType::Ident,
             lexeme: String::new(),
             line: 1,
+            column: 0,
+            file: 0,
         }
     }
 }
@@ -82,61 +127,83 @@ impl std::fmt::Display for Token {
     }
 }
 
+/// The keyword `lexeme` spells, `None` for any other identifier.
+fn keyword(lexeme: &str) -> Option<TokenType> {
+    let typ3 = match lexeme {
+        "print" => TokenType::Print,
+        "if" => TokenType::If,
+        "else" => TokenType::Else,
+        "assert" => TokenType::Assert,
+        "let" => TokenType::Let,
+        "macro" => TokenType::Macro,
+        "test" => TokenType::Test,
+        "struct" => TokenType::Struct,
+        "self" => TokenType::SSelf,
+        "while" => TokenType::While,
+        "for" => TokenType::For,
+        "return" => TokenType::Return,
+        "fun" => TokenType::Fun,
+        "true" => TokenType::True,
+        "false" => TokenType::False,
+        "null" => TokenType::Null,
+        _ => return None,
+    };
+
+    Some(typ3)
+}
+
 pub struct Lexer<'a> {
-    source: &'a str,
+    // lexemes are built from 'chars', the source is only borrowed
+    source: PhantomData<&'a str>,
     chars: Vec<char>,
     start: usize,
     cursor: usize,
     line: usize,
-    keywords: HashMap<String, TokenType>,
+    // index into 'chars' of the first char on the current line
+    line_start: usize,
+    // column of the token being read
+    column: usize,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(source: &'a str) -> Self {
         Self {
-            source,
+            source: PhantomData,
             chars: source.chars().collect(),
             start: 0,
             cursor: 0,
             line: 1,
-            keywords: vec![
-                ("print", TokenType::Print),
-                ("if", TokenType::If),
-                ("else", TokenType::Else),
-                ("&&", TokenType::And),
-                ("||", TokenType::Or),
-                ("let", TokenType::Let),
-                ("struct", TokenType::Struct),
-                ("self", TokenType::SSelf),
-                ("while", TokenType::While),
-                ("for", TokenType::For),
-                ("return", TokenType::Return),
-                ("fun", TokenType::Fun),
-                ("true", TokenType::True),
-                ("false", TokenType::False),
-                ("null", TokenType::Null),
-            ]
-            .into_iter()
-            .map(|(k, v)| (String::from(k), v))
-            .collect(),
+            line_start: 0,
+            column: 1,
         }
     }
 
+    // lookahead past the end of the source is '\0', which nothing in the
+    // language starts or continues with, so scanning stops there
+
+    /// The char at the cursor, moving past it unless that's the end.
     fn advance(&mut self) -> char {
-        self.cursor += 1;
-        self.chars[self.cursor - 1]
+        let c = self.peek();
+        if !self.is_at_end() {
+            self.cursor += 1;
+        }
+        c
     }
 
     fn peek(&self) -> char {
-        self.chars[self.cursor]
+        self.chars.get(self.cursor).copied().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
+        self.chars.get(self.cursor + 1).copied().unwrap_or('\0')
+    }
 
-        self.chars[self.cursor + 1]
+    /// Starts over on `source`, e.g. after it was preprocessed.
+    pub fn reset(&mut self, source: &str) {
+        self.chars = source.chars().collect();
+        self.start = 0;
+        self.cursor = 0;
+        self.line = 1;
     }
 
     fn trim(&mut self) {
@@ -151,18 +218,15 @@ impl<'a> Lexer<'a> {
                     self.advance();
                     continue;
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next() == '/' => {
+                    while !self.is_at_end() && self.peek() != '\n' {
+                        self.advance();
                     }
                 }
                 '\n' => {
                     self.line += 1;
                     self.advance();
+                    self.line_start = self.cursor;
                     continue;
                 }
                 _ => return,
@@ -177,7 +241,7 @@ impl<'a> Lexer<'a> {
     fn matches(&mut self, c: char) -> bool {
         if self.is_at_end() {
             false
-        } else if self.chars[self.cursor] == c {
+        } else if self.peek() == c {
             self.advance();
             true
         } else {
@@ -188,44 +252,109 @@ impl<'a> Lexer<'a> {
     fn make_token(&self, typ3: TokenType) -> Token {
         Token {
             typ3,
-            lexeme: String::from(&self.source[self.start..self.cursor]),
+            lexeme: self.lexeme(),
             line: self.line,
+            column: self.column,
+            file: 0,
         }
     }
 
+    // 'start' and 'cursor' index chars not bytes, so the lexeme can't be
+    // sliced straight out of the source
+    fn lexeme(&self) -> String {
+        self.chars[self.start..self.cursor].iter().collect()
+    }
+
     fn error_token(&mut self, msg: String) -> Token {
         Token {
             typ3: TokenType::Error,
             lexeme: msg,
             line: self.line,
+            column: self.column,
+            file: 0,
         }
     }
 
+    /// A char literal holds one char or an escape sequence standing for
+    /// one, see `unescape_char`. The token's lexeme is what's between the
+    /// quotes as written, e.g. `\n` for `'\n'`.
     fn char(&mut self) -> Option<Token> {
         self.start += 1;
-        self.advance();
+        if self.is_at_end() {
+            return Some(self.error_token("Unterminated character literal".to_string()));
+        }
 
-        let res = Some(self.make_token(TokenType::Char));
+        match self.advance() {
+            '\'' => return Some(self.error_token("Empty character literal".to_string())),
+            '\n' => {
+                self.line += 1;
+                self.line_start = self.cursor;
+            }
+            '\\' if !self.is_at_end() => self.escape(),
+            _ => (),
+        }
 
-        if self.peek() != '\'' {
-            return Some(self.error_token("Unterminated character literal".to_string()));
+        if self.is_at_end() || self.peek() != '\'' {
+            return Some(self.unclosed_char());
         }
 
+        let token = self.make_token(TokenType::Char);
         self.advance();
-        res
+
+        match unescape_char(&token.lexeme) {
+            Some(..) => Some(token),
+            None => Some(self.error_token(format!(
+                "Invalid escape sequence '{}' in character literal",
+                token.lexeme
+            ))),
+        }
+    }
+
+    /// Skips what follows a backslash in a char literal, the digits of a
+    /// `\u{...}` escape included.
+    fn escape(&mut self) {
+        if self.advance() == 'u' && self.matches('{') {
+            while !self.is_at_end() && self.peek().is_ascii_hexdigit() {
+                self.advance();
+            }
+            self.matches('}');
+        }
+    }
+
+    /// A char literal that doesn't close after its char either holds more
+    /// than one, if there's a quote later on the line, or was never closed.
+    fn unclosed_char(&mut self) -> Token {
+        let rest = self.chars[self.cursor..]
+            .iter()
+            .take_while(|&&c| c != '\n')
+            .position(|&c| c == '\'');
+
+        match rest {
+            Some(quote) => {
+                self.cursor += quote + 1;
+                self.error_token("Character literal holds more than one char".to_string())
+            }
+            None => self.error_token("Unterminated character literal".to_string()),
+        }
     }
 
     fn string(&mut self) -> Option<Token> {
+        // an unterminated string is reported where it starts, not at the end
+        // of the file
+        let line = self.line;
+
         while !self.is_at_end() && self.peek() != '"' {
             if self.peek() == '\n' {
                 self.line += 1;
+                self.line_start = self.cursor + 1;
             }
 
             self.advance();
         }
 
         if self.is_at_end() {
-            return Some(self.error_token("Unterminated string literal".to_string()));
+            let token = self.error_token("Unterminated string literal".to_string());
+            return Some(Token { line, ..token });
         }
 
         self.advance();
@@ -233,14 +362,10 @@ impl<'a> Lexer<'a> {
     }
 
     fn number(&mut self) -> Option<Token> {
-        while !self.is_at_end() && self.peek().is_ascii_digit() || "_'".contains(self.peek()) {
+        while self.peek().is_ascii_digit() || "_'".contains(self.peek()) {
             self.advance();
         }
 
-        if self.is_at_end() {
-            return Some(self.make_token(TokenType::Number));
-        }
-
         if self.peek() == '.' {
             self.advance();
             while self.peek().is_ascii_digit() || "_'".contains(self.peek()) {
@@ -251,20 +376,30 @@ impl<'a> Lexer<'a> {
         Some(self.make_token(TokenType::Number))
     }
 
-    fn identifier(&mut self) -> Option<Token> {
-        while self.peek().is_ascii_alphanumeric() || "_&|".contains(self.peek()) {
+    fn directive(&mut self) -> Option<Token> {
+        while !self.is_at_end() && self.peek().is_ascii_alphabetic() {
             self.advance();
         }
 
-        let value = String::from(&self.source[self.start..self.cursor]);
+        match self.lexeme().as_str() {
+            "#include" => Some(self.make_token(TokenType::Include)),
+            directive => {
+                let err = format!("Unknown directive '{directive}'");
+                Some(self.error_token(err))
+            }
+        }
+    }
 
-        let typ3 = if self.keywords.iter().any(|(s, ..)| s == &value) {
-            *self.keywords.get(&value).unwrap()
-        } else {
-            TokenType::Ident
-        };
+    fn identifier(&mut self) -> Option<Token> {
+        while self.peek().is_ascii_alphanumeric() || self.peek() == '_' {
+            self.advance();
+        }
 
-        Some(self.make_token(typ3))
+        let token = self.make_token(TokenType::Ident);
+        Some(Token {
+            typ3: keyword(&token.lexeme).unwrap_or(TokenType::Ident),
+            ..token
+        })
     }
 }
 
@@ -274,6 +409,7 @@ impl<'a> Iterator for Lexer<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         self.trim();
 
+        self.column = self.cursor - self.line_start + 1;
         if self.is_at_end() {
             return Some(self.make_token(TokenType::Eof));
         }
@@ -283,7 +419,7 @@ impl<'a> Iterator for Lexer<'a> {
 
         if c.is_ascii_digit() {
             return self.number();
-        } else if c.is_alphabetic() || "_&|".contains(c) {
+        } else if c.is_alphabetic() || c == '_' {
             return self.identifier();
         }
 
@@ -298,7 +434,14 @@ impl<'a> Iterator for Lexer<'a> {
             '-' => Some(self.make_token(TokenType::Minus)),
             '+' => Some(self.make_token(TokenType::Plus)),
             '/' => Some(self.make_token(TokenType::Slash)),
-            '*' => Some(self.make_token(TokenType::Star)),
+            '*' => {
+                let typ3 = if self.matches('*') {
+                    TokenType::StarStar
+                } else {
+                    TokenType::Star
+                };
+                Some(self.make_token(typ3))
+            }
             '!' => {
                 let typ3 = if self.matches('=') {
                     TokenType::BangEq
@@ -331,6 +474,9 @@ impl<'a> Iterator for Lexer<'a> {
                 };
                 Some(self.make_token(typ3))
             }
+            '&' if self.matches('&') => Some(self.make_token(TokenType::And)),
+            '|' if self.matches('|') => Some(self.make_token(TokenType::Or)),
+            '#' => self.directive(),
             '\'' => self.char(),
             '"' => self.string(),
             _ => {
@@ -0,0 +1,100 @@
+//! Token level macros.
+//!
+//! `macro twice(x) { x; x; }` records the body tokens, and every later use
+//! `twice(expr);` is replaced with the body wrapped in its own block, each
+//! parameter substituted with its parenthesized argument. Variables declared
+//! inside the body are renamed per expansion so they can't capture names
+//! used by the arguments.
+
+use crate::lexer::{Token, TokenType};
+
+/// How deep macro uses may expand into other macro uses.
+pub const MACRO_DEPTH_LIMIT: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct Macro {
+    pub params: Vec<String>,
+    pub body: Vec<Token>,
+}
+
+// tokens written by the expansion are attributed to the use site
+fn synthetic(typ3: TokenType, lexeme: &str, site: &Token) -> Token {
+    Token {
+        typ3,
+        lexeme: lexeme.to_string(),
+        line: site.line,
+        column: site.column,
+        file: site.file,
+    }
+}
+
+/// Names declared with `let` inside the macro body.
+fn declared_names(body: &[Token]) -> Vec<String> {
+    let mut names = Vec::new();
+
+    let mut i = 0;
+    while i < body.len() {
+        if body[i].typ3 == TokenType::Let {
+            i += 1;
+
+            if body.get(i).map(|t| t.typ3) == Some(TokenType::LParen) {
+                while i < body.len() && body[i].typ3 != TokenType::RParen {
+                    if body[i].typ3 == TokenType::Ident {
+                        names.push(body[i].lexeme.clone());
+                    }
+                    i += 1;
+                }
+            } else if let Some(token) = body.get(i).filter(|t| t.typ3 == TokenType::Ident) {
+                names.push(token.lexeme.clone());
+            }
+        }
+
+        i += 1;
+    }
+
+    names
+}
+
+impl Macro {
+    /// Expands a use of this macro at `site`, `id` makes renamed locals unique.
+    pub fn expand(&self, args: &[Vec<Token>], site: &Token, id: usize) -> Vec<Token> {
+        let declared = declared_names(&self.body);
+
+        let mut tokens = vec![synthetic(TokenType::LCurly, "{", site)];
+
+        for (i, token) in self.body.iter().enumerate() {
+            // a method name after '.' isn't a variable, even if it's spelled
+            // like a parameter or a local
+            let method = i > 0 && self.body[i - 1].typ3 == TokenType::Dot;
+            if token.typ3 != TokenType::Ident || method {
+                tokens.push(Token {
+                    line: site.line,
+                    column: site.column,
+                    file: site.file,
+                    ..token.clone()
+                });
+                continue;
+            }
+
+            if let Some(index) = self.params.iter().position(|p| p == &token.lexeme) {
+                tokens.push(synthetic(TokenType::LParen, "(", site));
+                tokens.extend(args[index].iter().cloned());
+                tokens.push(synthetic(TokenType::RParen, ")", site));
+            } else if declared.contains(&token.lexeme) {
+                // '@' can't appear in source identifiers, so this never collides
+                let lexeme = format!("{}@{id}", token.lexeme);
+                tokens.push(synthetic(TokenType::Ident, &lexeme, site));
+            } else {
+                tokens.push(Token {
+                    line: site.line,
+                    column: site.column,
+                    file: site.file,
+                    ..token.clone()
+                });
+            }
+        }
+
+        tokens.push(synthetic(TokenType::RCurly, "}", site));
+        tokens
+    }
+}
@@ -1,35 +1,245 @@
 pub mod byte_block;
+pub mod channel;
+#[doc(hidden)]
+pub mod cli;
+#[doc(hidden)]
+pub mod codegen;
+#[doc(hidden)]
+pub mod compile_output;
+#[doc(hidden)]
 pub mod compiler;
 pub mod constant;
+#[doc(hidden)]
+pub mod dap;
+pub mod debugger;
+pub mod diagnostic;
+#[doc(hidden)]
 pub mod disassembler;
+pub mod engine;
+pub mod error;
+pub mod gc;
+#[doc(hidden)]
+pub mod graph;
+#[doc(hidden)]
+pub mod learn;
+#[doc(hidden)]
 pub mod lexer;
+#[doc(hidden)]
+pub mod macros;
+pub mod native;
+#[doc(hidden)]
+pub mod optimizer;
+#[doc(hidden)]
+pub mod parser;
+pub mod prelude;
+#[doc(hidden)]
+pub mod preprocessor;
+pub mod profile;
+pub mod semantic;
+pub mod serialize;
+#[doc(hidden)]
 pub mod stack;
+pub mod stdlib;
+pub mod testing;
+#[doc(hidden)]
+pub mod verifier;
 pub mod virtual_machine;
 
+use byte_block::ByteBlock;
+use cli::{Command, Input};
 use compiler::Compiler;
+use disassembler::Disassembler;
+use engine::Engine;
+use error::DynamixError;
+use lexer::{Lexer, TokenType};
+use testing::TestOutcome;
 use virtual_machine::{InterpretResult, VirtualMachine};
 
 use std::{
-    io::{stdin, stdout, Write},
+    collections::HashMap,
+    io::{stdin, stdout, Read, Write},
     path::Path,
+    process::ExitCode,
+    sync::Arc,
 };
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-pub type RuntimeResult = (InterpretResult, String);
+/// A value produced by a script.
+pub type Value = constant::Constant;
 
-pub fn runtime_start() {
-    let mut args = std::env::args();
+/// Runs the binary, the exit code is 0 unless something failed.
+#[doc(hidden)]
+pub fn runtime_start() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
 
-    match args.len() {
-        1 => repl(),
-        2 | 3 | 4 => {
-            let (..) = run_file(&args.nth(1).unwrap()).unwrap();
+    if args.first().map(String::as_str) == Some("dap") {
+        let port = match args.get(1) {
+            Some(port) => match port.parse() {
+                Ok(port) => port,
+                Err(..) => return usage(),
+            },
+            None => dap::DEFAULT_PORT,
+        };
+
+        if let Err(err) = dap::serve(port) {
+            eprintln!("Debug adapter failed: {err}");
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    if args.first().map(String::as_str) == Some("graph") {
+        return print_graph(args[1..].to_vec());
+    }
+
+    if args.first().map(String::as_str) == Some("learn") {
+        return start_lessons(&args[1..]);
+    }
+
+    let options = match cli::parse(&args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{err}");
+            return usage();
+        }
+    };
+
+    let Some(input) = &options.input else {
+        repl();
+        return ExitCode::SUCCESS;
+    };
+
+    if options.tokens {
+        return print_tokens(input);
+    }
+
+    let mut engine = Engine::builder()
+        .with_defines(&options.defines)
+        .with_profiling(options.profile.is_some())
+        .with_optimizations(options.optimize)
+        .with_warning_sink(|warning| eprintln!("{warning}"))
+        .build();
+    let block = match input {
+        Input::Path(path) => engine.load_file(path),
+        Input::Source(source) => engine.compile(source),
+        Input::Stdin => read_stdin()
+            .map_err(DynamixError::from)
+            .and_then(|source| engine.compile_at(&source, Some(input.name()))),
+    };
+
+    let block = match block {
+        Ok(block) => block,
+        Err(err) => return report(&Err(err), input.name()),
+    };
+
+    match options.command {
+        Command::Run if options.disassemble => {
+            Disassembler::disassemble_all(&block);
+            ExitCode::SUCCESS
+        }
+        Command::Run => {
+            let result = engine.eval_block(block);
+            if let (Some(path), Some(profile)) = (&options.profile, engine.vm().profile()) {
+                if let Err(err) = std::fs::write(path, profile.folded()) {
+                    eprintln!("Failed to write profile to '{path}': {err}");
+                    return ExitCode::FAILURE;
+                }
+            }
+            report(&result, input.name())
+        }
+        Command::Test => match engine.test_block(block) {
+            Ok(outcomes) => {
+                testing::print_report(&outcomes);
+                if outcomes.iter().all(TestOutcome::passed) {
+                    ExitCode::SUCCESS
+                } else {
+                    ExitCode::FAILURE
+                }
+            }
+            Err(err) => report(&Err(err), input.name()),
+        },
+        Command::Compile => {
+            let output = options.output_path().unwrap_or_default();
+            let written = block
+                .serialize()
+                .and_then(|bytes| std::fs::write(&output, bytes));
+
+            match written {
+                Ok(()) => {
+                    println!("compiled '{}' to '{output}'", input.name());
+                    ExitCode::SUCCESS
+                }
+                Err(err) => {
+                    eprintln!("Failed to write bytecode to '{output}': {err}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+    }
+}
+
+/// `dynamix graph <entry> [--format dot|json]`
+fn print_graph(args: Vec<String>) -> ExitCode {
+    let (entry, format) = match args.as_slice() {
+        [entry] => (entry, Some(graph::GraphFormat::Dot)),
+        [entry, flag, format] if flag == "--format" => {
+            (entry, graph::GraphFormat::from_name(format))
+        }
+        _ => return usage(),
+    };
+
+    let Some(format) = format else {
+        return usage();
+    };
+
+    match graph::DependencyGraph::build(entry) {
+        Ok(graph) => {
+            println!("{}", graph.render(format));
+            ExitCode::SUCCESS
+        }
+        Err(..) => {
+            eprintln!("Failed to open file from path: /{entry}");
+            ExitCode::from(error::EXIT_NO_INPUT)
         }
-        _ => print_usage(),
     }
 }
 
+/// `dynamix learn [lesson]`, the lesson is counted from 1.
+fn start_lessons(args: &[String]) -> ExitCode {
+    let start = match args {
+        [] => 0,
+        [lesson] => match lesson.parse::<usize>() {
+            Ok(lesson @ 1..) if lesson <= learn::LESSONS.len() => lesson - 1,
+            _ => {
+                eprintln!("There are lessons 1 to {}", learn::LESSONS.len());
+                return usage();
+            }
+        },
+        _ => return usage(),
+    };
+
+    match learn::run(start, &mut stdin().lock(), &mut stdout()) {
+        Ok(..) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// How many lines a statement typed into the REPL can span before it's
+/// abandoned, so a stray `{` can't swallow the rest of the session.
+const MAX_PENDING_LINES: usize = 256;
+
+/// Reads statements from stdin and runs each in the same VM.
+///
+/// Lines share the VM's globals, and functions look up the globals they use
+/// by name every time they're called instead of capturing them when they're
+/// defined. Redefining a function on a later line changes what every
+/// function calling it does, and a function can use a global that's only
+/// defined after it, as long as it exists by the time it's called.
+#[doc(hidden)]
 pub fn repl() {
     println!(
         "Welcome to Dynamix {VERSION}, running {} on platform {}",
@@ -38,69 +248,238 @@ pub fn repl() {
     );
 
     let mut vm = VirtualMachine::new();
+    let mut last_block: Option<Arc<ByteBlock>> = None;
+    // each line is compiled on its own, macros it defines are passed on
+    let mut macros = HashMap::new();
+
+    // lines of a statement that hasn't been finished yet
+    let mut pending = String::new();
+    let mut pending_lines = 0;
 
     loop {
-        print!(">> ");
+        print!("{}", if pending.is_empty() { ">> " } else { ".. " });
         stdout().flush().unwrap();
 
         let mut line = String::new();
 
-        stdin().read_line(&mut line).unwrap();
-        let mut compiler = Compiler::new(&line);
+        // end of input, e.g. ctrl-d
+        if stdin().read_line(&mut line).unwrap_or(0) == 0 {
+            println!();
+            break;
+        }
+
+        // ':cancel' abandons a half typed statement
+        if line.trim() == ":cancel" {
+            if pending.is_empty() {
+                println!("nothing to cancel");
+            }
+            pending.clear();
+            pending_lines = 0;
+            continue;
+        }
+
+        // ':help' lists the natives that can be called
+        if pending.is_empty() && line.trim() == ":help" {
+            println!("{}", native::help(&vm.natives()));
+            continue;
+        }
+
+        // ':trace on' and ':trace off' toggle tracing the lines that follow
+        if pending.is_empty() && line.trim().starts_with(":trace") {
+            match line.trim()[":trace".len()..].trim() {
+                "on" => vm.set_tracing(true),
+                "off" => vm.set_tracing(false),
+                _ => println!("usage: :trace on|off"),
+            }
+            continue;
+        }
 
-        if !compiler.compile() {
+        // ':constants' shows the pool of the last line that compiled
+        if pending.is_empty() && line.trim() == ":constants" {
+            match &last_block {
+                Some(block) => Disassembler::constants(block),
+                None => println!("nothing has been compiled yet"),
+            }
             continue;
         }
 
-        let byte_code = compiler.byte_code();
-        let result = vm.interpret(byte_code);
-        if let InterpretResult::RuntimeError = result {
-            let error = vm.last_runtime_error();
-            print_result(result, "<stdin>", error);
+        pending.push_str(&line);
+        pending_lines += 1;
+
+        if unclosed_delimiters(&pending) > 0 {
+            if pending_lines >= MAX_PENDING_LINES {
+                println!("input wasn't closed after {MAX_PENDING_LINES} lines, discarding it");
+                pending.clear();
+                pending_lines = 0;
+            }
+            continue;
+        }
+
+        let source = std::mem::take(&mut pending);
+        pending_lines = 0;
+
+        let mut compiler = Compiler::new(&source)
+            .with_name("<stdin>")
+            .with_echo(true)
+            .with_optional_final_semicolon(true)
+            .with_macros(macros.clone());
+
+        let output = compiler.compile();
+        diagnostic::print_diagnostics(&output.diagnostics);
+        if !output.succeeded() {
+            continue;
+        }
+        macros = compiler.macros().clone();
+
+        let byte_code = Arc::new(output.block);
+        last_block = Some(byte_code.clone());
+        if let InterpretResult::RuntimeError = vm.interpret(byte_code) {
+            eprintln!("thread 'main' panicked at: {}", vm.last_runtime_error());
         }
     }
 }
 
-pub fn run(source: &str) -> RuntimeResult {
-    let mut compiler = Compiler::new(source);
+/// How many `{`, `(` and strings in `source` haven't been closed, the REPL
+/// keeps reading lines until there are none.
+pub(crate) fn unclosed_delimiters(source: &str) -> usize {
+    let mut depth: usize = 0;
+    for token in Lexer::new(source).take_while(|token| token.typ3 != TokenType::Eof) {
+        match token.typ3 {
+            TokenType::LCurly | TokenType::LParen => depth += 1,
+            TokenType::RCurly | TokenType::RParen => depth = depth.saturating_sub(1),
+            // strings can span lines, the lexer only gives up at the end
+            TokenType::Error if token.lexeme == "Unterminated string literal" => depth += 1,
+            _ => (),
+        }
+    }
+    depth
+}
+
+/// Runs `source` on a fresh VM, use an `Engine` to keep state between runs.
+pub fn run(source: &str) -> Result<Value, DynamixError> {
+    Engine::new().eval(source)
+}
+
+pub fn run_file(path: &str) -> Result<Value, DynamixError> {
+    run_file_with_defines(path, &[])
+}
+
+/// Like `run_file`, `defines` are the names `#if` regions are compiled against.
+pub fn run_file_with_defines(path: &str, defines: &[String]) -> Result<Value, DynamixError> {
+    Engine::builder()
+        .with_defines(defines)
+        .build()
+        .eval_file(path)
+}
+
+/// `dynamix --tokens <script>`, prints what the lexer makes of the script
+/// without preprocessing or compiling it.
+fn print_tokens(input: &Input) -> ExitCode {
+    let source = match input {
+        Input::Path(path) => match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(err) => return report(&Err(err.into()), path),
+        },
+        Input::Source(source) => source.clone(),
+        Input::Stdin => match read_stdin() {
+            Ok(source) => source,
+            Err(err) => return report(&Err(err.into()), input.name()),
+        },
+    };
 
-    if !compiler.compile() {
-        return (InterpretResult::CompileError, "".to_string());
+    let mut lexer = Lexer::new(&source);
+    loop {
+        let token = lexer.next().unwrap();
+        let position = format!("{}:{}", token.line, token.column);
+        let typ3 = format!("{:?}", token.typ3);
+
+        if token.typ3 == TokenType::Eof {
+            println!("{position:>8}  {typ3}");
+            break ExitCode::SUCCESS;
+        }
+
+        // escaped so a multi-line string stays on one line
+        println!(
+            "{position:>8}  {typ3:10}  {}",
+            token.lexeme.replace('\n', "\\n")
+        );
     }
+}
 
-    let mut vm = VirtualMachine::new();
-    let byte_code = compiler.byte_code();
-    let result = vm.interpret(byte_code);
-    let error = vm.last_runtime_error();
-    (result, error)
+/// Reads a whole script piped into `dynamix -`.
+fn read_stdin() -> std::io::Result<String> {
+    let mut source = String::new();
+    stdin().read_to_string(&mut source)?;
+    Ok(source)
 }
 
-pub fn run_file(path: &str) -> Result<RuntimeResult, ()> {
-    if let Ok(source) = std::fs::read_to_string(path) {
-        let (result, error) = run(&source);
-        let filename = Path::new(path).file_stem().unwrap().to_str().unwrap();
-        print_result(result, filename, error.clone());
-        Ok((result, error))
-    } else {
-        println!("Failed to open file from path: /{path}");
-        Err(())
+/// Prints the result like `print_result` and returns the exit code it
+/// deserves.
+fn report(result: &Result<Value, DynamixError>, path: &str) -> ExitCode {
+    print_result(result, path);
+    match result {
+        Ok(..) => ExitCode::SUCCESS,
+        Err(err) => ExitCode::from(err.exit_code()),
     }
 }
 
-pub fn print_result(result: InterpretResult, name: &str, error: String) {
+/// Reports how running the script at `path` went, the way the CLI does:
+/// success on stdout and errors on stderr, apart from the script's output.
+#[doc(hidden)]
+pub fn print_result(result: &Result<Value, DynamixError>, path: &str) {
     match result {
-        InterpretResult::Ok => println!("program exited successfully..."),
-        InterpretResult::CompileError => {
-            println!("could not compile '{name}' due to previous error")
+        Ok(..) => println!("program exited successfully..."),
+        Err(DynamixError::CompileError(diagnostics)) => {
+            diagnostic::print_diagnostics(diagnostics);
+            let name = Path::new(path).file_stem().unwrap_or_default();
+            eprintln!(
+                "could not compile '{}' due to previous error",
+                name.to_string_lossy()
+            );
+        }
+        Err(DynamixError::RuntimeError { message, .. }) => {
+            eprintln!("thread 'main' panicked at: {message}")
+        }
+        Err(DynamixError::Io(err)) if err.kind() == std::io::ErrorKind::InvalidData => {
+            eprintln!("Failed to load '{path}': {err}")
+        }
+        Err(DynamixError::Io(..)) => eprintln!("Failed to open file from path: /{path}"),
+        Err(
+            err @ (DynamixError::OutOfFuel | DynamixError::Interrupted | DynamixError::TimedOut),
+        ) => {
+            eprintln!("thread 'main' panicked at: {err}")
         }
-        InterpretResult::RuntimeError => println!("thread 'main' panicked at: {error}"),
+        Err(err @ DynamixError::UnknownModule(..)) => eprintln!("{err}"),
     }
 }
 
+fn usage() -> ExitCode {
+    print_usage();
+    ExitCode::from(error::EXIT_USAGE)
+}
+
+#[doc(hidden)]
 pub fn print_usage() {
-    println!("Usage: dynamix <script>");
+    println!(
+        "Usage: dynamix [--disassemble | --tokens | --profile output] [--optimize] [-D name]... <input>"
+    );
+    println!("       dynamix test [--optimize] [-D name]... <input>");
+    println!("       dynamix compile [--optimize] [-D name]... <input> [-o output]");
+    println!("       dynamix dap [port]");
+    println!("       dynamix graph <script> [--format dot|json]");
+    println!("       dynamix learn [lesson]");
     println!("Args:");
-    println!("\tscript: source filepath");
+    println!("\tinput: a script, '-e source' or '-' to read the script from stdin");
+    println!("\tscript: source or compiled bytecode (.dynb) filepath");
+    println!("\t-e, --eval source: run 'source' instead of a script");
+    println!("\t-D name: define 'name' for '#if name' regions");
+    println!("\t-o output: where 'compile' writes the bytecode (default script.dynb)");
+    println!("\t--disassemble: print the script's bytecode instead of running it");
+    println!("\t--tokens: print the script's tokens instead of running it");
+    println!("\t--optimize: fold constants, drop code that can't run and redundant stack traffic");
+    println!("\t--profile output: write the run's call stacks as folded stacks for flame graphs");
+    println!("\tport: debug adapter port (default {})", dap::DEFAULT_PORT);
+    println!("\tlesson: which of the bundled lessons to start from (default 1)");
     println!();
     println!("(Hint: run dynamix with no args to start the interactive REPL)");
 }
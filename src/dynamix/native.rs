@@ -0,0 +1,76 @@
+use crate::{constant::Constant, virtual_machine::VirtualMachine};
+
+use std::fmt::Debug;
+
+pub type NativeFn = fn(&mut VirtualMachine, &[Constant]) -> Result<Constant, String>;
+
+/// A function implemented in rust that scripts can call like any other value.
+#[derive(Clone, Copy)]
+pub struct Native {
+    pub name: &'static str,
+    pub arity: usize,
+    pub function: NativeFn,
+    /// A sentence for `help()` describing what the native does.
+    pub doc: &'static str,
+    /// The stdlib module that had to be enabled for the native to exist,
+    /// `None` if the host registered it.
+    pub module: Option<&'static str>,
+}
+
+impl Native {
+    pub const fn new(name: &'static str, arity: usize, function: NativeFn) -> Self {
+        Self {
+            name,
+            arity,
+            function,
+            doc: "",
+            module: None,
+        }
+    }
+
+    pub const fn with_doc(mut self, doc: &'static str) -> Self {
+        self.doc = doc;
+        self
+    }
+}
+
+/// Lists `natives` one per line, e.g. `clamp/3  [math]  Limits x to lo..hi`.
+pub fn help(natives: &[Native]) -> String {
+    let width = natives
+        .iter()
+        .map(|native| native.name.len() + native.arity.to_string().len() + 1)
+        .max()
+        .unwrap_or(0);
+
+    let lines: Vec<String> = natives
+        .iter()
+        .map(|native| {
+            let signature = format!("{}/{}", native.name, native.arity);
+            let module = format!("[{}]", native.module.unwrap_or("host"));
+            format!("{signature:width$}  {module:8}  {}", native.doc)
+                .trim_end()
+                .to_string()
+        })
+        .collect();
+
+    lines.join("\n")
+}
+
+impl Debug for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+// natives are identified by name, comparing function pointers isn't reliable
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl PartialOrd for Native {
+    fn partial_cmp(&self, _other: &Self) -> Option<std::cmp::Ordering> {
+        None
+    }
+}
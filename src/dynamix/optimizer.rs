@@ -0,0 +1,696 @@
+//! Rewrites compiled bytecode into smaller code that does the same, enabled
+//! with `Compiler::with_optimizations`.
+//!
+//! A block is decoded into a list of instructions whose jumps name the
+//! instruction they land on, the passes edit that list and it's encoded
+//! back. Jump distances, lines and local ranges are only worked out when
+//! encoding, so a pass can remove an instruction without patching anything.
+//! Encoding is also where a jump too long for two bytes becomes a long jump,
+//! which is how the compiler lays out the jumps it couldn't patch.
+
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    byte_block::{decode_short, encode_long, encode_short, ByteBlock, OpCode, TestCase},
+    constant::{Constant, ConstantPool, Function},
+};
+
+/// Passes are run until none of them changes anything, or this many times.
+const MAX_ROUNDS: usize = 16;
+
+/// The optimized copy of `block` and of every function and test in it.
+/// Bytecode the optimizer can't make sense of is copied unchanged.
+pub fn optimize(block: &ByteBlock) -> ByteBlock {
+    let mut constants = ConstantPool::new();
+    for (_, constant) in block.constants() {
+        constants.push(match constant {
+            Constant::Function(function) => Constant::Function(optimize_function(function)),
+            constant => constant.clone(),
+        });
+    }
+
+    // folded constants are added to the pool, the ones they replace stay
+    let mut optimized = match Code::decode(block, &HashMap::new()) {
+        Some(mut code) => {
+            let mut pool = constants.clone();
+            code.run_passes(&mut pool);
+            match code.encode(block) {
+                Some(optimized) => {
+                    constants = pool;
+                    optimized
+                }
+                None => block.clone(),
+            }
+        }
+        None => block.clone(),
+    };
+    optimized.constants = constants;
+
+    optimized.tests = block
+        .tests
+        .iter()
+        .map(|test| TestCase {
+            name: test.name.clone(),
+            function: optimize_function(&test.function),
+        })
+        .collect();
+
+    optimized
+}
+
+/// `block` with each jump at an offset in `targets` landing on the offset
+/// it's mapped to, whatever its operand says, and with long jumps wherever
+/// a distance doesn't fit in two bytes. `None` if the block can't be
+/// decoded or a jump is too long even for four bytes.
+pub fn widen_jumps(block: &ByteBlock, targets: &HashMap<usize, usize>) -> Option<ByteBlock> {
+    Code::decode(block, targets)?.encode(block)
+}
+
+fn optimize_function(function: &Function) -> Arc<Function> {
+    Arc::new(Function {
+        name: function.name.clone(),
+        arity: function.arity,
+        block: Arc::new(optimize(&function.block)),
+    })
+}
+
+#[derive(Debug, Clone)]
+struct Instruction {
+    /// Jumps are always the short form, `encode` picks the width.
+    opcode: OpCode,
+    /// The operand bytes, empty for jumps which use `target` instead.
+    operands: Vec<u8>,
+    /// Index of the instruction a jump lands on, the number of
+    /// instructions if it jumps to the end of the block.
+    target: Option<usize>,
+    line: u32,
+    file: u16,
+    /// Offset in the block the instruction was decoded from.
+    offset: usize,
+    removed: bool,
+}
+
+impl Instruction {
+    fn is_unconditional(&self) -> bool {
+        matches!(self.opcode, OpCode::Jmp | OpCode::Loop | OpCode::Return)
+    }
+}
+
+struct Code {
+    instructions: Vec<Instruction>,
+}
+
+impl Code {
+    /// Jumps at the offsets in `targets` land where they're mapped to.
+    fn decode(block: &ByteBlock, targets: &HashMap<usize, usize>) -> Option<Code> {
+        let mut instructions = Vec::new();
+        let mut indices = HashMap::new();
+        let mut offset = 0;
+        while offset < block.bytes.len() {
+            let opcode = OpCode::from(block.bytes[offset]).ok()?;
+            let width = opcode.width();
+            let operands = match opcode.jump_direction() {
+                Some(..) => Vec::new(),
+                None => block.bytes.get(offset + 1..offset + width)?.to_vec(),
+            };
+
+            indices.insert(offset, instructions.len());
+            instructions.push(Instruction {
+                opcode: opcode.narrowed(),
+                operands,
+                target: None,
+                line: block.lines[offset],
+                file: block.files[offset],
+                offset,
+                removed: false,
+            });
+            offset += width;
+        }
+        indices.insert(offset, instructions.len());
+
+        for instruction in instructions.iter_mut() {
+            if instruction.opcode.jump_direction().is_some() {
+                let target = match targets.get(&instruction.offset) {
+                    Some(&target) => target,
+                    None => block.jump_target(instruction.offset)?,
+                };
+                instruction.target = Some(*indices.get(&target)?);
+            }
+        }
+
+        Some(Code { instructions })
+    }
+
+    /// The bytecode of the remaining instructions, `None` if a jump is too
+    /// long even for a long jump.
+    fn encode(&self, block: &ByteBlock) -> Option<ByteBlock> {
+        // widening a jump moves the code after it, which can push another
+        // jump past two bytes, so jumps are widened until none has to be
+        let mut wide = vec![false; self.instructions.len()];
+        let offsets = loop {
+            let offsets = self.layout(&wide);
+            let mut widened = false;
+            for (i, instruction) in self.instructions.iter().enumerate() {
+                if instruction.removed || instruction.target.is_none() || wide[i] {
+                    continue;
+                }
+
+                let (_, distance) = self.jump(i, &offsets, false)?;
+                if distance > u16::MAX as usize {
+                    wide[i] = true;
+                    widened = true;
+                }
+            }
+
+            if !widened {
+                break offsets;
+            }
+        };
+        let offset = offsets[self.instructions.len()];
+
+        let mut optimized = ByteBlock {
+            bytes: Vec::new(),
+            lines: Vec::new(),
+            files: Vec::new(),
+            ..block.clone()
+        };
+
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            if instruction.removed {
+                continue;
+            }
+
+            let mut opcode = instruction.opcode;
+            let mut operands = instruction.operands.clone();
+            if instruction.target.is_some() {
+                let (jump, distance) = self.jump(i, &offsets, wide[i])?;
+                (opcode, operands) = match wide[i] {
+                    true => (
+                        jump.widened(),
+                        encode_long(u32::try_from(distance).ok()?).to_vec(),
+                    ),
+                    false => (jump, encode_short(distance as u16).to_vec()),
+                };
+            }
+
+            optimized.push_in(opcode as u8, instruction.line, instruction.file);
+            for operand in operands {
+                optimized.push_in(operand, instruction.line, instruction.file);
+            }
+        }
+
+        // local ranges are instruction offsets, some are left open
+        let old_offsets: HashMap<usize, usize> = self
+            .instructions
+            .iter()
+            .enumerate()
+            .map(|(i, instruction)| (instruction.offset, offsets[i]))
+            .chain([(block.bytes.len(), offset)])
+            .collect();
+        for local in optimized.locals.iter_mut() {
+            local.start = old_offsets
+                .get(&local.start)
+                .copied()
+                .unwrap_or(local.start);
+            local.end = old_offsets.get(&local.end).copied().unwrap_or(local.end);
+        }
+
+        Some(optimized)
+    }
+
+    /// Where each instruction starts when the jumps marked in `wide` are
+    /// long jumps, followed by where the block ends. A removed
+    /// instruction's offset is that of the next one left, so jumps to it
+    /// land where it would have been.
+    fn layout(&self, wide: &[bool]) -> Vec<usize> {
+        let mut offsets = vec![0; self.instructions.len() + 1];
+        let mut offset = 0;
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            offsets[i] = offset;
+            if !instruction.removed {
+                offset += match wide[i] {
+                    true => instruction.opcode.widened().width(),
+                    false => instruction.opcode.width(),
+                };
+            }
+        }
+        offsets[self.instructions.len()] = offset;
+        offsets
+    }
+
+    /// The opcode and distance of the jump at `index` laid out at
+    /// `offsets`, `None` for a conditional jump that would go backwards.
+    fn jump(&self, index: usize, offsets: &[usize], wide: bool) -> Option<(OpCode, usize)> {
+        let instruction = &self.instructions[index];
+        let width = match wide {
+            true => instruction.opcode.widened().width(),
+            false => instruction.opcode.width(),
+        };
+        let end = offsets[index] + width;
+        let target = offsets[instruction.target?];
+
+        // only unconditional jumps can go either way
+        match instruction.opcode {
+            OpCode::Jmp | OpCode::Loop if target < end => Some((OpCode::Loop, end - target)),
+            OpCode::Jmp | OpCode::Loop => Some((OpCode::Jmp, target - end)),
+            opcode => Some((opcode, target.checked_sub(end)?)),
+        }
+    }
+
+    fn live(&self) -> impl Iterator<Item = &Instruction> {
+        self.instructions
+            .iter()
+            .filter(|instruction| !instruction.removed)
+    }
+
+    /// The first instruction left at or after `index`.
+    fn resolve(&self, index: usize) -> usize {
+        (index..self.instructions.len())
+            .find(|&i| !self.instructions[i].removed)
+            .unwrap_or(self.instructions.len())
+    }
+
+    fn next(&self, index: usize) -> usize {
+        self.resolve(index + 1)
+    }
+
+    fn get(&self, index: usize) -> Option<&Instruction> {
+        self.instructions.get(index)
+    }
+
+    /// Whether a jump that's left lands on each instruction.
+    fn targets(&self) -> Vec<bool> {
+        let mut targets = vec![false; self.instructions.len() + 1];
+        for instruction in self.live() {
+            if let Some(target) = instruction.target {
+                targets[self.resolve(target)] = true;
+            }
+        }
+        targets
+    }
+
+    fn run_passes(&mut self, constants: &mut ConstantPool) {
+        for _ in 0..MAX_ROUNDS {
+            let changed = self.fold_constants(constants)
+                | self.thread_jumps()
+                | self.fold_constant_branches(constants)
+                | self.remove_unreachable()
+                | self.remove_jumps_to_next()
+                | self.apply_rules();
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    /// A jump that lands on an unconditional jump goes straight to where
+    /// that one goes.
+    fn thread_jumps(&mut self) -> bool {
+        let mut changed = false;
+        for i in 0..self.instructions.len() {
+            let instruction = &self.instructions[i];
+            let Some(start) = instruction.target.filter(|_| !instruction.removed) else {
+                continue;
+            };
+
+            let mut target = self.resolve(start);
+            // a loop of jumps never ends, there's no last jump to go to
+            for _ in 0..self.instructions.len() {
+                match self.get(target) {
+                    Some(next) if matches!(next.opcode, OpCode::Jmp | OpCode::Loop) => {
+                        target = self.resolve(next.target.unwrap());
+                    }
+                    _ => break,
+                }
+            }
+
+            // a conditional jump only goes forward
+            let forward = target > i || self.instructions[i].opcode != OpCode::Jz;
+            if target != self.resolve(start) && forward {
+                self.instructions[i].target = Some(target);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// `OP_JUMP_IF_FALSE` right after a constant either never jumps or
+    /// always does, so it's dropped or made unconditional.
+    fn fold_constant_branches(&mut self, constants: &ConstantPool) -> bool {
+        let mut changed = false;
+        let mut targets = self.targets();
+        for i in 0..self.instructions.len() {
+            let jump = self.next(i);
+            let truthy = match self
+                .constant(i, constants)
+                .as_ref()
+                .map(Constant::is_truthy)
+            {
+                Some(truthy)
+                    if self.instructions.get(jump).map(|j| j.opcode) == Some(OpCode::Jz) =>
+                {
+                    truthy
+                }
+                _ => continue,
+            };
+            if targets[jump] {
+                continue;
+            }
+
+            if truthy {
+                // the condition is popped straight away, none of it is needed
+                let pop = self.next(jump);
+                if self.get(pop).map(|pop| pop.opcode) == Some(OpCode::Pop) && !targets[pop] {
+                    self.instructions[i].removed = true;
+                    self.instructions[pop].removed = true;
+                }
+                self.instructions[jump].removed = true;
+            } else {
+                let target = self.resolve(self.instructions[jump].target.unwrap());
+                // an else branch starts by popping the condition, jump past that
+                if self.get(target).map(|pop| pop.opcode) == Some(OpCode::Pop) {
+                    self.instructions[i].removed = true;
+                    self.instructions[jump].target = Some(self.next(target));
+                }
+                self.instructions[jump].opcode = OpCode::Jmp;
+            }
+
+            targets = self.targets();
+            changed = true;
+        }
+        changed
+    }
+
+    /// The constant the instruction at `index` pushes, `None` if it isn't
+    /// one that pushes a constant.
+    fn constant(&self, index: usize, constants: &ConstantPool) -> Option<Constant> {
+        let instruction = self.get(index).filter(|i| !i.removed)?;
+        let constant = match instruction.opcode {
+            OpCode::True => return Some(Constant::Bool(true)),
+            OpCode::False => return Some(Constant::Bool(false)),
+            OpCode::Null => return Some(Constant::Null),
+            OpCode::Constant | OpCode::Char => instruction.operands[0] as usize,
+            OpCode::ConstantLong => {
+                decode_short([instruction.operands[0], instruction.operands[1]]) as usize
+            }
+            _ => return None,
+        };
+
+        constants.constants.get(constant).cloned()
+    }
+
+    /// An operator applied to constants is replaced by a constant of its
+    /// result, e.g. `1 + 2 * 3` by `7` and `!true` by `false`. Operations
+    /// that would fail, or whose result depends on how the VM is set up
+    /// like dividing by zero, are left to happen at runtime.
+    fn fold_constants(&mut self, constants: &mut ConstantPool) -> bool {
+        let mut changed = false;
+        let mut targets = self.targets();
+        for i in 0..self.instructions.len() {
+            let Some(lhs) = self.constant(i, constants) else {
+                continue;
+            };
+
+            // the operands and the operator run one after the other
+            let unary = self.next(i);
+            let binary = self.next(unary);
+            let folded = match self.get(unary).map(|op| op.opcode) {
+                Some(opcode) if !targets[unary] => match fold_unary(opcode, &lhs) {
+                    Some(result) => Some((result, vec![unary])),
+                    None => self
+                        .constant(unary, constants)
+                        .filter(|_| !targets[binary])
+                        .and_then(|rhs| {
+                            let opcode = self.get(binary)?.opcode;
+                            fold_binary(opcode, &lhs, &rhs)
+                        })
+                        .map(|result| (result, vec![unary, binary])),
+                },
+                _ => None,
+            };
+            let Some((result, folded)) = folded else {
+                continue;
+            };
+
+            let Some((opcode, operands)) = push_constant(result, constants) else {
+                continue;
+            };
+            self.instructions[i].opcode = opcode;
+            self.instructions[i].operands = operands;
+            for index in folded {
+                self.instructions[index].removed = true;
+            }
+
+            targets = self.targets();
+            changed = true;
+        }
+        changed
+    }
+
+    /// Instructions after an unconditional jump or return that no jump
+    /// lands on can't run.
+    fn remove_unreachable(&mut self) -> bool {
+        let mut changed = false;
+        let mut reachable = true;
+        let targets = self.targets();
+        for (i, instruction) in self.instructions.iter_mut().enumerate() {
+            if instruction.removed {
+                continue;
+            }
+
+            reachable |= targets[i];
+            if !reachable {
+                instruction.removed = true;
+                changed = true;
+            } else if instruction.is_unconditional() {
+                reachable = false;
+            }
+        }
+        changed
+    }
+
+    /// Rewrites the instructions that match one of the `RULES`.
+    fn apply_rules(&mut self) -> bool {
+        let mut changed = false;
+        let mut targets = self.targets();
+        for i in 0..self.instructions.len() {
+            if self.instructions[i].removed {
+                continue;
+            }
+
+            for rule in RULES {
+                let mut matched = vec![i];
+                while matched.len() < rule.pattern.len() {
+                    matched.push(self.next(*matched.last().unwrap()));
+                }
+
+                let instructions: Vec<&Instruction> =
+                    matched.iter().filter_map(|&j| self.get(j)).collect();
+                let applies = instructions.len() == rule.pattern.len()
+                    && instructions
+                        .iter()
+                        .zip(rule.pattern)
+                        .all(|(instruction, &opcode)| instruction.opcode == opcode)
+                    && matched[1..].iter().all(|&j| !targets[j])
+                    && (rule.applies)(&instructions);
+                if !applies {
+                    continue;
+                }
+
+                for (n, &j) in matched.iter().enumerate() {
+                    self.instructions[j].removed |= !rule.keep.contains(&n);
+                }
+                targets = self.targets();
+                changed = true;
+                break;
+            }
+        }
+        changed
+    }
+
+    fn remove_jumps_to_next(&mut self) -> bool {
+        let mut changed = false;
+        for i in 0..self.instructions.len() {
+            let instruction = &self.instructions[i];
+            let Some(target) = instruction.target.filter(|_| !instruction.removed) else {
+                continue;
+            };
+
+            // a conditional jump leaves the condition either way
+            if self.resolve(target) == self.next(i) {
+                self.instructions[i].removed = true;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+/// Instructions that run one after another, which can be replaced by some
+/// of them. Only the first may be one a jump lands on.
+struct Rule {
+    pattern: &'static [OpCode],
+    /// Indices into `pattern` of the instructions left.
+    keep: &'static [usize],
+    /// Whether the matched instructions can be replaced.
+    applies: fn(&[&Instruction]) -> bool,
+}
+
+/// The rules `--optimize` applies, in the order they're tried.
+const RULES: &[Rule] = &[
+    // storing a local's own value back in it changes nothing
+    Rule {
+        pattern: &[OpCode::GetLocal, OpCode::SetLocal, OpCode::Pop],
+        keep: &[],
+        applies: same_operands,
+    },
+    // assignment leaves the value on the stack, no need to read it back
+    Rule {
+        pattern: &[OpCode::SetLocal, OpCode::Pop, OpCode::GetLocal],
+        keep: &[0],
+        applies: same_operands,
+    },
+    Rule {
+        pattern: &[OpCode::SetGlobal, OpCode::Pop, OpCode::GetGlobal],
+        keep: &[0],
+        applies: same_operands,
+    },
+    Rule {
+        pattern: &[OpCode::SetGlobalLong, OpCode::Pop, OpCode::GetGlobalLong],
+        keep: &[0],
+        applies: same_operands,
+    },
+    // '!' makes a bool, so '!!!x' is '!x'
+    Rule {
+        pattern: &[OpCode::Not, OpCode::Not, OpCode::Not],
+        keep: &[0],
+        applies: always,
+    },
+    // values that are popped straight away needn't be pushed, unless
+    // pushing them can fail like reading a global can
+    Rule {
+        pattern: &[OpCode::GetLocal, OpCode::Pop],
+        keep: &[],
+        applies: always,
+    },
+    Rule {
+        pattern: &[OpCode::Constant, OpCode::Pop],
+        keep: &[],
+        applies: always,
+    },
+    Rule {
+        pattern: &[OpCode::ConstantLong, OpCode::Pop],
+        keep: &[],
+        applies: always,
+    },
+    Rule {
+        pattern: &[OpCode::True, OpCode::Pop],
+        keep: &[],
+        applies: always,
+    },
+    Rule {
+        pattern: &[OpCode::False, OpCode::Pop],
+        keep: &[],
+        applies: always,
+    },
+    Rule {
+        pattern: &[OpCode::Null, OpCode::Pop],
+        keep: &[],
+        applies: always,
+    },
+    Rule {
+        pattern: &[OpCode::Char, OpCode::Pop],
+        keep: &[],
+        applies: always,
+    },
+];
+
+fn always(_: &[&Instruction]) -> bool {
+    true
+}
+
+/// The instructions that have operands all have the same ones, e.g. the
+/// same slot or global.
+fn same_operands(instructions: &[&Instruction]) -> bool {
+    let mut operands = instructions
+        .iter()
+        .map(|instruction| &instruction.operands)
+        .filter(|operands| !operands.is_empty());
+    let first = operands.next();
+    operands.all(|operands| Some(operands) == first)
+}
+
+/// Whether folding an operator over `value` gives what the VM would. Only
+/// plain values qualify, a function is compared by identity at runtime.
+fn is_foldable(value: &Constant) -> bool {
+    matches!(
+        value,
+        Constant::Number(..) | Constant::Bool(..) | Constant::Null
+    ) || value.as_str().is_some()
+}
+
+fn fold_unary(opcode: OpCode, value: &Constant) -> Option<Constant> {
+    if !is_foldable(value) {
+        return None;
+    }
+
+    match (opcode, value) {
+        (OpCode::Not, value) => Some(Constant::Bool(!value.is_truthy())),
+        (OpCode::Negate, Constant::Number(x)) => Some(Constant::Number(-x)),
+        _ => None,
+    }
+}
+
+fn fold_binary(opcode: OpCode, lhs: &Constant, rhs: &Constant) -> Option<Constant> {
+    if !is_foldable(lhs) || !is_foldable(rhs) {
+        return None;
+    }
+
+    if opcode == OpCode::Equal {
+        return Some(Constant::Bool(lhs == rhs));
+    }
+
+    if let (OpCode::Add, Some(lhs), Some(rhs)) = (opcode, lhs.as_str(), rhs.as_str()) {
+        return Some(Constant::new_string(&format!("{lhs}{rhs}")));
+    }
+
+    let (&Constant::Number(x), &Constant::Number(y)) = (lhs, rhs) else {
+        return None;
+    };
+    let result = match opcode {
+        OpCode::Add => Constant::Number(x + y),
+        OpCode::Sub => Constant::Number(x - y),
+        OpCode::Mul => Constant::Number(x * y),
+        // whether dividing by zero is an error is up to the VM
+        OpCode::Div if y != 0.0 => Constant::Number(x / y),
+        OpCode::Pow => Constant::Number(x.powf(y)),
+        OpCode::Greater => Constant::Bool(x > y),
+        OpCode::Less => Constant::Bool(x < y),
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// The instruction that pushes `value`, adding it to the pool if it needs
+/// to be there. `None` if the pool is full.
+fn push_constant(value: Constant, constants: &mut ConstantPool) -> Option<(OpCode, Vec<u8>)> {
+    match value {
+        Constant::Bool(true) => return Some((OpCode::True, Vec::new())),
+        Constant::Bool(false) => return Some((OpCode::False, Vec::new())),
+        Constant::Null => return Some((OpCode::Null, Vec::new())),
+        _ => (),
+    }
+
+    let index = constants.len();
+    match u8::try_from(index) {
+        Ok(index) => {
+            constants.push(value);
+            Some((OpCode::Constant, vec![index]))
+        }
+        Err(..) => {
+            let index = u16::try_from(index).ok()?;
+            constants.push(value);
+            Some((OpCode::ConstantLong, encode_short(index).to_vec()))
+        }
+    }
+}
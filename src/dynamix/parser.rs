@@ -0,0 +1,1579 @@
+//! Turns source into a typed syntax tree, which `codegen` lowers to
+//! bytecode.
+//!
+//! Everything that works on tokens happens here: the preprocessor, includes
+//! and macro expansion, so the tree is of the script as it's compiled. The
+//! tree keeps the tokens its nodes were parsed from, they place the code
+//! generated for them and the errors reported about them.
+//!
+//! A declaration that fails to parse becomes `Stmt::Error` once the parser
+//! has recovered, the errors themselves are in `Parser::diagnostics`.
+
+use crate::{
+    diagnostic::{Diagnostic, Severity, Span},
+    lexer::{unescape_char, Lexer, Token, TokenType},
+    macros::{Macro, MACRO_DEPTH_LIMIT},
+    preprocessor::{preprocess, PreprocessError},
+};
+
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+};
+
+/// A parsed script.
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub statements: Vec<Stmt>,
+    /// The end of the main file, the script returns there.
+    pub eof: Token,
+    /// Paths of the included files, `includes[n - 1]` is the file of tokens
+    /// with `file: n`.
+    pub includes: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    /// `let name = value;`, the value is optional.
+    Let {
+        keyword: Token,
+        name: Token,
+        value: Option<Expr>,
+        end: Token,
+    },
+    /// `let (a, b) = value;`
+    LetTuple {
+        keyword: Token,
+        names: Vec<Token>,
+        value: Expr,
+        end: Token,
+    },
+    Fun {
+        name: Token,
+        function: Function,
+    },
+    /// `test "name" { body }`, `name` is the string token.
+    Test {
+        keyword: Token,
+        name: Token,
+        body: Block,
+    },
+    Print {
+        keyword: Token,
+        value: Expr,
+        end: Token,
+    },
+    Assert {
+        keyword: Token,
+        condition: Expr,
+        message: Option<Expr>,
+        end: Token,
+    },
+    Expression {
+        expr: Expr,
+        end: Token,
+    },
+    Return {
+        keyword: Token,
+        value: Option<Expr>,
+        end: Token,
+    },
+    If {
+        keyword: Token,
+        condition: Expr,
+        then: Block,
+        otherwise: Option<Block>,
+    },
+    While {
+        keyword: Token,
+        condition: Expr,
+        body: Block,
+    },
+    /// `semicolon` ends the condition, `close` is the ')' of the header.
+    For {
+        keyword: Token,
+        initializer: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        semicolon: Token,
+        increment: Option<Expr>,
+        close: Token,
+        body: Block,
+    },
+    Block(Block),
+    /// A declaration that didn't parse.
+    Error,
+}
+
+impl Stmt {
+    /// The first token of the statement, `None` for one that didn't parse.
+    pub fn start(&self) -> Option<&Token> {
+        match self {
+            Stmt::Let { keyword, .. }
+            | Stmt::LetTuple { keyword, .. }
+            | Stmt::Test { keyword, .. }
+            | Stmt::Print { keyword, .. }
+            | Stmt::Assert { keyword, .. }
+            | Stmt::Return { keyword, .. }
+            | Stmt::If { keyword, .. }
+            | Stmt::While { keyword, .. }
+            | Stmt::For { keyword, .. } => Some(keyword),
+            Stmt::Fun { function, .. } => Some(&function.keyword),
+            Stmt::Expression { expr, .. } => Some(expr.start()),
+            Stmt::Block(block) => Some(&block.open),
+            Stmt::Error => None,
+        }
+    }
+}
+
+/// Statements between `open` and `close`, the braces.
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub open: Token,
+    pub statements: Vec<Stmt>,
+    pub close: Token,
+}
+
+/// The parameters and body of a `fun` declaration or lambda.
+#[derive(Debug, Clone)]
+pub struct Function {
+    /// The `fun` keyword.
+    pub keyword: Token,
+    pub params: Vec<Token>,
+    pub body: Block,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Number(f64),
+    String(String),
+    Char(char),
+    Bool(bool),
+    Null,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Literal {
+        value: Literal,
+        token: Token,
+    },
+    Variable(Token),
+    Assign {
+        name: Token,
+        value: Box<Expr>,
+    },
+    /// `-operand` or `!operand`.
+    Unary {
+        operator: Token,
+        operand: Box<Expr>,
+    },
+    Binary {
+        left: Box<Expr>,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    /// `and` and `or`, which only evaluate `right` when they need to.
+    Logical {
+        left: Box<Expr>,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    Grouping {
+        open: Token,
+        expr: Box<Expr>,
+        close: Token,
+    },
+    Tuple {
+        open: Token,
+        elements: Vec<Expr>,
+        close: Token,
+    },
+    Call {
+        callee: Box<Expr>,
+        args: Vec<Expr>,
+        close: Token,
+    },
+    /// `receiver.name(args)`
+    Invoke {
+        receiver: Box<Expr>,
+        name: Token,
+        args: Vec<Expr>,
+        close: Token,
+    },
+    Function(Box<Function>),
+    /// An expression that didn't parse, only found inside `Stmt::Error`
+    /// or while parsing.
+    Error(Token),
+}
+
+impl Expr {
+    /// The first token of the expression.
+    pub fn start(&self) -> &Token {
+        // a loop since chains of operators nest as deep as they're long
+        let mut expr = self;
+        loop {
+            expr = match expr {
+                Expr::Literal { token, .. } => return token,
+                Expr::Variable(name) | Expr::Assign { name, .. } => return name,
+                Expr::Unary { operator, .. } => return operator,
+                Expr::Binary { left, .. } | Expr::Logical { left, .. } => left,
+                Expr::Grouping { open, .. } | Expr::Tuple { open, .. } => return open,
+                Expr::Call { callee, .. } => callee,
+                Expr::Invoke { receiver, .. } => receiver,
+                Expr::Function(function) => return &function.keyword,
+                Expr::Error(token) => return token,
+            };
+        }
+    }
+
+    /// Splits a chain of binary and logical operators, `a + b - c` is `a`
+    /// followed by `+ b` and `- c`. Anything else is a chain of one.
+    ///
+    /// Operators are left associative, so a long chain nests as deep as it
+    /// is long, this walks it without recursing.
+    pub fn chain(&self) -> (&Expr, Vec<(&Token, &Expr)>) {
+        let mut operations = Vec::new();
+        let mut first = self;
+        while let Expr::Binary {
+            left,
+            operator,
+            right,
+        }
+        | Expr::Logical {
+            left,
+            operator,
+            right,
+        } = first
+        {
+            operations.push((operator, right.as_ref()));
+            first = left;
+        }
+
+        operations.reverse();
+        (first, operations)
+    }
+
+    /// The left operand of a binary or logical operator, replaced by an
+    /// error so the node can be dropped without it.
+    fn take_left(&mut self) -> Option<Box<Expr>> {
+        match self {
+            Expr::Binary { left, .. } | Expr::Logical { left, .. } => {
+                Some(std::mem::replace(left, Box::new(Expr::Error(Token::new()))))
+            }
+            _ => None,
+        }
+    }
+
+    /// The last token of the expression.
+    pub fn end(&self) -> &Token {
+        match self {
+            Expr::Literal { token, .. } => token,
+            Expr::Variable(name) => name,
+            Expr::Assign { value, .. } => value.end(),
+            Expr::Unary { operand, .. } => operand.end(),
+            Expr::Binary { right, .. } | Expr::Logical { right, .. } => right.end(),
+            Expr::Grouping { close, .. }
+            | Expr::Tuple { close, .. }
+            | Expr::Call { close, .. }
+            | Expr::Invoke { close, .. } => close,
+            Expr::Function(function) => &function.body.close,
+            Expr::Error(token) => token,
+        }
+    }
+}
+
+impl Drop for Expr {
+    // drops the left operands of a chain one at a time, recursing would run
+    // out of stack on long ones
+    fn drop(&mut self) {
+        let mut left = self.take_left();
+        while let Some(mut expr) = left {
+            left = expr.take_left();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq)]
+enum Precedence {
+    None,
+    Assignment,
+    Or,
+    And,
+    Equality,
+    Comparison,
+    Term,
+    Factor,
+    Exponent,
+    Unary,
+    Call,
+    Atom,
+}
+
+impl Precedence {
+    fn from_u32(value: u32) -> Self {
+        use Precedence::*;
+        match value {
+            0 => None,
+            1 => Assignment,
+            2 => Or,
+            3 => And,
+            4 => Equality,
+            5 => Comparison,
+            6 => Term,
+            7 => Factor,
+            8 => Exponent,
+            9 => Unary,
+            10 => Call,
+            11 => Atom,
+            _ => panic!("Unknown value: {value}"),
+        }
+    }
+}
+
+type PrefixFn<'a> = fn(&mut Parser<'a>, bool) -> Expr;
+type InfixFn<'a> = fn(&mut Parser<'a>, Expr, bool) -> Expr;
+
+struct ParseRule<'a> {
+    prefix: Option<PrefixFn<'a>>,
+    infix: Option<InfixFn<'a>>,
+    precedence: Precedence,
+}
+
+impl ParseRule<'_> {
+    const NONE: Self = ParseRule {
+        prefix: None,
+        infix: None,
+        precedence: Precedence::None,
+    };
+}
+
+const TOKEN_TYPES: usize = TokenType::Eof as usize + 1;
+
+/// How deep expressions and blocks may nest. The tree and everything that
+/// walks it recurse once per level, past this they'd run out of stack.
+/// Chains of operators like `a + b + c` don't count, they're walked in a
+/// loop however long they are.
+pub const MAX_DEPTH: usize = 256;
+
+pub struct Parser<'a> {
+    source: &'a str,
+    defines: Vec<String>,
+    lexer: Lexer<'a>,
+    cursor: Token,
+    previous: Token,
+    panic_mode: bool,
+    macros: HashMap<String, Macro>,
+    // tokens produced by macro expansion, with their expansion depth
+    pending: VecDeque<(Token, usize)>,
+    expansions: usize,
+    defining_macro: bool,
+    // path of the main file, includes are resolved relative to it
+    path: Option<PathBuf>,
+    // every included file with the id of the file that included it
+    includes: Vec<(PathBuf, u16)>,
+    include_names: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
+    // lets the last statement leave out its ';', for REPL lines
+    optional_final_semicolon: bool,
+    // levels of expressions and blocks the next node is nested in
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// How each token parses in an expression, indexed by `TokenType`.
+    /// Tokens without a rule can't start or continue one.
+    // not a static since the fns are generic over the parser's lifetime,
+    // it's promoted to one where `rule` borrows it
+    const PARSE_RULES: [ParseRule<'a>; TOKEN_TYPES] = {
+        let mut rules = [ParseRule::NONE; TOKEN_TYPES];
+        rules[TokenType::LParen as usize] = ParseRule {
+            prefix: Some(Parser::grouping),
+            infix: Some(Parser::call),
+            precedence: Precedence::Call,
+        };
+        rules[TokenType::Dot as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Parser::dot),
+            precedence: Precedence::Call,
+        };
+        rules[TokenType::Minus as usize] = ParseRule {
+            prefix: Some(Parser::unary),
+            infix: Some(Parser::binary),
+            precedence: Precedence::Term,
+        };
+        rules[TokenType::Plus as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::Term,
+        };
+        rules[TokenType::Slash as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::Factor,
+        };
+        rules[TokenType::Star as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::Factor,
+        };
+        rules[TokenType::StarStar as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::Exponent,
+        };
+        rules[TokenType::Bang as usize] = ParseRule {
+            prefix: Some(Parser::unary),
+            infix: None,
+            precedence: Precedence::None,
+        };
+        rules[TokenType::BangEq as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::Equality,
+        };
+        rules[TokenType::EqEq as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::Equality,
+        };
+        rules[TokenType::Gt as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::Comparison,
+        };
+        rules[TokenType::Gte as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::Comparison,
+        };
+        rules[TokenType::Lt as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::Comparison,
+        };
+        rules[TokenType::Lte as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Parser::binary),
+            precedence: Precedence::Comparison,
+        };
+        rules[TokenType::Ident as usize] = ParseRule {
+            prefix: Some(Parser::variable),
+            infix: None,
+            precedence: Precedence::None,
+        };
+        rules[TokenType::String as usize] = ParseRule {
+            prefix: Some(Parser::string),
+            infix: None,
+            precedence: Precedence::None,
+        };
+        rules[TokenType::Number as usize] = ParseRule {
+            prefix: Some(Parser::number),
+            infix: None,
+            precedence: Precedence::None,
+        };
+        rules[TokenType::Char as usize] = ParseRule {
+            prefix: Some(Parser::character),
+            infix: None,
+            precedence: Precedence::None,
+        };
+        rules[TokenType::And as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Parser::and),
+            precedence: Precedence::And,
+        };
+        rules[TokenType::False as usize] = ParseRule {
+            prefix: Some(Parser::literal),
+            infix: None,
+            precedence: Precedence::None,
+        };
+        rules[TokenType::Fun as usize] = ParseRule {
+            prefix: Some(Parser::lambda),
+            infix: None,
+            precedence: Precedence::None,
+        };
+        rules[TokenType::Null as usize] = ParseRule {
+            prefix: Some(Parser::literal),
+            infix: None,
+            precedence: Precedence::None,
+        };
+        rules[TokenType::Or as usize] = ParseRule {
+            prefix: None,
+            infix: Some(Parser::or),
+            precedence: Precedence::Or,
+        };
+        rules[TokenType::True as usize] = ParseRule {
+            prefix: Some(Parser::literal),
+            infix: None,
+            precedence: Precedence::None,
+        };
+        rules
+    };
+
+    fn rule(typ3: TokenType) -> &'a ParseRule<'a> {
+        &Self::PARSE_RULES[typ3 as usize]
+    }
+
+    pub fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            defines: Vec::new(),
+            lexer: Lexer::new(source),
+            cursor: Token::new(),
+            previous: Token::new(),
+            panic_mode: false,
+            macros: HashMap::new(),
+            pending: VecDeque::new(),
+            expansions: 0,
+            defining_macro: false,
+            path: None,
+            includes: Vec::new(),
+            include_names: Vec::new(),
+            diagnostics: Vec::new(),
+            optional_final_semicolon: false,
+            depth: 0,
+        }
+    }
+
+    /// Names that `#if NAME` regions test for.
+    pub fn with_defines(mut self, defines: &[String]) -> Self {
+        self.defines = defines.to_vec();
+        self
+    }
+
+    /// Sets the path of the source being parsed, `#include` paths are
+    /// resolved relative to the directory it's in.
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(PathBuf::from(path));
+        self
+    }
+
+    /// Accepts a last statement without its ';'.
+    pub fn with_optional_final_semicolon(mut self, enabled: bool) -> Self {
+        self.optional_final_semicolon = enabled;
+        self
+    }
+
+    /// Starts with macros defined elsewhere.
+    pub fn with_macros(mut self, macros: HashMap<String, Macro>) -> Self {
+        self.macros = macros;
+        self
+    }
+
+    /// Every macro defined so far, those passed to `with_macros` included.
+    pub fn macros(&self) -> &HashMap<String, Macro> {
+        &self.macros
+    }
+
+    /// The syntax errors found by the last `parse`, in the order they were
+    /// found.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    pub fn parse(&mut self) -> Program {
+        let mut statements = Vec::new();
+
+        match preprocess(self.source, &self.defines) {
+            Ok(source) => self.lexer.reset(&source),
+            Err(err) => {
+                self.preprocess_error(err, 0);
+                return self.program(statements);
+            }
+        }
+
+        self.advance();
+
+        while !self.matches(TokenType::Eof) {
+            statements.extend(self.declaration());
+        }
+
+        let program = self.program(statements);
+        self.consume(TokenType::Eof, "Expected end of expression".to_string());
+        program
+    }
+
+    fn program(&self, statements: Vec<Stmt>) -> Program {
+        Program {
+            statements,
+            eof: self.previous.clone(),
+            includes: self.include_names.clone(),
+        }
+    }
+
+    fn advance(&mut self) {
+        self.previous = self.cursor.clone();
+
+        loop {
+            self.cursor = self.next_token();
+            if self.cursor.typ3 != TokenType::Error {
+                break;
+            }
+
+            self.error_at_cursor(&self.cursor.lexeme.clone());
+        }
+    }
+
+    fn fetch_token(&mut self) -> (Token, usize) {
+        match self.pending.pop_front() {
+            Some(pending) => pending,
+            None => (self.lexer.next().unwrap(), 0),
+        }
+    }
+
+    /// Returns the next token for the parser, expanding macro uses in place.
+    fn next_token(&mut self) -> Token {
+        loop {
+            let (token, depth) = self.fetch_token();
+
+            if token.typ3 == TokenType::Include {
+                self.include(&token);
+                continue;
+            }
+
+            let is_macro_use = token.typ3 == TokenType::Ident
+                && !self.defining_macro
+                && self.previous.typ3 != TokenType::Macro
+                && self.macros.contains_key(&token.lexeme);
+
+            if !is_macro_use {
+                return token;
+            }
+
+            let next = self.fetch_token();
+            if next.0.typ3 != TokenType::LParen {
+                self.pending.push_front(next);
+                return token;
+            }
+
+            if depth + 1 > MACRO_DEPTH_LIMIT {
+                let err = format!(
+                    "Macro recursion limit exceeded expanding '{}'",
+                    token.lexeme
+                );
+                self.error_at(&token, &err);
+                // drop the rest of the expansion so compilation can finish
+                self.pending.clear();
+                continue;
+            }
+
+            if let Some(args) = self.macro_arguments(&token) {
+                let mac = self.macros[&token.lexeme].clone();
+                if args.len() != mac.params.len() {
+                    let err = format!(
+                        "Macro '{}' expects {} arguments but got {}",
+                        token.lexeme,
+                        mac.params.len(),
+                        args.len()
+                    );
+                    self.error_at(&token, &err);
+                    continue;
+                }
+
+                // a use written as a statement doesn't need its ';'
+                let next = self.fetch_token();
+                if next.0.typ3 != TokenType::Semicolon {
+                    self.pending.push_front(next);
+                }
+
+                self.expansions += 1;
+                let expansion = mac.expand(&args, &token, self.expansions);
+                for token in expansion.into_iter().rev() {
+                    self.pending.push_front((token, depth + 1));
+                }
+            }
+        }
+    }
+
+    /// Replaces `#include "path"` with the tokens of the file at `path`.
+    fn include(&mut self, directive: &Token) {
+        let (target, ..) = self.fetch_token();
+        if target.typ3 != TokenType::String {
+            self.error_at(&target, "Expected file path after '#include'");
+            self.pending.push_front((target, 0));
+            return;
+        }
+
+        let name = target.lexeme.trim_matches('"');
+        let path = match self.file_path(directive.file) {
+            Some(including) => including.with_file_name(name),
+            None => PathBuf::from(name),
+        };
+
+        if self.is_included_by(&path, directive.file) {
+            let err = format!("Recursive include of '{name}'");
+            self.error_at(&target, &err);
+            return;
+        }
+
+        let source = match std::fs::read_to_string(&path) {
+            Ok(source) => source,
+            Err(err) => {
+                let err = format!("Failed to include '{name}' ({err})");
+                self.error_at(&target, &err);
+                return;
+            }
+        };
+
+        if self.includes.len() == u16::MAX as usize {
+            self.error_at(&target, "Too many included files");
+            return;
+        }
+
+        self.includes.push((path.clone(), directive.file));
+        self.include_names.push(path.display().to_string());
+        let file = self.includes.len() as u16;
+
+        let source = match preprocess(&source, &self.defines) {
+            Ok(source) => source,
+            Err(err) => return self.preprocess_error(err, file),
+        };
+
+        let tokens: Vec<Token> = Lexer::new(&source)
+            .take_while(|token| token.typ3 != TokenType::Eof)
+            .map(|token| Token { file, ..token })
+            .collect();
+
+        for token in tokens.into_iter().rev() {
+            self.pending.push_front((token, 0));
+        }
+    }
+
+    fn preprocess_error(&mut self, err: PreprocessError, file: u16) {
+        let token = Token {
+            typ3: TokenType::Error,
+            lexeme: err.msg.clone(),
+            line: err.line,
+            column: 0,
+            file,
+        };
+        self.error_at(&token, &err.msg);
+    }
+
+    fn file_path(&self, file: u16) -> Option<&Path> {
+        match file {
+            0 => self.path.as_deref(),
+            file => Some(&self.includes[file as usize - 1].0),
+        }
+    }
+
+    /// Whether `path` is `file` itself or one of the files that included it.
+    fn is_included_by(&self, path: &Path, mut file: u16) -> bool {
+        let canonical = |path: &Path| path.canonicalize().unwrap_or(path.to_path_buf());
+        let path = canonical(path);
+
+        loop {
+            if let Some(including) = self.file_path(file) {
+                if canonical(including) == path {
+                    return true;
+                }
+            }
+
+            match file {
+                0 => return false,
+                _ => file = self.includes[file as usize - 1].1,
+            }
+        }
+    }
+
+    /// Collects the comma separated argument tokens of a macro use, the
+    /// opening '(' has already been consumed.
+    fn macro_arguments(&mut self, name: &Token) -> Option<Vec<Vec<Token>>> {
+        let mut args: Vec<Vec<Token>> = Vec::new();
+        let mut current = Vec::new();
+        let mut nesting = 0;
+
+        loop {
+            let (token, ..) = self.fetch_token();
+
+            match token.typ3 {
+                TokenType::Eof => {
+                    let err = format!("Unterminated use of macro '{}'", name.lexeme);
+                    self.error_at(name, &err);
+                    self.pending.push_front((token, 0));
+                    return None;
+                }
+                TokenType::RParen if nesting == 0 => {
+                    if !current.is_empty() || !args.is_empty() {
+                        args.push(current);
+                    }
+                    return Some(args);
+                }
+                TokenType::Comma if nesting == 0 => {
+                    args.push(std::mem::take(&mut current));
+                    continue;
+                }
+                TokenType::LParen | TokenType::LCurly => nesting += 1,
+                TokenType::RParen | TokenType::RCurly => nesting -= 1,
+                _ => (),
+            }
+
+            current.push(token);
+        }
+    }
+
+    fn expression(&mut self) -> Expr {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    fn block(&mut self) -> Block {
+        let open = self.previous.clone();
+        let mut statements = Vec::new();
+
+        let depth = self.depth;
+        if self.nest("Blocks nested too deeply") {
+            while !self.check(TokenType::RCurly) && !self.check(TokenType::Eof) {
+                statements.extend(self.declaration());
+            }
+        } else {
+            // skip to the matching '}', the blocks around it parse as usual
+            let mut unclosed = 1;
+            while !self.check(TokenType::Eof) {
+                match self.cursor.typ3 {
+                    TokenType::LCurly => unclosed += 1,
+                    TokenType::RCurly if unclosed == 1 => break,
+                    TokenType::RCurly => unclosed -= 1,
+                    _ => (),
+                }
+                self.advance();
+            }
+            self.panic_mode = false;
+        }
+        self.depth = depth;
+
+        self.consume(TokenType::RCurly, "Expected '}' after block".to_string());
+
+        Block {
+            open,
+            statements,
+            close: self.previous.clone(),
+        }
+    }
+
+    fn let_declaration(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        if self.matches(TokenType::LParen) {
+            return self.destructuring_let_declaration(keyword);
+        }
+
+        let name = self.parse_variable("Expected variable name".to_string());
+
+        let value = match self.matches(TokenType::Eq) {
+            true => Some(self.expression()),
+            false => None,
+        };
+
+        self.consume_semicolon("Expected ';' after expression");
+
+        Stmt::Let {
+            keyword,
+            name,
+            value,
+            end: self.previous.clone(),
+        }
+    }
+
+    fn destructuring_let_declaration(&mut self, keyword: Token) -> Stmt {
+        let mut names = Vec::new();
+
+        loop {
+            names.push(self.parse_variable("Expected variable name".to_string()));
+
+            if !self.matches(TokenType::Comma) || self.check(TokenType::RParen) {
+                break;
+            }
+        }
+
+        self.consume(
+            TokenType::RParen,
+            "Expected ')' after variable names".to_string(),
+        );
+        self.consume(
+            TokenType::Eq,
+            "Expected '=' after destructuring pattern".to_string(),
+        );
+
+        let value = self.expression();
+        self.consume_semicolon("Expected ';' after expression");
+
+        if names.len() > u8::MAX as usize {
+            self.error("Too many variables in destructuring pattern");
+        }
+
+        Stmt::LetTuple {
+            keyword,
+            names,
+            value,
+            end: self.previous.clone(),
+        }
+    }
+
+    fn fun_declaration(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        let name = self.parse_variable("Expected function name".to_string());
+
+        Stmt::Fun {
+            name,
+            function: self.function(keyword),
+        }
+    }
+
+    /// `fun (params) { body }` used as a value.
+    fn lambda(&mut self, _can_assign: bool) -> Expr {
+        Expr::Function(Box::new(self.function(self.previous.clone())))
+    }
+
+    /// Parses a parameter list and body.
+    fn function(&mut self, keyword: Token) -> Function {
+        self.consume(
+            TokenType::LParen,
+            "Expected '(' after function name".to_string(),
+        );
+
+        let mut params = Vec::new();
+        if !self.check(TokenType::RParen) {
+            loop {
+                if params.len() == u8::MAX as usize {
+                    self.error_at_cursor("Can't have more than 255 parameters");
+                }
+
+                params.push(self.parse_variable("Expected parameter name".to_string()));
+
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(
+            TokenType::RParen,
+            "Expected ')' after parameters".to_string(),
+        );
+        self.consume(
+            TokenType::LCurly,
+            "Expected '{' before function body".to_string(),
+        );
+
+        Function {
+            keyword,
+            params,
+            body: self.block(),
+        }
+    }
+
+    /// `test "name" { body }`
+    fn test_declaration(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+
+        self.consume(
+            TokenType::String,
+            "Expected test name after 'test'".to_string(),
+        );
+        let name = self.previous.clone();
+
+        self.consume(
+            TokenType::LCurly,
+            "Expected '{' before test body".to_string(),
+        );
+
+        Stmt::Test {
+            keyword,
+            name,
+            body: self.block(),
+        }
+    }
+
+    fn macro_declaration(&mut self) {
+        self.defining_macro = true;
+
+        self.consume(TokenType::Ident, "Expected macro name".to_string());
+        let name = self.previous.lexeme.clone();
+
+        self.consume(
+            TokenType::LParen,
+            "Expected '(' after macro name".to_string(),
+        );
+
+        let mut params = Vec::new();
+        if !self.check(TokenType::RParen) {
+            loop {
+                self.consume(TokenType::Ident, "Expected parameter name".to_string());
+                params.push(self.previous.lexeme.clone());
+
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(
+            TokenType::RParen,
+            "Expected ')' after macro parameters".to_string(),
+        );
+        self.consume(
+            TokenType::LCurly,
+            "Expected '{' before macro body".to_string(),
+        );
+
+        let mut body = Vec::new();
+        let mut nesting = 0;
+        while !self.check(TokenType::Eof) {
+            match self.cursor.typ3 {
+                TokenType::RCurly if nesting == 0 => break,
+                TokenType::LCurly => nesting += 1,
+                TokenType::RCurly => nesting -= 1,
+                _ => (),
+            }
+
+            body.push(self.cursor.clone());
+            self.advance();
+        }
+
+        // register before consuming '}' so the token after the body can
+        // already be a use of this macro
+        self.macros.insert(name, Macro { params, body });
+        self.defining_macro = false;
+
+        self.consume(
+            TokenType::RCurly,
+            "Expected '}' after macro body".to_string(),
+        );
+    }
+
+    fn print_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        let value = self.expression();
+        self.consume_semicolon("Expected ';' after expression");
+
+        Stmt::Print {
+            keyword,
+            value,
+            end: self.previous.clone(),
+        }
+    }
+
+    /// `assert(condition, message);`, the message is optional.
+    fn assert_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        self.consume(TokenType::LParen, "Expected '(' after 'assert'".to_string());
+        let condition = self.expression();
+        let message = match self.matches(TokenType::Comma) {
+            true => Some(self.expression()),
+            false => None,
+        };
+        self.consume(
+            TokenType::RParen,
+            "Expected ')' after assertion".to_string(),
+        );
+        self.consume_semicolon("Expected ';' after assertion");
+
+        Stmt::Assert {
+            keyword,
+            condition,
+            message,
+            end: self.previous.clone(),
+        }
+    }
+
+    fn expression_statement(&mut self) -> Stmt {
+        let expr = self.expression();
+        self.consume_semicolon("Expected ';' after expression");
+
+        Stmt::Expression {
+            expr,
+            end: self.previous.clone(),
+        }
+    }
+
+    fn return_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+
+        let value = match self.matches(TokenType::Semicolon) {
+            true => None,
+            false => {
+                let value = self.expression();
+                self.consume_semicolon("Expected ';' after return value");
+                Some(value)
+            }
+        };
+
+        Stmt::Return {
+            keyword,
+            value,
+            end: self.previous.clone(),
+        }
+    }
+
+    fn if_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        let condition = self.expression();
+
+        self.consume(TokenType::LCurly, "Expected '{' after if".to_string());
+        let then = self.block();
+
+        let mut otherwise = None;
+        if self.matches(TokenType::Else) {
+            self.consume(TokenType::LCurly, "Expected '}' after block".to_string());
+            otherwise = Some(self.block());
+        }
+
+        Stmt::If {
+            keyword,
+            condition,
+            then,
+            otherwise,
+        }
+    }
+
+    fn while_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        let condition = self.expression();
+
+        self.consume(TokenType::LCurly, "Expected '{' after while".to_string());
+
+        Stmt::While {
+            keyword,
+            condition,
+            body: self.block(),
+        }
+    }
+
+    fn for_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        self.consume(TokenType::LParen, "Expected '(' after for".to_string());
+
+        let initializer = if self.matches(TokenType::Semicolon) {
+            None
+        } else if self.matches(TokenType::Let) {
+            Some(Box::new(self.let_declaration()))
+        } else {
+            Some(Box::new(self.expression_statement()))
+        };
+
+        let mut condition = None;
+        if !self.matches(TokenType::Semicolon) {
+            condition = Some(self.expression());
+            self.consume(
+                TokenType::Semicolon,
+                "Expected ';' after expression".to_string(),
+            );
+        }
+        let semicolon = self.previous.clone();
+
+        let mut increment = None;
+        if !self.matches(TokenType::RParen) {
+            increment = Some(self.expression());
+            self.consume(
+                TokenType::RParen,
+                "Expected ')' after for statement".to_string(),
+            );
+        }
+        let close = self.previous.clone();
+
+        self.consume(
+            TokenType::LCurly,
+            "Expected '{' after for statement".to_string(),
+        );
+
+        Stmt::For {
+            keyword,
+            initializer,
+            condition,
+            semicolon,
+            increment,
+            close,
+            body: self.block(),
+        }
+    }
+
+    /// Macro declarations leave nothing behind, they're expanded away.
+    fn declaration(&mut self) -> Option<Stmt> {
+        let statement = if self.matches(TokenType::Let) {
+            Some(self.let_declaration())
+        } else if self.matches(TokenType::Fun) {
+            Some(self.fun_declaration())
+        } else if self.matches(TokenType::Macro) {
+            self.macro_declaration();
+            None
+        } else if self.matches(TokenType::Test) {
+            Some(self.test_declaration())
+        } else {
+            Some(self.statement())
+        };
+
+        if self.panic_mode {
+            self.synchronize();
+            return Some(Stmt::Error);
+        }
+
+        statement
+    }
+
+    fn statement(&mut self) -> Stmt {
+        if self.matches(TokenType::Print) {
+            self.print_statement()
+        } else if self.matches(TokenType::Assert) {
+            self.assert_statement()
+        } else if self.matches(TokenType::If) {
+            self.if_statement()
+        } else if self.matches(TokenType::While) {
+            self.while_statement()
+        } else if self.matches(TokenType::For) {
+            self.for_statement()
+        } else if self.matches(TokenType::Return) {
+            self.return_statement()
+        } else if self.matches(TokenType::LCurly) {
+            Stmt::Block(self.block())
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.cursor.typ3 != TokenType::Eof {
+            if let TokenType::Semicolon = self.previous.typ3 {
+                break;
+            }
+
+            use TokenType::*;
+            match self.cursor.typ3 {
+                Struct | Fun | For | If | While | Let | Macro | Test | Print | Assert | Return => {
+                    break
+                }
+                _ => (),
+            }
+
+            self.advance();
+        }
+    }
+
+    fn grouping(&mut self, _can_assign: bool) -> Expr {
+        let open = self.previous.clone();
+
+        // '()' is the empty tuple
+        if self.matches(TokenType::RParen) {
+            return Expr::Tuple {
+                open,
+                elements: Vec::new(),
+                close: self.previous.clone(),
+            };
+        }
+
+        let expr = self.expression();
+
+        if self.matches(TokenType::Comma) {
+            return self.tuple(open, expr);
+        }
+
+        self.consume(
+            TokenType::RParen,
+            "Expected ')' after expression".to_string(),
+        );
+
+        Expr::Grouping {
+            open,
+            expr: Box::new(expr),
+            close: self.previous.clone(),
+        }
+    }
+
+    fn tuple(&mut self, open: Token, first: Expr) -> Expr {
+        let mut elements = vec![first];
+
+        while !self.check(TokenType::RParen) && !self.check(TokenType::Eof) {
+            elements.push(self.expression());
+
+            if !self.matches(TokenType::Comma) {
+                break;
+            }
+        }
+
+        self.consume(TokenType::RParen, "Expected ')' after tuple".to_string());
+
+        if elements.len() > u8::MAX as usize {
+            self.error("Too many elements in tuple");
+        }
+
+        Expr::Tuple {
+            open,
+            elements,
+            close: self.previous.clone(),
+        }
+    }
+
+    fn binary(&mut self, left: Expr, _can_assign: bool) -> Expr {
+        let operator = self.previous.clone();
+        let precedence = Self::rule(operator.typ3).precedence;
+
+        // '**' is right associative so its rhs binds at the same level
+        let right = match operator.typ3 {
+            TokenType::StarStar => self.parse_precedence(precedence),
+            _ => self.parse_precedence(Precedence::from_u32(precedence as u32 + 1)),
+        };
+
+        Expr::Binary {
+            left: Box::new(left),
+            operator,
+            right: Box::new(right),
+        }
+    }
+
+    fn call(&mut self, callee: Expr, _can_assign: bool) -> Expr {
+        let args = self.argument_list();
+
+        Expr::Call {
+            callee: Box::new(callee),
+            args,
+            close: self.previous.clone(),
+        }
+    }
+
+    fn dot(&mut self, receiver: Expr, _can_assign: bool) -> Expr {
+        self.consume(
+            TokenType::Ident,
+            "Expected method name after '.'".to_string(),
+        );
+        let name = self.previous.clone();
+
+        self.consume(
+            TokenType::LParen,
+            "Expected '(' after method name".to_string(),
+        );
+        let args = self.argument_list();
+
+        Expr::Invoke {
+            receiver: Box::new(receiver),
+            name,
+            args,
+            close: self.previous.clone(),
+        }
+    }
+
+    fn argument_list(&mut self) -> Vec<Expr> {
+        let mut args = Vec::new();
+
+        if !self.check(TokenType::RParen) {
+            loop {
+                args.push(self.expression());
+
+                if args.len() == u8::MAX as usize + 1 {
+                    self.error("Can't have more than 255 arguments");
+                }
+
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(
+            TokenType::RParen,
+            "Expected ')' after arguments".to_string(),
+        );
+
+        args
+    }
+
+    fn literal(&mut self, _can_assign: bool) -> Expr {
+        let value = match self.previous.typ3 {
+            TokenType::False => Literal::Bool(false),
+            TokenType::True => Literal::Bool(true),
+            TokenType::Null => Literal::Null,
+            _ => unreachable!(),
+        };
+
+        self.literal_of(value)
+    }
+
+    fn literal_of(&self, value: Literal) -> Expr {
+        Expr::Literal {
+            value,
+            token: self.previous.clone(),
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) -> Expr {
+        let name = self.previous.clone();
+
+        if can_assign && self.matches(TokenType::Eq) {
+            return Expr::Assign {
+                name,
+                value: Box::new(self.expression()),
+            };
+        }
+
+        Expr::Variable(name)
+    }
+
+    fn string(&mut self, _can_assign: bool) -> Expr {
+        let lexeme = &self.previous.lexeme;
+
+        // remove quotes from conversion
+        let value = lexeme[1..lexeme.len() - 1].to_string();
+        self.literal_of(Literal::String(value))
+    }
+
+    fn number(&mut self, _can_assign: bool) -> Expr {
+        let mut lexeme = self.previous.lexeme.clone();
+        if lexeme.contains('_') {
+            lexeme = lexeme.replace('_', "");
+        }
+        if lexeme.contains('\'') {
+            lexeme = lexeme.replace('\'', "");
+        }
+
+        let value = lexeme.parse::<f64>().unwrap();
+        self.literal_of(Literal::Number(value))
+    }
+
+    fn character(&mut self, _can_assign: bool) -> Expr {
+        // the lexer only makes tokens of literals that unescape
+        let value = unescape_char(&self.previous.lexeme).unwrap();
+        self.literal_of(Literal::Char(value))
+    }
+
+    fn unary(&mut self, _can_assign: bool) -> Expr {
+        let operator = self.previous.clone();
+
+        Expr::Unary {
+            operator,
+            operand: Box::new(self.parse_precedence(Precedence::Unary)),
+        }
+    }
+
+    // 'and' and 'or' are left associative like the other operators, so a
+    // chain of them doesn't nest
+    fn and(&mut self, left: Expr, _can_assign: bool) -> Expr {
+        let operator = self.previous.clone();
+
+        Expr::Logical {
+            left: Box::new(left),
+            operator,
+            right: Box::new(self.parse_precedence(Precedence::Equality)),
+        }
+    }
+
+    fn or(&mut self, left: Expr, _can_assign: bool) -> Expr {
+        let operator = self.previous.clone();
+
+        Expr::Logical {
+            left: Box::new(left),
+            operator,
+            right: Box::new(self.parse_precedence(Precedence::And)),
+        }
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> Expr {
+        let depth = self.depth;
+        if !self.nest("Expression nested too deeply") {
+            return Expr::Error(self.cursor.clone());
+        }
+
+        self.advance();
+
+        let can_assign = precedence <= Precedence::Assignment;
+        let mut expr = Expr::Error(self.previous.clone());
+
+        match Self::rule(self.previous.typ3).prefix {
+            Some(func) => expr = func(self, can_assign),
+            None => {
+                let err = format!("Expected expression found '{}'", self.previous.lexeme);
+                self.error(&err);
+            }
+        }
+
+        while precedence <= Self::rule(self.cursor.typ3).precedence {
+            // a call nests what it calls, operators form chains that don't
+            // nest, see `Expr::chain`
+            let call = matches!(self.cursor.typ3, TokenType::LParen | TokenType::Dot);
+            if call && !self.nest("Expression nested too deeply") {
+                break;
+            }
+
+            self.advance();
+
+            match Self::rule(self.previous.typ3).infix {
+                Some(func) => expr = func(self, expr, can_assign),
+                None => break,
+            }
+        }
+
+        if can_assign && self.matches(TokenType::Eq) {
+            self.error("Invalid assignment target");
+        }
+
+        self.depth = depth;
+        expr
+    }
+
+    /// Enters another level of nesting, false with an error once that's
+    /// deeper than `MAX_DEPTH`.
+    fn nest(&mut self, msg: &str) -> bool {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            self.error_at_cursor(msg);
+            return false;
+        }
+        true
+    }
+
+    fn parse_variable(&mut self, error: String) -> Token {
+        self.consume(TokenType::Ident, error);
+        self.previous.clone()
+    }
+
+    fn consume(&mut self, typ3: TokenType, msg: String) {
+        if typ3 == self.cursor.typ3 {
+            self.advance();
+            return;
+        }
+
+        self.error_at_cursor(&msg);
+    }
+
+    fn matches(&mut self, typ3: TokenType) -> bool {
+        if !self.check(typ3) {
+            return false;
+        }
+
+        self.advance();
+        true
+    }
+
+    fn check(&self, typ3: TokenType) -> bool {
+        self.cursor.typ3 == typ3
+    }
+
+    /// Consumes the ';' that ends a statement, which the last statement may
+    /// leave out `with_optional_final_semicolon`.
+    fn consume_semicolon(&mut self, msg: &str) {
+        if self.optional_final_semicolon && self.check(TokenType::Eof) {
+            return;
+        }
+
+        self.consume(TokenType::Semicolon, msg.to_string());
+    }
+
+    fn error_at_cursor(&mut self, msg: &str) {
+        self.error_at(&self.cursor.clone(), msg)
+    }
+
+    fn error(&mut self, msg: &str) {
+        self.error_at(&self.previous.clone(), msg)
+    }
+
+    fn error_at(&mut self, token: &Token, msg: &str) {
+        if self.panic_mode {
+            return;
+        } else {
+            self.panic_mode = true;
+        }
+
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message: msg.to_string(),
+            span: Span::of(token, &self.include_names),
+        });
+    }
+}
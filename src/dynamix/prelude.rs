@@ -0,0 +1,22 @@
+//! What embedding dynamix usually takes, in one import.
+//!
+//! ```
+//! use dynamix::prelude::*;
+//!
+//! let mut engine = Engine::new();
+//! match engine.eval("print 1 +;") {
+//!     Err(CompileError(diagnostics)) => assert_eq!(diagnostics.len(), 1),
+//!     Err(RuntimeError { message, .. }) => panic!("{message}"),
+//!     other => panic!("{other:?}"),
+//! }
+//! ```
+//!
+//! Modules hidden from the docs are internals of the binary and compiler,
+//! they can change in any release.
+
+pub use super::{
+    diagnostic::Diagnostic,
+    engine::{Engine, EngineBuilder},
+    error::DynamixError::{self, CompileError, RuntimeError},
+    Value,
+};
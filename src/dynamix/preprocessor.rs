@@ -0,0 +1,88 @@
+//! Conditional compilation.
+//!
+//! `#if NAME` / `#if !NAME`, `#else` and `#endif` lines select regions of the
+//! source depending on which names were defined (`-D NAME` on the command
+//! line). Excluded regions are blanked out before lexing so they cost nothing
+//! at runtime, and every line stays where it was so diagnostics still point
+//! at the right place.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreprocessError {
+    pub line: usize,
+    pub msg: String,
+}
+
+struct Region {
+    line: usize,
+    // whether the enclosing region is being kept
+    parent_active: bool,
+    active: bool,
+    seen_else: bool,
+}
+
+pub fn preprocess(source: &str, defines: &[String]) -> Result<String, PreprocessError> {
+    let mut output = String::with_capacity(source.len());
+    let mut regions: Vec<Region> = Vec::new();
+
+    for (i, line) in source.split('\n').enumerate() {
+        let number = i + 1;
+        let active = regions.last().is_none_or(|region| region.active);
+
+        if i != 0 {
+            output.push('\n');
+        }
+
+        // directives may be followed by a comment
+        let code = line.split("//").next().unwrap_or_default();
+        let mut words = code.split_whitespace();
+        match words.next() {
+            Some("#if") => {
+                let condition = match words.next() {
+                    Some(name) if words.next().is_none() => name,
+                    _ => return Err(error(number, "Expected a single name after '#if'")),
+                };
+
+                let defined = match condition.strip_prefix('!') {
+                    Some(name) => !defines.iter().any(|d| d == name),
+                    None => defines.iter().any(|d| d == condition),
+                };
+
+                regions.push(Region {
+                    line: number,
+                    parent_active: active,
+                    active: active && defined,
+                    seen_else: false,
+                });
+            }
+            Some("#else") => {
+                let region = match regions.last_mut() {
+                    Some(region) if !region.seen_else => region,
+                    Some(..) => return Err(error(number, "Duplicate '#else'")),
+                    None => return Err(error(number, "'#else' without matching '#if'")),
+                };
+
+                region.seen_else = true;
+                region.active = region.parent_active && !region.active;
+            }
+            Some("#endif") => {
+                regions
+                    .pop()
+                    .ok_or_else(|| error(number, "'#endif' without matching '#if'"))?;
+            }
+            _ if active => output.push_str(line),
+            _ => (),
+        }
+    }
+
+    match regions.last() {
+        Some(region) => Err(error(region.line, "Unterminated '#if', expected '#endif'")),
+        None => Ok(output),
+    }
+}
+
+fn error(line: usize, msg: &str) -> PreprocessError {
+    PreprocessError {
+        line,
+        msg: msg.to_string(),
+    }
+}
@@ -0,0 +1,46 @@
+//! Counts where a script spends its instructions, per call stack.
+//!
+//! Every instruction the VM executes is a sample of the stack of functions
+//! it ran in. `folded` writes the counts in the folded stacks format read by
+//! flame graph tools, e.g. `flamegraph.pl profile.folded > profile.svg` or
+//! `inferno-flamegraph`.
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    // the stack, outermost function first and separated by ';', to its samples
+    stacks: HashMap<String, u64>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn record(&mut self, stack: String) {
+        *self.stacks.entry(stack).or_insert(0) += 1;
+    }
+
+    /// How many instructions were sampled.
+    pub fn samples(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+
+    /// The samples of `stack`, e.g. `"script;fib;fib"`.
+    pub fn samples_of(&self, stack: &str) -> u64 {
+        self.stacks.get(stack).copied().unwrap_or(0)
+    }
+
+    /// One `stack count` line per distinct stack, sorted so the same run
+    /// always writes the same file.
+    pub fn folded(&self) -> String {
+        let mut stacks: Vec<_> = self.stacks.iter().collect();
+        stacks.sort();
+
+        stacks
+            .into_iter()
+            .map(|(stack, count)| format!("{stack} {count}\n"))
+            .collect()
+    }
+}
@@ -0,0 +1,146 @@
+//! Classifies the tokens of a script for syntax highlighting.
+//!
+//! Only the lexer runs, so highlighting works on scripts that don't compile.
+//! An identifier is a function if the script declares it with `fun`, it's
+//! called, or it names a stdlib native, anything else is a variable.
+
+use crate::{
+    lexer::{Lexer, Token, TokenType},
+    stdlib,
+};
+
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Keyword,
+    Function,
+    Variable,
+    Number,
+    String,
+    Char,
+}
+
+/// Where a token is, `column` and `len` count chars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenSpan {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+/// The highlighted tokens of `source` in order, operators and punctuation
+/// are left out.
+pub fn semantic_tokens(source: &str) -> Vec<(TokenSpan, TokenKind)> {
+    let tokens: Vec<Token> = Lexer::new(source)
+        .take_while(|token| token.typ3 != TokenType::Eof)
+        .collect();
+
+    let functions = declared_functions(&tokens);
+
+    tokens
+        .iter()
+        .enumerate()
+        .filter_map(|(i, token)| {
+            let kind = match token.typ3 {
+                TokenType::Ident => {
+                    let called = tokens.get(i + 1).map(|t| t.typ3) == Some(TokenType::LParen);
+                    if called
+                        || functions.contains(token.lexeme.as_str())
+                        || is_native(&token.lexeme)
+                    {
+                        TokenKind::Function
+                    } else {
+                        TokenKind::Variable
+                    }
+                }
+                TokenType::Number => TokenKind::Number,
+                TokenType::String => TokenKind::String,
+                TokenType::Char => TokenKind::Char,
+                TokenType::Error => return None,
+                _ if is_keyword(token.typ3) => TokenKind::Keyword,
+                _ => return None,
+            };
+
+            Some((span(token), kind))
+        })
+        .collect()
+}
+
+fn is_keyword(typ3: TokenType) -> bool {
+    use TokenType::*;
+    matches!(
+        typ3,
+        And | Assert
+            | Struct
+            | Else
+            | False
+            | For
+            | Fun
+            | If
+            | Null
+            | Or
+            | Print
+            | Return
+            | Super
+            | SSelf
+            | True
+            | Let
+            | Macro
+            | Test
+            | While
+            | Include
+    )
+}
+
+fn is_native(name: &str) -> bool {
+    stdlib::MODULES
+        .iter()
+        .flat_map(|module| module.natives)
+        .any(|native| native.name == name)
+}
+
+/// Names declared with `fun name` or `let name = fun`.
+fn declared_functions(tokens: &[Token]) -> HashSet<&str> {
+    let mut functions = HashSet::new();
+
+    for (i, token) in tokens.iter().enumerate() {
+        let name = match token.typ3 {
+            TokenType::Fun => tokens.get(i + 1),
+            TokenType::Let if tokens.get(i + 3).map(|t| t.typ3) == Some(TokenType::Fun) => {
+                tokens.get(i + 1)
+            }
+            _ => None,
+        };
+
+        if let Some(name) = name.filter(|name| name.typ3 == TokenType::Ident) {
+            functions.insert(name.lexeme.as_str());
+        }
+    }
+
+    functions
+}
+
+fn span(token: &Token) -> TokenSpan {
+    let len = token.lexeme.chars().count();
+
+    match token.typ3 {
+        // the lexeme of a char literal leaves out its quotes
+        TokenType::Char => TokenSpan {
+            line: token.line,
+            column: token.column,
+            len: len + 2,
+        },
+        // a string's line is the one it ends on
+        TokenType::String => TokenSpan {
+            line: token.line - token.lexeme.matches('\n').count(),
+            column: token.column,
+            len,
+        },
+        _ => TokenSpan {
+            line: token.line,
+            column: token.column,
+            len,
+        },
+    }
+}
@@ -0,0 +1,385 @@
+//! The `.dynb` format, compiled bytecode saved by `dynamix compile` so a
+//! script can be run without compiling it again.
+//!
+//! A file is the magic bytes, a version and the top level block. Integers
+//! are little endian and every list is prefixed with its length as a `u32`.
+//! Functions are stored inline in the constant pool with their own block.
+
+use crate::{
+    byte_block::{ByteBlock, LocalInfo, TestCase},
+    constant::{Constant, Function, Interner},
+    verifier,
+};
+
+use std::{
+    io::{self, ErrorKind},
+    sync::Arc,
+};
+
+pub const MAGIC: &[u8; 4] = b"DYNB";
+
+/// Bumped whenever the layout changes, older files are rejected.
+pub const VERSION: u16 = 1;
+
+// tags of the constants in the pool
+const NULL: u8 = 0;
+const BOOL: u8 = 1;
+const NUMBER: u8 = 2;
+const CHAR: u8 = 3;
+const STRING: u8 = 4;
+const TUPLE: u8 = 5;
+const FUNCTION: u8 = 6;
+
+// how deep tuples and functions can nest, the reader recurses into them
+const MAX_NESTING: usize = 128;
+
+/// Returns true if `bytes` start like a `.dynb` file.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+impl ByteBlock {
+    /// Fails if the pool holds a value only the VM creates, e.g. a native.
+    pub fn serialize(&self) -> io::Result<Vec<u8>> {
+        let mut writer = Writer { bytes: Vec::new() };
+        writer.bytes.extend_from_slice(MAGIC);
+        writer.u16(VERSION);
+        writer.block(self)?;
+        Ok(writer.bytes)
+    }
+
+    /// Fails if the bytes aren't a block this version wrote, or if the
+    /// block wouldn't run safely, see `verifier::verify`.
+    pub fn deserialize(bytes: &[u8]) -> io::Result<ByteBlock> {
+        if !is_bytecode(bytes) {
+            return Err(invalid("Not a dynamix bytecode file".to_string()));
+        }
+
+        let mut reader = Reader {
+            bytes,
+            cursor: MAGIC.len(),
+            strings: Interner::new(),
+            depth: 0,
+        };
+
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(invalid(format!(
+                "Unsupported bytecode version {version}, expected {VERSION}"
+            )));
+        }
+
+        let block = reader.block(None)?;
+        if reader.cursor != bytes.len() {
+            return Err(invalid(
+                "Trailing bytes after the top level block".to_string(),
+            ));
+        }
+
+        Ok(block)
+    }
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(len as u32);
+    }
+
+    fn str(&mut self, value: &str) {
+        self.raw(value.as_bytes());
+    }
+
+    fn raw(&mut self, bytes: &[u8]) {
+        self.len(bytes.len());
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    fn block(&mut self, block: &ByteBlock) -> io::Result<()> {
+        match &block.name {
+            Some(name) => {
+                self.u8(1);
+                self.str(name);
+            }
+            None => self.u8(0),
+        }
+
+        self.raw(&block.bytes);
+
+        self.len(block.lines.len());
+        block.lines.iter().for_each(|line| self.u32(*line));
+
+        self.len(block.files.len());
+        block.files.iter().for_each(|file| self.u16(*file));
+
+        self.len(block.includes.len());
+        block.includes.iter().for_each(|include| self.str(include));
+
+        self.len(block.locals.len());
+        for local in block.locals.iter() {
+            self.str(&local.name);
+            self.len(local.slot);
+            self.len(local.start);
+            self.len(local.end);
+        }
+
+        self.len(block.constants.len());
+        for constant in block.constants.constants.iter() {
+            self.constant(constant)?;
+        }
+
+        self.len(block.tests.len());
+        for test in block.tests.iter() {
+            self.str(&test.name);
+            self.function(&test.function)?;
+        }
+
+        Ok(())
+    }
+
+    fn constant(&mut self, constant: &Constant) -> io::Result<()> {
+        match constant {
+            Constant::Null => self.u8(NULL),
+            Constant::Bool(value) => {
+                self.u8(BOOL);
+                self.u8(*value as u8);
+            }
+            Constant::Number(value) => {
+                self.u8(NUMBER);
+                self.bytes.extend_from_slice(&value.to_le_bytes());
+            }
+            Constant::Char(value) => {
+                self.u8(CHAR);
+                self.u32(*value as u32);
+            }
+            Constant::Obj(obj) => {
+                self.u8(STRING);
+                self.raw(&obj.bytes);
+            }
+            Constant::Tuple(values) => {
+                self.u8(TUPLE);
+                self.len(values.len());
+                for value in values.iter() {
+                    self.constant(value)?;
+                }
+            }
+            Constant::Function(function) => {
+                self.u8(FUNCTION);
+                self.function(function)?;
+            }
+            Constant::Native(..)
+            | Constant::Memoized(..)
+            | Constant::Task(..)
+            | Constant::Channel(..) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Can't serialize a '{}' constant", constant.type_to_string()),
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn function(&mut self, function: &Function) -> io::Result<()> {
+        self.str(&function.name);
+        self.len(function.arity);
+        self.block(&function.block)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    cursor: usize,
+    // strings are interned like the compiler does, so equal literals share a handle
+    strings: Interner,
+    // tuples and functions the reader is inside of
+    depth: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> io::Result<&[u8]> {
+        let end = self
+            .cursor
+            .checked_add(count)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| invalid(format!("Unexpected end of bytecode at {}", self.cursor)))?;
+
+        let bytes = &self.bytes[self.cursor..end];
+        self.cursor = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn len(&mut self) -> io::Result<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn raw(&mut self) -> io::Result<&[u8]> {
+        let len = self.len()?;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> io::Result<String> {
+        let at = self.cursor;
+        String::from_utf8(self.raw()?.to_vec())
+            .map_err(|_| invalid(format!("Invalid UTF-8 string at {at}")))
+    }
+
+    /// Reads a block and checks it can be run, `params` is the arity of
+    /// the function it belongs to, see `verifier::verify`.
+    fn block(&mut self, params: Option<usize>) -> io::Result<ByteBlock> {
+        let mut block = ByteBlock::new();
+
+        block.name = match self.u8()? {
+            0 => None,
+            _ => Some(self.str()?),
+        };
+
+        block.bytes = self.raw()?.to_vec();
+
+        for _ in 0..self.len()? {
+            block.lines.push(self.u32()?);
+        }
+
+        for _ in 0..self.len()? {
+            block.files.push(self.u16()?);
+        }
+
+        for _ in 0..self.len()? {
+            block.includes.push(self.str()?);
+        }
+
+        for _ in 0..self.len()? {
+            block.locals.push(LocalInfo {
+                name: self.str()?,
+                slot: self.len()?,
+                start: self.len()?,
+                end: self.len()?,
+            });
+        }
+
+        for _ in 0..self.len()? {
+            let constant = self.constant()?;
+            block.constants.push(constant);
+        }
+
+        for _ in 0..self.len()? {
+            block.tests.push(TestCase {
+                name: self.str()?,
+                function: Arc::new(self.function()?),
+            });
+        }
+
+        if block.lines.len() != block.bytes.len() || block.files.len() != block.bytes.len() {
+            return Err(invalid(format!(
+                "Line table of '{}' doesn't match its bytes",
+                block.name()
+            )));
+        }
+
+        if let Some(file) = block
+            .files
+            .iter()
+            .find(|&&file| file as usize > block.includes.len())
+        {
+            return Err(invalid(format!(
+                "File index {file} of '{}' is out of range",
+                block.name()
+            )));
+        }
+
+        verifier::verify(&block, params).map_err(invalid)?;
+        Ok(block)
+    }
+
+    fn constant(&mut self) -> io::Result<Constant> {
+        let at = self.cursor;
+        let tag = self.u8()?;
+        if matches!(tag, TUPLE | FUNCTION) {
+            self.depth += 1;
+            if self.depth > MAX_NESTING {
+                return Err(invalid(format!(
+                    "Constants nested more than {MAX_NESTING} deep at {at}"
+                )));
+            }
+        }
+
+        let constant = match tag {
+            NULL => Constant::Null,
+            BOOL => Constant::Bool(self.u8()? != 0),
+            NUMBER => {
+                let bytes = self.take(8)?;
+                Constant::Number(f64::from_le_bytes(bytes.try_into().unwrap()))
+            }
+            CHAR => {
+                let value = self.u32()?;
+                Constant::Char(
+                    char::from_u32(value)
+                        .ok_or_else(|| invalid(format!("Invalid char {value:#x} at {at}")))?,
+                )
+            }
+            STRING => {
+                let value = self.str()?;
+                self.strings.string(&value)
+            }
+            TUPLE => {
+                let len = self.len()?;
+                let mut values = Vec::new();
+                for _ in 0..len {
+                    values.push(self.constant()?);
+                }
+                Constant::Tuple(values)
+            }
+            FUNCTION => Constant::Function(Arc::new(self.function()?)),
+            tag => return Err(invalid(format!("Unknown constant tag {tag} at {at}"))),
+        };
+
+        if matches!(tag, TUPLE | FUNCTION) {
+            self.depth -= 1;
+        }
+        Ok(constant)
+    }
+
+    fn function(&mut self) -> io::Result<Function> {
+        let name = self.str()?;
+        let arity = self.len()?;
+        Ok(Function {
+            name,
+            arity,
+            block: Arc::new(self.block(Some(arity))?),
+        })
+    }
+}
@@ -20,16 +20,36 @@ impl<T> Stack<T> {
         self.data.pop()
     }
 
+    /// Drops every value from `len` up, keeping the bottom `len` values.
+    pub fn truncate(&mut self, len: usize) {
+        self.data.truncate(len);
+    }
+
     pub fn remove(&mut self, index: usize) -> T {
         self.data.remove(index)
     }
 
-    pub fn as_ptr(&self) -> *const T {
-        self.data.as_ptr()
+    /// Iterates from the bottom of the stack to the top without popping.
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.data.iter()
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.data.get(index)
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        self.data.get_mut(index)
     }
 
-    pub fn top_as_ptr(&self) -> *const T {
-        unsafe { self.as_ptr().add(self.len()) }
+    /// Borrows the value `distance` slots below the top, `peek(0)` is the top.
+    pub fn peek(&self, distance: usize) -> Option<&T> {
+        let index = self.data.len().checked_sub(distance + 1)?;
+        self.data.get(index)
     }
 
     pub fn last(&self) -> Option<&T> {
@@ -63,14 +83,11 @@ impl<T> Index<usize> for Stack<T> {
     }
 }
 
-impl<T> Iterator for Stack<T> {
-    type Item = T;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.is_empty() {
-            return None;
-        }
+impl<'a, T> IntoIterator for &'a Stack<T> {
+    type Item = &'a T;
+    type IntoIter = std::slice::Iter<'a, T>;
 
-        Some(self.data.remove(0))
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
     }
 }
@@ -0,0 +1,104 @@
+//! Assertions for `test` blocks, a failure is a runtime error that shows
+//! both values and, for tuples, where they differ.
+
+use crate::{
+    constant::Constant,
+    native::Native,
+    virtual_machine::{quoted, VirtualMachine},
+};
+
+use super::Module;
+
+pub const MODULE: Module = Module {
+    name: "assert",
+    natives: &[
+        Native::new("assert_eq", 2, assert_eq).with_doc("Fails unless a == b, showing both values"),
+        Native::new("assert_ne", 2, assert_ne).with_doc("Fails if a == b, showing both values"),
+        Native::new("diff", 2, diff).with_doc("Where a and b differ as a string, null if a == b"),
+    ],
+    constants: &[],
+};
+
+fn compare(native: &str, args: &[Constant], expected: bool) -> Result<Constant, String> {
+    let (left, right) = (&args[0], &args[1]);
+    if (left == right) == expected {
+        return Ok(Constant::Null);
+    }
+
+    let mut message = format!(
+        "{native} failed, left: {}, right: {}",
+        quoted(left),
+        quoted(right)
+    );
+    // two scalars already show their whole difference
+    if let (Constant::Tuple(..), Constant::Tuple(..)) = (left, right) {
+        if let Some(differences) = describe(left, right) {
+            message.push_str(&format!(", differs at {differences}"));
+        }
+    }
+
+    Err(message)
+}
+
+/// Most differences `describe` lists before it summarizes the rest.
+const MAX_DIFFERENCES: usize = 4;
+
+/// Every place `left` and `right` differ, e.g.
+/// `value[1][0]: 3 != 4; value[2]: 'a' (char) != "a" (String)`.
+pub(crate) fn describe(left: &Constant, right: &Constant) -> Option<String> {
+    let mut differences = Vec::new();
+    differences_at("value", left, right, &mut differences);
+    if differences.is_empty() {
+        return None;
+    }
+
+    let more = differences.len().saturating_sub(MAX_DIFFERENCES);
+    differences.truncate(MAX_DIFFERENCES);
+    if more > 0 {
+        differences.push(format!("and {more} more"));
+    }
+    Some(differences.join("; "))
+}
+
+fn differences_at(path: &str, left: &Constant, right: &Constant, differences: &mut Vec<String>) {
+    if left == right {
+        return;
+    }
+
+    match (left, right) {
+        (Constant::Tuple(left), Constant::Tuple(right)) => {
+            if left.len() != right.len() {
+                differences.push(format!(
+                    "{path}: {} items != {} items",
+                    left.len(),
+                    right.len()
+                ));
+            }
+            for (i, (left, right)) in left.iter().zip(right.iter()).enumerate() {
+                differences_at(&format!("{path}[{i}]"), left, right, differences);
+            }
+        }
+        _ if left.type_to_string() != right.type_to_string() => differences.push(format!(
+            "{path}: {} ({}) != {} ({})",
+            quoted(left),
+            left.type_to_string(),
+            quoted(right),
+            right.type_to_string()
+        )),
+        _ => differences.push(format!("{path}: {} != {}", quoted(left), quoted(right))),
+    }
+}
+
+fn assert_eq(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    compare("assert_eq", args, true)
+}
+
+fn assert_ne(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    compare("assert_ne", args, false)
+}
+
+fn diff(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    Ok(describe(&args[0], &args[1])
+        .map(|differences| Constant::new_string(&differences))
+        .unwrap_or(Constant::Null))
+}
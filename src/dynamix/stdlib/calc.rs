@@ -0,0 +1,181 @@
+//! Natives for using dynamix as a calculator, see `EngineBuilder::calculator`.
+//!
+//! The whole number natives work on `i128`s, so a result is either exact or
+//! an error, never silently rounded like `*` and `**` on large numbers.
+
+use crate::{
+    constant::Constant,
+    native::Native,
+    virtual_machine::{quoted, AngleMode, VirtualMachine},
+};
+
+use super::{number, Module};
+
+pub const MODULE: Module = Module {
+    name: "calc",
+    natives: &[
+        Native::new("angle_mode", 1, angle_mode)
+            .with_doc("Switches trig to \"degrees\" or \"radians\", returns the old mode"),
+        Native::new("round_to", 2, round_to).with_doc("x rounded to n decimal places"),
+        Native::new("to_fixed", 2, to_fixed).with_doc("x as a String with n decimal places"),
+        Native::new("factorial", 1, factorial).with_doc("n! for a whole number n"),
+        Native::new("gcd", 2, gcd).with_doc("The greatest common divisor of a and b"),
+        Native::new("lcm", 2, lcm).with_doc("The least common multiple of a and b"),
+        Native::new("idiv", 2, idiv).with_doc("a / b rounded down, for whole numbers"),
+        Native::new("imod", 2, imod).with_doc("The remainder of idiv, with the sign of b"),
+        Native::new("ipow", 2, ipow).with_doc("a ** b exactly, for whole numbers"),
+    ],
+    constants: &[],
+};
+
+/// Whole numbers up to this size are exact as `f64`s.
+const MAX_EXACT: i128 = 1 << 53;
+
+/// Most decimal places `round_to` and `to_fixed` take.
+const MAX_DIGITS: i128 = 20;
+
+fn whole(native: &str, value: &Constant) -> Result<i128, String> {
+    let x = number(native, value)?;
+    if x.fract() != 0.0 || x.abs() > MAX_EXACT as f64 {
+        return Err(format!(
+            "Expected a whole number argument to '{native}' found {x}"
+        ));
+    }
+    Ok(x as i128)
+}
+
+fn exact(native: &str, result: Option<i128>) -> Result<Constant, String> {
+    match result {
+        Some(result) if result.abs() <= MAX_EXACT => Ok(Constant::Number(result as f64)),
+        _ => Err(format!("Result of '{native}' is too large to be exact")),
+    }
+}
+
+fn digits(native: &str, value: &Constant, min: i128) -> Result<i128, String> {
+    let digits = whole(native, value)?;
+    if !(min..=MAX_DIGITS).contains(&digits) {
+        return Err(format!(
+            "Expected {min} to {MAX_DIGITS} decimal places in call to '{native}' found {digits}"
+        ));
+    }
+    Ok(digits)
+}
+
+fn angle_mode(vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let mode = match args[0].as_str() {
+        Some("degrees") => AngleMode::Degrees,
+        Some("radians") => AngleMode::Radians,
+        _ => {
+            return Err(format!(
+                "Expected \"degrees\" or \"radians\" in call to 'angle_mode' found {}",
+                quoted(&args[0])
+            ))
+        }
+    };
+
+    let previous = vm.angle_mode();
+    vm.set_angle_mode(mode);
+    Ok(Constant::new_string(previous.name()))
+}
+
+/// Negative places round to tens, hundreds and so on.
+fn round_to(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let x = number("round_to", &args[0])?;
+    let places = digits("round_to", &args[1], -MAX_DIGITS)?;
+
+    // dividing by a power of ten is exact where multiplying by its inverse isn't
+    let scale = 10f64.powi(places.unsigned_abs() as i32);
+    let rounded = match places {
+        0.. => (x * scale).round() / scale,
+        _ => (x / scale).round() * scale,
+    };
+    Ok(Constant::Number(rounded))
+}
+
+fn to_fixed(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let x = number("to_fixed", &args[0])?;
+    let places = digits("to_fixed", &args[1], 0)? as usize;
+    Ok(Constant::new_string(&format!("{x:.places$}")))
+}
+
+fn factorial(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let n = whole("factorial", &args[0])?;
+    if n < 0 {
+        return Err(format!("Expected n >= 0 in call to 'factorial' found {n}"));
+    }
+
+    let result = (2..=n).try_fold(1i128, |product, i| {
+        product
+            .checked_mul(i)
+            .filter(|product| *product <= MAX_EXACT)
+    });
+    exact("factorial", result)
+}
+
+fn gcd_of(mut a: i128, mut b: i128) -> i128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a.abs()
+}
+
+fn gcd(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let (a, b) = (whole("gcd", &args[0])?, whole("gcd", &args[1])?);
+    exact("gcd", Some(gcd_of(a, b)))
+}
+
+/// 0 if either number is.
+fn lcm(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let (a, b) = (whole("lcm", &args[0])?, whole("lcm", &args[1])?);
+    let result = match gcd_of(a, b) {
+        0 => 0,
+        gcd => (a / gcd * b).abs(),
+    };
+    exact("lcm", Some(result))
+}
+
+fn divisor(native: &str, args: &[Constant]) -> Result<(i128, i128), String> {
+    let (a, b) = (whole(native, &args[0])?, whole(native, &args[1])?);
+    if b == 0 {
+        return Err(format!("Division by zero in call to '{native}'"));
+    }
+    Ok((a, b))
+}
+
+fn idiv(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let (a, b) = divisor("idiv", args)?;
+    exact(
+        "idiv",
+        Some(a.div_euclid(b) - i128::from(b < 0 && a.rem_euclid(b) != 0)),
+    )
+}
+
+fn imod(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let (a, b) = divisor("imod", args)?;
+    let remainder = a.rem_euclid(b);
+    exact(
+        "imod",
+        Some(if b < 0 && remainder != 0 {
+            remainder + b
+        } else {
+            remainder
+        }),
+    )
+}
+
+fn ipow(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let (a, b) = (whole("ipow", &args[0])?, whole("ipow", &args[1])?);
+    if b < 0 {
+        return Err(format!("Expected a power >= 0 in call to 'ipow' found {b}"));
+    }
+
+    let result = match a {
+        -1 if b % 2 == 1 => Some(-1),
+        -1 | 1 => Some(1),
+        0 => Some(i128::from(b == 0)),
+        // anything else is past MAX_EXACT well before this
+        _ if b > 64 => None,
+        _ => a.checked_pow(b as u32),
+    };
+    exact("ipow", result)
+}
@@ -0,0 +1,81 @@
+//! Reading configuration files, enabled with the `config` feature.
+//!
+//! There's no map type, so a TOML table or YAML mapping becomes a tuple of
+//! `(key, value)` pairs in the order the file lists them, and an array is a
+//! tuple of its values. `config_get` looks values up by a dotted path.
+
+mod toml;
+mod yaml;
+
+use crate::{constant::Constant, native::Native, virtual_machine::VirtualMachine};
+
+use super::Module;
+
+pub const MODULE: Module = Module {
+    name: "config",
+    natives: &[
+        Native::new("toml_parse", 1, toml_parse)
+            .with_doc("Parses TOML text into (key, value) pairs"),
+        Native::new("yaml_parse", 1, yaml_parse)
+            .with_doc("Parses YAML text into (key, value) pairs"),
+        Native::new("config_get", 2, config_get)
+            .with_doc("The value at a path like \"server.ports.0\", or null"),
+    ],
+    constants: &[],
+};
+
+fn text<'a>(native: &str, value: &'a Constant) -> Result<&'a str, String> {
+    value.as_str().ok_or(format!(
+        "Expected String argument to '{native}' found '{}'",
+        value.type_to_string()
+    ))
+}
+
+/// A table as scripts see it.
+fn table(entries: Vec<(String, Constant)>) -> Constant {
+    Constant::Tuple(
+        entries
+            .into_iter()
+            .map(|(key, value)| Constant::Tuple(vec![Constant::new_string(&key), value]))
+            .collect(),
+    )
+}
+
+/// The value of `key` if `value` is a tuple of `(key, value)` pairs.
+fn entry<'a>(value: &'a Constant, key: &str) -> Option<&'a Constant> {
+    let Constant::Tuple(pairs) = value else {
+        return None;
+    };
+
+    pairs.iter().find_map(|pair| match pair {
+        Constant::Tuple(pair) if pair.len() == 2 && pair[0].as_str() == Some(key) => Some(&pair[1]),
+        _ => None,
+    })
+}
+
+fn toml_parse(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    toml::parse(text("toml_parse", &args[0])?)
+}
+
+fn yaml_parse(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    yaml::parse(text("yaml_parse", &args[0])?)
+}
+
+fn config_get(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let path = text("config_get", &args[1])?;
+
+    let mut value = &args[0];
+    for segment in path.split('.') {
+        let found = entry(value, segment).or_else(|| match (value, segment.parse::<usize>()) {
+            (Constant::Tuple(items), Ok(index)) => items.get(index),
+            _ => None,
+        });
+
+        match found {
+            Some(found) => value = found,
+            None => return Ok(Constant::Null),
+        }
+    }
+
+    Ok(value.clone())
+}
@@ -0,0 +1,410 @@
+//! The parts of TOML configuration files use: tables, arrays of tables,
+//! dotted keys, strings, numbers, booleans, arrays and inline tables.
+//! Dates and multi-line strings aren't supported.
+
+use crate::constant::Constant;
+
+enum Node {
+    Table(Vec<(String, Node)>),
+    Array(Vec<Node>),
+    Value(Constant),
+}
+
+impl Node {
+    fn into_constant(self) -> Constant {
+        match self {
+            Node::Table(entries) => super::table(
+                entries
+                    .into_iter()
+                    .map(|(key, node)| (key, node.into_constant()))
+                    .collect(),
+            ),
+            Node::Array(items) => {
+                Constant::Tuple(items.into_iter().map(Node::into_constant).collect())
+            }
+            Node::Value(value) => value,
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Constant, String> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        cursor: 0,
+        line: 1,
+    };
+
+    let mut root = Vec::new();
+    // the table named by the last '[header]', keys below it go in there
+    let mut current = Vec::new();
+    // every '[header]' so far, each table can only have one
+    let mut headers: Vec<Vec<String>> = Vec::new();
+
+    loop {
+        parser.skip_blank();
+        if parser.peek().is_none() {
+            break;
+        }
+
+        if parser.eat('[') {
+            let array = parser.eat('[');
+            let path = parser.key()?;
+            parser.expect(']')?;
+            if array {
+                parser.expect(']')?;
+                push_table(&mut root, &path, parser.line)?;
+                // the tables under it are those of the new element
+                headers.retain(|header| !header.starts_with(&path));
+            } else if headers.contains(&path) {
+                return Err(format!(
+                    "Duplicate table '{}' on line {}",
+                    path.join("."),
+                    parser.line
+                ));
+            } else {
+                table_at(&mut root, &path, parser.line)?;
+                headers.push(path.clone());
+            }
+            current = path;
+        } else {
+            let key = parser.key()?;
+            parser.expect('=')?;
+            let value = parser.value()?;
+            let table = table_at(&mut root, &current, parser.line)?;
+            insert(table, &key, value, parser.line)?;
+        }
+
+        parser.end_of_line()?;
+    }
+
+    Ok(Node::Table(root).into_constant())
+}
+
+/// The table at `path`, missing tables are created on the way. A path
+/// through an array of tables goes into its last table.
+fn table_at<'a>(
+    entries: &'a mut Vec<(String, Node)>,
+    path: &[String],
+    line: usize,
+) -> Result<&'a mut Vec<(String, Node)>, String> {
+    let Some((first, rest)) = path.split_first() else {
+        return Ok(entries);
+    };
+
+    let index = match entries.iter().position(|(key, ..)| key == first) {
+        Some(index) => index,
+        None => {
+            entries.push((first.clone(), Node::Table(Vec::new())));
+            entries.len() - 1
+        }
+    };
+
+    match &mut entries[index].1 {
+        Node::Table(table) => table_at(table, rest, line),
+        Node::Array(items) => match items.last_mut() {
+            Some(Node::Table(table)) => table_at(table, rest, line),
+            _ => Err(format!("Key '{first}' isn't a table on line {line}")),
+        },
+        Node::Value(..) => Err(format!("Key '{first}' isn't a table on line {line}")),
+    }
+}
+
+/// `[[path]]`, starts another table in the array at `path`.
+fn push_table(root: &mut Vec<(String, Node)>, path: &[String], line: usize) -> Result<(), String> {
+    let (last, parent) = path.split_last().unwrap();
+    let parent = table_at(root, parent, line)?;
+
+    match parent.iter_mut().find(|(key, ..)| key == last) {
+        Some((.., Node::Array(items))) => items.push(Node::Table(Vec::new())),
+        Some(..) => return Err(format!("Key '{last}' isn't an array on line {line}")),
+        None => parent.push((last.clone(), Node::Array(vec![Node::Table(Vec::new())]))),
+    }
+
+    Ok(())
+}
+
+fn insert(
+    table: &mut Vec<(String, Node)>,
+    key: &[String],
+    value: Node,
+    line: usize,
+) -> Result<(), String> {
+    let (last, parent) = key.split_last().unwrap();
+    let parent = table_at(table, parent, line)?;
+
+    if parent.iter().any(|(key, ..)| key == last) {
+        return Err(format!("Duplicate key '{}' on line {line}", key.join(".")));
+    }
+
+    parent.push((last.clone(), value));
+    Ok(())
+}
+
+struct Parser {
+    chars: Vec<char>,
+    cursor: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.cursor).copied()
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.cursor += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.advance();
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_spaces();
+        if !self.eat(expected) {
+            return Err(format!("Expected '{expected}' on line {}", self.line));
+        }
+        self.skip_spaces();
+        Ok(())
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.advance();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), None | Some('\n')) {
+                self.advance();
+            }
+        }
+    }
+
+    /// Skips whitespace, new lines and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            if !matches!(self.peek(), Some('\n' | '\r')) {
+                break;
+            }
+            self.advance();
+        }
+    }
+
+    fn end_of_line(&mut self) -> Result<(), String> {
+        self.skip_spaces();
+        self.skip_comment();
+        self.eat('\r');
+        match self.peek() {
+            None | Some('\n') => Ok(()),
+            Some(..) => Err(format!("Expected a new line on line {}", self.line)),
+        }
+    }
+
+    /// A key, its dotted parts split up.
+    fn key(&mut self) -> Result<Vec<String>, String> {
+        let mut parts = Vec::new();
+        loop {
+            self.skip_spaces();
+            let part = match self.peek() {
+                Some('"') => self.basic_string()?,
+                Some('\'') => self.literal_string()?,
+                _ => {
+                    let start = self.cursor;
+                    while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                    {
+                        self.advance();
+                    }
+                    if start == self.cursor {
+                        return Err(format!("Expected a key on line {}", self.line));
+                    }
+                    self.chars[start..self.cursor].iter().collect()
+                }
+            };
+            parts.push(part);
+
+            self.skip_spaces();
+            if !self.eat('.') {
+                return Ok(parts);
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Node, String> {
+        let value = match self.peek() {
+            Some('"') => Constant::new_string(&self.basic_string()?),
+            Some('\'') => Constant::new_string(&self.literal_string()?),
+            Some('[') => return self.array(),
+            Some('{') => return self.inline_table(),
+            _ => self.bare_value()?,
+        };
+
+        Ok(Node::Value(value))
+    }
+
+    fn array(&mut self) -> Result<Node, String> {
+        self.advance();
+
+        let mut items = Vec::new();
+        loop {
+            // arrays can span lines and hold comments
+            self.skip_blank();
+            if self.eat(']') {
+                break;
+            }
+
+            items.push(self.value()?);
+
+            self.skip_blank();
+            if !self.eat(',') {
+                self.skip_blank();
+                if !self.eat(']') {
+                    return Err(format!("Expected ']' after array on line {}", self.line));
+                }
+                break;
+            }
+        }
+
+        Ok(Node::Array(items))
+    }
+
+    fn inline_table(&mut self) -> Result<Node, String> {
+        self.advance();
+
+        let mut entries = Vec::new();
+        self.skip_spaces();
+        if self.eat('}') {
+            return Ok(Node::Table(entries));
+        }
+
+        loop {
+            let key = self.key()?;
+            self.expect('=')?;
+            let value = self.value()?;
+            insert(&mut entries, &key, value, self.line)?;
+
+            self.skip_spaces();
+            if self.eat('}') {
+                return Ok(Node::Table(entries));
+            }
+            if !self.eat(',') {
+                return Err(format!(
+                    "Expected '}}' after inline table on line {}",
+                    self.line
+                ));
+            }
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, String> {
+        let line = self.line;
+        self.advance();
+        if self.peek() == Some('"') && self.chars.get(self.cursor + 1) == Some(&'"') {
+            return Err(format!(
+                "Multi-line strings aren't supported on line {line}"
+            ));
+        }
+
+        let mut string = String::new();
+        loop {
+            match self.advance() {
+                Some('"') => return Ok(string),
+                Some('\\') => string.push(self.escape()?),
+                Some('\n') | None => return Err(format!("Unterminated string on line {line}")),
+                Some(c) => string.push(c),
+            }
+        }
+    }
+
+    fn escape(&mut self) -> Result<char, String> {
+        let c = match self.advance() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some(u @ ('u' | 'U')) => {
+                let len = if u == 'u' { 4 } else { 8 };
+                let digits: String = (0..len).filter_map(|_| self.advance()).collect();
+                u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or(format!("Invalid unicode escape on line {}", self.line))?
+            }
+            _ => return Err(format!("Invalid escape on line {}", self.line)),
+        };
+
+        Ok(c)
+    }
+
+    fn literal_string(&mut self) -> Result<String, String> {
+        let line = self.line;
+        self.advance();
+
+        let mut string = String::new();
+        loop {
+            match self.advance() {
+                Some('\'') => return Ok(string),
+                Some('\n') | None => return Err(format!("Unterminated string on line {line}")),
+                Some(c) => string.push(c),
+            }
+        }
+    }
+
+    /// Booleans and numbers.
+    fn bare_value(&mut self) -> Result<Constant, String> {
+        let start = self.cursor;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || "+-._:".contains(c)) {
+            self.advance();
+        }
+        let word: String = self.chars[start..self.cursor].iter().collect();
+
+        // an underscore separates two digits, '1__0' and '_1' aren't numbers
+        let chars = &self.chars[start..self.cursor];
+        let digit = |at: Option<usize>| {
+            at.and_then(|at| chars.get(at))
+                .is_some_and(char::is_ascii_hexdigit)
+        };
+        let separated = (0..chars.len())
+            .filter(|&at| chars[at] == '_')
+            .all(|at| digit(at.checked_sub(1)) && digit(Some(at + 1)));
+        if !separated {
+            return Err(format!("Invalid value '{word}' on line {}", self.line));
+        }
+
+        let number =
+            |digits: &str, radix| i64::from_str_radix(digits, radix).ok().map(|n| n as f64);
+        let clean = word.replace('_', "");
+        let value = match word.as_str() {
+            "true" => Some(Constant::Bool(true)),
+            "false" => Some(Constant::Bool(false)),
+            "inf" | "+inf" => Some(Constant::Number(f64::INFINITY)),
+            "-inf" => Some(Constant::Number(f64::NEG_INFINITY)),
+            "nan" | "+nan" | "-nan" => Some(Constant::Number(f64::NAN)),
+            _ => match clean.get(..2) {
+                Some("0x") => number(&clean[2..], 16),
+                Some("0o") => number(&clean[2..], 8),
+                Some("0b") => number(&clean[2..], 2),
+                _ if clean.starts_with(|c: char| c.is_ascii_digit() || "+-".contains(c)) => {
+                    clean.parse().ok()
+                }
+                _ => None,
+            }
+            .map(Constant::Number),
+        };
+
+        value.ok_or(format!("Invalid value '{word}' on line {}", self.line))
+    }
+}
@@ -0,0 +1,338 @@
+//! The block style YAML configuration files use: mappings, sequences,
+//! quoted and plain scalars, and single line `[a, b]` and `{a: 1}` flow
+//! collections. Anchors, tags, block scalars and multiple documents aren't
+//! supported.
+
+use crate::constant::Constant;
+
+struct Line {
+    indent: usize,
+    text: String,
+    number: usize,
+}
+
+pub fn parse(text: &str) -> Result<Constant, String> {
+    let mut parser = Parser {
+        lines: lines(text)?,
+        cursor: 0,
+    };
+
+    let Some(first) = parser.lines.first() else {
+        return Ok(Constant::Null);
+    };
+
+    let value = parser.block(first.indent)?;
+    match parser.lines.get(parser.cursor) {
+        Some(line) => Err(format!("Unexpected indentation on line {}", line.number)),
+        None => Ok(value),
+    }
+}
+
+/// The lines that hold something, comments stripped.
+fn lines(text: &str) -> Result<Vec<Line>, String> {
+    let mut lines = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let content = strip_comment(line).trim_end();
+        let text = content.trim_start();
+        if text.is_empty() || text == "---" && lines.is_empty() {
+            continue;
+        }
+        if text == "---" || text == "..." {
+            return Err(format!(
+                "Multiple documents aren't supported on line {number}"
+            ));
+        }
+
+        let indent = &content[..content.len() - text.len()];
+        if indent.contains('\t') {
+            return Err(format!("Tabs can't indent on line {number}"));
+        }
+
+        lines.push(Line {
+            indent: indent.len(),
+            text: text.to_string(),
+            number,
+        });
+    }
+
+    Ok(lines)
+}
+
+/// Whether a quote after `previous` starts a quoted scalar, rather than
+/// being part of a plain one like `it's`.
+fn opens_quote(previous: char) -> bool {
+    previous.is_whitespace() || "[{,:".contains(previous)
+}
+
+/// `line` up to a `#` that starts a comment, one outside quotes that's at
+/// the start or follows a space.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+
+    for (index, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') if opens_quote(previous) => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            (None, '#') if previous.is_whitespace() => return &line[..index],
+            _ => (),
+        }
+        previous = c;
+    }
+
+    line
+}
+
+fn is_item(text: &str) -> bool {
+    text == "-" || text.starts_with("- ")
+}
+
+/// Splits `key: value` at the first `:` outside quotes that ends the line
+/// or is followed by a space.
+fn split_key(text: &str) -> Option<(String, &str)> {
+    if text.starts_with(['[', '{']) {
+        return None;
+    }
+
+    let mut quote = None;
+    let mut previous = ' ';
+    for (index, c) in text.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') if opens_quote(previous) => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            (None, ':') => {
+                let value = &text[index + 1..];
+                let key = text[..index].trim_end();
+                if !key.is_empty() && (value.is_empty() || value.starts_with(' ')) {
+                    return Some((unquote(key), value.trim()));
+                }
+            }
+            _ => (),
+        }
+        previous = c;
+    }
+
+    None
+}
+
+fn unquote(key: &str) -> String {
+    match scalar(key, 0) {
+        Ok(value) if key.starts_with(['"', '\'']) => value.to_string(),
+        _ => key.to_string(),
+    }
+}
+
+struct Parser {
+    lines: Vec<Line>,
+    cursor: usize,
+}
+
+impl Parser {
+    /// The mapping or sequence whose lines start at `indent`.
+    fn block(&mut self, indent: usize) -> Result<Constant, String> {
+        match self.lines.get(self.cursor) {
+            Some(line) if is_item(&line.text) => self.sequence(indent),
+            Some(..) => self.mapping(indent),
+            None => Ok(Constant::Null),
+        }
+    }
+
+    /// The block indented under the line just read, null if there's none.
+    fn nested(&mut self, indent: usize) -> Result<Constant, String> {
+        match self.lines.get(self.cursor) {
+            Some(line) if line.indent > indent => {
+                let indent = line.indent;
+                self.block(indent)
+            }
+            _ => Ok(Constant::Null),
+        }
+    }
+
+    fn sequence(&mut self, indent: usize) -> Result<Constant, String> {
+        let mut items = Vec::new();
+
+        while let Some(line) = self.lines.get_mut(self.cursor) {
+            if line.indent != indent || !is_item(&line.text) {
+                break;
+            }
+
+            let rest = line.text[1..].trim_start().to_string();
+            if rest.is_empty() {
+                self.cursor += 1;
+                items.push(self.nested(indent)?);
+                continue;
+            }
+
+            // '- key: value' starts a mapping at the column after the dash
+            if is_item(&rest) || split_key(&rest).is_some() {
+                let column = indent + line.text.len() - rest.len();
+                line.indent = column;
+                line.text = rest;
+                items.push(self.block(column)?);
+            } else {
+                let number = line.number;
+                self.cursor += 1;
+                items.push(scalar(&rest, number)?);
+            }
+        }
+
+        Ok(Constant::Tuple(items))
+    }
+
+    fn mapping(&mut self, indent: usize) -> Result<Constant, String> {
+        let mut entries: Vec<(String, Constant)> = Vec::new();
+
+        while let Some(line) = self.lines.get(self.cursor) {
+            if line.indent != indent || is_item(&line.text) {
+                break;
+            }
+
+            let number = line.number;
+            unsupported(&line.text, number)?;
+            let (key, value) = split_key(&line.text)
+                .map(|(key, value)| (key, value.to_string()))
+                .ok_or(format!("Expected 'key: value' on line {number}"))?;
+            self.cursor += 1;
+
+            let value = match value.as_str() {
+                "" => match self.lines.get(self.cursor) {
+                    // a sequence can sit at the same indent as its key
+                    Some(next) if next.indent == indent && is_item(&next.text) => {
+                        self.sequence(indent)?
+                    }
+                    _ => self.nested(indent)?,
+                },
+                "|" | ">" | "|-" | ">-" => {
+                    return Err(format!("Block scalars aren't supported on line {number}"))
+                }
+                value => scalar(value, number)?,
+            };
+
+            if entries.iter().any(|(existing, ..)| *existing == key) {
+                return Err(format!("Duplicate key '{key}' on line {number}"));
+            }
+            entries.push((key, value));
+        }
+
+        Ok(super::table(entries))
+    }
+}
+
+fn scalar(text: &str, line: usize) -> Result<Constant, String> {
+    let value = match text {
+        "~" | "null" | "Null" | "NULL" => Constant::Null,
+        "true" | "True" | "TRUE" => Constant::Bool(true),
+        "false" | "False" | "FALSE" => Constant::Bool(false),
+        _ if text.starts_with('"') => Constant::new_string(&double_quoted(text, line)?),
+        _ if text.starts_with('\'') => match text.strip_suffix('\'') {
+            Some(inner) if text.len() > 1 => Constant::new_string(&inner[1..].replace("''", "'")),
+            _ => return Err(format!("Unterminated string on line {line}")),
+        },
+        _ if text.starts_with('[') => flow_sequence(text, line)?,
+        _ if text.starts_with('{') => flow_mapping(text, line)?,
+        _ if text.contains(": ") || text.ends_with(':') => {
+            return Err(format!("Unexpected ':' in a plain value on line {line}"))
+        }
+        _ => {
+            unsupported(text, line)?;
+            match text.parse::<f64>() {
+                Ok(number)
+                    if text.starts_with(|c: char| c.is_ascii_digit() || "+-.".contains(c)) =>
+                {
+                    Constant::Number(number)
+                }
+                _ => Constant::new_string(text),
+            }
+        }
+    };
+
+    Ok(value)
+}
+
+/// Fails for a plain scalar or key that starts with an anchor or alias.
+fn unsupported(text: &str, line: usize) -> Result<(), String> {
+    if text.starts_with(['&', '*']) {
+        return Err(format!(
+            "Anchors and aliases aren't supported on line {line}"
+        ));
+    }
+    Ok(())
+}
+
+fn double_quoted(text: &str, line: usize) -> Result<String, String> {
+    let mut string = String::new();
+    let mut chars = text[1..].chars();
+
+    loop {
+        match chars.next() {
+            Some('"') if chars.as_str().is_empty() => return Ok(string),
+            Some('\\') => string.push(match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some(c @ ('"' | '\\' | '/')) => c,
+                _ => return Err(format!("Invalid escape on line {line}")),
+            }),
+            Some('"') | None => return Err(format!("Unterminated string on line {line}")),
+            Some(c) => string.push(c),
+        }
+    }
+}
+
+/// The comma separated items between the brackets of a flow collection.
+fn flow_items(text: &str, close: char, line: usize) -> Result<Vec<&str>, String> {
+    let inner = text[1..]
+        .strip_suffix(close)
+        .ok_or(format!("Expected '{close}' at the end of line {line}"))?;
+
+    let mut items = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut start = 0;
+    let mut previous = ' ';
+    for (index, c) in inner.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') if opens_quote(previous) => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            (None, '[' | '{') => depth += 1,
+            (None, ']' | '}') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                items.push(inner[start..index].trim());
+                start = index + 1;
+            }
+            _ => (),
+        }
+        previous = c;
+    }
+
+    // '[a, b,]' has no empty last item
+    let last = inner[start..].trim();
+    if !last.is_empty() {
+        items.push(last);
+    }
+    Ok(items)
+}
+
+fn flow_sequence(text: &str, line: usize) -> Result<Constant, String> {
+    let items = flow_items(text, ']', line)?
+        .into_iter()
+        .map(|item| scalar(item, line))
+        .collect::<Result<_, _>>()?;
+
+    Ok(Constant::Tuple(items))
+}
+
+fn flow_mapping(text: &str, line: usize) -> Result<Constant, String> {
+    let mut entries = Vec::new();
+    for item in flow_items(text, '}', line)? {
+        unsupported(item, line)?;
+        let (key, value) =
+            split_key(item).ok_or(format!("Expected 'key: value' on line {line}"))?;
+        entries.push((key, scalar(value, line)?));
+    }
+
+    Ok(super::table(entries))
+}
@@ -0,0 +1,124 @@
+//! Reading and writing CSV text, enabled with the `csv` feature.
+//!
+//! A table is a tuple of rows and a row is a tuple of its fields. Fields are
+//! always parsed as strings, a header row is just the first row.
+
+use crate::{constant::Constant, native::Native, virtual_machine::VirtualMachine};
+
+use super::Module;
+
+pub const MODULE: Module = Module {
+    name: "csv",
+    natives: &[
+        Native::new("csv_parse", 1, csv_parse).with_doc("Parses CSV text into a tuple of rows"),
+        Native::new("csv_write", 1, csv_write).with_doc("Writes a tuple of rows as CSV text"),
+    ],
+    constants: &[],
+};
+
+fn csv_parse(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let text = args[0].as_str().ok_or(format!(
+        "Expected String argument to 'csv_parse' found '{}'",
+        args[0].type_to_string()
+    ))?;
+
+    let rows = parse(text)?
+        .into_iter()
+        .map(|row| {
+            Constant::Tuple(
+                row.iter()
+                    .map(|field| Constant::new_string(field))
+                    .collect(),
+            )
+        })
+        .collect();
+
+    Ok(Constant::Tuple(rows))
+}
+
+fn csv_write(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let Constant::Tuple(rows) = &args[0] else {
+        return Err(format!(
+            "Expected tuple argument to 'csv_write' found '{}'",
+            args[0].type_to_string()
+        ));
+    };
+
+    let mut text = String::new();
+    for row in rows.iter() {
+        let Constant::Tuple(fields) = row else {
+            return Err(format!(
+                "Expected every row passed to 'csv_write' to be a tuple, found '{}'",
+                row.type_to_string()
+            ));
+        };
+
+        let fields: Vec<String> = fields
+            .iter()
+            .map(|field| quote(&field.to_string()))
+            .collect();
+        text.push_str(&fields.join(","));
+        text.push('\n');
+    }
+
+    Ok(Constant::new_string(&text))
+}
+
+/// Splits `text` into rows of fields as RFC 4180 describes, quoted fields
+/// may hold commas, newlines and `""` for a quote.
+fn parse(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut line = 1;
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if field.is_empty() => {
+                let start = line;
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            field.push(c);
+                        }
+                        None => return Err(format!("Unterminated quoted field on line {start}")),
+                    }
+                }
+            }
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => (),
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+                line += 1;
+            }
+            c => field.push(c),
+        }
+    }
+
+    // the last line doesn't need a newline
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+
+    Ok(rows)
+}
+
+/// Quotes a field if it wouldn't read back the same otherwise.
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
@@ -0,0 +1,29 @@
+//! Natives that take or make functions.
+
+use crate::{
+    constant::{Constant, Memoized},
+    native::Native,
+    virtual_machine::VirtualMachine,
+};
+
+use super::Module;
+
+pub const MODULE: Module = Module {
+    name: "func",
+    natives: &[Native::new("memoize", 1, memoize)
+        .with_doc("Wraps a fn so calls with the same arguments are only run once")],
+    constants: &[],
+};
+
+/// The arguments must be values that can be keys, e.g. numbers, strings
+/// and tuples of them. Only use it on functions without side effects.
+fn memoize(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    match &args[0] {
+        Constant::Function(function) => Ok(Constant::Memoized(Memoized::new(function.clone()))),
+        memoized @ Constant::Memoized(..) => Ok(memoized.clone()),
+        value => Err(format!(
+            "Expected fn argument to 'memoize' found '{}'",
+            value.type_to_string()
+        )),
+    }
+}
@@ -0,0 +1,18 @@
+use crate::{
+    constant::Constant,
+    native::Native,
+    virtual_machine::{output_error, VirtualMachine},
+};
+
+use super::Module;
+
+pub const MODULE: Module = Module {
+    name: "io",
+    natives: &[Native::new("flush", 0, flush).with_doc("Writes out everything printed so far")],
+    constants: &[],
+};
+
+fn flush(vm: &mut VirtualMachine, _args: &[Constant]) -> Result<Constant, String> {
+    vm.flush().map_err(output_error)?;
+    Ok(Constant::Null)
+}
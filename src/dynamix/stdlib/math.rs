@@ -0,0 +1,116 @@
+//! Math functions and constants, behind the `stdlib` feature. `clamp`,
+//! `between` and `lerp` are in `ranges`, which is always there.
+
+use crate::{constant::Constant, native::Native, virtual_machine::VirtualMachine};
+
+use super::{number, Module};
+
+pub const MODULE: Module = Module {
+    name: "math",
+    natives: &[
+        Native::new("sqrt", 1, sqrt).with_doc("The square root of x, NaN if x is negative"),
+        Native::new("abs", 1, abs).with_doc("x without its sign"),
+        Native::new("floor", 1, floor).with_doc("The largest integer <= x"),
+        Native::new("ceil", 1, ceil).with_doc("The smallest integer >= x"),
+        Native::new("round", 1, round).with_doc("x to the nearest integer, halves away from 0"),
+        Native::new("sin", 1, sin).with_doc("The sine of an angle"),
+        Native::new("cos", 1, cos).with_doc("The cosine of an angle"),
+        Native::new("tan", 1, tan).with_doc("The tangent of an angle"),
+        Native::new("asin", 1, asin).with_doc("The angle whose sine is x"),
+        Native::new("acos", 1, acos).with_doc("The angle whose cosine is x"),
+        Native::new("atan", 1, atan).with_doc("The angle whose tangent is x"),
+        Native::new("min", 2, min).with_doc("The smaller of a and b"),
+        Native::new("max", 2, max).with_doc("The larger of a and b"),
+        Native::new("pow", 2, pow).with_doc("x raised to the power y, like x ** y"),
+    ],
+    constants: &[("PI", std::f64::consts::PI), ("E", std::f64::consts::E)],
+};
+
+/// Applies `f` to the one number argument of `native`.
+fn unary(native: &str, args: &[Constant], f: fn(f64) -> f64) -> Result<Constant, String> {
+    Ok(Constant::Number(f(number(native, &args[0])?)))
+}
+
+fn binary(native: &str, args: &[Constant], f: fn(f64, f64) -> f64) -> Result<Constant, String> {
+    let (a, b) = (number(native, &args[0])?, number(native, &args[1])?);
+    Ok(Constant::Number(f(a, b)))
+}
+
+fn sqrt(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    unary("sqrt", args, f64::sqrt)
+}
+
+fn abs(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    unary("abs", args, f64::abs)
+}
+
+fn floor(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    unary("floor", args, f64::floor)
+}
+
+fn ceil(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    unary("ceil", args, f64::ceil)
+}
+
+fn round(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    unary("round", args, f64::round)
+}
+
+/// Angles are in the VM's `AngleMode`, radians unless it says otherwise.
+fn trig(
+    vm: &VirtualMachine,
+    native: &str,
+    args: &[Constant],
+    f: fn(f64) -> f64,
+) -> Result<Constant, String> {
+    let radians = vm.angle_mode().to_radians(number(native, &args[0])?);
+    Ok(Constant::Number(f(radians)))
+}
+
+/// The inverse functions return angles in the VM's `AngleMode`.
+fn inverse_trig(
+    vm: &VirtualMachine,
+    native: &str,
+    args: &[Constant],
+    f: fn(f64) -> f64,
+) -> Result<Constant, String> {
+    let radians = f(number(native, &args[0])?);
+    Ok(Constant::Number(vm.angle_mode().from_radians(radians)))
+}
+
+fn sin(vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    trig(vm, "sin", args, f64::sin)
+}
+
+fn cos(vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    trig(vm, "cos", args, f64::cos)
+}
+
+fn tan(vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    trig(vm, "tan", args, f64::tan)
+}
+
+fn asin(vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    inverse_trig(vm, "asin", args, f64::asin)
+}
+
+fn acos(vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    inverse_trig(vm, "acos", args, f64::acos)
+}
+
+fn atan(vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    inverse_trig(vm, "atan", args, f64::atan)
+}
+
+/// NaN only if both arguments are, the other one wins otherwise.
+fn min(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    binary("min", args, f64::min)
+}
+
+fn max(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    binary("max", args, f64::max)
+}
+
+fn pow(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    binary("pow", args, f64::powf)
+}
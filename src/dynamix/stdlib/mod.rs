@@ -0,0 +1,60 @@
+pub mod assert;
+#[cfg(feature = "stdlib")]
+pub mod calc;
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "csv")]
+pub mod csv;
+pub mod func;
+pub mod io;
+#[cfg(feature = "stdlib")]
+pub mod math;
+pub mod random;
+pub mod ranges;
+pub mod string;
+pub mod task;
+pub mod time;
+pub mod types;
+pub mod vm;
+
+use crate::{constant::Constant, native::Native};
+
+/// A named group of natives that can be enabled on a `VirtualMachine`.
+pub struct Module {
+    pub name: &'static str,
+    pub natives: &'static [Native],
+    /// Globals the module defines besides its natives, e.g. `PI`.
+    pub constants: &'static [(&'static str, f64)],
+}
+
+pub const MODULES: &[Module] = &[
+    assert::MODULE,
+    #[cfg(feature = "stdlib")]
+    calc::MODULE,
+    #[cfg(feature = "config")]
+    config::MODULE,
+    #[cfg(feature = "csv")]
+    csv::MODULE,
+    func::MODULE,
+    io::MODULE,
+    #[cfg(feature = "stdlib")]
+    math::MODULE,
+    random::MODULE,
+    ranges::MODULE,
+    task::MODULE,
+    time::MODULE,
+    types::MODULE,
+    vm::MODULE,
+];
+
+pub fn find_module(name: &str) -> Option<&'static Module> {
+    MODULES.iter().find(|module| module.name == name)
+}
+
+/// The number a native was passed, an error naming `native` otherwise.
+pub(crate) fn number(native: &str, value: &Constant) -> Result<f64, String> {
+    value.as_number().copied().ok_or(format!(
+        "Expected number argument to '{native}' found '{}'",
+        value.type_to_string()
+    ))
+}
@@ -0,0 +1,78 @@
+//! Random numbers for games and simulations, not for anything that has to
+//! be unpredictable.
+
+use crate::{constant::Constant, native::Native, virtual_machine::VirtualMachine};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::Module;
+
+pub const MODULE: Module = Module {
+    name: "random",
+    natives: &[
+        Native::new("random", 0, random).with_doc("A random number from 0 up to 1"),
+        Native::new("random_range", 2, random_range)
+            .with_doc("A random whole number from a up to but not including b"),
+    ],
+    constants: &[],
+};
+
+/// The splitmix64 generator, small and good enough for scripts.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn from_time() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or(0);
+        Self::new(nanos)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..1`, from the top 53 bits so every value is exact.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn random(vm: &mut VirtualMachine, _args: &[Constant]) -> Result<Constant, String> {
+    Ok(Constant::Number(vm.rng().next_f64()))
+}
+
+fn random_range(vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let whole = |value: &Constant| match value {
+        Constant::Number(n) if n.fract() == 0.0 => Ok(*n),
+        Constant::Number(n) => Err(format!(
+            "Expected whole number argument to 'random_range' found {n}"
+        )),
+        value => Err(format!(
+            "Expected number argument to 'random_range' found '{}'",
+            value.type_to_string()
+        )),
+    };
+    let (lo, hi) = (whole(&args[0])?, whole(&args[1])?);
+
+    if lo >= hi {
+        return Err(format!(
+            "Empty range {lo}..{hi} in call to 'random_range', a must be less than b"
+        ));
+    }
+
+    let span = (hi - lo) as u64;
+    Ok(Constant::Number(lo + (vm.rng().next_u64() % span) as f64))
+}
@@ -0,0 +1,54 @@
+//! Range checks that come up in most embeddings, e.g. keeping a value on
+//! screen or blending between two.
+
+use crate::{constant::Constant, native::Native, virtual_machine::VirtualMachine};
+
+use super::{number, Module};
+
+pub const MODULE: Module = Module {
+    name: "ranges",
+    natives: &[
+        Native::new("clamp", 3, clamp).with_doc("Limits x to the range lo..hi"),
+        Native::new("between", 3, between).with_doc("Whether lo <= x <= hi"),
+        Native::new("lerp", 3, lerp).with_doc("Interpolates from a to b by t"),
+    ],
+    constants: &[],
+};
+
+/// Reads the `(x, lo, hi)` arguments shared by `clamp` and `between`.
+fn range(native: &str, args: &[Constant]) -> Result<(f64, f64, f64), String> {
+    let (x, lo, hi) = (
+        number(native, &args[0])?,
+        number(native, &args[1])?,
+        number(native, &args[2])?,
+    );
+
+    if lo > hi {
+        return Err(format!(
+            "Lower bound {lo} is greater than upper bound {hi} in call to '{native}'"
+        ));
+    }
+
+    Ok((x, lo, hi))
+}
+
+fn clamp(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let (x, lo, hi) = range("clamp", args)?;
+    Ok(Constant::Number(x.max(lo).min(hi)))
+}
+
+/// Inclusive on both ends.
+fn between(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let (x, lo, hi) = range("between", args)?;
+    Ok(Constant::Bool(lo <= x && x <= hi))
+}
+
+/// `t` isn't clamped, values outside `0..1` extrapolate past `a` and `b`.
+fn lerp(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let (a, b, t) = (
+        number("lerp", &args[0])?,
+        number("lerp", &args[1])?,
+        number("lerp", &args[2])?,
+    );
+    Ok(Constant::Number(a + (b - a) * t))
+}
@@ -0,0 +1,102 @@
+//! Methods callable on string values, e.g. `"abc".upper()`.
+//!
+//! The receiver is passed as the first argument and isn't counted in the
+//! method's arity.
+
+use crate::{constant::Constant, native::Native, virtual_machine::VirtualMachine};
+
+pub const METHODS: &[Native] = &[
+    Native::new("len", 0, len).with_doc("Number of chars in the string"),
+    Native::new("upper", 0, upper).with_doc("The string in upper case"),
+    Native::new("lower", 0, lower).with_doc("The string in lower case"),
+    Native::new("contains", 1, contains).with_doc("Whether the string contains a string or char"),
+    Native::new("split", 1, split).with_doc("Splits the string around a string or char"),
+    Native::new("find", 1, find).with_doc("Char index of the first match, or null"),
+    Native::new("rfind", 1, rfind).with_doc("Char index of the last match, or null"),
+    Native::new("starts_with", 1, starts_with).with_doc("Whether the string starts with a pattern"),
+    Native::new("ends_with", 1, ends_with).with_doc("Whether the string ends with a pattern"),
+    Native::new("count", 1, count).with_doc("Number of non-overlapping matches"),
+];
+
+pub fn find_method(name: &str) -> Option<&'static Native> {
+    METHODS.iter().find(|method| method.name == name)
+}
+
+fn receiver(args: &[Constant]) -> &str {
+    args[0].as_str().unwrap()
+}
+
+/// Accepts either a string or a char as a pattern argument.
+fn pattern(method: &str, value: &Constant) -> Result<String, String> {
+    match value {
+        Constant::Char(c) => Ok(c.to_string()),
+        _ => value.as_str().map(String::from).ok_or(format!(
+            "Expected String or char argument to '{method}' found '{}'",
+            value.type_to_string()
+        )),
+    }
+}
+
+/// Turns a byte index `str` methods return into the char index scripts use.
+fn char_index(string: &str, byte: Option<usize>) -> Constant {
+    match byte {
+        Some(byte) => Constant::Number(string[..byte].chars().count() as f64),
+        None => Constant::Null,
+    }
+}
+
+fn len(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    Ok(Constant::Number(receiver(args).chars().count() as f64))
+}
+
+fn upper(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    Ok(Constant::new_string(&receiver(args).to_uppercase()))
+}
+
+fn lower(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    Ok(Constant::new_string(&receiver(args).to_lowercase()))
+}
+
+fn contains(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let pattern = pattern("contains", &args[1])?;
+    Ok(Constant::Bool(receiver(args).contains(&pattern)))
+}
+
+fn split(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let pattern = pattern("split", &args[1])?;
+    let parts = receiver(args)
+        .split(pattern.as_str())
+        .map(Constant::new_string)
+        .collect();
+
+    Ok(Constant::Tuple(parts))
+}
+
+fn find(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let pattern = pattern("find", &args[1])?;
+    let string = receiver(args);
+    Ok(char_index(string, string.find(&pattern)))
+}
+
+fn rfind(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let pattern = pattern("rfind", &args[1])?;
+    let string = receiver(args);
+    Ok(char_index(string, string.rfind(&pattern)))
+}
+
+fn starts_with(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let pattern = pattern("starts_with", &args[1])?;
+    Ok(Constant::Bool(receiver(args).starts_with(&pattern)))
+}
+
+fn ends_with(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let pattern = pattern("ends_with", &args[1])?;
+    Ok(Constant::Bool(receiver(args).ends_with(&pattern)))
+}
+
+fn count(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let pattern = pattern("count", &args[1])?;
+    Ok(Constant::Number(
+        receiver(args).matches(&pattern).count() as f64
+    ))
+}
@@ -0,0 +1,111 @@
+//! Cooperative tasks. Every task runs on the same VM and thread, the running
+//! task keeps going until it calls `yield` or `sleep`, blocks in `join` or
+//! `recv`, or returns.
+//!
+//! ```text
+//! let worker = spawn(fun () { yield(); return 42; });
+//! print join(worker); // 42
+//! ```
+//!
+//! A program isn't finished until every task it spawned is. Tasks pass
+//! values to each other, and to the host, over channels.
+
+use crate::{
+    channel::Channel, constant::Constant, gc, native::Native, virtual_machine::VirtualMachine,
+};
+
+use super::Module;
+
+use std::time::Duration;
+
+pub const MODULE: Module = Module {
+    name: "task",
+    natives: &[
+        Native::new("spawn", 1, spawn)
+            .with_doc("Starts a task running a function that takes no arguments"),
+        Native::new("join", 1, join).with_doc("Waits for a task to finish and returns its result"),
+        Native::new("yield", 0, yield_now).with_doc("Lets the other tasks run"),
+        Native::new("sleep", 1, sleep).with_doc("Suspends the task for a number of milliseconds"),
+        Native::new("channel", 0, channel)
+            .with_doc("Creates a channel for sending values between tasks"),
+        Native::new("send", 2, send).with_doc("Queues a value on a channel"),
+        Native::new("recv", 1, recv).with_doc("Waits for the next value on a channel"),
+    ],
+    constants: &[],
+};
+
+fn spawn(vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    match &args[0] {
+        Constant::Function(function) => vm.spawn(function.clone()).map(Constant::Task),
+        value => Err(format!(
+            "Expected fn argument to 'spawn' found '{}'",
+            value.type_to_string()
+        )),
+    }
+}
+
+/// Blocks until the task is done and returns what it returned.
+fn join(vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    match &args[0] {
+        Constant::Task(id) => Ok(vm.join(*id)?.unwrap_or(Constant::Null)),
+        value => Err(format!(
+            "Expected task argument to 'join' found '{}'",
+            value.type_to_string()
+        )),
+    }
+}
+
+/// Lets the other tasks run before the caller continues.
+fn yield_now(vm: &mut VirtualMachine, _args: &[Constant]) -> Result<Constant, String> {
+    vm.yield_now();
+    Ok(Constant::Null)
+}
+
+/// Suspends the caller for at least `ms` milliseconds without blocking the
+/// other tasks, or the host when it's driving the VM with `run_for`.
+fn sleep(vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    match &args[0] {
+        Constant::Number(ms) if *ms >= 0.0 => {
+            vm.sleep(Duration::from_secs_f64(ms / 1000.0));
+            Ok(Constant::Null)
+        }
+        Constant::Number(ms) => Err(format!("Can't sleep for a negative duration ({ms}ms)")),
+        value => Err(format!(
+            "Expected number argument to 'sleep' found '{}'",
+            value.type_to_string()
+        )),
+    }
+}
+
+fn channel(_vm: &mut VirtualMachine, _args: &[Constant]) -> Result<Constant, String> {
+    Ok(Constant::Channel(Channel::new()))
+}
+
+fn expect_channel<'a>(native: &str, value: &'a Constant) -> Result<&'a Channel, String> {
+    match value {
+        Constant::Channel(channel) => Ok(channel),
+        value => Err(format!(
+            "Expected channel argument to '{native}' found '{}'",
+            value.type_to_string()
+        )),
+    }
+}
+
+/// Channels are unbounded, so sending never blocks. A channel can't be sent
+/// into itself, even inside a tuple or another channel, the cycle would
+/// never be freed.
+fn send(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let channel = expect_channel("send", &args[0])?;
+    if gc::reaches(&args[1], &args[0]) {
+        return Err("Can't send a channel into itself".to_string());
+    }
+
+    channel.send(args[1].clone());
+    Ok(Constant::Null)
+}
+
+/// Blocks until a value is sent if the channel is empty.
+fn recv(vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let channel = expect_channel("recv", &args[0])?;
+    Ok(vm.recv(channel).unwrap_or(Constant::Null))
+}
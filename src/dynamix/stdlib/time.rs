@@ -0,0 +1,28 @@
+//! Clocks for timing scripts. `sleep` lives in the task module, since it
+//! suspends the running task.
+
+use crate::{constant::Constant, native::Native, virtual_machine::VirtualMachine};
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::Module;
+
+pub const MODULE: Module = Module {
+    name: "time",
+    natives: &[
+        Native::new("clock", 0, clock).with_doc("Seconds since the VM started, for benchmarks"),
+        Native::new("now", 0, now).with_doc("Seconds since the unix epoch"),
+    ],
+    constants: &[],
+};
+
+fn clock(vm: &mut VirtualMachine, _args: &[Constant]) -> Result<Constant, String> {
+    Ok(Constant::Number(vm.uptime().as_secs_f64()))
+}
+
+fn now(_vm: &mut VirtualMachine, _args: &[Constant]) -> Result<Constant, String> {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|err| format!("The system clock is before the unix epoch: {err}"))?;
+    Ok(Constant::Number(elapsed.as_secs_f64()))
+}
@@ -0,0 +1,86 @@
+//! Natives about the types of values and converting between them.
+//!
+//! A conversion raises an error when the value's type can't be converted
+//! at all, and returns null when it can but this value doesn't convert,
+//! e.g. `to_number("abc")`.
+
+use crate::{constant::Constant, native::Native, virtual_machine::VirtualMachine};
+
+use super::Module;
+
+pub const MODULE: Module = Module {
+    name: "types",
+    natives: &[
+        Native::new("typeof", 1, type_of)
+            .with_doc("The name of a value's type, e.g. \"number\" or \"String\""),
+        Native::new("to_string", 1, to_string).with_doc("The value as print shows it"),
+        Native::new("to_number", 1, to_number)
+            .with_doc("Parses a String or char, true is 1 and false 0, null if it isn't a number"),
+        Native::new("to_char", 1, to_char)
+            .with_doc("The char of a one char String or a code point, otherwise null"),
+    ],
+    constants: &[],
+};
+
+/// The names are the ones errors use, see `Constant::type_to_string`.
+fn type_of(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    Ok(Constant::new_string(args[0].type_to_string()))
+}
+
+fn unsupported(native: &str, expected: &str, value: &Constant) -> String {
+    format!(
+        "Expected {expected} argument to '{native}' found '{}'",
+        value.type_to_string()
+    )
+}
+
+fn to_string(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    match &args[0] {
+        string @ Constant::Obj(..) => Ok(string.clone()),
+        value => Ok(Constant::new_string(&value.to_string())),
+    }
+}
+
+/// Number text as scripts write it, `inf` and `NaN` aren't numbers here.
+fn parse_number(text: &str) -> Option<f64> {
+    let text = text.trim();
+    if !text.starts_with(|c: char| c.is_ascii_digit() || "+-.".contains(c)) {
+        return None;
+    }
+    text.parse().ok().filter(|number: &f64| number.is_finite())
+}
+
+fn to_number(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let number = match &args[0] {
+        Constant::Number(x) => Some(*x),
+        Constant::Bool(x) => Some(if *x { 1.0 } else { 0.0 }),
+        Constant::Char(c) => c.to_digit(10).map(f64::from),
+        Constant::Obj(..) => parse_number(args[0].as_str().unwrap_or_default()),
+        value => {
+            return Err(unsupported(
+                "to_number",
+                "String, char, number or bool",
+                value,
+            ))
+        }
+    };
+
+    Ok(number.map_or(Constant::Null, Constant::Number))
+}
+
+fn to_char(_vm: &mut VirtualMachine, args: &[Constant]) -> Result<Constant, String> {
+    let c = match &args[0] {
+        Constant::Char(c) => Some(*c),
+        Constant::Number(x) if x.fract() == 0.0 && *x >= 0.0 && *x <= u32::MAX as f64 => {
+            char::from_u32(*x as u32)
+        }
+        Constant::Number(..) => None,
+        Constant::Obj(..) => {
+            let mut chars = args[0].as_str().unwrap_or_default().chars();
+            chars.next().filter(|_| chars.next().is_none())
+        }
+        value => return Err(unsupported("to_char", "String, char or number", value)),
+    };
+
+    Ok(c.map_or(Constant::Null, Constant::Char))
+}
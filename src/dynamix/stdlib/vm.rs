@@ -0,0 +1,54 @@
+//! Read-only views of the interpreter's internals, e.g. to check that an
+//! optimized version of a script executes fewer instructions.
+
+use crate::{
+    constant::Constant,
+    gc,
+    native::{self, Native},
+    virtual_machine::VirtualMachine,
+};
+
+use super::Module;
+
+pub const MODULE: Module = Module {
+    name: "vm",
+    natives: &[
+        Native::new("stack_depth", 0, stack_depth)
+            .with_doc("Values on the stack, not counting this call"),
+        Native::new("gc_stats", 0, gc_stats)
+            .with_doc("(interned strings, live values, globals) the VM keeps alive"),
+        Native::new("help", 0, help).with_doc("Lists the natives scripts can call"),
+        Native::new("instruction_count", 0, instruction_count)
+            .with_doc("Instructions executed since the script started"),
+    ],
+    constants: &[],
+};
+
+/// Values on the stack, the callee and its arguments aren't counted.
+fn stack_depth(vm: &mut VirtualMachine, _args: &[Constant]) -> Result<Constant, String> {
+    Ok(Constant::Number(vm.stack().len() as f64))
+}
+
+/// Values are reference counted and can't form cycles (see `gc`), so
+/// nothing is left for a collector to find. This reports what's alive:
+/// `(interned strings, live values, globals)`, where live values are every
+/// value reachable from the stacks and globals.
+fn gc_stats(vm: &mut VirtualMachine, _args: &[Constant]) -> Result<Constant, String> {
+    Ok(Constant::Tuple(vec![
+        Constant::Number(vm.interned_strings() as f64),
+        Constant::Number(gc::live_values(vm.roots()) as f64),
+        Constant::Number(vm.globals().len() as f64),
+    ]))
+}
+
+/// Instructions executed since the current program was loaded, including
+/// the call to `instruction_count` itself.
+fn instruction_count(vm: &mut VirtualMachine, _args: &[Constant]) -> Result<Constant, String> {
+    Ok(Constant::Number(vm.instruction_count() as f64))
+}
+
+fn help(vm: &mut VirtualMachine, _args: &[Constant]) -> Result<Constant, String> {
+    let help = native::help(&vm.natives());
+    vm.print_line(&help)?;
+    Ok(Constant::Null)
+}
@@ -0,0 +1,65 @@
+//! `dynamix test`, runs every `test "name" { ... }` block in a script.
+//!
+//! The script's top level runs once to define what the tests share, then
+//! each test starts from the globals it left behind, so a test can't pass
+//! or fail because of what an earlier one assigned.
+
+use crate::{byte_block::ByteBlock, constant::Constant, virtual_machine::VirtualMachine};
+
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestOutcome {
+    pub name: String,
+    /// The runtime error the test failed with, `None` if it passed.
+    pub failure: Option<String>,
+}
+
+impl TestOutcome {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// Runs the tests declared in `block` on `vm`, whose globals are the ones
+/// the block's top level defined.
+pub(crate) fn run(vm: &mut VirtualMachine, block: &ByteBlock) -> Vec<TestOutcome> {
+    let globals = vm.globals().clone();
+
+    block
+        .tests
+        .iter()
+        .map(|test| {
+            vm.set_globals(globals.clone());
+            let function = Constant::Function(Arc::clone(&test.function));
+
+            TestOutcome {
+                name: test.name.clone(),
+                failure: vm.apply(function, &[]).err().map(|err| err.to_string()),
+            }
+        })
+        .collect()
+}
+
+/// Prints one line per test and a summary, the way `cargo test` does.
+pub fn print_report(outcomes: &[TestOutcome]) {
+    println!("running {} tests", outcomes.len());
+
+    for outcome in outcomes {
+        match &outcome.failure {
+            None => println!("test {} ... ok", outcome.name),
+            Some(failure) => {
+                println!("test {} ... FAILED", outcome.name);
+                for line in failure.lines() {
+                    println!("    {line}");
+                }
+            }
+        }
+    }
+
+    let passed = outcomes.iter().filter(|outcome| outcome.passed()).count();
+    let failed = outcomes.len() - passed;
+    let status = if failed == 0 { "ok" } else { "FAILED" };
+    println!();
+    println!("test result: {status}. {passed} passed; {failed} failed");
+}
@@ -0,0 +1,185 @@
+//! Checks bytecode loaded from a `.dynb` file before the VM runs it.
+//!
+//! The compiler only emits well formed blocks, but a file can hold
+//! anything. Every instruction has to decode, use constants and locals that
+//! exist, jump to the start of an instruction, and find the values it pops
+//! on the stack. Where paths meet they must agree on how deep the stack is,
+//! the same way they do in compiled code.
+
+use crate::byte_block::{ByteBlock, OpCode};
+
+/// Fails with what's wrong with the first bad instruction found. `params`
+/// is the arity of the function the block belongs to, `None` for a script.
+pub fn verify(block: &ByteBlock, params: Option<usize>) -> Result<(), String> {
+    let instructions = decode(block)?;
+
+    // the called function sits below its arguments, a script starts empty
+    let start = params.map_or(0, |params| params + 1);
+
+    // the stack depth at every instruction start reached so far
+    let mut depths: Vec<Option<usize>> = vec![None; block.bytes.len() + 1];
+    depths[0] = Some(start);
+    let mut pending = vec![0];
+
+    while let Some(offset) = pending.pop() {
+        if offset == block.bytes.len() {
+            continue;
+        }
+
+        // only offsets checked to start an instruction are pending
+        let (Some(depth), Some(instruction)) = (depths[offset], &instructions[offset]) else {
+            continue;
+        };
+
+        let (pops, pushes) = instruction.effect;
+        if depth < pops {
+            return Err(error(block, offset, "Stack underflow"));
+        }
+        if let Some(slot) = instruction.slot {
+            if slot >= depth {
+                let message = format!("Local slot {slot} is past the top of the stack");
+                return Err(error(block, offset, &message));
+            }
+        }
+
+        let depth = depth - pops + pushes;
+        for &next in &instruction.next {
+            if next > block.bytes.len()
+                || (next < block.bytes.len() && instructions[next].is_none())
+            {
+                let message = format!("Jump to offset {next} is not an instruction");
+                return Err(error(block, offset, &message));
+            }
+
+            match depths[next] {
+                None => {
+                    depths[next] = Some(depth);
+                    pending.push(next);
+                }
+                Some(known) if known != depth => {
+                    let message = format!(
+                        "Stack depth {depth} doesn't match the depth {known} at offset {next}"
+                    );
+                    return Err(error(block, offset, &message));
+                }
+                Some(..) => (),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+struct Instruction {
+    // values popped and pushed
+    effect: (usize, usize),
+    // the local it reads or writes
+    slot: Option<usize>,
+    // where it can continue, at most the next instruction and a jump target
+    next: Vec<usize>,
+}
+
+/// Every instruction keyed by the offset it starts at.
+fn decode(block: &ByteBlock) -> Result<Vec<Option<Instruction>>, String> {
+    let mut instructions: Vec<Option<Instruction>> = Vec::new();
+    instructions.resize_with(block.bytes.len(), || None);
+
+    let mut offset = 0;
+    while offset < block.bytes.len() {
+        let byte = block.bytes[offset];
+        let opcode = OpCode::from(byte)
+            .map_err(|_| error(block, offset, &format!("Unknown opcode {byte}")))?;
+
+        let end = offset + opcode.width();
+        if end > block.bytes.len() {
+            return Err(error(block, offset, "Operands cut off by the end"));
+        }
+
+        let byte = |at: usize| block.bytes[offset + at] as usize;
+        let constant = match opcode {
+            OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::Constant
+            | OpCode::Char
+            | OpCode::Invoke => Some(byte(1)),
+            OpCode::ConstantLong
+            | OpCode::DefineGlobalLong
+            | OpCode::GetGlobalLong
+            | OpCode::SetGlobalLong
+            | OpCode::InvokeLong => block.read_short(offset + 1).map(usize::from),
+            _ => None,
+        };
+        if let Some(index) = constant {
+            if index >= block.constants.len() {
+                let message = format!("Constant index {index} is out of range");
+                return Err(error(block, offset, &message));
+            }
+        }
+
+        let effect = match opcode {
+            OpCode::Print | OpCode::Pop | OpCode::DefineGlobal | OpCode::DefineGlobalLong => (1, 0),
+            OpCode::GetGlobal
+            | OpCode::GetGlobalLong
+            | OpCode::GetLocal
+            | OpCode::Constant
+            | OpCode::ConstantLong
+            | OpCode::True
+            | OpCode::False
+            | OpCode::Char
+            | OpCode::Null => (0, 1),
+            OpCode::SetGlobal
+            | OpCode::SetGlobalLong
+            | OpCode::SetLocal
+            | OpCode::Jz
+            | OpCode::JzLong
+            | OpCode::Negate
+            | OpCode::Not => (1, 1),
+            OpCode::Jmp | OpCode::JmpLong | OpCode::Loop | OpCode::LoopLong => (0, 0),
+            OpCode::Tuple => (byte(1), 1),
+            OpCode::Unpack => (1, byte(1)),
+            OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Add
+            | OpCode::Sub
+            | OpCode::Mul
+            | OpCode::Div
+            | OpCode::Pow => (2, 1),
+            OpCode::Call => (byte(1) + 1, 1),
+            OpCode::Invoke => (byte(2) + 1, 1),
+            OpCode::InvokeLong => (byte(3) + 1, 1),
+            // a script returns from an empty stack, the result is null
+            OpCode::Return => (0, 0),
+            OpCode::Assert => (2, 0),
+        };
+
+        let slot = match opcode {
+            OpCode::GetLocal | OpCode::SetLocal => Some(byte(1)),
+            _ => None,
+        };
+
+        let mut next = Vec::with_capacity(2);
+        if !matches!(
+            opcode,
+            OpCode::Jmp | OpCode::JmpLong | OpCode::Loop | OpCode::LoopLong | OpCode::Return
+        ) {
+            next.push(end);
+        }
+        if opcode.jump_direction().is_some() {
+            let target = block
+                .jump_target(offset)
+                .ok_or_else(|| error(block, offset, "Jump before the start of the block"))?;
+            next.push(target);
+        }
+
+        instructions[offset] = Some(Instruction { effect, slot, next });
+        offset = end;
+    }
+
+    Ok(instructions)
+}
+
+fn error(block: &ByteBlock, offset: usize, message: &str) -> String {
+    format!("{message} at offset {offset} of '{}'", block.name())
+}
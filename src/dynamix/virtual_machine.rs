@@ -1,20 +1,166 @@
 use crate::{
     byte_block::{ByteBlock, OpCode},
-    constant::{Constant, Object, ObjectType},
+    channel::Channel,
+    constant::{Constant, Function, HashKey, Interner, Memoized, Object, ObjectType},
     disassembler::Disassembler,
+    error::{DynamixError, TraceFrame},
+    gc,
+    native::Native,
+    profile::Profile,
     stack::Stack,
+    stdlib::{self, random::Rng, string, Module},
 };
 
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::BTreeMap,
+    io::{self, stderr, stdout, BufWriter, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering as AtomicOrdering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// The name held by the constant operand of a global or method
+/// instruction, only copied if a malformed block used something other than
+/// a string.
+fn name_of(name: &Constant) -> Cow<'_, str> {
+    match name.as_str() {
+        Some(name) => Cow::Borrowed(name),
+        None => Cow::Owned(name.to_string()),
+    }
+}
 
-fn type_mismatch(vm: &mut VirtualMachine, op_char: char, lhs_type: &str, rhs_type: &str) {
+fn type_mismatch(vm: &mut VirtualMachine, op: &str, lhs_type: &str, rhs_type: &str) {
     vm.runtime_error(format!(
-        "Type mismatch, operator '{op_char}' not supported for types '{lhs_type}' and '{rhs_type}'",
+        "Type mismatch, operator '{op}' not supported for types '{lhs_type}' and '{rhs_type}'",
     ))
 }
 
+/// Applies `op` to the code point of `x` and a char or a whole number `rhs`,
+/// e.g. `'a' + 1` is `'b'`. It's an error if the result isn't a char.
+fn char_arithmetic(op: &str, x: char, rhs: &Constant) -> Result<char, String> {
+    let y = match *rhs {
+        Constant::Char(y) => Some(y as u32),
+        Constant::Number(y) if y.fract() == 0.0 && (0.0..=u32::MAX as f64).contains(&y) => {
+            Some(y as u32)
+        }
+        _ => None,
+    };
+
+    let code = y.and_then(|y| match op {
+        "+" => (x as u32).checked_add(y),
+        "-" => (x as u32).checked_sub(y),
+        "*" => (x as u32).checked_mul(y),
+        "/" => (x as u32).checked_div(y),
+        _ => None,
+    });
+
+    code.and_then(char::from_u32)
+        .ok_or_else(|| format!("Result of '{x}' {op} {} isn't a valid char", quoted(rhs)))
+}
+
+/// Orders two values for '<' and '>', `None` if either is NaN.
+fn compare(lhs: &Constant, rhs: &Constant) -> Result<Option<Ordering>, String> {
+    match (lhs, rhs) {
+        (Constant::Number(x), Constant::Number(y)) => Ok(x.partial_cmp(y)),
+        (Constant::Char(x), Constant::Char(y)) => Ok(Some(x.cmp(y))),
+        // strings compare lexicographically by their bytes
+        (Constant::Obj(x), Constant::Obj(y)) => Ok(Some(x.bytes.cmp(&y.bytes))),
+        _ => Err(format!(
+            "Type mismatch, cannot compare types '{}' and '{}'",
+            lhs.type_to_string(),
+            rhs.type_to_string()
+        )),
+    }
+}
+
+/// How many characters of a value the stack trace shows.
+const TRACE_VALUE_WIDTH: usize = 12;
+
+/// Shortens `text` to `width` characters, marking the cut with '…'.
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+
+    let mut short: String = text.chars().take(width - 1).collect();
+    short.push('…');
+    short
+}
+
+/// Formats `value` the way it would be written in source, for diagnostics.
+pub(crate) fn quoted(value: &Constant) -> String {
+    match value {
+        Constant::Char(c) => format!("'{c}'"),
+        Constant::Obj(..) => format!("\"{value}\""),
+        _ => value.to_string(),
+    }
+}
+
+const TRUNCATED: &str = "Truncated instruction operand";
+
+/// Reads an operand, bailing out of `step` with a runtime error if the
+/// instruction was cut short.
+macro_rules! operand {
+    ($self:expr, $read:ident) => {
+        match $self.$read() {
+            Some(operand) => operand,
+            None => {
+                $self.runtime_error(TRUNCATED.to_string());
+                return Some(InterpretResult::RuntimeError);
+            }
+        }
+    };
+}
+
+/// Reads a constant operand, bailing out of `step` with a runtime error if
+/// the instruction was cut short or the index is outside the pool.
+macro_rules! constant {
+    ($self:expr, $read:ident $(, $arg:expr)?) => {
+        match $self.$read($($arg)?) {
+            Ok(constant) => constant,
+            Err(err) => {
+                $self.runtime_error(err);
+                return Some(InterpretResult::RuntimeError);
+            }
+        }
+    };
+}
+
+const UNDERFLOW: &str = "Stack underflow";
+
+/// Pops a value, bailing out of `step` with a runtime error if the stack is
+/// empty, which only bytecode the compiler didn't emit can cause.
+macro_rules! pop {
+    ($self:expr) => {
+        match $self.stack.pop() {
+            Some(value) => value,
+            None => {
+                $self.runtime_error(UNDERFLOW.to_string());
+                return Some(InterpretResult::RuntimeError);
+            }
+        }
+    };
+}
+
+/// Borrows the top of the stack, like `pop!` without taking it off.
+macro_rules! peek {
+    ($self:expr) => {
+        match $self.stack.peek(0) {
+            Some(value) => value,
+            None => {
+                $self.runtime_error(UNDERFLOW.to_string());
+                return Some(InterpretResult::RuntimeError);
+            }
+        }
+    };
+}
+
 macro_rules! binary_op {
-    ($self:expr, $op:tt, $op_char:expr, $result:expr) => {
+    ($self:expr, $op:tt, $op_char:expr) => {
         if let Some(rhs) = $self.stack.pop() {
             if let Some(lhs) = $self.stack.pop() {
                 if let Constant::Number(x) = lhs {
@@ -22,53 +168,54 @@ macro_rules! binary_op {
                         $self.stack.push(Constant::Number(x $op y));
                     } else {
                         type_mismatch($self, $op_char, lhs.type_to_string(), rhs.type_to_string());
-                        $result = InterpretResult::RuntimeError;
-                        break;
+                        return Some(InterpretResult::RuntimeError);
                     }
                 } else if let Constant::Char(x) = lhs {
-                    if let Constant::Char(y) = rhs {
-                        $self.stack.push(Constant::Char((x as u8 $op y as u8) as char));
-                    } else if let Constant::Number(y) = rhs {
-                        $self.stack.push(Constant::Char((x as u8 $op y as u8) as char));
+                    if let Constant::Char(..) | Constant::Number(..) = rhs {
+                        match char_arithmetic($op_char, x, &rhs) {
+                            Ok(c) => $self.stack.push(Constant::Char(c)),
+                            Err(err) => {
+                                $self.runtime_error(err);
+                                return Some(InterpretResult::RuntimeError);
+                            }
+                        }
                     } else {
                         type_mismatch($self, $op_char, lhs.type_to_string(), rhs.type_to_string());
-                        $result = InterpretResult::RuntimeError;
-                        break;
+                        return Some(InterpretResult::RuntimeError);
                     }
                 } else if let Constant::Obj(x) = lhs.clone() {
                     match x.typ3 {
                         ObjectType::String => {
-                            let mut string = x.bytes.clone();
+                            let mut string = x.bytes.to_vec();
 
                             if let Constant::Obj(y) = rhs.clone() {
                                 if lhs.type_to_string() != rhs.type_to_string() {
                                     type_mismatch($self, $op_char, lhs.type_to_string(), rhs.type_to_string());
-                                    $result = InterpretResult::RuntimeError;
-                                    break;
+                                    return Some(InterpretResult::RuntimeError);
                                 }
 
-                                string.append(&mut y.bytes.clone());
-                            } else if let Constant::Number(x) = rhs {
-                                let mut value: Vec<u8> = x.to_string().bytes().collect();
-                                string.append(&mut value);
+                                string.extend_from_slice(&y.bytes);
+                            } else if let Constant::Number(..) = rhs {
+                                // formatted the way 'print' shows it
+                                string.extend_from_slice(rhs.to_string().as_bytes());
                             } else if let Constant::Char(x) = rhs {
-                                string.push(x as u8);
+                                string.extend_from_slice(x.encode_utf8(&mut [0; 4]).as_bytes());
                             } else {
                                 type_mismatch($self, $op_char, lhs.type_to_string(), rhs.type_to_string());
-                                $result = InterpretResult::RuntimeError;
-                                break;
+                                return Some(InterpretResult::RuntimeError);
                             }
 
+                            // only literals are interned, interning every
+                            // string built at runtime would keep them all alive
                             $self.stack.push(Constant::Obj(Object {
                                 typ3: ObjectType::String,
-                                bytes: string,
+                                bytes: Arc::from(string),
                             }))
                         }
                     }
                 } else {
                     type_mismatch($self, $op_char, lhs.type_to_string(), rhs.type_to_string());
-                    $result = InterpretResult::RuntimeError;
-                    break;
+                    return Some(InterpretResult::RuntimeError);
                 }
             }
         }
@@ -77,288 +224,1766 @@ macro_rules! binary_op {
 
 const STACK_STARTING_CAP: usize = 256;
 
-#[derive(Debug, Clone, Copy)]
+/// How deep calls can nest before it's a stack overflow.
+pub const DEFAULT_MAX_FRAMES: usize = 1024;
+
+/// How many values the stack can hold before a call is a stack overflow,
+/// enough for every frame to use all its locals.
+pub const DEFAULT_MAX_STACK: usize = DEFAULT_MAX_FRAMES * 256;
+
+// the longest cycle of calls a stack overflow error looks for
+const MAX_OVERFLOW_CYCLE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InterpretResult {
     Ok,
     CompileError,
     RuntimeError,
+    /// The fuel set with `set_fuel` ran out, the instruction it stopped at
+    /// hasn't run.
+    OutOfFuel,
+    /// The host cancelled the run through a `CancellationToken`.
+    Interrupted,
+    /// The run took longer than the timeout set with `set_timeout`.
+    TimedOut,
+}
+
+/// The caller of the running function, restored when it returns.
+struct CallFrame {
+    block: Arc<ByteBlock>,
+    ip: usize,
+    base: usize,
+    // where the result goes if the function was called through 'memoize'
+    memo: Option<(Memoized, HashKey)>,
+}
+
+/// Everything a task needs to pick up where it left off.
+struct Context {
+    block: Arc<ByteBlock>,
+    ip: usize,
+    base: usize,
+    stack: Stack<Constant>,
+    frames: Vec<CallFrame>,
+}
+
+enum TaskState {
+    Ready,
+    /// Blocked until the task with this id is done.
+    Joining(usize),
+    /// Blocked until a value is sent on the channel.
+    Receiving(Channel),
+    Sleeping(Instant),
+    Done(Constant),
+}
+
+/// The main program is task 0, the rest were started with `spawn`.
+struct Task {
+    state: TaskState,
+    // saved while another task runs
+    context: Option<Context>,
+}
+
+/// Why the running task asked to be switched out.
+pub(crate) enum Suspend {
+    Yield,
+    Join(usize),
+    Recv(Channel),
+    Sleep(Instant),
+}
+
+/// Turns instruction tracing on and off while a script runs, from another
+/// thread or a signal handler. Every clone switches the same VM.
+#[derive(Debug, Clone, Default)]
+pub struct TraceSwitch(Arc<AtomicBool>);
+
+impl TraceSwitch {
+    pub fn set(&self, on: bool) {
+        self.0.store(on, AtomicOrdering::Relaxed);
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.0.load(AtomicOrdering::Relaxed)
+    }
+}
+
+/// Stops a running script from another thread, e.g. when a UI's stop
+/// button is pressed. Every clone cancels the same VM.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Makes the VM stop with `InterpretResult::Interrupted` within the
+    /// next `CANCEL_POLL_INTERVAL` instructions. If nothing is running the
+    /// next run stops instead.
+    pub fn cancel(&self) {
+        self.0.store(true, AtomicOrdering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Whether a cancel is pending, clearing it so the VM can run again.
+    fn take(&self) -> bool {
+        self.0.swap(false, AtomicOrdering::Relaxed)
+    }
+}
+
+/// How many instructions run between checks of the `CancellationToken`
+/// and the timeout.
+pub const CANCEL_POLL_INTERVAL: u64 = 1024;
+
+// the longest a sleeping script goes without checking for a cancel
+const IDLE_POLL: Duration = Duration::from_millis(10);
+
+/// The unit the trig natives take and return angles in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AngleMode {
+    #[default]
+    Radians,
+    Degrees,
+}
+
+impl AngleMode {
+    pub fn to_radians(self, angle: f64) -> f64 {
+        match self {
+            AngleMode::Radians => angle,
+            AngleMode::Degrees => angle.to_radians(),
+        }
+    }
+
+    pub fn from_radians(self, radians: f64) -> f64 {
+        match self {
+            AngleMode::Radians => radians,
+            AngleMode::Degrees => radians.to_degrees(),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AngleMode::Radians => "radians",
+            AngleMode::Degrees => "degrees",
+        }
+    }
 }
 
 pub struct VirtualMachine {
-    block: ByteBlock,
-    ip: *const u8,
-    origin: *const u8,
+    block: Arc<ByteBlock>,
+    // index of the next byte to read in 'block.bytes'
+    ip: usize,
+    // stack slot of the running function, its locals are relative to it
+    base: usize,
     stack: Stack<Constant>,
-    globals: HashMap<String, Constant>,
+    frames: Vec<CallFrame>,
+    tasks: Vec<Task>,
+    current: usize,
+    suspend: Option<Suspend>,
+    // set while every unfinished task is asleep, until the first one wakes
+    idle_until: Option<Instant>,
+    // ordered so anything listing the globals prints them the same way every run
+    globals: BTreeMap<String, Constant>,
+    strings: Interner,
+    checked_division: bool,
+    max_frames: usize,
+    max_stack: usize,
+    // instructions executed since the last 'load'
+    instructions: u64,
+    // instructions left to execute, `None` is unlimited
+    fuel: Option<u64>,
+    // how long a run may take, and when the current one has to stop
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    // samples of the call stack, only taken when profiling
+    profile: Option<Profile>,
+    // when the VM was built, what 'clock' counts from
+    started: Instant,
+    rng: Rng,
+    angle_mode: AngleMode,
+    // checked before every instruction, so tracing can start mid-run
+    trace: TraceSwitch,
+    cancel: CancellationToken,
+    // whether the last instruction was traced, a trace that starts prints
+    // its header
+    traced: bool,
     last_runtime_error: String,
+    last_runtime_line: u32,
+    last_backtrace: Vec<TraceFrame>,
+    out: BufWriter<Box<dyn Write + Send>>,
+    // where traced instructions go, kept apart from what scripts print
+    trace_out: Box<dyn Write + Send>,
+}
+
+/// Configures which natives a `VirtualMachine` starts with.
+///
+/// By default every stdlib module is registered, `with_stdlib(false)` starts
+/// from an empty environment where only modules passed to `enable_module`
+/// are available, which is useful for sandboxing untrusted scripts.
+pub struct VirtualMachineBuilder {
+    stdlib: bool,
+    modules: Vec<&'static Module>,
+    checked_division: bool,
+    stack_capacity: usize,
+    max_frames: usize,
+    max_stack: usize,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    profiling: bool,
+    tracing: bool,
+    seed: Option<u64>,
+    angle_mode: AngleMode,
+    output: Option<Box<dyn Write + Send>>,
+    trace_output: Option<Box<dyn Write + Send>>,
+}
+
+impl VirtualMachineBuilder {
+    pub fn new() -> Self {
+        Self {
+            stdlib: true,
+            modules: Vec::new(),
+            checked_division: true,
+            stack_capacity: STACK_STARTING_CAP,
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_stack: DEFAULT_MAX_STACK,
+            fuel: None,
+            timeout: None,
+            profiling: false,
+            // the feature only decides how a VM starts, `trace_switch` still
+            // turns it off
+            tracing: cfg!(debug_assertions) && cfg!(feature = "stack-trace"),
+            seed: None,
+            angle_mode: AngleMode::Radians,
+            output: None,
+            trace_output: None,
+        }
+    }
+
+    pub fn with_stdlib(mut self, enabled: bool) -> Self {
+        self.stdlib = enabled;
+        self
+    }
+
+    /// When enabled (the default) dividing a number by zero is a runtime
+    /// error, otherwise it evaluates to `inf` or `NaN`.
+    pub fn with_checked_division(mut self, enabled: bool) -> Self {
+        self.checked_division = enabled;
+        self
+    }
+
+    /// How many values the stack has room for before it needs to grow.
+    pub fn with_stack_capacity(mut self, capacity: usize) -> Self {
+        self.stack_capacity = capacity;
+        self
+    }
+
+    /// How many calls can be running at once before a call is a stack
+    /// overflow error, `DEFAULT_MAX_FRAMES` unless set.
+    pub fn with_max_frames(mut self, max_frames: usize) -> Self {
+        self.max_frames = max_frames;
+        self
+    }
+
+    /// How many values the stack can hold before a call is a stack overflow
+    /// error, `DEFAULT_MAX_STACK` unless set. It's checked when a function
+    /// is called, so the call that crosses it fails, not the push.
+    pub fn with_max_stack(mut self, max_stack: usize) -> Self {
+        self.max_stack = max_stack;
+        self
+    }
+
+    /// Starts the VM with `fuel`, see `VirtualMachine::set_fuel`.
+    pub fn with_fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    /// Limits how long a run may take, see `VirtualMachine::set_timeout`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Samples the call stack of every instruction executed, see
+    /// `VirtualMachine::profile`. Off by default, it slows the VM down.
+    pub fn with_profiling(mut self, enabled: bool) -> Self {
+        self.profiling = enabled;
+        self
+    }
+
+    /// Writes every instruction executed and the stack it runs on to the
+    /// trace output, see `VirtualMachine::trace_switch` to toggle it mid-run.
+    /// Starts on in debug builds with the `stack-trace` feature.
+    pub fn with_tracing(mut self, enabled: bool) -> Self {
+        self.tracing = enabled;
+        self
+    }
+
+    /// Makes `random` and `random_range` return the same numbers every run,
+    /// they're seeded from the time otherwise.
+    pub fn with_random_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Whether `sin`, `cos` and the other trig natives work in radians (the
+    /// default) or degrees. Scripts can switch with `angle_mode`.
+    pub fn with_angle_mode(mut self, mode: AngleMode) -> Self {
+        self.angle_mode = mode;
+        self
+    }
+
+    /// Where `print` writes, stdout unless set. Output is buffered until the
+    /// run finishes or the script calls `flush`.
+    pub fn with_output(mut self, output: impl Write + Send + 'static) -> Self {
+        self.output = Some(Box::new(output));
+        self
+    }
+
+    /// Where tracing writes, stderr unless set. It's never mixed into the
+    /// `print` output, pass the same writer to both to interleave them.
+    pub fn with_trace_output(mut self, output: impl Write + Send + 'static) -> Self {
+        self.trace_output = Some(Box::new(output));
+        self
+    }
+
+    /// Enables the module of `stdlib::MODULES` called `name`, fails with
+    /// `DynamixError::UnknownModule` if there isn't one.
+    pub fn enable_module(self, name: &str) -> Result<Self, DynamixError> {
+        match stdlib::find_module(name) {
+            Some(module) => Ok(self.with_module(module)),
+            None => Err(DynamixError::UnknownModule(name.to_string())),
+        }
+    }
+
+    /// Enables `module`, e.g. `&stdlib::io::MODULE`.
+    pub fn with_module(mut self, module: &'static Module) -> Self {
+        self.modules.push(module);
+        self
+    }
+
+    pub fn build(self) -> VirtualMachine {
+        let mut vm = VirtualMachine::empty();
+        vm.checked_division = self.checked_division;
+        vm.stack = Stack::new(self.stack_capacity);
+        vm.max_frames = self.max_frames;
+        vm.max_stack = self.max_stack;
+        vm.fuel = self.fuel;
+        vm.timeout = self.timeout;
+        vm.profile = self.profiling.then(Profile::new);
+        vm.trace.set(self.tracing);
+        if let Some(seed) = self.seed {
+            vm.rng = Rng::new(seed);
+        }
+        vm.angle_mode = self.angle_mode;
+        if let Some(output) = self.output {
+            vm.out = BufWriter::new(output);
+        }
+        if let Some(output) = self.trace_output {
+            vm.trace_out = output;
+        }
+
+        if self.stdlib {
+            stdlib::MODULES
+                .iter()
+                .for_each(|module| vm.register_module(module));
+        }
+
+        self.modules
+            .into_iter()
+            .for_each(|module| vm.register_module(module));
+        vm
+    }
+}
+
+impl Default for VirtualMachineBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VirtualMachine {
     pub fn new() -> Self {
+        VirtualMachineBuilder::new().build()
+    }
+
+    pub fn builder() -> VirtualMachineBuilder {
+        VirtualMachineBuilder::new()
+    }
+
+    fn empty() -> Self {
         Self {
-            block: ByteBlock::new(),
-            ip: std::ptr::null::<u8>(),
-            origin: std::ptr::null::<u8>(),
+            block: Arc::new(ByteBlock::new()),
+            ip: 0,
+            base: 0,
             stack: Stack::new(STACK_STARTING_CAP),
-            globals: HashMap::new(),
+            frames: Vec::new(),
+            tasks: Vec::new(),
+            current: 0,
+            suspend: None,
+            idle_until: None,
+            globals: BTreeMap::new(),
+            strings: Interner::new(),
+            checked_division: true,
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_stack: DEFAULT_MAX_STACK,
+            instructions: 0,
+            fuel: None,
+            timeout: None,
+            deadline: None,
+            profile: None,
+            started: Instant::now(),
+            rng: Rng::from_time(),
+            angle_mode: AngleMode::Radians,
+            trace: TraceSwitch::default(),
+            cancel: CancellationToken::default(),
+            traced: false,
             last_runtime_error: String::new(),
+            last_runtime_line: 0,
+            last_backtrace: Vec::new(),
+            out: BufWriter::new(Box::new(stdout())),
+            trace_out: Box::new(stderr()),
         }
     }
 
+    pub fn register_module(&mut self, module: &Module) {
+        for native in module.natives {
+            self.register_native(Native {
+                module: Some(module.name),
+                ..*native
+            });
+        }
+
+        for (name, value) in module.constants {
+            self.globals
+                .insert(name.to_string(), Constant::Number(*value));
+        }
+    }
+
+    /// Every native a script can call, sorted by name.
+    pub fn natives(&self) -> Vec<Native> {
+        self.globals
+            .values()
+            .filter_map(|global| match global {
+                Constant::Native(native) => Some(**native),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn register_native(&mut self, native: Native) {
+        self.globals
+            .insert(native.name.to_string(), Constant::Native(Arc::new(native)));
+    }
+
+    /// Defines or overwrites the global `name`, e.g. to hand configuration
+    /// to a script before it runs.
+    pub fn set_global(&mut self, name: &str, value: Constant) {
+        self.globals.insert(name.to_string(), value);
+    }
+
+    /// The current value of the global `name`, e.g. a result left behind by
+    /// a script that finished running.
+    pub fn get_global(&self, name: &str) -> Option<Constant> {
+        self.globals.get(name).cloned()
+    }
+
     pub fn last_runtime_error(&self) -> String {
         self.last_runtime_error.clone()
     }
 
-    pub fn interpret(&mut self, block: &ByteBlock) -> InterpretResult {
-        self.block = block.clone();
-        self.origin = self.block.bytes.as_ptr();
-        self.ip = self.origin as *mut u8;
+    /// Source line of the instruction that raised the last runtime error.
+    pub fn last_runtime_line(&self) -> u32 {
+        self.last_runtime_line
+    }
 
-        self.run()
+    /// The calls that were running when the last runtime error was raised,
+    /// innermost first.
+    pub fn last_backtrace(&self) -> &[TraceFrame] {
+        &self.last_backtrace
     }
 
-    fn advance_ip(&mut self) -> u8 {
-        let byte: u8;
-        unsafe {
-            byte = *self.ip;
-            self.ip = self.ip.add(1);
-            byte
+    /// The value the finished program left behind, `null` if there is none.
+    pub(crate) fn take_result(&mut self) -> Constant {
+        match self.tasks.first_mut().map(|main| &mut main.state) {
+            Some(TaskState::Done(value)) => std::mem::replace(value, Constant::Null),
+            _ => Constant::Null,
         }
     }
 
-    fn descend_ip(&mut self) -> u8 {
-        let byte: u8;
-        unsafe {
-            byte = *self.ip;
-            self.ip = self.ip.sub(1);
-            byte
+    /// Loads `block` to be run in slices with `run_for`.
+    pub fn start(&mut self, block: impl Into<Arc<ByteBlock>>) {
+        self.load(block);
+    }
+
+    /// Runs the loaded program for up to `budget`, returning early when
+    /// every task is asleep so a host loop never blocks on a script. `None`
+    /// means the program isn't finished yet.
+    pub fn run_for(&mut self, budget: Duration) -> Option<InterpretResult> {
+        let start = Instant::now();
+
+        let result = loop {
+            if self
+                .idle_until
+                .is_some_and(|deadline| Instant::now() < deadline)
+            {
+                break None;
+            }
+
+            if let Some(result) = self.step() {
+                break Some(result);
+            }
+
+            // checking the clock on every instruction would dominate small ones
+            if self.instructions.is_multiple_of(64) && start.elapsed() >= budget {
+                break None;
+            }
+        };
+
+        match result {
+            Some(result) => Some(self.flush_after(result)),
+            None => self.flush().err().map(|err| self.output_failed(err)),
         }
     }
 
-    fn advance_ip_by(&mut self, count: usize) -> u8 {
-        let mut result = 0;
+    /// Runs `block` to completion. Pass an `Arc` to keep using the block
+    /// afterwards, the VM shares it instead of copying the bytecode.
+    pub fn interpret(&mut self, block: impl Into<Arc<ByteBlock>>) -> InterpretResult {
+        self.load(block);
+
+        let result = self.run();
+        self.flush_after(result)
+    }
 
-        for _ in 0..count {
-            result = self.advance_ip();
+    /// Prepares `block` for execution without running any instructions.
+    pub(crate) fn load(&mut self, block: impl Into<Arc<ByteBlock>>) {
+        let block = block.into();
+
+        // the literals and identifiers of every block loaded, strings built
+        // at runtime aren't interned
+        for constant in block.constants.constants.iter() {
+            if let Constant::Obj(obj) = constant {
+                self.strings.adopt(&obj.bytes);
+            }
         }
 
-        result
+        self.reset(block);
+    }
+
+    /// Calls the global function `name` with `args` and runs it to
+    /// completion, e.g. an event handler defined by a script that already
+    /// ran. Spawned tasks are finished before it returns.
+    pub fn call(&mut self, name: &str, args: &[Constant]) -> Result<Constant, DynamixError> {
+        let callee = match self.globals.get(name) {
+            Some(callee @ (Constant::Function(..) | Constant::Native(..))) => callee.clone(),
+            Some(value) => {
+                return Err(DynamixError::RuntimeError {
+                    message: format!(
+                        "'{name}' is not a function, found '{}'",
+                        value.type_to_string()
+                    ),
+                    line: 0,
+                    backtrace: Vec::new(),
+                })
+            }
+            None => {
+                return Err(DynamixError::RuntimeError {
+                    message: format!("Undefined function '{name}'"),
+                    line: 0,
+                    backtrace: Vec::new(),
+                })
+            }
+        };
+
+        self.apply(callee, args)
     }
 
-    fn descend_ip_by(&mut self, count: usize) -> u8 {
-        let mut result = 0;
+    /// Calls `callee` with `args` and runs it to completion.
+    pub(crate) fn apply(
+        &mut self,
+        callee: Constant,
+        args: &[Constant],
+    ) -> Result<Constant, DynamixError> {
+        // the function returns into an empty block, which ends the run
+        self.reset(Arc::new(ByteBlock::new()));
+        self.stack.push(callee);
+        args.iter().for_each(|arg| self.stack.push(arg.clone()));
 
-        for _ in 0..count {
-            result = self.descend_ip();
+        let result = match self.call_value(args.len()) {
+            Ok(()) => self.run(),
+            Err(err) => {
+                self.runtime_error(err);
+                InterpretResult::RuntimeError
+            }
+        };
+        let result = self.flush_after(result);
+
+        match result {
+            InterpretResult::RuntimeError => Err(DynamixError::RuntimeError {
+                message: self.last_runtime_error(),
+                line: self.last_runtime_line(),
+                backtrace: self.last_backtrace.clone(),
+            }),
+            InterpretResult::OutOfFuel => Err(DynamixError::OutOfFuel),
+            InterpretResult::Interrupted => Err(DynamixError::Interrupted),
+            InterpretResult::TimedOut => Err(DynamixError::TimedOut),
+            _ => Ok(self.take_result()),
         }
+    }
 
-        result
+    /// Starts over at the beginning of `block` as the main task.
+    fn reset(&mut self, block: Arc<ByteBlock>) {
+        self.block = block;
+        self.ip = 0;
+        self.base = 0;
+        self.stack.clear();
+        self.frames.clear();
+
+        // finished tasks keep their ids so handles stored in globals can still
+        // be joined, anything an error left unfinished is abandoned
+        for task in self.tasks.iter_mut() {
+            if !matches!(task.state, TaskState::Done(..)) {
+                task.state = TaskState::Done(Constant::Null);
+            }
+            task.context = None;
+        }
+        match self.tasks.first_mut() {
+            Some(main) => main.state = TaskState::Ready,
+            None => self.tasks.push(Task {
+                state: TaskState::Ready,
+                context: None,
+            }),
+        }
+        self.current = 0;
+        self.suspend = None;
+        self.idle_until = None;
+        self.instructions = 0;
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
     }
 
-    fn read_byte(&mut self) -> Option<u8> {
-        if self.ip.is_null() {
-            return None;
+    /// Offset of the next instruction to execute.
+    pub(crate) fn offset(&self) -> usize {
+        self.ip
+    }
+
+    pub(crate) fn block(&self) -> &ByteBlock {
+        &self.block
+    }
+
+    /// Every global, natives included, iterated in order of name.
+    pub fn globals(&self) -> &BTreeMap<String, Constant> {
+        &self.globals
+    }
+
+    /// Replaces every global, e.g. to undo what a test assigned.
+    pub(crate) fn set_globals(&mut self, globals: BTreeMap<String, Constant>) {
+        self.globals = globals;
+    }
+
+    /// What the running task's instructions were sampled as, `None` unless
+    /// the VM was built `with_profiling`. Samples add up across runs.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// A switch that turns tracing on or off before the next instruction,
+    /// even while the VM is running on another thread.
+    pub fn trace_switch(&self) -> TraceSwitch {
+        self.trace.clone()
+    }
+
+    pub fn set_tracing(&self, on: bool) {
+        self.trace.set(on);
+    }
+
+    /// A token that stops the running script, even while the VM is running
+    /// on another thread.
+    ///
+    /// ```
+    /// use dynamix::{compiler::Compiler, virtual_machine::{InterpretResult, VirtualMachine}};
+    /// use std::{thread, time::Duration};
+    ///
+    /// let mut vm = VirtualMachine::new();
+    /// let token = vm.cancellation_token();
+    /// thread::spawn(move || {
+    ///     thread::sleep(Duration::from_millis(20));
+    ///     token.cancel();
+    /// });
+    ///
+    /// let output = Compiler::new("while true {}").compile();
+    /// assert_eq!(vm.interpret(output.block), InterpretResult::Interrupted);
+    /// ```
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// The names of the running functions, outermost first and separated
+    /// by ';'.
+    fn call_stack(&self) -> String {
+        self.frames
+            .iter()
+            .map(|frame| frame.block.name())
+            .chain([self.block.name()])
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+
+    pub(crate) fn stack(&self) -> &[Constant] {
+        self.stack.as_slice()
+    }
+
+    /// Stack slot the running function's locals are relative to.
+    pub(crate) fn frame_base(&self) -> usize {
+        self.base
+    }
+
+    /// How many calls the running function is nested in.
+    pub(crate) fn call_depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The running functions innermost first, each at the line it's paused
+    /// on. Callers are at the call they're waiting on.
+    pub(crate) fn call_frames(&self) -> Vec<TraceFrame> {
+        std::iter::once((&self.block, self.ip))
+            .chain(
+                self.frames
+                    .iter()
+                    .rev()
+                    .map(|frame| (&frame.block, frame.ip.saturating_sub(1))),
+            )
+            .filter(|(block, _)| !block.bytes.is_empty())
+            .map(|(block, offset)| TraceFrame {
+                function: block.name().to_string(),
+                line: block.lines.get(offset).copied().unwrap_or(0),
+                file: block.include(offset).map(String::from),
+            })
+            .collect()
+    }
+
+    /// Every value the VM holds directly: the stack, the globals, the stacks
+    /// of tasks that aren't running and the results of finished ones.
+    pub(crate) fn roots(&self) -> impl Iterator<Item = &Constant> {
+        let tasks = self.tasks.iter().flat_map(|task| {
+            let stack = task
+                .context
+                .iter()
+                .flat_map(|context| context.stack.as_slice());
+            let result = match &task.state {
+                TaskState::Done(result) => Some(result),
+                _ => None,
+            };
+            stack.chain(result)
+        });
+
+        self.stack
+            .as_slice()
+            .iter()
+            .chain(self.globals.values())
+            .chain(tasks)
+    }
+
+    pub(crate) fn interned_strings(&self) -> usize {
+        self.strings.len()
+    }
+
+    /// Instructions executed by the last `interpret` call, or so far when
+    /// asked while it's still running.
+    pub fn instruction_count(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Lets the VM execute at most `fuel` more instructions, after which
+    /// running stops with `InterpretResult::OutOfFuel`. What's left carries
+    /// over between runs, and a run that ran out continues with `run_for`
+    /// once refueled.
+    ///
+    /// ```
+    /// use dynamix::{compiler::Compiler, virtual_machine::{InterpretResult, VirtualMachine}};
+    ///
+    /// let output = Compiler::new("while true {}").compile();
+    /// let mut vm = VirtualMachine::new();
+    /// vm.set_fuel(1_000);
+    ///
+    /// assert_eq!(vm.interpret(output.block), InterpretResult::OutOfFuel);
+    /// assert_eq!(vm.fuel(), Some(0));
+    /// ```
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = Some(fuel);
+    }
+
+    /// Instructions the VM may still execute, `None` if it's unlimited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Stops every later run that takes longer than `timeout` with
+    /// `InterpretResult::TimedOut`, time spent asleep included. The clock
+    /// starts when a block is loaded or a function is called from the host,
+    /// it's checked every `CANCEL_POLL_INTERVAL` instructions.
+    ///
+    /// ```
+    /// use dynamix::{compiler::Compiler, virtual_machine::{InterpretResult, VirtualMachine}};
+    /// use std::time::Duration;
+    ///
+    /// let output = Compiler::new("while true {}").compile();
+    /// let mut vm = VirtualMachine::new();
+    /// vm.set_timeout(Duration::from_millis(10));
+    ///
+    /// assert_eq!(vm.interpret(output.block), InterpretResult::TimedOut);
+    /// ```
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    /// How long ago the VM was built.
+    pub(crate) fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub(crate) fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    pub fn angle_mode(&self) -> AngleMode {
+        self.angle_mode
+    }
+
+    pub fn set_angle_mode(&mut self, mode: AngleMode) {
+        self.angle_mode = mode;
+    }
+
+    /// Prints `text` on its own line, wherever the script's output goes.
+    pub(crate) fn print_line(&mut self, text: &str) -> Result<(), String> {
+        writeln!(self.out, "{text}").map_err(output_error)
+    }
+
+    /// Flushes any buffered program output, this happens automatically
+    /// at the end of every `interpret` call.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Flushes the output at the end of a run, a run that succeeded fails
+    /// if the output can't be written. An earlier error is kept.
+    pub(crate) fn flush_after(&mut self, result: InterpretResult) -> InterpretResult {
+        match self.flush() {
+            Err(err) if result == InterpretResult::Ok => self.output_failed(err),
+            Err(..) => {
+                self.discard_output();
+                result
+            }
+            Ok(()) => result,
         }
+    }
+
+    fn output_failed(&mut self, err: io::Error) -> InterpretResult {
+        self.discard_output();
+        self.runtime_error(output_error(err));
+        InterpretResult::RuntimeError
+    }
 
-        let byte = self.advance_ip();
+    // what couldn't be written is dropped, the next run would fail on it
+    fn discard_output(&mut self) {
+        let out = std::mem::replace(&mut self.out, BufWriter::new(Box::new(io::sink())));
+        self.out = BufWriter::new(out.into_parts().0);
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let byte = *self.block.bytes.get(self.ip)?;
+        self.ip += 1;
         Some(byte)
     }
 
     fn read_short(&mut self) -> Option<u16> {
-        self.advance_ip_by(2);
-        let first_bit = unsafe { self.ip.sub(2) };
-        let second_bit = unsafe { self.ip.sub(1) };
-        unsafe { Some((((*first_bit as u16) << 8) as u16 | *second_bit as u16) as u16) }
+        let short = self.block.read_short(self.ip)?;
+        self.ip += 2;
+        Some(short)
     }
 
-    fn read_constant(&mut self) -> Option<Constant> {
-        if let Some(byte) = self.read_byte() {
-            let constant = self.block.constants[byte as usize].clone();
-            return Some(constant);
+    fn read_long(&mut self) -> Option<u32> {
+        let long = self.block.read_long(self.ip)?;
+        self.ip += 4;
+        Some(long)
+    }
+
+    fn read_constant(&mut self) -> Result<Constant, String> {
+        let index = self.read_byte().ok_or(TRUNCATED)?;
+        self.constant(index as usize, 2)
+    }
+
+    fn read_constant_long(&mut self) -> Result<Constant, String> {
+        let index = self.read_short().ok_or(TRUNCATED)?;
+        self.constant(index as usize, 3)
+    }
+
+    /// The name operand of a global or invoke instruction, which is a long
+    /// index for the `_LONG` variants.
+    fn read_name(&mut self, opcode: OpCode) -> Result<Constant, String> {
+        match opcode {
+            OpCode::DefineGlobalLong
+            | OpCode::GetGlobalLong
+            | OpCode::SetGlobalLong
+            | OpCode::InvokeLong => self.read_constant_long(),
+            _ => self.read_constant(),
         }
+    }
 
-        None
+    /// The constant at `index`, `width` is how many bytes of the instruction
+    /// have been read so the error can point at its start.
+    fn constant(&self, index: usize, width: usize) -> Result<Constant, String> {
+        self.block
+            .constants
+            .constants
+            .get(index)
+            .cloned()
+            .ok_or_else(|| {
+                let offset = self.ip - width;
+                format!("Invalid constant index {index} at offset {offset:04}")
+            })
     }
 
-    fn run(&mut self) -> InterpretResult {
-        let mut result = InterpretResult::Ok;
+    /// Moves the instruction pointer by `offset`, jumping to the very end
+    /// of the block is allowed and finishes the program.
+    fn jump(&mut self, offset: isize) -> Result<(), String> {
+        match self.ip.checked_add_signed(offset) {
+            Some(ip) if ip <= self.block.bytes.len() => {
+                self.ip = ip;
+                Ok(())
+            }
+            _ => Err(format!(
+                "Jump to offset {} is out of bounds",
+                self.ip as isize + offset
+            )),
+        }
+    }
+
+    /// Writes one row of the trace: the instruction about to run and the
+    /// stack it runs on.
+    fn trace(&mut self) -> io::Result<()> {
+        let offset = self.ip;
+        let Some(Ok(opcode)) = self.block.bytes.get(offset).map(|&byte| OpCode::from(byte)) else {
+            return Ok(());
+        };
+
+        // what was printed so far comes before the row, even in one writer
+        self.flush()?;
 
+        if offset == 0 || !self.traced {
+            writeln!(self.trace_out, "-- {} --", self.block.name())?;
+            writeln!(
+                self.trace_out,
+                "{:6} {:>4} {:18} {:24} stack",
+                "offset", "line", "opcode", "operands"
+            )?;
+        }
+
+        let line = self.block.lines[offset];
+        let operands = truncate(&Disassembler::operands(&self.block, offset), 24);
+        let stack: String = self
+            .stack
+            .iter()
+            .map(|value| format!("[ {} ]", truncate(&value.to_string(), TRACE_VALUE_WIDTH)))
+            .collect();
+
+        writeln!(
+            self.trace_out,
+            "{offset:06} {line:>4} {:18} {operands:24} {stack}",
+            opcode.name()
+        )
+    }
+
+    fn run(&mut self) -> InterpretResult {
         loop {
-            let mut offset = unsafe { self.ip.offset_from(self.origin) as usize };
-
-            if cfg!(debug_assertions) && cfg!(feature = "stack-trace") {
-                print!("{:10}", ' ');
-                let mut slot = self.stack.as_ptr();
-                let top = self.stack.top_as_ptr();
-                while (slot as usize) < top as usize {
-                    unsafe {
-                        print!("[ {} ]", *slot);
-                        slot = slot.add(1);
-                    }
+            if let Some(deadline) = self.idle_until {
+                if let Err(err) = self.flush() {
+                    return self.output_failed(err);
                 }
-                println!();
-                Disassembler::disassemble_instruction(&self.block, &mut offset);
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                std::thread::sleep(remaining.min(IDLE_POLL));
             }
 
-            let instruction = if let Some(code) = self.read_byte() {
-                code
-            } else {
-                break;
-            };
+            if let Some(result) = self.step() {
+                return result;
+            }
+        }
+    }
+
+    /// Executes a single instruction, returning the final result once
+    /// the program has finished.
+    pub(crate) fn step(&mut self) -> Option<InterpretResult> {
+        // not checked on every instruction, tight loops would pay for it
+        let poll =
+            self.idle_until.is_some() || self.instructions.is_multiple_of(CANCEL_POLL_INTERVAL);
+        if poll && self.cancel.take() {
+            return Some(InterpretResult::Interrupted);
+        }
+        if poll
+            && self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Some(InterpretResult::TimedOut);
+        }
+
+        if let Some(deadline) = self.idle_until {
+            if Instant::now() < deadline {
+                return None;
+            }
+
+            self.idle_until = None;
+            if let Err(err) = self.schedule(None) {
+                self.runtime_error(err);
+                return Some(InterpretResult::RuntimeError);
+            }
+        }
+
+        let trace = self.trace.is_on();
+        if trace {
+            if let Err(err) = self.trace() {
+                return Some(self.output_failed(err));
+            }
+        }
+        self.traced = trace;
+
+        let instruction = if let Some(code) = self.read_byte() {
+            code
+        } else {
+            let value = self.stack.pop().unwrap_or(Constant::Null);
+            return self.finish_task(value);
+        };
+
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel == 0 {
+                // put the instruction back so a refueled VM runs it next
+                self.ip -= 1;
+                return Some(InterpretResult::OutOfFuel);
+            }
+            *fuel -= 1;
+        }
+        self.instructions += 1;
+
+        if self.profile.is_some() {
+            let stack = self.call_stack();
+            if let Some(profile) = &mut self.profile {
+                profile.record(stack);
+            }
+        }
 
-            match OpCode::from(instruction) {
-                Ok(opcode) => match opcode {
-                    OpCode::Print => {
-                        if let Some(constant) = self.stack.pop() {
-                            println!("{constant}");
+        match OpCode::from(instruction) {
+            Ok(opcode) => match opcode {
+                OpCode::Print => {
+                    if let Some(constant) = self.stack.pop() {
+                        if let Err(err) = writeln!(self.out, "{constant}") {
+                            return Some(self.output_failed(err));
                         }
                     }
-                    OpCode::Pop => {
-                        if !self.stack.is_empty() {
-                            self.stack.pop();
-                        }
+                }
+                OpCode::Pop => {
+                    if !self.stack.is_empty() {
+                        self.stack.pop();
                     }
-                    OpCode::DefineGlobal => {
-                        if let Some(name) = self.read_constant() {
-                            let value = self.stack.clone().last().unwrap();
-                            self.globals.insert(name.to_string(), value);
-                            self.stack.pop();
+                }
+                OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                    let name = constant!(self, read_name, opcode);
+                    let value = pop!(self);
+                    self.globals.insert(name_of(&name).into_owned(), value);
+                }
+                OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                    let name = constant!(self, read_name, opcode);
+                    match self.globals.get(&*name_of(&name)) {
+                        Some(constant) => self.stack.push(constant.clone()),
+                        None => {
+                            let err = format!("Undefined variable '{name}'");
+                            self.runtime_error(err);
+                            return Some(InterpretResult::RuntimeError);
                         }
                     }
-                    OpCode::GetGlobal => {
-                        if let Some(name) = self.read_constant() {
-                            let value = self.globals.get_key_value(&name.to_string());
-                            match value {
-                                Some((.., constant)) => self.stack.push(constant.clone()),
-                                None => {
-                                    let err = format!("Undefined variable '{name}'");
-                                    self.runtime_error(err);
-                                    result = InterpretResult::RuntimeError;
-                                    break;
-                                }
-                            }
+                }
+                OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                    let name = constant!(self, read_name, opcode);
+                    let top = peek!(self).clone();
+                    match self.globals.get_mut(&*name_of(&name)) {
+                        Some(global) => *global = top,
+                        None => {
+                            let err = format!("Undefined variable '{name}'");
+                            self.runtime_error(err);
+                            return Some(InterpretResult::RuntimeError);
                         }
                     }
-                    OpCode::SetGlobal => {
-                        if let Some(name) = self.read_constant() {
-                            if self.globals.contains_key(&name.to_string()) {
-                                let top = self.stack.clone().last().unwrap();
-                                self.globals.insert(name.to_string(), top);
-                            }
+                }
+                OpCode::GetLocal => {
+                    let slot = operand!(self, read_byte);
+                    match self.stack.get(self.base + slot as usize) {
+                        Some(value) => self.stack.push(value.clone()),
+                        None => {
+                            self.invalid_slot("OP_GET_LOCAL", slot);
+                            return Some(InterpretResult::RuntimeError);
                         }
                     }
-                    OpCode::GetLocal => {
-                        if let Some(slot) = self.read_byte() {
-                            self.stack.push(self.stack[slot as usize].clone());
+                }
+                OpCode::SetLocal => {
+                    let slot = operand!(self, read_byte);
+                    let value = self.stack.peek(0).cloned();
+                    match (self.stack.get_mut(self.base + slot as usize), value) {
+                        (Some(local), Some(value)) => *local = value,
+                        _ => {
+                            self.invalid_slot("OP_SET_LOCAL", slot);
+                            return Some(InterpretResult::RuntimeError);
                         }
                     }
-                    OpCode::SetLocal => {
-                        if let Some(slot) = self.read_byte() {
-                            self.stack[slot as usize] = self.stack.clone().last().unwrap();
+                }
+                OpCode::Jz | OpCode::JzLong => {
+                    let offset = match opcode {
+                        OpCode::Jz => operand!(self, read_short) as usize,
+                        _ => operand!(self, read_long) as usize,
+                    };
+                    let expr = peek!(self);
+                    if let Constant::Bool(true) = self.is_falsey(expr) {
+                        if let Err(err) = self.jump(offset as isize) {
+                            self.runtime_error(err);
+                            return Some(InterpretResult::RuntimeError);
                         }
                     }
-                    OpCode::Jz => {
-                        if let Some(offset) = self.read_short() {
-                            let expr = self.stack.clone().last().unwrap();
-                            if let Constant::Bool(x) = self.is_falsey(expr) {
-                                if x {
-                                    self.advance_ip_by(offset as usize);
-                                }
-                            }
-                        }
+                }
+                OpCode::Jmp | OpCode::JmpLong => {
+                    let offset = match opcode {
+                        OpCode::Jmp => operand!(self, read_short) as usize,
+                        _ => operand!(self, read_long) as usize,
+                    };
+                    if let Err(err) = self.jump(offset as isize) {
+                        self.runtime_error(err);
+                        return Some(InterpretResult::RuntimeError);
                     }
-                    OpCode::Jmp => {
-                        if let Some(offset) = self.read_short() {
-                            self.advance_ip_by(offset as usize);
-                        }
+                }
+                OpCode::Loop | OpCode::LoopLong => {
+                    let offset = match opcode {
+                        OpCode::Loop => operand!(self, read_short) as usize,
+                        _ => operand!(self, read_long) as usize,
+                    };
+                    if let Err(err) = self.jump(-(offset as isize)) {
+                        self.runtime_error(err);
+                        return Some(InterpretResult::RuntimeError);
                     }
-                    OpCode::Loop => {
-                        if let Some(offset) = self.read_short() {
-                            self.descend_ip_by(offset as usize);
-                        }
+                }
+                OpCode::Constant => {
+                    // remember OP_CONSTANT instruction 'loads' a constant onto the stack
+                    let constant = constant!(self, read_constant);
+                    self.stack.push(constant);
+                }
+                OpCode::ConstantLong => {
+                    let constant = constant!(self, read_constant_long);
+                    self.stack.push(constant);
+                }
+                OpCode::True => self.stack.push(Constant::Bool(true)),
+                OpCode::False => self.stack.push(Constant::Bool(false)),
+                OpCode::Char => {
+                    let constant = constant!(self, read_constant);
+                    self.stack.push(constant);
+                }
+                OpCode::Null => self.stack.push(Constant::Null),
+                OpCode::Tuple => {
+                    let count = operand!(self, read_byte);
+                    let mut items = Vec::with_capacity(count as usize);
+                    for _ in 0..count {
+                        items.push(pop!(self));
                     }
-                    OpCode::Constant => {
-                        // remember OP_CONSTANT instruction 'loads' a constant onto the stack
-                        if let Some(constant) = self.read_constant() {
-                            self.stack.push(constant);
+                    items.reverse();
+                    self.stack.push(Constant::Tuple(items));
+                }
+                OpCode::Unpack => {
+                    let count = operand!(self, read_byte);
+                    match self.stack.pop() {
+                        Some(Constant::Tuple(items)) if items.len() == count as usize => {
+                            for item in items {
+                                self.stack.push(item);
+                            }
+                        }
+                        Some(Constant::Tuple(items)) => {
+                            let err = format!(
+                                "Cannot destructure tuple of {} elements into {count} variables",
+                                items.len()
+                            );
+                            self.runtime_error(err);
+                            return Some(InterpretResult::RuntimeError);
+                        }
+                        Some(constant) => {
+                            let err = format!(
+                                "Cannot destructure value of type '{}'",
+                                constant.type_to_string()
+                            );
+                            self.runtime_error(err);
+                            return Some(InterpretResult::RuntimeError);
                         }
+                        None => (),
                     }
-                    OpCode::True => self.stack.push(Constant::Bool(true)),
-                    OpCode::False => self.stack.push(Constant::Bool(false)),
-                    OpCode::Char => {
-                        if let Some(constant) = self.read_constant() {
-                            self.stack.push(constant);
+                }
+                OpCode::Equal => {
+                    if let Some(rhs) = self.stack.pop() {
+                        if let Some(lhs) = self.stack.pop() {
+                            let equal = lhs == rhs;
+                            self.stack.push(Constant::Bool(equal));
                         }
                     }
-                    OpCode::Null => self.stack.push(Constant::Null),
-                    OpCode::Equal => {
-                        if let Some(rhs) = self.stack.pop() {
-                            if let Some(lhs) = self.stack.pop() {
-                                let equal = lhs == rhs;
-                                self.stack.push(Constant::Bool(equal));
+                }
+                OpCode::Greater | OpCode::Less => {
+                    if let Some(rhs) = self.stack.pop() {
+                        if let Some(lhs) = self.stack.pop() {
+                            let expected = match opcode {
+                                OpCode::Greater => Ordering::Greater,
+                                _ => Ordering::Less,
+                            };
+
+                            match compare(&lhs, &rhs) {
+                                Ok(ordering) => {
+                                    let result = ordering == Some(expected);
+                                    self.stack.push(Constant::Bool(result));
+                                }
+                                Err(err) => {
+                                    self.runtime_error(err);
+                                    return Some(InterpretResult::RuntimeError);
+                                }
                             }
                         }
                     }
-                    OpCode::Greater => {
-                        if let Some(rhs) = self.stack.pop() {
-                            if let Some(lhs) = self.stack.pop() {
-                                let greater = lhs > rhs;
-                                self.stack.push(Constant::Bool(greater));
-                            }
+                }
+                OpCode::Negate => {
+                    if let Some(constant) = self.stack.pop() {
+                        if let Constant::Number(x) = constant {
+                            self.stack.push(Constant::Number(-x))
+                        } else {
+                            let err = format!(
+                                "Operand of unary '-' must be a number, found {} {}",
+                                constant.type_to_string(),
+                                quoted(&constant)
+                            );
+                            self.runtime_error(err);
+                            return Some(InterpretResult::RuntimeError);
                         }
                     }
-                    OpCode::Less => {
-                        if let Some(rhs) = self.stack.pop() {
-                            if let Some(lhs) = self.stack.pop() {
-                                let less = lhs < rhs;
-                                self.stack.push(Constant::Bool(less));
+                }
+                // every value has a truthiness, so '!' never fails
+                OpCode::Not => {
+                    if let Some(constant) = self.stack.pop() {
+                        self.stack.push(self.is_falsey(&constant));
+                    }
+                }
+                OpCode::Add => binary_op!(self, +, "+"),
+                OpCode::Sub => binary_op!(self, -, "-"),
+                OpCode::Mul => binary_op!(self, *, "*"),
+                OpCode::Div => {
+                    if self.divides_by_zero() {
+                        let (lhs, rhs) = (self.stack.peek(1).unwrap(), self.stack.peek(0).unwrap());
+                        let err =
+                            format!("Division by zero in '{} / {}'", quoted(lhs), quoted(rhs));
+                        self.runtime_error(err);
+                        return Some(InterpretResult::RuntimeError);
+                    }
+
+                    binary_op!(self, /, "/")
+                }
+                OpCode::Pow => {
+                    if let Some(rhs) = self.stack.pop() {
+                        if let Some(lhs) = self.stack.pop() {
+                            match (&lhs, &rhs) {
+                                (Constant::Number(x), Constant::Number(y)) => {
+                                    self.stack.push(Constant::Number(x.powf(*y)))
+                                }
+                                _ => {
+                                    let (lhs, rhs) = (lhs.type_to_string(), rhs.type_to_string());
+                                    type_mismatch(self, "**", lhs, rhs);
+                                    return Some(InterpretResult::RuntimeError);
+                                }
                             }
                         }
                     }
-                    OpCode::Negate => {
-                        if let Some(constant) = self.stack.pop() {
-                            if let Constant::Number(x) = constant {
-                                self.stack.push(Constant::Number(-x))
-                            } else {
-                                self.runtime_error("Operand must be a number".to_string());
-                                result = InterpretResult::RuntimeError;
-                                break;
-                            }
+                }
+                OpCode::Call => {
+                    let arg_count = operand!(self, read_byte);
+                    if let Err(err) = self.call_value(arg_count as usize) {
+                        self.runtime_error(err);
+                        return Some(InterpretResult::RuntimeError);
+                    }
+
+                    if let Some(suspend) = self.suspend.take() {
+                        if let Err(err) = self.schedule(Some(suspend)) {
+                            self.runtime_error(err);
+                            return Some(InterpretResult::RuntimeError);
                         }
                     }
-                    OpCode::Not => {
-                        if let Some(constant) = self.stack.pop() {
-                            self.stack.push(self.is_falsey(constant));
+                }
+                OpCode::Invoke | OpCode::InvokeLong => {
+                    let name = constant!(self, read_name, opcode);
+                    let arg_count = operand!(self, read_byte);
+                    if let Err(err) = self.invoke(&name_of(&name), arg_count as usize) {
+                        self.runtime_error(err);
+                        return Some(InterpretResult::RuntimeError);
+                    }
+                }
+                OpCode::Assert => {
+                    let message = self.stack.pop().unwrap_or(Constant::Null);
+                    let condition = self.stack.pop().unwrap_or(Constant::Null);
+                    if let Constant::Bool(true) = self.is_falsey(&condition) {
+                        let err = match message {
+                            Constant::Null => "Assertion failed".to_string(),
+                            message => format!("Assertion failed: {message}"),
+                        };
+                        self.runtime_error(err);
+                        return Some(InterpretResult::RuntimeError);
+                    }
+                }
+                OpCode::Return => {
+                    let value = self.stack.pop().unwrap_or(Constant::Null);
+                    match self.frames.pop() {
+                        Some(frame) => {
+                            // a result that holds the memoized fn would keep
+                            // its own cache alive, so it isn't cached
+                            if let Some((memoized, args)) = frame.memo {
+                                if !gc::reaches(&value, &Constant::Memoized(memoized.clone())) {
+                                    memoized.insert(args, value.clone());
+                                }
+                            }
+
+                            // drop the callee, its arguments and locals
+                            self.stack.truncate(self.base);
+                            self.stack.push(value);
+                            self.block = frame.block;
+                            self.ip = frame.ip;
+                            self.base = frame.base;
                         }
+                        None => return self.finish_task(value),
                     }
-                    OpCode::Add => binary_op!(self, +, '+',result),
-                    OpCode::Sub => binary_op!(self, -, '-',result),
-                    OpCode::Mul => binary_op!(self, *, '*',result),
-                    OpCode::Div => binary_op!(self, /, '/',result),
-                    OpCode::Return => break,
-                },
-                Err(..) => result = InterpretResult::RuntimeError,
+                }
+            },
+            Err(..) => {
+                self.runtime_error(format!("Unknown opcode {instruction}"));
+                return Some(InterpretResult::RuntimeError);
+            }
+        }
+
+        None
+    }
+
+    fn invoke(&mut self, name: &str, arg_count: usize) -> Result<(), String> {
+        let receiver = self
+            .stack
+            .peek(arg_count)
+            .ok_or_else(|| UNDERFLOW.to_string())?;
+
+        let method = match receiver.as_str() {
+            Some(..) => string::find_method(name),
+            None => None,
+        };
+
+        let method = match method {
+            Some(method) => method,
+            None => {
+                return Err(format!(
+                    "Undefined method '{name}' on type '{}'",
+                    receiver.type_to_string()
+                ))
+            }
+        };
+
+        if method.arity != arg_count {
+            return Err(format!(
+                "Expected {} arguments but got {arg_count} in call to '{name}'",
+                method.arity
+            ));
+        }
+
+        // the receiver is passed along as the first argument
+        let mut args = Vec::with_capacity(arg_count + 1);
+        for _ in 0..=arg_count {
+            args.push(self.stack.pop().unwrap());
+        }
+        args.reverse();
+
+        let value = (method.function)(self, &args)?;
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn call_value(&mut self, arg_count: usize) -> Result<(), String> {
+        let callee = self
+            .stack
+            .peek(arg_count)
+            .ok_or_else(|| UNDERFLOW.to_string())?
+            .clone();
+
+        match callee {
+            Constant::Native(native) => {
+                if native.arity != arg_count {
+                    return Err(format!(
+                        "Expected {} arguments but got {arg_count} in call to '{}'",
+                        native.arity, native.name
+                    ));
+                }
+
+                let mut args = Vec::with_capacity(arg_count);
+                for _ in 0..arg_count {
+                    args.push(self.stack.pop().unwrap());
+                }
+                args.reverse();
+                let callee = self.stack.pop().unwrap();
+
+                let value = (native.function)(self, &args)?;
+
+                // a blocked 'join' or 'recv' runs again once it can succeed
+                if let Some(Suspend::Join(..) | Suspend::Recv(..)) = self.suspend {
+                    self.stack.push(callee);
+                    args.into_iter().for_each(|arg| self.stack.push(arg));
+                    self.ip -= 2;
+                    return Ok(());
+                }
+
+                self.stack.push(value);
+                Ok(())
+            }
+            Constant::Function(function) => {
+                if function.arity != arg_count {
+                    return Err(format!(
+                        "Expected {} arguments but got {arg_count} in call to '{}'",
+                        function.arity, function.name
+                    ));
+                }
+
+                if self.frames.len() >= self.max_frames {
+                    return Err(format!(
+                        "Stack overflow, more than {} nested calls: {}",
+                        self.max_frames,
+                        self.overflow_chain(&function.name)
+                    ));
+                }
+
+                if self.stack.len() > self.max_stack {
+                    return Err(format!(
+                        "Stack overflow, more than {} values on the stack: {}",
+                        self.max_stack,
+                        self.overflow_chain(&function.name)
+                    ));
+                }
+
+                self.frames.push(CallFrame {
+                    block: std::mem::replace(&mut self.block, function.block.clone()),
+                    ip: self.ip,
+                    base: self.base,
+                    memo: None,
+                });
+                self.ip = 0;
+                self.base = self.stack.len() - arg_count - 1;
+                Ok(())
+            }
+            Constant::Memoized(memoized) => {
+                let args = self.stack.as_slice()[self.stack.len() - arg_count..].to_vec();
+                let key = HashKey::try_from(&Constant::Tuple(args)).map_err(|err| {
+                    format!(
+                        "Can't cache the arguments of '{}': {err}",
+                        memoized.function.name
+                    )
+                })?;
+
+                if let Some(value) = memoized.get(&key) {
+                    self.stack.truncate(self.stack.len() - arg_count - 1);
+                    self.stack.push(value);
+                    return Ok(());
+                }
+
+                let slot = self.stack.len() - arg_count - 1;
+                self.stack[slot] = Constant::Function(memoized.function.clone());
+                self.call_value(arg_count)?;
+
+                // the result is cached when the frame just pushed returns
+                if let Some(frame) = self.frames.last_mut() {
+                    frame.memo = Some((memoized, key));
+                }
+                Ok(())
+            }
+            _ => Err(format!(
+                "Can only call functions, found '{}'",
+                callee.type_to_string()
+            )),
+        }
+    }
+
+    /// The calls that overflowed the stack, the cycle they repeat if there is
+    /// one, e.g. `'even -> odd -> even' repeats`, otherwise the innermost.
+    fn overflow_chain(&self, callee: &str) -> String {
+        let names: Vec<&str> = [callee, self.block.name()]
+            .into_iter()
+            .chain(self.frames.iter().rev().map(|frame| frame.block.name()))
+            .take(MAX_OVERFLOW_CYCLE * 2)
+            .collect();
+
+        let cycle = (1..=MAX_OVERFLOW_CYCLE)
+            .find(|&len| names.len() >= len * 2 && names[..len] == names[len..len * 2]);
+
+        match cycle {
+            Some(len) => {
+                let chain: Vec<&str> = names[..=len].iter().rev().copied().collect();
+                format!("'{}' repeats", chain.join(" -> "))
+            }
+            None => {
+                let shown = MAX_OVERFLOW_CYCLE.min(names.len());
+                let mut chain: Vec<&str> = names[..shown].iter().rev().copied().collect();
+                // the callee and running function aren't in 'frames'
+                if self.frames.len() + 2 > shown {
+                    chain.insert(0, "...");
+                }
+                format!("'{}'", chain.join(" -> "))
+            }
+        }
+    }
+
+    /// Starts `function` as a new task, it first runs when the running task
+    /// yields or blocks.
+    pub(crate) fn spawn(&mut self, function: Arc<Function>) -> Result<usize, String> {
+        if function.arity != 0 {
+            return Err(format!(
+                "Expected a function without parameters in call to 'spawn', '{}' takes {}",
+                function.name, function.arity
+            ));
+        }
+
+        let mut stack = Stack::new(STACK_STARTING_CAP);
+        let block = function.block.clone();
+        stack.push(Constant::Function(function));
+
+        self.tasks.push(Task {
+            state: TaskState::Ready,
+            context: Some(Context {
+                block,
+                ip: 0,
+                base: 0,
+                stack,
+                frames: Vec::new(),
+            }),
+        });
+        Ok(self.tasks.len() - 1)
+    }
+
+    /// The result of task `id` if it's done, otherwise the running task is
+    /// suspended until it is.
+    pub(crate) fn join(&mut self, id: usize) -> Result<Option<Constant>, String> {
+        if id == self.current {
+            return Err("A task can't join itself".to_string());
+        }
+
+        match self.tasks.get(id).map(|task| &task.state) {
+            Some(TaskState::Done(value)) => Ok(Some(value.clone())),
+            Some(..) => {
+                self.suspend = Some(Suspend::Join(id));
+                Ok(None)
             }
+            None => Err(format!("Unknown task {id}")),
+        }
+    }
+
+    /// The oldest value sent on `channel`, otherwise the running task is
+    /// suspended until there is one.
+    pub(crate) fn recv(&mut self, channel: &Channel) -> Option<Constant> {
+        let value = channel.try_recv();
+        if value.is_none() {
+            self.suspend = Some(Suspend::Recv(channel.clone()));
         }
+        value
+    }
+
+    /// Suspends the running task for `duration`, other tasks run meanwhile.
+    pub(crate) fn sleep(&mut self, duration: Duration) {
+        self.suspend = Some(Suspend::Sleep(Instant::now() + duration));
+    }
 
-        result
+    pub(crate) fn yield_now(&mut self) {
+        self.suspend = Some(Suspend::Yield);
     }
 
-    fn is_falsey(&self, constant: Constant) -> Constant {
-        match constant {
-            Constant::Number(x) => Constant::Bool(x == 0.0),
-            Constant::Bool(x) => Constant::Bool(!x),
-            Constant::Char(..) => Constant::Bool(false),
-            Constant::Obj(obj) => Constant::Bool(obj.bytes.is_empty()),
-            Constant::Null => Constant::Bool(true),
+    fn is_runnable(&self, id: usize) -> bool {
+        match self.tasks[id].state {
+            TaskState::Ready => true,
+            TaskState::Joining(other) => matches!(self.tasks[other].state, TaskState::Done(..)),
+            TaskState::Receiving(ref channel) => !channel.is_empty(),
+            TaskState::Sleeping(deadline) => Instant::now() >= deadline,
+            TaskState::Done(..) => false,
         }
     }
 
+    /// Switches to the next task that can make progress, going round robin
+    /// from the running one so every task gets a turn.
+    fn schedule(&mut self, suspend: Option<Suspend>) -> Result<(), String> {
+        match suspend {
+            Some(Suspend::Join(id)) => self.tasks[self.current].state = TaskState::Joining(id),
+            Some(Suspend::Recv(channel)) => {
+                self.tasks[self.current].state = TaskState::Receiving(channel)
+            }
+            Some(Suspend::Sleep(deadline)) => {
+                self.tasks[self.current].state = TaskState::Sleeping(deadline)
+            }
+            Some(Suspend::Yield) | None => (),
+        }
+
+        let count = self.tasks.len();
+        let next = (1..=count)
+            .map(|i| (self.current + i) % count)
+            .find(|&id| self.is_runnable(id));
+
+        if let Some(id) = next {
+            self.switch_to(id);
+            return Ok(());
+        }
+
+        // nothing can run right now, but a sleeping task will be able to
+        let wakes_at = self
+            .tasks
+            .iter()
+            .filter_map(|task| match task.state {
+                TaskState::Sleeping(deadline) => Some(deadline),
+                _ => None,
+            })
+            .min();
+
+        match wakes_at {
+            Some(deadline) => {
+                self.idle_until = Some(deadline);
+                Ok(())
+            }
+            None => Err("Deadlock, every task is waiting on another task".to_string()),
+        }
+    }
+
+    fn switch_to(&mut self, id: usize) {
+        if id != self.current {
+            let context = Context {
+                block: self.block.clone(),
+                ip: self.ip,
+                base: self.base,
+                stack: std::mem::replace(&mut self.stack, Stack::new(0)),
+                frames: std::mem::take(&mut self.frames),
+            };
+
+            if !matches!(self.tasks[self.current].state, TaskState::Done(..)) {
+                self.tasks[self.current].context = Some(context);
+            }
+
+            let context = self.tasks[id].context.take().unwrap();
+            self.block = context.block;
+            self.ip = context.ip;
+            self.base = context.base;
+            self.stack = context.stack;
+            self.frames = context.frames;
+            self.current = id;
+        }
+
+        self.tasks[id].state = TaskState::Ready;
+    }
+
+    /// The running task returned `value`, the program is finished once
+    /// every task is.
+    fn finish_task(&mut self, value: Constant) -> Option<InterpretResult> {
+        match self.tasks.get_mut(self.current) {
+            Some(task) => task.state = TaskState::Done(value),
+            // stepping a VM that never loaded a block
+            None => return Some(InterpretResult::Ok),
+        }
+
+        if self
+            .tasks
+            .iter()
+            .all(|task| matches!(task.state, TaskState::Done(..)))
+        {
+            return Some(InterpretResult::Ok);
+        }
+
+        match self.schedule(None) {
+            Ok(()) => None,
+            Err(err) => {
+                self.runtime_error(err);
+                Some(InterpretResult::RuntimeError)
+            }
+        }
+    }
+
+    /// Whether the operands of the next division divide by zero, chars are
+    /// divided as bytes so dividing them by zero is always an error.
+    fn divides_by_zero(&self) -> bool {
+        match (self.stack.peek(1), self.stack.peek(0)) {
+            (Some(Constant::Number(..)), Some(Constant::Number(y))) => {
+                self.checked_division && *y == 0.0
+            }
+            (Some(Constant::Char(..)), Some(Constant::Char(y))) => *y as u8 == 0,
+            (Some(Constant::Char(..)), Some(Constant::Number(y))) => *y as u8 == 0,
+            _ => false,
+        }
+    }
+
+    fn is_falsey(&self, constant: &Constant) -> Constant {
+        Constant::Bool(!constant.is_truthy())
+    }
+
+    fn invalid_slot(&mut self, opcode: &str, slot: u8) {
+        // the slot operand was just read, the instruction starts one byte before it
+        let offset = self.ip - 2;
+        let depth = self.stack.len();
+        self.runtime_error(format!(
+            "Invalid local slot {slot} for {opcode} at offset {offset:04} (stack depth {depth})"
+        ));
+    }
+
     fn runtime_error(&mut self, msg: String) {
-        let instruction = self.ip as usize - self.origin as usize;
-        let line = self.block.lines[instruction];
-        self.last_runtime_error = format!("[line:{line:2}] Runtime Error: {msg}");
+        // the last byte read belongs to the failing instruction
+        let offset = self.ip.saturating_sub(1);
+        let location = self.block.location(offset);
+        self.last_runtime_line = self.block.lines.get(offset).copied().unwrap_or(0);
+        let mut report = format!("[{location}] Runtime Error: {msg}");
+
+        // every frame but the innermost is at the call it's waiting on,
+        // whose operand is the last byte it read
+        let active = std::iter::once((&self.block, self.ip))
+            .chain(
+                self.frames
+                    .iter()
+                    .rev()
+                    .map(|frame| (&frame.block, frame.ip)),
+            )
+            // 'apply' calls from an empty block, it's not part of the script
+            .filter(|(block, _)| !block.bytes.is_empty());
+
+        let mut backtrace: Vec<TraceFrame> = Vec::new();
+        let mut repeats = 0;
+        for (block, ip) in active {
+            let offset = ip.saturating_sub(1);
+            let frame = TraceFrame {
+                function: block.name().to_string(),
+                line: block.lines.get(offset).copied().unwrap_or(0),
+                file: block.include(offset).map(String::from),
+            };
+
+            // deep recursion is the same frame over and over
+            if backtrace.last() == Some(&frame) {
+                repeats += 1;
+            } else {
+                write_repeats(&mut report, repeats);
+                repeats = 0;
+                let location = block.location(offset);
+                report.push_str(&format!("\n    in {} [{location}]", frame.function));
+            }
+            backtrace.push(frame);
+        }
+        write_repeats(&mut report, repeats);
+
+        self.last_runtime_error = report;
+        self.last_backtrace = backtrace;
+
         self.stack.clear();
+        self.frames.clear();
+    }
+}
+
+pub(crate) fn output_error(err: io::Error) -> String {
+    format!("Failed to write output: {err}")
+}
+
+fn write_repeats(report: &mut String, repeats: usize) {
+    if repeats > 0 {
+        report.push_str(&format!("\n    ... repeated {repeats} more times"));
     }
 }
 
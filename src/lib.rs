@@ -1,4 +1,5 @@
 mod dynamix;
+#[cfg(test)]
 mod tests;
 
 pub use self::dynamix::*;
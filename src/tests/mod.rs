@@ -1,19 +1,2500 @@
-#[cfg(test)]
-mod tests {
+use crate::dynamix::{virtual_machine::InterpretResult, *};
 
-    use crate::dynamix::*;
+fn try_run_script(path: &str) {
+    if let Err(err) = run_file(path) {
+        panic!("Failed to run '{path}': {err}");
+    }
+}
+
+/// Output written to a `with_output` VM, shared so it can be read after.
+#[derive(Clone, Default)]
+struct Captured(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl Captured {
+    fn text(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl std::io::Write for Captured {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(bytes)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn script() {
+    try_run_script("examples/script.dyn");
+}
+
+#[test]
+fn run_results() {
+    use error::DynamixError;
+
+    assert!(matches!(run("let x = 1;"), Ok(Value::Null)));
+
+    match run("let x = 1;\nprint x +;") {
+        Err(DynamixError::CompileError(diagnostics)) => {
+            assert_eq!(diagnostics.len(), 1);
+            assert_eq!(diagnostics[0].span.line, 2);
+        }
+        other => panic!("expected a compile error, got {other:?}"),
+    }
+
+    match run("let x = 1;\n\nprint -\"x\";") {
+        Err(DynamixError::RuntimeError { message, line, .. }) => {
+            assert_eq!(line, 3);
+            assert!(message.contains("Operand of unary '-' must be a number"));
+        }
+        other => panic!("expected a runtime error, got {other:?}"),
+    }
+
+    assert!(matches!(
+        run_file("examples/missing.dyn"),
+        Err(DynamixError::Io(..))
+    ));
+}
+
+#[test]
+fn engine_keeps_globals() {
+    use engine::Engine;
+    use error::DynamixError;
+
+    let mut engine = Engine::builder()
+        .with_define("FAST")
+        .with_stack_capacity(16)
+        .build();
+    engine.eval("let count = 1;").unwrap();
+    engine.eval("count = count + 1;").unwrap();
+    engine
+        .eval("#if FAST\ncount = count * 10;\n#endif")
+        .unwrap();
+
+    let count = engine.vm().globals().get("count").cloned();
+    assert_eq!(count, Some(Value::Number(20.0)));
+
+    // a failed evaluation leaves the engine usable
+    assert!(matches!(
+        engine.eval("count = missing;"),
+        Err(DynamixError::RuntimeError { .. })
+    ));
+    assert!(engine.eval("count = count + 1;").is_ok());
+}
+
+#[test]
+fn call_script_functions() {
+    use engine::Engine;
+    use error::DynamixError;
+
+    let mut engine = Engine::new();
+    let source = "let hp = 10;\nfun on_hit(damage) {\n    hp = hp - damage;\n    return hp;\n}";
+    engine.eval(source).unwrap();
+
+    assert_eq!(
+        engine.call("on_hit", &[3.0.into()]).unwrap(),
+        Value::Number(7.0)
+    );
+    assert_eq!(
+        engine.call("on_hit", &[3.0.into()]).unwrap(),
+        Value::Number(4.0)
+    );
+    assert_eq!(
+        engine
+            .call("clamp", &[12.0.into(), 0.0.into(), 10.0.into()])
+            .unwrap(),
+        Value::Number(10.0)
+    );
+
+    match engine.call("on_hit", &["x".into()]) {
+        Err(DynamixError::RuntimeError { line, .. }) => assert_eq!(line, 3),
+        other => panic!("expected a runtime error, got {other:?}"),
+    }
+    match engine.call("on_hit", &[]) {
+        Err(DynamixError::RuntimeError { message, .. }) => {
+            assert!(message.contains("Expected 1 arguments but got 0"))
+        }
+        other => panic!("expected a runtime error, got {other:?}"),
+    }
+    assert!(engine.call("missing", &[]).is_err());
+    assert!(engine.call("hp", &[]).is_err());
+
+    // the engine is still usable after failed calls
+    assert_eq!(
+        engine.call("on_hit", &[1.0.into()]).unwrap(),
+        Value::Number(3.0)
+    );
+}
+
+#[test]
+fn test_blocks() {
+    use engine::Engine;
+
+    let source = "let hp = 10;
+test \"damage\" {
+    hp = hp - 3;
+    assert_eq(hp, 7);
+}
+test \"fresh globals\" {
+    assert_eq(hp, 10);
+}
+test \"fails\" {
+    assert_eq(hp, 11);
+}";
+    let outcomes = Engine::new().test(source).unwrap();
+
+    let names: Vec<&str> = outcomes.iter().map(|o| o.name.as_str()).collect();
+    assert_eq!(names, ["damage", "fresh globals", "fails"]);
+    assert!(outcomes[0].passed());
+    assert!(outcomes[1].passed());
+
+    let failure = outcomes[2].failure.as_deref().unwrap();
+    assert!(failure.contains("assert_eq failed, left: 10, right: 11"));
+
+    // a normal run skips the tests
+    assert!(run(source).is_ok());
+}
+
+#[test]
+fn values_stay_small() {
+    // every stack slot, local and global is one of these
+    assert!(std::mem::size_of::<constant::Constant>() <= 32);
+}
+
+#[test]
+fn cloned_blocks_share_constants() {
+    let mut compiler = compiler::Compiler::new("let a = \"shared\"; print a;");
+    assert!(compiler.compile().succeeded());
+
+    let block = compiler.byte_code().clone();
+    assert!(block
+        .constants
+        .shares_storage(&compiler.byte_code().constants));
+
+    // VMs given the same Arc share the whole block
+    let block = std::sync::Arc::new(block);
+    for _ in 0..2 {
+        let mut vm = virtual_machine::VirtualMachine::new();
+        assert!(matches!(
+            vm.interpret(block.clone()),
+            virtual_machine::InterpretResult::Ok
+        ));
+    }
+}
+
+#[test]
+fn strings_are_interned() {
+    let mut compiler = compiler::Compiler::new("let a = \"ab\"; let b = \"ab\"; print a == b;");
+    assert!(compiler.compile().succeeded());
+
+    // one slot each for 'a', 'b' and the shared literal
+    let constants = &compiler.byte_code().constants;
+    assert_eq!(constants.len(), 3);
+
+    let mut strings = constant::Interner::new();
+    let (x, y) = (strings.string("ab"), strings.string("ab"));
+    match (x, y) {
+        (constant::Constant::Obj(x), constant::Constant::Obj(y)) => assert!(x.same_handle(&y)),
+        _ => unreachable!(),
+    }
+    assert_eq!(strings.len(), 1);
+}
+
+#[test]
+fn runtime_strings_are_not_interned() {
+    let source = "let s = \"\"; let i = 0; while i < 100 { s = s + \"x\"; i = i + 1; }";
+    let output = compiler::Compiler::new(source).compile();
+    assert!(output.succeeded());
+
+    let mut vm = virtual_machine::VirtualMachine::new();
+    assert_eq!(vm.interpret(output.block), InterpretResult::Ok);
+
+    // the names and literals, none of the 100 strings the loop built
+    assert_eq!(vm.interned_strings(), 4);
+    let s = vm.get_global("s").unwrap();
+    assert_eq!(s.as_str().map(str::len), Some(100));
+}
+
+#[test]
+fn conditional_compilation() {
+    let source = "#if DEBUG\nlet mode = \"debug\";\n#else\nlet mode = \"release\";\n#endif";
+    let output = preprocessor::preprocess(source, &["DEBUG".to_string()]).unwrap();
+    assert_eq!(output, "\nlet mode = \"debug\";\n\n\n");
 
-    fn try_run_script(path: &str) {
-        match run_file(path) {
-            Ok((result, error)) => {
-                assert!(result as u32 == 0 && error.is_empty())
+    let mut vm = virtual_machine::VirtualMachine::new();
+    let output = compiler::Compiler::new(source)
+        .with_defines(&["DEBUG".to_string()])
+        .compile();
+    assert!(output.succeeded());
+    vm.interpret(output.block);
+    assert_eq!(
+        vm.globals().get("mode").and_then(|mode| mode.as_str()),
+        Some("debug")
+    );
+}
+
+#[test]
+fn malformed_bytecode_is_a_runtime_error() {
+    use byte_block::{ByteBlock, OpCode};
+
+    let mut truncated = ByteBlock::new();
+    truncated.push(OpCode::Constant as u8, 1);
+
+    let mut out_of_bounds = ByteBlock::new();
+    for byte in [OpCode::Jmp as u8, 0xff, 0xff] {
+        out_of_bounds.push(byte, 1);
+    }
+
+    let mut unknown = ByteBlock::new();
+    unknown.push(0xff, 1);
+
+    let mut bad_slot = ByteBlock::new();
+    bad_slot.push(OpCode::GetLocal as u8, 1);
+    bad_slot.push(3, 1);
+
+    let mut bad_constant = ByteBlock::new();
+    bad_constant.push_constant(constant::Constant::Number(1.0));
+    for byte in [OpCode::ConstantLong as u8, 0x00, 0x01] {
+        bad_constant.push(byte, 1);
+    }
+
+    let cases = [
+        (truncated, "Truncated instruction operand"),
+        (out_of_bounds, "is out of bounds"),
+        (unknown, "Unknown opcode"),
+        (
+            bad_slot,
+            "Invalid local slot 3 for OP_GET_LOCAL at offset 0000",
+        ),
+        (bad_constant, "Invalid constant index 1 at offset 0000"),
+    ];
+
+    for (block, error) in cases {
+        let mut vm = virtual_machine::VirtualMachine::new();
+        assert_eq!(vm.interpret(block), InterpretResult::RuntimeError);
+        assert!(
+            vm.last_runtime_error().contains(error),
+            "{}",
+            vm.last_runtime_error()
+        );
+    }
+}
+
+#[test]
+fn long_constant_indices() {
+    let terms: Vec<String> = (0..300).map(|i| i.to_string()).collect();
+    let source = format!("let total = {};", terms.join(" + "));
+
+    let output = compiler::Compiler::new(&source).compile();
+    assert!(output.succeeded());
+    assert!(output.block.constants.len() > u8::MAX as usize);
+
+    let mut vm = virtual_machine::VirtualMachine::new();
+    assert_eq!(vm.interpret(output.block), InterpretResult::Ok);
+    assert_eq!(
+        vm.globals()
+            .get("total")
+            .and_then(|total| total.as_number()),
+        Some(&44850.0)
+    );
+}
+
+#[test]
+fn long_global_indices() {
+    use byte_block::ByteBlock;
+
+    let mut source: String = (0..300).map(|i| format!("let g{i} = {i};\n")).collect();
+    source.push_str("g299 = g299 + 1;\nlet size = \"abc\".len();\n");
+
+    for optimize in [false, true] {
+        let mut compiler = compiler::Compiler::new(&source).with_optimizations(optimize);
+        assert!(compiler.compile().succeeded());
+
+        let listing = disassembler::Disassembler::listing(compiler.byte_code());
+        for name in [
+            "OP_DEFINE_GLOBAL_LONG",
+            "OP_GET_GLOBAL_LONG",
+            "OP_SET_GLOBAL_LONG",
+            "OP_INVOKE_LONG",
+        ] {
+            assert!(listing.contains(name), "{name} missing from\n{listing}");
+        }
+
+        let bytes = compiler.byte_code().serialize().unwrap();
+        let block = ByteBlock::deserialize(&bytes).unwrap_or_else(|err| panic!("{err}"));
+
+        let mut vm = virtual_machine::VirtualMachine::new();
+        assert_eq!(vm.interpret(block), InterpretResult::Ok);
+        let global = |name: &str| vm.globals().get(name).and_then(|g| g.as_number()).copied();
+        assert_eq!(global("g0"), Some(0.0));
+        assert_eq!(global("g299"), Some(300.0));
+        assert_eq!(global("size"), Some(3.0));
+    }
+}
+
+#[test]
+fn short_operands_are_big_endian() {
+    use byte_block::{decode_short, encode_short};
+
+    assert_eq!(encode_short(0x1234), [0x12, 0x34]);
+    for value in [0, 1, 0xff, 0x100, 0x1234, u16::MAX] {
+        assert_eq!(decode_short(encode_short(value)), value);
+    }
+}
+
+/// Compiles `source`, checks every jump lands on an instruction boundary
+/// and returns the value of the global `result` after running it.
+fn run_jumps(source: &str) -> constant::Constant {
+    use byte_block::OpCode;
+
+    let output = compiler::Compiler::new(source).compile();
+    assert!(output.succeeded());
+    let block = output.block;
+
+    let mut boundaries = Vec::new();
+    let mut offset = 0;
+    while offset < block.bytes.len() {
+        boundaries.push(offset);
+        offset += OpCode::from(block.bytes[offset]).ok().unwrap().width();
+    }
+    assert_eq!(offset, block.bytes.len());
+
+    let mut jumps = 0;
+    for &offset in boundaries.iter() {
+        let opcode = OpCode::from(block.bytes[offset]).ok().unwrap();
+        if let Some(target) = block.jump_target(offset) {
+            jumps += 1;
+            assert!(boundaries.contains(&target), "jump at {offset} to {target}");
+            match opcode {
+                OpCode::Loop | OpCode::LoopLong => assert!(target < offset),
+                _ => assert!(target > offset),
+            }
+        }
+    }
+    assert!(jumps > 0);
+
+    let mut vm = virtual_machine::VirtualMachine::new();
+    assert_eq!(vm.interpret(block), InterpretResult::Ok);
+    // 'Jz' peeks the condition, the compiler pops it on both branches
+    assert!(vm.stack().is_empty());
+    vm.globals()["result"].clone()
+}
+
+#[test]
+fn jumps() {
+    use constant::Constant;
+
+    let cases = [
+        (
+            "let result = 0; if true { result = 1; } else { result = 2; }",
+            1.0,
+        ),
+        (
+            "let result = 0; if false { result = 1; } else { result = 2; }",
+            2.0,
+        ),
+        ("let result = 0; if false { result = 1; }", 0.0),
+        (
+            "let result = 0; while result < 5 { result = result + 1; }",
+            5.0,
+        ),
+        (
+            "let result = 0; for (let i = 0; i < 4; i = i + 1) { result = result + i; }",
+            6.0,
+        ),
+        ("let result = false || 3;", 3.0),
+        ("let result = 0 && 3;", 0.0),
+        (
+            "let result = 0;
+            let i = 0;
+            while i < 3 {
+                let j = 0;
+                while j < 3 {
+                    if i == j {
+                        result = result + 10;
+                    } else {
+                        if i < j { result = result + 1; }
+                    }
+                    j = j + 1;
+                }
+                i = i + 1;
+            }",
+            33.0,
+        ),
+    ];
+
+    for (source, expected) in cases {
+        assert_eq!(run_jumps(source), Constant::Number(expected), "{source}");
+    }
+}
+
+/// `count` statements of 6 bytes each that add no constants, so the code
+/// around them grows without pushing other operands past a byte.
+fn padding(count: usize) -> String {
+    format!("let pad = 1;\n{}", "pad = -pad;\n".repeat(count))
+}
+
+fn compiled_len(source: &str) -> usize {
+    let mut compiler = compiler::Compiler::new(source);
+    assert!(compiler.compile().succeeded(), "{source}");
+    compiler.byte_code().bytes.len()
+}
+
+#[test]
+fn long_loops_and_jumps() {
+    use byte_block::OpCode;
+    use constant::Constant;
+
+    // loops starting past offset 256 and 65536, and bodies longer than a
+    // byte and just short of a short
+    let cases = [
+        (
+            format!("let result = 0; {{ {} }} while result < 3 {{ result = result + 1; }}", padding(50)),
+            256,
+            3.0,
+        ),
+        (
+            format!("let result = 0; {{ {} }} while result < 3 {{ result = result + 1; }}", padding(11_000)),
+            65_536,
+            3.0,
+        ),
+        (
+            format!("let result = 0; while result < 3 {{ {} result = result + 1; }}", padding(50)),
+            256,
+            3.0,
+        ),
+        (
+            format!("let result = 0; while result < 3 {{ {} result = result + 1; }}", padding(10_800)),
+            64_800,
+            3.0,
+        ),
+        (
+            format!(
+                "let result = 0; {{ {} }} for (let i = 0; i < 4; i = i + 1) {{ {} result = result + i; }}",
+                padding(11_000),
+                padding(10_800)
+            ),
+            65_536,
+            6.0,
+        ),
+        (
+            format!("let result = 0; if false {{ {} }} else {{ result = 2; }}", padding(10_800)),
+            64_800,
+            2.0,
+        ),
+    ];
+
+    for (source, min_len, expected) in cases {
+        assert!(compiled_len(&source) > min_len);
+        assert_eq!(run_jumps(&source), Constant::Number(expected));
+    }
+
+    // bodies too long for a short distance get long jumps, in functions too
+    let long = [
+        (
+            format!("let result = 0; while result < 3 {{ {} result = result + 1; }}", padding(11_000)),
+            OpCode::LoopLong,
+            3.0,
+        ),
+        (
+            format!("let result = 0; if false {{ {} }} else {{ result = 2; }}", padding(11_000)),
+            OpCode::JzLong,
+            2.0,
+        ),
+        (
+            format!(
+                "let result = 0; if result == 0 {{ {} result = 5; }} else {{ {} }}",
+                padding(11_000),
+                padding(11_000)
+            ),
+            OpCode::JmpLong,
+            5.0,
+        ),
+        (
+            format!(
+                "fun f(n) {{ let total = 0; for (let i = 0; i < n; i = i + 1) {{ {} total = total + i; }} return total; }} let result = 0; if true {{ result = f(4); }}",
+                padding(11_000)
+            ),
+            OpCode::LoopLong,
+            6.0,
+        ),
+    ];
+
+    for (source, opcode, expected) in long {
+        let mut compiler = compiler::Compiler::new(&source);
+        assert!(
+            compiler.compile().succeeded(),
+            "{:?}",
+            compiler.diagnostics()
+        );
+        let block = compiler.byte_code();
+        let mut blocks = vec![block.clone()];
+        for (_, constant) in block.constants() {
+            if let Constant::Function(function) = constant {
+                blocks.push((*function.block).clone());
             }
-            Err(..) => panic!("Failed to open file: /{path}"),
         }
+        assert!(blocks.iter().any(|block| opcodes(block).contains(&opcode)));
+
+        assert_eq!(run_jumps(&source), Constant::Number(expected));
     }
+}
 
-    #[test]
-    fn script() {
-        try_run_script("examples/script.dyn");
+fn opcodes(block: &byte_block::ByteBlock) -> Vec<byte_block::OpCode> {
+    let mut opcodes = Vec::new();
+    let mut offset = 0;
+    while offset < block.bytes.len() {
+        let opcode = byte_block::OpCode::from(block.bytes[offset]).ok().unwrap();
+        opcodes.push(opcode);
+        offset += opcode.width();
     }
+    opcodes
+}
+
+#[test]
+fn dependency_graph() {
+    use graph::{DependencyGraph, GraphFormat};
+
+    let graph = DependencyGraph::build("tests/spec/include/nested.dyn").unwrap();
+    assert_eq!(
+        graph.files,
+        [
+            "tests/spec/include/nested.dyn",
+            "tests/spec/include/lib/nested.dyn",
+            "tests/spec/include/lib/common.dyn",
+        ]
+    );
+    assert_eq!(graph.edges.len(), 2);
+    assert!(!graph.has_cycles());
+    assert_eq!(
+        graph.render(GraphFormat::Dot).lines().nth(4),
+        Some("    \"tests/spec/include/nested.dyn\" -> \"tests/spec/include/lib/nested.dyn\";")
+    );
+
+    let graph = DependencyGraph::build("tests/spec/include/lib/cycle.dyn").unwrap();
+    assert_eq!(graph.files.len(), 1);
+    assert!(graph.has_cycles());
+
+    let graph = DependencyGraph::build("tests/spec/include/missing_file.dyn").unwrap();
+    assert_eq!(graph.missing, [1]);
+    let json = dap::json::parse(&graph.render(GraphFormat::Json)).unwrap();
+    assert_eq!(json.get("cycles"), Some(&dap::json::Json::Bool(false)));
+}
+
+#[test]
+fn constant_references() {
+    let mut compiler = compiler::Compiler::new("let a = 1; a = a + 1; print \"a\";");
+    assert!(compiler.compile().succeeded());
+    let block = compiler.byte_code();
+
+    let types: Vec<&str> = block
+        .constants()
+        .map(|(.., constant)| constant.type_to_string())
+        .collect();
+    assert_eq!(types, ["String", "number", "number"]);
+
+    // the name 'a' and the literal "a" share an interned constant
+    let references = block.constant_references();
+    assert_eq!(references[0], [2, 4, 9, 12]);
+    assert_eq!(references[1], [0]);
+    assert_eq!(references[2], [6]);
+}
+
+#[test]
+fn hash_keys() {
+    use constant::{Constant, HashKey};
+    use std::collections::HashMap;
+
+    let key = |value: Constant| HashKey::try_from(&value).unwrap();
+
+    assert_eq!(key(Constant::Number(0.0)), key(Constant::Number(-0.0)));
+    assert_eq!(
+        key(Constant::Number(f64::NAN)),
+        key(Constant::Number(-f64::NAN))
+    );
+    assert_ne!(key(Constant::Number(1.0)), key(Constant::Char('1')));
+
+    let mut map = HashMap::new();
+    map.insert(key(Constant::new_string("one")), 1);
+    map.insert(
+        key(Constant::Tuple(vec![Constant::Bool(true), Constant::Null])),
+        2,
+    );
+    assert_eq!(map.get(&key(Constant::new_string("one"))), Some(&1));
+    assert_eq!(
+        map.get(&key(Constant::Tuple(vec![
+            Constant::Bool(true),
+            Constant::Null
+        ]))),
+        Some(&2)
+    );
+
+    let native = stdlib::io::MODULE.natives[0];
+    let err = HashKey::try_from(&Constant::Tuple(vec![Constant::Native(
+        std::sync::Arc::new(native),
+    )]))
+    .unwrap_err();
+    assert_eq!(err, "Value of type 'native fn' can't be used as a key");
+
+    let value = Constant::new_string("round trip");
+    assert_eq!(Constant::from(&key(value.clone())), value);
+}
+
+#[test]
+fn stack_peek() {
+    let mut stack = stack::Stack::new(4);
+    assert_eq!(stack.peek(0), None);
+
+    stack.push(1);
+    stack.push(2);
+    assert_eq!(stack.peek(0), Some(&2));
+    assert_eq!(stack.peek(1), Some(&1));
+    assert_eq!(stack.peek(2), None);
+    assert_eq!(stack.len(), 2);
+}
+
+#[test]
+fn tuples() {
+    try_run_script("examples/tuples.dyn");
+}
+
+fn interpret_with(vm: &mut virtual_machine::VirtualMachine, source: &str) -> InterpretResult {
+    let output = compiler::Compiler::new(source).compile();
+    assert!(output.succeeded());
+    vm.interpret(output.block)
+}
+
+#[test]
+fn checked_division() {
+    let mut vm = virtual_machine::VirtualMachine::new();
+    assert_eq!(
+        interpret_with(&mut vm, "let x = 1 / 0;"),
+        InterpretResult::RuntimeError
+    );
+    assert!(vm
+        .last_runtime_error()
+        .contains("Division by zero in '1 / 0'"));
+
+    let mut vm = virtual_machine::VirtualMachine::builder()
+        .with_checked_division(false)
+        .build();
+    assert_eq!(
+        interpret_with(&mut vm, "let x = 1 / 0; let y = 0 / 0;"),
+        InterpretResult::Ok
+    );
+    assert_eq!(vm.globals()["x"].as_number(), Some(&f64::INFINITY));
+    assert!(vm.globals()["y"].as_number().unwrap().is_nan());
+
+    // chars divide as bytes, which can't be allowed to divide by zero
+    assert_eq!(
+        interpret_with(&mut vm, "let z = 'a' / 0;"),
+        InterpretResult::RuntimeError
+    );
+}
+
+#[test]
+fn stdlib_can_be_disabled() {
+    let mut vm = virtual_machine::VirtualMachine::builder()
+        .with_stdlib(false)
+        .build();
+    assert!(matches!(
+        interpret_with(&mut vm, "flush();"),
+        InterpretResult::RuntimeError
+    ));
+    assert!(vm
+        .last_runtime_error()
+        .contains("Undefined variable 'flush'"));
+
+    let mut vm = virtual_machine::VirtualMachine::builder()
+        .with_stdlib(false)
+        .enable_module("io")
+        .unwrap()
+        .build();
+    assert!(matches!(
+        interpret_with(&mut vm, "flush();"),
+        InterpretResult::Ok
+    ));
+
+    let err = virtual_machine::VirtualMachine::builder()
+        .enable_module("sockets")
+        .err()
+        .unwrap();
+    assert!(matches!(err, error::DynamixError::UnknownModule(..)));
+    assert_eq!(err.to_string(), "Unknown stdlib module 'sockets'");
+    assert_eq!(err.exit_code(), error::EXIT_USAGE);
+}
+
+#[test]
+fn compile_diagnostics() {
+    use crate::dynamix::diagnostic::{Near, Severity};
+
+    let mut compiler = compiler::Compiler::new("let x = 1;\nprint x +;\nlet = 2;");
+    assert!(!compiler.compile().succeeded());
+
+    let diagnostics = compiler.diagnostics();
+    assert_eq!(diagnostics.len(), 2);
+    assert!(diagnostics.iter().all(|d| d.severity == Severity::Error));
+    assert_eq!(diagnostics[0].span.line, 2);
+    assert_eq!(diagnostics[0].span.near, Near::Lexeme(";".to_string()));
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "[line: 2] Compiler Error: at ';': Expected expression found ';'"
+    );
+    assert_eq!(diagnostics[1].span.line, 3);
+
+    let mut compiler = compiler::Compiler::new("let x = 1;");
+    assert!(compiler.compile().succeeded());
+    assert!(compiler.diagnostics().is_empty());
+}
+
+#[test]
+fn block_names() {
+    let mut compiler = compiler::Compiler::new("print -true;");
+    assert!(compiler.compile().succeeded());
+    assert_eq!(compiler.byte_code().name(), "script");
+
+    let mut compiler = compiler::Compiler::new("print -true;").with_name("examples/script.dyn");
+    assert!(compiler.compile().succeeded());
+    assert_eq!(compiler.byte_code().name(), "examples/script.dyn");
+
+    let mut vm = virtual_machine::VirtualMachine::new();
+    vm.interpret(compiler.byte_code().clone());
+    assert!(vm
+        .last_runtime_error()
+        .ends_with("\n    in examples/script.dyn [line: 1]"));
+}
+
+#[test]
+fn bytecode_round_trip() {
+    use byte_block::ByteBlock;
+    use constant::Constant;
+
+    let source = "fun greet(name) {\n    return \"hi \" + name;\n}\nlet pair = (greet(\"bob\"), 'c', 2.5, true);";
+    let mut compiler = compiler::Compiler::new(source).with_name("greet.dyn");
+    assert!(compiler.compile().succeeded());
+    let block = compiler.byte_code();
+
+    let bytes = block.serialize().unwrap();
+    assert!(serialize::is_bytecode(&bytes));
+
+    let loaded = ByteBlock::deserialize(&bytes).unwrap();
+    assert_eq!(loaded.name(), "greet.dyn");
+    assert_eq!(loaded.bytes, block.bytes);
+    assert_eq!(loaded.lines, block.lines);
+    assert_eq!(loaded.constants.len(), block.constants.len());
+
+    let mut vm = virtual_machine::VirtualMachine::new();
+    assert_eq!(vm.interpret(loaded), InterpretResult::Ok);
+    assert_eq!(
+        vm.get_global("pair"),
+        Some(Constant::Tuple(vec![
+            "hi bob".into(),
+            'c'.into(),
+            2.5.into(),
+            true.into()
+        ]))
+    );
+
+    assert!(ByteBlock::deserialize(b"#!dynamix").is_err());
+    assert!(ByteBlock::deserialize(&bytes[..bytes.len() - 1]).is_err());
+
+    let mut newer = bytes.clone();
+    newer[4] = serialize::VERSION as u8 + 1;
+    let err = ByteBlock::deserialize(&newer).unwrap_err();
+    assert!(err.to_string().contains("Unsupported bytecode version"));
+}
+
+#[test]
+fn malformed_bytecode_is_rejected() {
+    use byte_block::{ByteBlock, OpCode};
+    use constant::Constant;
+
+    let load = |block: &ByteBlock| {
+        let bytes = block.serialize().unwrap();
+        ByteBlock::deserialize(&bytes).unwrap_err().to_string()
+    };
+
+    // OP_DEFINE_GLOBAL with nothing to define
+    let mut underflow = ByteBlock::new();
+    underflow.push(OpCode::DefineGlobal as u8, 1);
+    underflow.write_constant("x".into(), 1);
+    let err = load(&underflow);
+    assert!(err.contains("Stack underflow at offset 0"), "{err}");
+
+    // and the VM reports it instead of panicking if it runs anyway
+    let mut vm = virtual_machine::VirtualMachine::new();
+    assert_eq!(vm.interpret(underflow), InterpretResult::RuntimeError);
+    assert!(vm.last_runtime_error().contains("Stack underflow"));
+
+    // a jump into the operand of OP_CONSTANT
+    let mut jump = ByteBlock::new();
+    jump.push(OpCode::Jmp as u8, 1);
+    jump.push(0, 1);
+    jump.push(1, 1);
+    jump.push(OpCode::Constant as u8, 1);
+    jump.write_constant(1.0.into(), 1);
+    let err = load(&jump);
+    assert!(
+        err.contains("Jump to offset 4 is not an instruction"),
+        "{err}"
+    );
+
+    // paths that meet with different stack depths
+    let mut depths = ByteBlock::new();
+    depths.push(OpCode::True as u8, 1);
+    depths.push(OpCode::Jz as u8, 1);
+    depths.push(0, 1);
+    depths.push(1, 1);
+    depths.push(OpCode::Null as u8, 1);
+    depths.push(OpCode::Pop as u8, 1);
+    let err = load(&depths);
+    assert!(err.contains("doesn't match the depth"), "{err}");
+
+    let mut include = ByteBlock::new();
+    include.push_in(OpCode::Null as u8, 1, 3);
+    let err = load(&include);
+    assert!(err.contains("File index 3"), "{err}");
+
+    let mut nested = Constant::Null;
+    for _ in 0..200 {
+        nested = Constant::Tuple(vec![nested]);
+    }
+    let mut block = ByteBlock::new();
+    block.push_constant(nested);
+    let err = load(&block);
+    assert!(err.contains("nested more than"), "{err}");
+
+    // far deeper than the writer would recurse, the reader must stop early
+    let mut block = ByteBlock::new();
+    block.push_constant(Constant::Null);
+    let bytes = block.serialize().unwrap();
+    // the pool ends with the null's tag, then the empty list of tests
+    let (head, tests) = bytes.split_at(bytes.len() - 5);
+    let mut deep = head.to_vec();
+    for _ in 0..300_000 {
+        // a tuple of one element
+        deep.extend_from_slice(&[5, 1, 0, 0, 0]);
+    }
+    deep.extend_from_slice(tests);
+    let err = ByteBlock::deserialize(&deep).unwrap_err().to_string();
+    assert!(err.contains("nested more than"), "{err}");
+}
+
+/// The compiler's output always passes the checks loaded bytecode goes
+/// through.
+#[test]
+fn compiled_scripts_verify() {
+    use byte_block::ByteBlock;
+
+    let mut paths = Vec::new();
+    let mut dirs = vec![
+        std::path::PathBuf::from("tests/spec"),
+        std::path::PathBuf::from("examples"),
+    ];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            match path.extension() {
+                _ if path.is_dir() => dirs.push(path),
+                Some(extension) if extension == "dyn" => paths.push(path),
+                _ => (),
+            }
+        }
+    }
+
+    for path in paths {
+        let source = std::fs::read_to_string(&path).unwrap();
+        for optimize in [false, true] {
+            let mut compiler = compiler::Compiler::new(&source)
+                .with_path(path.to_str().unwrap())
+                .with_optimizations(optimize);
+            if !compiler.compile().succeeded() {
+                continue;
+            }
+
+            let bytes = compiler.byte_code().serialize().unwrap();
+            if let Err(err) = ByteBlock::deserialize(&bytes) {
+                panic!("{path:?} (optimized: {optimize}): {err}");
+            }
+        }
+    }
+}
+
+#[test]
+fn repl_unclosed_delimiters() {
+    assert_eq!(unclosed_delimiters("print 1;"), 0);
+    assert_eq!(unclosed_delimiters("if true {\n"), 1);
+    assert_eq!(unclosed_delimiters("while x {\n    if y {\n    }\n"), 1);
+    assert_eq!(unclosed_delimiters("fun f() {\n}\n"), 0);
+    assert_eq!(unclosed_delimiters("print clamp(1,\n"), 1);
+    assert_eq!(unclosed_delimiters("print \"multi\n"), 1);
+    assert_eq!(unclosed_delimiters("print \"multi\nline\";\n"), 0);
+    // braces inside strings don't count
+    assert_eq!(unclosed_delimiters("print \"{\";"), 0);
+    // a stray '}' is left for the compiler to report
+    assert_eq!(unclosed_delimiters("}"), 0);
+}
+
+#[test]
+fn native_docs() {
+    use engine::Engine;
+    use native::Native;
+
+    fn greet(_vm: &mut virtual_machine::VirtualMachine, _args: &[Value]) -> Result<Value, String> {
+        Ok(Value::Null)
+    }
+
+    let mut engine = Engine::new();
+    engine
+        .vm_mut()
+        .register_native(Native::new("greet", 1, greet).with_doc("Says hello"));
+
+    let natives = engine.natives();
+    assert!(natives.windows(2).all(|pair| pair[0].name < pair[1].name));
+    assert!(natives.iter().all(|native| !native.doc.is_empty()));
+
+    let clamp = natives
+        .iter()
+        .find(|native| native.name == "clamp")
+        .unwrap();
+    assert_eq!((clamp.arity, clamp.module), (3, Some("ranges")));
+
+    let greet = natives
+        .iter()
+        .find(|native| native.name == "greet")
+        .unwrap();
+    assert_eq!(greet.module, None);
+    assert_eq!(native::help(&[*greet]), "greet/1  [host]    Says hello");
+}
+
+#[test]
+fn token_columns() {
+    use lexer::{Lexer, TokenType};
+
+    let positions: Vec<(TokenType, usize, usize)> = Lexer::new("let x = 1;\n  print x;")
+        .map(|token| (token.typ3, token.line, token.column))
+        .take_while(|(typ3, ..)| *typ3 != TokenType::Eof)
+        .collect();
+
+    assert_eq!(
+        positions,
+        [
+            (TokenType::Let, 1, 1),
+            (TokenType::Ident, 1, 5),
+            (TokenType::Eq, 1, 7),
+            (TokenType::Number, 1, 9),
+            (TokenType::Semicolon, 1, 10),
+            (TokenType::Print, 2, 3),
+            (TokenType::Ident, 2, 9),
+            (TokenType::Semicolon, 2, 10),
+        ]
+    );
+}
+
+#[test]
+fn literal_termination() {
+    use lexer::{Lexer, TokenType};
+
+    let lex = |source: &str| -> Vec<(TokenType, String, usize)> {
+        Lexer::new(source)
+            .take_while(|token| token.typ3 != TokenType::Eof)
+            .map(|token| (token.typ3, token.lexeme, token.line))
+            .collect()
+    };
+    let error = |message: &str, line| (TokenType::Error, message.to_string(), line);
+    let char = |c: &str, line| (TokenType::Char, c.to_string(), line);
+
+    assert_eq!(lex("'"), [error("Unterminated character literal", 1)]);
+    assert_eq!(lex("'a"), [error("Unterminated character literal", 1)]);
+    assert_eq!(lex("'\\"), [error("Unterminated character literal", 1)]);
+    assert_eq!(lex("''"), [error("Empty character literal", 1)]);
+    assert_eq!(lex("'\\'"), [error("Unterminated character literal", 1)]);
+    assert_eq!(
+        lex("'''"),
+        [
+            error("Empty character literal", 1),
+            error("Unterminated character literal", 1)
+        ]
+    );
+    assert_eq!(lex("'\n'\n'x'"), [char("\n", 2), char("x", 3)]);
+
+    // escapes are kept as written, `unescape_char` reads them
+    assert_eq!(lex("'\\''"), [char("\\'", 1)]);
+    assert_eq!(
+        lex("'\\u{1F600}' 'é'"),
+        [char("\\u{1F600}", 1), char("é", 1)]
+    );
+    assert_eq!(lexer::unescape_char("\\u{1F600}"), Some('😀'));
+    assert_eq!(lexer::unescape_char("\\n"), Some('\n'));
+    assert_eq!(lexer::unescape_char("é"), Some('é'));
+    for invalid in ["\\q", "\\u{}", "\\u{D800}", "\\u{1234567}", "\\u41", "ab"] {
+        assert_eq!(lexer::unescape_char(invalid), None, "{invalid}");
+    }
+    assert_eq!(
+        lex("'\\q' 'ab' ;"),
+        [
+            error("Invalid escape sequence '\\q' in character literal", 1),
+            error("Character literal holds more than one char", 1),
+            (TokenType::Semicolon, ";".to_string(), 1)
+        ]
+    );
+
+    assert_eq!(lex("\""), [error("Unterminated string literal", 1)]);
+    assert_eq!(lex("\"abc\\"), [error("Unterminated string literal", 1)]);
+    assert_eq!(lex("\n\"a\nb"), [error("Unterminated string literal", 2)]);
+    assert_eq!(
+        lex("\"a\\\"\""),
+        [
+            (TokenType::String, "\"a\\\"".to_string(), 1),
+            error("Unterminated string literal", 1)
+        ]
+    );
+}
+
+/// Lexes every short input made of the chars that start, end or sit in
+/// literals, none of them may panic or stop before `Eof`.
+#[test]
+fn literal_termination_fuzz() {
+    use lexer::{Lexer, TokenType};
+
+    const CHARS: [char; 10] = ['\'', '"', '\\', '\n', ' ', '/', '{', 'u', '1', '.'];
+
+    let mut sources = vec![String::new()];
+    let mut longest = vec![String::new()];
+    for _ in 0..5 {
+        longest = longest
+            .iter()
+            .flat_map(|source| CHARS.iter().map(move |c| format!("{source}{c}")))
+            .collect();
+        sources.extend(longest.iter().cloned());
+    }
+
+    for source in sources {
+        let tokens: Vec<TokenType> = Lexer::new(&source)
+            .map(|token| token.typ3)
+            .take(source.len() + 1)
+            .collect();
+        assert_eq!(tokens.last(), Some(&TokenType::Eof), "{source:?}");
+    }
+}
+
+/// Every script cut off after each of its chars lexes to the end without
+/// panicking, and compiles without panicking either.
+#[test]
+fn truncated_scripts() {
+    use lexer::{Lexer, TokenType};
+
+    let mut paths = Vec::new();
+    let mut dirs = vec![std::path::PathBuf::from("tests/spec")];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            match path.extension() {
+                _ if path.is_dir() => dirs.push(path),
+                Some(extension) if extension == "dyn" => paths.push(path),
+                _ => (),
+            }
+        }
+    }
+    assert!(!paths.is_empty());
+
+    for path in paths {
+        let source = std::fs::read_to_string(&path).unwrap();
+        for (end, _) in source.char_indices() {
+            let truncated = &source[..end];
+            let tokens: Vec<TokenType> = Lexer::new(truncated)
+                .map(|token| token.typ3)
+                .take(truncated.len() + 1)
+                .collect();
+            assert_eq!(tokens.last(), Some(&TokenType::Eof), "{path:?} at {end}");
+
+            compiler::Compiler::new(truncated).compile();
+        }
+    }
+}
+
+#[test]
+fn globals_in_name_order() {
+    let mut vm = virtual_machine::VirtualMachine::builder()
+        .with_stdlib(false)
+        .build();
+    let source = "let zeta = 1;\nlet alpha = 2;\nlet mid = 3;";
+    assert_eq!(interpret_with(&mut vm, source), InterpretResult::Ok);
+
+    let names: Vec<&str> = vm.globals().keys().map(String::as_str).collect();
+    assert_eq!(names, ["alpha", "mid", "zeta"]);
+}
+
+#[test]
+fn cli_options() {
+    use cli::{Command, Input};
+
+    let parse = |args: &[&str]| {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        cli::parse(&args)
+    };
+
+    let options = parse(&["-e", "print 1;", "-DDEBUG", "-D", "FAST"]).unwrap();
+    assert_eq!(options.command, Command::Run);
+    assert_eq!(options.input, Some(Input::Source("print 1;".to_string())));
+    assert_eq!(options.defines, ["DEBUG", "FAST"]);
+
+    let options = parse(&["compile", "game.dyn"]).unwrap();
+    assert_eq!(options.command, Command::Compile);
+    assert_eq!(options.output_path().as_deref(), Some("game.dynb"));
+    assert!(parse(&["test", "--optimize", "game.dyn"]).unwrap().optimize);
+
+    // no arguments starts the REPL
+    assert_eq!(parse(&[]).unwrap().input, None);
+    assert_eq!(parse(&["test", "-"]).unwrap().input, Some(Input::Stdin));
+
+    assert!(parse(&["--eval"]).is_err());
+    assert!(parse(&["a.dyn", "-e", "print 1;"]).is_err());
+    assert!(parse(&["a.dyn", "-o", "a.dynb"]).is_err());
+    assert!(parse(&["compile", "-e", "print 1;"]).is_err());
+    assert!(parse(&["--verbose", "a.dyn"]).is_err());
+    assert!(parse(&["test", "a.dyn", "--profile", "a.folded"]).is_err());
+    assert!(parse(&["--optimize"]).is_err());
+}
+
+#[test]
+fn semantic_token_kinds() {
+    use semantic::{semantic_tokens, TokenKind, TokenSpan};
+
+    let source =
+        "fun greet(name) {\n    print \"hi \" + name;\n}\nlet c = 'x';\ngreet(clamp(c, 1.5, 2));";
+    let kinds: Vec<TokenKind> = semantic_tokens(source)
+        .into_iter()
+        .map(|(.., kind)| kind)
+        .collect();
+
+    use TokenKind::*;
+    assert_eq!(
+        kinds,
+        [
+            Keyword, Function, Variable, // fun greet(name)
+            Keyword, String, Variable, // print "hi " + name
+            Keyword, Variable, Char, // let c = 'x'
+            Function, Function, Variable, Number, Number,
+        ]
+    );
+
+    let tokens = semantic_tokens(source);
+    assert_eq!(
+        tokens[4],
+        (
+            TokenSpan {
+                line: 2,
+                column: 11,
+                len: 5
+            },
+            String
+        )
+    );
+    assert_eq!(
+        tokens[8].0,
+        TokenSpan {
+            line: 4,
+            column: 9,
+            len: 3
+        }
+    );
+}
+
+#[test]
+fn constant_conversions() {
+    use constant::Constant;
+
+    assert_eq!(Constant::from(1.5), Constant::Number(1.5));
+    assert_eq!(Constant::from(true), Constant::Bool(true));
+    assert_eq!(Constant::from('x'), Constant::Char('x'));
+    assert_eq!(Constant::from("text").as_str(), Some("text"));
+    assert_eq!(
+        Constant::from(vec![1.0.into(), "a".into()]),
+        Constant::Tuple(vec![Constant::Number(1.0), Constant::new_string("a")])
+    );
+
+    assert_eq!(f64::try_from(Constant::Number(2.0)), Ok(2.0));
+    assert_eq!(String::try_from(Constant::from("hi")), Ok("hi".to_string()));
+    assert_eq!(
+        bool::try_from(Constant::Null),
+        Err("Expected bool, found 'null'".to_string())
+    );
+    assert_eq!(
+        f64::try_from(Constant::from("1")),
+        Err("Expected number, found 'String'".to_string())
+    );
+    assert_eq!(
+        String::try_from(Constant::Char('a')),
+        Err("Expected string, found 'char'".to_string())
+    );
+}
+
+#[test]
+fn host_globals() {
+    use constant::Constant;
+
+    let mut vm = virtual_machine::VirtualMachine::new();
+    vm.set_global("limit", Constant::Number(3.0));
+    vm.set_global("greeting", Constant::new_string("hello"));
+    assert_eq!(vm.get_global("result"), None);
+
+    let source = "let result = greeting + \" \" + limit;\nlimit = limit * 2;";
+    assert_eq!(interpret_with(&mut vm, source), InterpretResult::Ok);
+
+    assert_eq!(vm.get_global("limit"), Some(Constant::Number(6.0)));
+    assert_eq!(
+        vm.get_global("result").as_ref().and_then(Constant::as_str),
+        Some("hello 3")
+    );
+}
+
+#[test]
+fn run_for_returns_while_asleep() {
+    use std::time::{Duration, Instant};
+
+    let output = compiler::Compiler::new("let woke = false;\nsleep(30);\nwoke = true;").compile();
+    assert!(output.succeeded());
+
+    let mut vm = virtual_machine::VirtualMachine::new();
+    vm.start(output.block);
+
+    // the script is asleep, so the host gets control back well before the budget
+    let start = Instant::now();
+    assert_eq!(vm.run_for(Duration::from_secs(5)), None);
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(vm.get_global("woke"), Some(constant::Constant::Bool(false)));
+
+    let result = loop {
+        if let Some(result) = vm.run_for(Duration::from_millis(5)) {
+            break result;
+        }
+    };
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(vm.get_global("woke"), Some(constant::Constant::Bool(true)));
+}
+
+#[test]
+fn instruction_count() {
+    let mut vm = virtual_machine::VirtualMachine::new();
+    interpret_with(&mut vm, "let x = 1 + 2;");
+    // OP_CONSTANT, OP_CONSTANT, OP_ADD, OP_DEFINE_GLOBAL, OP_RETURN
+    assert_eq!(vm.instruction_count(), 5);
+
+    let mut count = |source| {
+        interpret_with(&mut vm, source);
+        vm.instruction_count()
+    };
+    assert!(count("let y = 3;") < count("let y = 1 + 2;"));
+}
+
+#[test]
+fn fuel_stops_runaway_scripts() {
+    use error::DynamixError;
+    use std::time::Duration;
+
+    let mut vm = virtual_machine::VirtualMachine::new();
+    vm.set_fuel(100);
+    interpret_with(&mut vm, "let x = 1 + 2;");
+    assert_eq!(vm.fuel(), Some(95));
+
+    // a refueled VM picks up at the instruction it stopped at
+    let output = compiler::Compiler::new("let i = 0;\nwhile i < 10 { i = i + 1; }").compile();
+    vm.set_fuel(10);
+    vm.start(output.block);
+    assert_eq!(
+        vm.run_for(Duration::from_secs(5)),
+        Some(InterpretResult::OutOfFuel)
+    );
+    assert_eq!(vm.instruction_count(), 10);
+    vm.set_fuel(1_000);
+    assert_eq!(
+        vm.run_for(Duration::from_secs(5)),
+        Some(InterpretResult::Ok)
+    );
+    assert_eq!(vm.get_global("i"), Some(10.0.into()));
+
+    let mut engine = engine::Engine::builder().with_fuel(1_000).build();
+    engine.eval("fun spin() { while true {} }").unwrap();
+    assert!(matches!(
+        engine.call("spin", &[]),
+        Err(DynamixError::OutOfFuel)
+    ));
+    assert!(matches!(engine.eval("1;"), Err(DynamixError::OutOfFuel)));
+
+    engine.vm_mut().set_fuel(1_000);
+    assert!(engine.eval("1;").is_ok());
+}
+
+#[test]
+fn debugger_stepping() {
+    use debugger::{DebugEvent, Debugger};
+
+    let source = "let a = 1;\n{\n    let b = a + 1;\n    print b;\n}\nprint a;";
+    let output = compiler::Compiler::new(source).compile();
+    assert!(output.succeeded());
+
+    let mut vm = virtual_machine::VirtualMachine::new();
+    let mut debugger = Debugger::attach(&mut vm);
+    debugger.load(output.block, "test.dyn");
+    debugger.set_breakpoint("test.dyn", 4);
+
+    assert_eq!(debugger.current_line(), Some(1));
+    assert_eq!(debugger.step(), DebugEvent::Stepped);
+
+    let event = debugger.resume();
+    assert_eq!(
+        event,
+        DebugEvent::Breakpoint {
+            file: "test.dyn".to_string(),
+            line: 4
+        }
+    );
+    assert_eq!(
+        debugger.read_locals(0),
+        Some(vec![("b".to_string(), constant::Constant::Number(2.0))])
+    );
+    assert_eq!(debugger.read_locals(1), None);
+
+    assert_eq!(debugger.resume(), DebugEvent::Finished(InterpretResult::Ok));
+    assert!(debugger.read_locals(0).unwrap().is_empty());
+}
+
+#[test]
+fn debugger_breakpoint_on_first_line() {
+    use debugger::{DebugEvent, Debugger};
+
+    let output = compiler::Compiler::new("let a = 1;\nlet b = 2;\nprint a + b;").compile();
+    assert!(output.succeeded());
+
+    let mut vm = virtual_machine::VirtualMachine::new();
+    let mut debugger = Debugger::attach(&mut vm);
+    debugger.load(output.block, "test.dyn");
+    debugger.set_breakpoint("test.dyn", 1);
+    debugger.set_breakpoint("test.dyn", 2);
+    let breakpoint = |line| DebugEvent::Breakpoint {
+        file: "test.dyn".to_string(),
+        line,
+    };
+
+    // stops before anything runs, and resuming from there moves on
+    assert_eq!(debugger.resume(), breakpoint(1));
+    assert_eq!(debugger.current_line(), Some(1));
+    assert_eq!(debugger.resume(), breakpoint(2));
+    assert_eq!(debugger.current_line(), Some(2));
+
+    // paused on a line with a breakpoint by stepping, resuming reports it
+    let output = compiler::Compiler::new("let a = 1;\nlet b = 2;\nprint a + b;").compile();
+    debugger.load(output.block, "test.dyn");
+    assert_eq!(debugger.step_line(), DebugEvent::Stepped);
+    assert_eq!(debugger.current_line(), Some(2));
+    assert_eq!(debugger.resume(), breakpoint(2));
+    assert_eq!(debugger.resume(), DebugEvent::Finished(InterpretResult::Ok));
+}
+
+#[test]
+fn debugger_steps_by_frame() {
+    use debugger::{DebugEvent, Debugger};
+
+    let source = "fun add(x) {\n    let y = x + 1;\n    return y;\n}\nlet a = add(1);\nprint a;";
+    let output = compiler::Compiler::new(source)
+        .with_name("test.dyn")
+        .compile();
+    assert!(output.succeeded());
+
+    let mut vm = virtual_machine::VirtualMachine::builder()
+        .with_output(std::io::sink())
+        .build();
+    let mut debugger = Debugger::attach(&mut vm);
+    debugger.load(output.block, "test.dyn");
+    let lines = |debugger: &Debugger| -> Vec<(String, u32)> {
+        debugger
+            .stack_frames()
+            .into_iter()
+            .map(|frame| (frame.function, frame.line))
+            .collect()
+    };
+
+    // step over the call to the next line of the script
+    while debugger.current_line() != Some(5) {
+        assert_eq!(debugger.step_line(), DebugEvent::Stepped);
+    }
+    assert_eq!(debugger.step_over(), DebugEvent::Stepped);
+    assert_eq!(lines(&debugger), [("test.dyn".to_string(), 6)]);
+
+    // step into the call, then out of it back to the caller
+    let output = compiler::Compiler::new(source)
+        .with_name("test.dyn")
+        .compile();
+    debugger.load(output.block, "test.dyn");
+    while debugger.current_line() != Some(5) {
+        debugger.step_line();
+    }
+    assert_eq!(debugger.step_line(), DebugEvent::Stepped);
+    assert_eq!(
+        lines(&debugger),
+        [("add".to_string(), 2), ("test.dyn".to_string(), 5)]
+    );
+    assert_eq!(debugger.step_over(), DebugEvent::Stepped);
+    assert_eq!(lines(&debugger)[0], ("add".to_string(), 3));
+    assert_eq!(debugger.step_out(), DebugEvent::Stepped);
+    assert_eq!(lines(&debugger), [("test.dyn".to_string(), 5)]);
+
+    // stepping out of the script runs it to the end
+    assert_eq!(
+        debugger.step_out(),
+        DebugEvent::Finished(InterpretResult::Ok)
+    );
+    assert!(debugger.stack_frames().is_empty());
+}
+
+#[test]
+fn json_round_trip() {
+    use dap::json::{self, Json};
+
+    let source = r#"{"a":[1,2.5,true,null],"b":"x\"y\n","c":{}}"#;
+    let value = json::parse(source).unwrap();
+
+    assert_eq!(value.get("b").and_then(Json::as_str), Some("x\"y\n"));
+    assert_eq!(json::parse(&value.to_string()).unwrap(), value);
+    assert!(json::parse("{\"a\":}").is_err());
+}
+
+#[test]
+fn dap_session() {
+    use dap::json::{self, Json};
+
+    let path = std::env::temp_dir().join("dynamix_dap_session.dyn");
+    std::fs::write(&path, "let a = 1;\n{\n    let b = a + 1;\n    b = b;\n}\n").unwrap();
+    let path = path.to_str().unwrap().replace('\\', "/");
+
+    let requests = [
+        r#"{"seq":1,"type":"request","command":"initialize","arguments":{}}"#.to_string(),
+        format!(
+            r#"{{"seq":2,"type":"request","command":"launch","arguments":{{"program":"{path}"}}}}"#
+        ),
+        format!(
+            r#"{{"seq":3,"type":"request","command":"setBreakpoints","arguments":{{"source":{{"path":"{path}"}},"breakpoints":[{{"line":4}}]}}}}"#
+        ),
+        r#"{"seq":4,"type":"request","command":"configurationDone"}"#.to_string(),
+        r#"{"seq":5,"type":"request","command":"variables","arguments":{"variablesReference":1}}"#
+            .to_string(),
+        r#"{"seq":6,"type":"request","command":"stackTrace","arguments":{"threadId":1}}"#
+            .to_string(),
+        r#"{"seq":7,"type":"request","command":"pause","arguments":{"threadId":1}}"#.to_string(),
+        r#"{"seq":8,"type":"request","command":"continue","arguments":{"threadId":1}}"#.to_string(),
+        r#"{"seq":9,"type":"request","command":"disconnect"}"#.to_string(),
+    ];
+    let input: String = requests
+        .iter()
+        .map(|body| format!("Content-Length: {}\r\n\r\n{body}", body.len()))
+        .collect();
+
+    let mut output = Vec::new();
+    let mut vm = virtual_machine::VirtualMachine::new();
+    dap::Session::new(
+        debugger::Debugger::attach(&mut vm),
+        std::io::Cursor::new(input),
+        &mut output,
+    )
+    .run()
+    .unwrap();
+
+    let output = String::from_utf8(output).unwrap();
+    let messages: Vec<Json> = output
+        .split("Content-Length: ")
+        .filter(|message| !message.is_empty())
+        .map(|message| json::parse(message.split("\r\n\r\n").nth(1).unwrap()).unwrap())
+        .collect();
+
+    let events: Vec<&str> = messages
+        .iter()
+        .filter_map(|message| message.get("event").and_then(Json::as_str))
+        .collect();
+    assert_eq!(events, ["initialized", "stopped", "exited", "terminated"]);
+
+    let variables = messages
+        .iter()
+        .find(|message| message.get("command").and_then(Json::as_str) == Some("variables"))
+        .and_then(|message| message.get("body")?.get("variables")?.as_array())
+        .unwrap();
+    assert_eq!(variables[0].get("name").and_then(Json::as_str), Some("b"));
+    assert_eq!(variables[0].get("value").and_then(Json::as_str), Some("2"));
+
+    let response = |command: &str| {
+        messages
+            .iter()
+            .find(|message| message.get("command").and_then(Json::as_str) == Some(command))
+            .unwrap()
+    };
+    let frames = response("stackTrace")
+        .get("body")
+        .and_then(|body| body.get("stackFrames")?.as_array())
+        .unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].get("line").and_then(Json::as_f64), Some(4.0));
+    assert_eq!(
+        response("pause").get("success").and_then(Json::as_bool),
+        Some(false)
+    );
+}
+
+#[test]
+fn dap_frames_in_included_files() {
+    use dap::json::{self, Json};
+
+    let dir = std::env::temp_dir().join("dynamix_dap_include");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("lib.dyn"), "fun helper() {\n    return 1;\n}\n").unwrap();
+    std::fs::write(dir.join("main.dyn"), "#include \"lib.dyn\"\nhelper();\n").unwrap();
+    let path = dir.join("main.dyn").to_str().unwrap().replace('\\', "/");
+    let include = dir.join("lib.dyn").to_str().unwrap().replace('\\', "/");
+
+    let requests = [
+        r#"{"seq":1,"type":"request","command":"initialize","arguments":{}}"#.to_string(),
+        format!(
+            r#"{{"seq":2,"type":"request","command":"launch","arguments":{{"program":"{path}"}}}}"#
+        ),
+        format!(
+            r#"{{"seq":3,"type":"request","command":"setBreakpoints","arguments":{{"source":{{"path":"{path}"}},"breakpoints":[{{"line":2}}]}}}}"#
+        ),
+        r#"{"seq":4,"type":"request","command":"configurationDone"}"#.to_string(),
+        r#"{"seq":5,"type":"request","command":"stepIn","arguments":{"threadId":1}}"#.to_string(),
+        r#"{"seq":6,"type":"request","command":"stackTrace","arguments":{"threadId":1}}"#
+            .to_string(),
+        r#"{"seq":7,"type":"request","command":"disconnect"}"#.to_string(),
+    ];
+    let input: String = requests
+        .iter()
+        .map(|body| format!("Content-Length: {}\r\n\r\n{body}", body.len()))
+        .collect();
+
+    let mut output = Vec::new();
+    let mut vm = virtual_machine::VirtualMachine::new();
+    dap::Session::new(
+        debugger::Debugger::attach(&mut vm),
+        std::io::Cursor::new(input),
+        &mut output,
+    )
+    .run()
+    .unwrap();
+
+    let output = String::from_utf8(output).unwrap();
+    let frames = output
+        .split("Content-Length: ")
+        .filter(|message| !message.is_empty())
+        .map(|message| json::parse(message.split("\r\n\r\n").nth(1).unwrap()).unwrap())
+        .find(|message| message.get("command").and_then(Json::as_str) == Some("stackTrace"))
+        .and_then(|message| {
+            message
+                .get("body")?
+                .get("stackFrames")?
+                .as_array()
+                .map(<[Json]>::to_vec)
+        })
+        .unwrap();
+
+    let source = |frame: &Json| {
+        let path = frame.get("source")?.get("path")?.as_str()?.to_string();
+        Some((path, frame.get("line")?.as_f64()?))
+    };
+    assert_eq!(source(&frames[0]), Some((include, 2.0)));
+    assert_eq!(source(&frames[1]), Some((path, 2.0)));
+}
+
+#[test]
+fn profile_folded_stacks() {
+    let mut engine = engine::Engine::builder().with_profiling(true).build();
+    engine
+        .eval("fun fib(n) { if n < 2 { return n; } return fib(n - 1) + fib(n - 2); }\nfib(3);")
+        .unwrap();
+
+    let profile = engine.vm().profile().unwrap();
+    assert!(profile.samples_of("script") > 0);
+    assert!(profile.samples_of("script;fib;fib;fib") > 0);
+    assert_eq!(profile.samples_of("script;fib;fib;fib;fib"), 0);
+
+    let folded = profile.folded();
+    assert!(folded.lines().all(|line| line.starts_with("script")));
+    let total: u64 = folded
+        .lines()
+        .map(|line| line.rsplit_once(' ').unwrap().1.parse::<u64>().unwrap())
+        .sum();
+    assert_eq!(total, profile.samples());
+
+    assert!(engine::Engine::new().vm().profile().is_none());
+}
+
+#[test]
+fn cancel_a_running_script() {
+    use error::DynamixError;
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    let mut engine = engine::Engine::new();
+    for source in ["while true {}", "sleep(60000);"] {
+        let token = engine.cancellation_token();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            token.cancel();
+        });
+
+        let start = Instant::now();
+        assert!(matches!(
+            engine.eval(source),
+            Err(DynamixError::Interrupted)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        canceller.join().unwrap();
+    }
+
+    // the cancel is used up by the run it stopped
+    assert!(!engine.cancellation_token().is_cancelled());
+    assert!(engine.eval("let x = 1;").is_ok());
+}
+
+#[test]
+fn timeout_stops_long_runs() {
+    use error::DynamixError;
+    use std::time::{Duration, Instant};
+
+    let mut engine = engine::Engine::builder()
+        .with_timeout(Duration::from_millis(50))
+        .build();
+    for source in ["while true {}", "sleep(60000);"] {
+        let start = Instant::now();
+        assert!(matches!(engine.eval(source), Err(DynamixError::TimedOut)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    // every run gets the whole timeout
+    engine.eval("fun nap() { sleep(30); }").unwrap();
+    assert!(engine.call("nap", &[]).is_ok());
+    assert!(engine.call("nap", &[]).is_ok());
+}
+
+#[test]
+fn stack_overflow_names_the_calls() {
+    let source = "fun d() { return 1; }\nfun c() { return d(); }\nfun b() { return c(); }\nfun a() { return b(); }";
+
+    let mut engine = engine::Engine::builder().with_max_frames(3).build();
+    engine.eval(source).unwrap();
+    assert!(engine.eval("b();").is_ok());
+
+    let err = engine.eval("a();").unwrap_err().to_string();
+    assert!(
+        err.contains("more than 3 nested calls: 'script -> a -> b -> c -> d'"),
+        "{err}"
+    );
+}
+
+#[test]
+fn stack_overflow_counts_values() {
+    // each call leaves its four arguments on the stack, long before the
+    // frames run out
+    let source = "fun f(a, b, c, d) { return f(a, b, c, d); }";
+
+    let mut engine = engine::Engine::builder().with_max_stack(64).build();
+    engine.eval(source).unwrap();
+
+    let err = engine.eval("f(1, 2, 3, 4);").unwrap_err().to_string();
+    assert!(
+        err.contains("more than 64 values on the stack: 'f -> f' repeats"),
+        "{err}"
+    );
+}
+
+#[test]
+fn runtime_errors_have_a_backtrace() {
+    use error::{DynamixError, TraceFrame};
+
+    let source = "fun inner(x) {\n    return x + true;\n}\nfun outer(x) {\n    return inner(x);\n}\nfun down(n) {\n    if n == 0 { return outer(n); }\n    return down(n - 1);\n}";
+    let mut engine = engine::Engine::new();
+    engine.eval(source).unwrap();
+
+    let frame = |function: &str, line| TraceFrame {
+        function: function.to_string(),
+        line,
+        file: None,
+    };
+    match engine.eval("down(2);") {
+        Err(DynamixError::RuntimeError {
+            message, backtrace, ..
+        }) => {
+            assert_eq!(
+                backtrace,
+                [
+                    frame("inner", 2),
+                    frame("outer", 5),
+                    frame("down", 8),
+                    frame("down", 9),
+                    frame("down", 9),
+                    frame("script", 1),
+                ]
+            );
+            assert!(
+                message.ends_with(
+                    "\n    in down [line: 9]\n    ... repeated 1 more times\n    in script [line: 1]"
+                ),
+                "{message}"
+            );
+        }
+        other => panic!("expected a runtime error, got {other:?}"),
+    }
+
+    // calls from the host start at the function
+    match engine.call("outer", &[1.0.into()]) {
+        Err(DynamixError::RuntimeError { backtrace, .. }) => {
+            assert_eq!(backtrace, [frame("inner", 2), frame("outer", 5)]);
+        }
+        other => panic!("expected a runtime error, got {other:?}"),
+    }
+}
+
+#[test]
+fn repl_echoes_expression_statements() {
+    use byte_block::OpCode;
+
+    let opcodes = |echo: bool| {
+        let source = "let x = 1;\nx = 2;\nx + 1;\nif true { x; }";
+        let mut compiler = compiler::Compiler::new(source).with_echo(echo);
+        assert!(compiler.compile().succeeded());
+
+        let block = compiler.byte_code();
+        let mut opcodes = Vec::new();
+        let mut offset = 0;
+        while offset < block.bytes.len() {
+            let opcode = OpCode::from(block.bytes[offset]).ok().unwrap();
+            opcodes.push(opcode);
+            offset += opcode.width();
+        }
+        opcodes
+    };
+
+    let count = |opcodes: &[OpCode], opcode| opcodes.iter().filter(|op| **op == opcode).count();
+
+    // only 'x + 1' is printed, not the assignment or the statement in a block
+    let echoed = opcodes(true);
+    assert_eq!(count(&echoed, OpCode::Print), 1);
+
+    let script = opcodes(false);
+    assert_eq!(count(&script, OpCode::Print), 0);
+    assert_eq!(count(&script, OpCode::Pop), count(&echoed, OpCode::Pop) + 1);
+}
+
+#[test]
+fn optional_final_semicolon() {
+    let compiles = |source: &str, optional: bool| {
+        compiler::Compiler::new(source)
+            .with_optional_final_semicolon(optional)
+            .compile()
+            .succeeded()
+    };
+
+    // REPL lines end with their new line
+    for source in [
+        "print 1 + 2\n",
+        "let x = 1;\nx = x + 1\n",
+        "let (a, b) = (1, 2)\n",
+    ] {
+        assert!(compiles(source, true), "{source}");
+        assert!(!compiles(source, false), "{source}");
+    }
+
+    // only the last statement may leave it out
+    assert!(!compiles("print 1 print 2\n", true));
+    assert!(!compiles("fun f() { return 1 }\n", true));
+}
+
+#[cfg(feature = "csv")]
+#[test]
+fn csv_round_trip() {
+    let mut engine = engine::Engine::new();
+
+    let text = "name,note\r\nada,\"says \"\"hi\"\", then\"\nbob,";
+    let rows = engine
+        .call("csv_parse", &[Value::new_string(text)])
+        .unwrap();
+    assert_eq!(
+        rows.to_string(),
+        "((name, note), (ada, says \"hi\", then), (bob, ))"
+    );
+
+    let written = engine.call("csv_write", &[rows]).unwrap();
+    assert_eq!(
+        written.as_str(),
+        Some("name,note\nada,\"says \"\"hi\"\", then\"\nbob,\n")
+    );
+
+    let unterminated = engine.call("csv_parse", &[Value::new_string("a,\"b")]);
+    assert!(unterminated.is_err());
+    assert!(engine.eval("csv_write((1, 2));").is_err());
+}
+
+#[cfg(feature = "config")]
+#[test]
+fn config_files() {
+    fn get(engine: &mut engine::Engine, config: &Value, path: &str) -> String {
+        let args = [config.clone(), Value::new_string(path)];
+        engine.call("config_get", &args).unwrap().to_string()
+    }
+
+    let mut engine = engine::Engine::new();
+    let mut parse = |native: &str, text: &str| engine.call(native, &[Value::new_string(text)]);
+
+    let toml = "title = \"demo\" # a comment\n\n[server]\nhost = 'localhost'\nports = [8000, 8_001,\n  0x1F42]\nlimits = { rate = 1.5, burst.max = 10 }\n\n[[users]]\nname = \"ada\"\n[[users]]\nname = \"bob\"\nadmin = true\n";
+    let yaml = "# settings\nname: demo\nserver:\n  host: \"local host\"\n  ports: [8000, 8001]\nusers:\n- name: ada\n  roles:\n    - admin\n    - dev\n- name: bob's # plain scalar\n  active: false\nempty:\n";
+    let toml = parse("toml_parse", toml).unwrap();
+    let yaml = parse("yaml_parse", yaml).unwrap();
+    let fruits = "[[fruit]]\n[fruit.info]\nn = 1\n[[fruit]]\n[fruit.info]\nn = 2";
+    let fruits = parse("toml_parse", fruits).unwrap();
+
+    for (native, text) in [
+        ("toml_parse", "a = 1\na = 2"),
+        ("toml_parse", "when = 1979-05-27"),
+        ("toml_parse", "[a]\nx = 1\n[b]\n[a]\ny = 2"),
+        ("toml_parse", "n = 1__0"),
+        ("toml_parse", "n = 1_"),
+        ("toml_parse", "n = 0x_ff"),
+        ("yaml_parse", "a: 1\n    b: 2"),
+        ("yaml_parse", "a: 1\na: 2"),
+        ("yaml_parse", ": 1"),
+        ("yaml_parse", "a: &anchor 1\nb: *anchor"),
+        ("yaml_parse", "b: *anchor"),
+        ("yaml_parse", "a: b: c"),
+        ("yaml_parse", "- a: b: c"),
+        ("yaml_parse", "a: {: 1}"),
+    ] {
+        assert!(parse(native, text).is_err(), "{native} accepted {text:?}");
+    }
+
+    assert_eq!(get(&mut engine, &toml, "title"), "demo");
+    assert_eq!(get(&mut engine, &toml, "server.host"), "localhost");
+    assert_eq!(
+        get(&mut engine, &toml, "server.ports"),
+        "(8000, 8001, 8002)"
+    );
+    assert_eq!(get(&mut engine, &toml, "server.limits.burst.max"), "10");
+    assert_eq!(get(&mut engine, &toml, "users.1.name"), "bob");
+    assert_eq!(get(&mut engine, &toml, "users.1.admin"), "true");
+    assert_eq!(get(&mut engine, &toml, "users.0.admin"), "null");
+
+    // every element of an array of tables has its own sub-tables
+    assert_eq!(get(&mut engine, &fruits, "fruit.1.info.n"), "2");
+
+    assert_eq!(get(&mut engine, &yaml, "server.host"), "local host");
+    assert_eq!(get(&mut engine, &yaml, "server.ports.1"), "8001");
+    assert_eq!(get(&mut engine, &yaml, "users.0.roles"), "(admin, dev)");
+    assert_eq!(get(&mut engine, &yaml, "users.1.name"), "bob's");
+    assert_eq!(get(&mut engine, &yaml, "users.1.active"), "false");
+    assert_eq!(get(&mut engine, &yaml, "empty"), "null");
+}
+
+#[test]
+fn print_to_custom_output() {
+    let captured = Captured::default();
+    let mut engine = engine::Engine::builder()
+        .with_output(captured.clone())
+        .build();
+    engine.eval("print 1 + 2;\nprint \"done\";").unwrap();
+
+    let output = captured.text();
+    assert_eq!(output, "3\ndone\n");
+}
+
+#[test]
+fn compiler_passes_rewrite_the_tree() {
+    use parser::{Expr, Literal, Program, Stmt};
+
+    // doubles every number printed, then drops the asserts
+    let double = |program: &mut Program| {
+        for stmt in &mut program.statements {
+            if let Stmt::Print {
+                value:
+                    Expr::Literal {
+                        value: Literal::Number(n),
+                        ..
+                    },
+                ..
+            } = stmt
+            {
+                *n *= 2.0;
+            }
+        }
+    };
+    let strip_asserts = |program: &mut Program| {
+        program
+            .statements
+            .retain(|stmt| !matches!(stmt, Stmt::Assert { .. }))
+    };
+
+    let captured = Captured::default();
+    let mut engine = engine::Engine::builder()
+        .with_output(captured.clone())
+        .with_pass(double)
+        .with_pass(strip_asserts)
+        .build();
+    engine
+        .eval("print 21;\nassert(false);\nprint \"x\";")
+        .unwrap();
+    assert_eq!(captured.text(), "42\nx\n");
+
+    // a script that doesn't parse reports its own errors
+    assert!(engine.eval("print 1 +;").is_err());
+}
+
+#[test]
+fn failing_output_is_a_runtime_error() {
+    use error::DynamixError;
+
+    struct BrokenPipe;
+
+    impl std::io::Write for BrokenPipe {
+        fn write(&mut self, _bytes: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut engine = engine::Engine::builder().with_output(BrokenPipe).build();
+
+    // buffered until the run ends, 'flush' and a full buffer write earlier
+    for source in [
+        "print 1;",
+        "print 1;\nflush();",
+        "let s = \"x\";\nwhile true { s = s + s; print s; }",
+    ] {
+        match engine.eval(source) {
+            Err(DynamixError::RuntimeError { message, .. }) => {
+                assert!(message.contains("Failed to write output"), "{message}")
+            }
+            other => panic!("expected a runtime error, got {other:?}"),
+        }
+    }
+
+    // what couldn't be written isn't tried again
+    engine.eval("print 1;\nflush();").unwrap_err();
+    assert!(engine.eval("let x = 1;").is_ok());
+}
+
+#[test]
+fn error_sink_collects_errors() {
+    use std::sync::{Arc, Mutex};
+
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let sink = errors.clone();
+    let mut engine = engine::Engine::builder()
+        .with_error_sink(move |err| sink.lock().unwrap().push(err.exit_code()))
+        .build();
+
+    engine.eval("print 1 +;").unwrap_err();
+    engine.eval("print -true;").unwrap_err();
+    engine.eval_file("tests/spec/missing.dyn").unwrap_err();
+    engine.call("missing", &[]).unwrap_err();
+    engine.eval("print 1;").unwrap();
+
+    assert_eq!(
+        *errors.lock().unwrap(),
+        [
+            error::EXIT_COMPILE_ERROR,
+            error::EXIT_RUNTIME_ERROR,
+            error::EXIT_NO_INPUT,
+            error::EXIT_RUNTIME_ERROR
+        ]
+    );
+}
+
+#[test]
+fn disassembly_listing() {
+    use disassembler::Disassembler;
+
+    let block = engine::Engine::new()
+        .compile("fun add(a, b) { return a + b; }\nprint add(1, 2);")
+        .unwrap();
+
+    let expected = "\
+-- script --
+0000 0001 OP_CONSTANT      0001 <fn add>
+0002    | OP_DEFINE_GLOBAL 0000 add
+0004 0002 OP_GET_GLOBAL    0000 add
+0006    | OP_CONSTANT      0002 1
+0008    | OP_CONSTANT      0003 2
+0010    | OP_CALL          0002
+0012    | OP_PRINT
+0013    | OP_RETURN
+
+-- add --
+0000 0001 OP_GET_LOCAL     0001
+0002    | OP_GET_LOCAL     0002
+0004    | OP_ADD
+0005    | OP_RETURN
+0006    | OP_NULL
+0007    | OP_RETURN
+";
+    assert_eq!(Disassembler::listing(&block), expected);
+
+    let mut constants = String::new();
+    Disassembler::write_constants(&mut constants, &block).unwrap();
+    assert!(constants.starts_with("-- constants --\n0000 String"));
+}
+
+#[test]
+fn toggle_tracing_mid_run() {
+    use native::Native;
+
+    fn trace(vm: &mut virtual_machine::VirtualMachine, args: &[Value]) -> Result<Value, String> {
+        vm.set_tracing(args[0] == Value::Bool(true));
+        Ok(Value::Null)
+    }
+
+    let captured = Captured::default();
+    let mut engine = engine::Engine::builder()
+        .with_output(captured.clone())
+        .with_trace_output(captured.clone())
+        .with_tracing(false)
+        .build();
+    engine
+        .vm_mut()
+        .register_native(Native::new("trace", 1, trace));
+
+    let switch = engine.trace_switch();
+    engine
+        .eval("print 1;\ntrace(true);\nprint 2;\ntrace(false);\nprint 3;")
+        .unwrap();
+    assert!(!switch.is_on());
+
+    let output = captured.text();
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.first(), Some(&"1"));
+    assert_eq!(lines.last(), Some(&"3"));
+    assert_eq!(lines[1], "-- script --");
+    assert!(lines[2].starts_with("offset line opcode"), "{output}");
+    // only the lines between the calls are traced
+    assert!(output.contains("    3 OP_PRINT"), "{output}");
+    assert!(!output.contains("    1 OP_PRINT"), "{output}");
+    assert!(!output.contains("    5 OP_PRINT"), "{output}");
+}
+
+#[test]
+fn seeded_random_numbers_repeat() {
+    let numbers = |seed| {
+        let mut engine = engine::Engine::builder().with_random_seed(seed).build();
+        (0..4)
+            .map(|_| engine.call("random_range", &[Value::Number(0.0), Value::Number(1e9)]))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    };
+
+    assert_eq!(numbers(7), numbers(7));
+    assert_ne!(numbers(7), numbers(8));
+}
+
+#[test]
+fn parser_builds_syntax_tree() {
+    use lexer::TokenType;
+    use parser::{Expr, Literal, Stmt};
+
+    let mut parser = parser::Parser::new("let x = 1 + 2 * (3);\nprint x +;\nf(x).g(\"s\");");
+    let program = parser.parse();
+
+    assert_eq!(parser.diagnostics().len(), 1);
+    assert_eq!(parser.diagnostics()[0].span.line, 2);
+    assert_eq!(program.eof.typ3, TokenType::Eof);
+    assert_eq!(program.statements.len(), 3);
+
+    let Stmt::Let {
+        name,
+        value:
+            Some(Expr::Binary {
+                left,
+                operator,
+                right,
+            }),
+        ..
+    } = &program.statements[0]
+    else {
+        panic!("expected a let with a binary value");
+    };
+    assert_eq!(name.lexeme, "x");
+    assert_eq!(operator.typ3, TokenType::Plus);
+    assert!(matches!(
+        **left,
+        Expr::Literal {
+            value: Literal::Number(n),
+            ..
+        } if n == 1.0
+    ));
+    // '*' binds tighter, the last token of the right operand is the ')'
+    assert!(matches!(**right, Expr::Binary { .. }));
+    assert_eq!(right.end().typ3, TokenType::RParen);
+
+    // the declaration that didn't parse is left out, the rest is kept
+    assert!(matches!(program.statements[1], Stmt::Error));
+    let Stmt::Expression {
+        expr:
+            Expr::Invoke {
+                receiver,
+                name,
+                args,
+                ..
+            },
+        ..
+    } = &program.statements[2]
+    else {
+        panic!("expected an invoke");
+    };
+    assert!(matches!(**receiver, Expr::Call { .. }));
+    assert_eq!(name.lexeme, "g");
+    assert!(matches!(
+        &args[..],
+        [Expr::Literal {
+            value: Literal::String(s),
+            ..
+        }] if s == "s"
+    ));
+}
+
+#[test]
+fn codegen_resolves_names() {
+    let mut compiler = compiler::Compiler::new("{ let a = 1; let a = 2; }\nprint 1 +;\nreturn 3;");
+    assert!(!compiler.compile().succeeded());
+
+    // syntax errors come first, then the errors found resolving names
+    let messages: Vec<(usize, &str)> = compiler
+        .diagnostics()
+        .iter()
+        .filter(|d| d.is_error())
+        .map(|d| (d.span.line, d.message.as_str()))
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            (2, "Expected expression found ';'"),
+            (1, "Redefined variable 'a' in the same scope"),
+            (3, "Can't return from top-level code"),
+        ]
+    );
+}
+
+#[test]
+fn codegen_warnings() {
+    use crate::dynamix::diagnostic::Severity;
+
+    let source = "fun f(a, _b) {\n  let x = 1;\n  let y = 2;\n  { let y = 3; print y; }\n  return a;\n  print y;\n}\nprint f(1, 2);";
+    let output = compiler::Compiler::new(source).compile();
+    assert!(output.succeeded());
+
+    let warnings: Vec<(usize, &str)> = output
+        .warnings()
+        .map(|d| (d.span.line, d.message.as_str()))
+        .collect();
+    assert_eq!(
+        warnings,
+        [
+            (4, "Variable 'y' shadows the one declared on line 3"),
+            (6, "Unreachable code"),
+            (2, "Unused variable 'x'"),
+        ]
+    );
+    assert!(output
+        .diagnostics
+        .iter()
+        .all(|d| d.severity == Severity::Warning));
+}
+
+#[test]
+fn compile_output_symbols_and_lines() {
+    use compile_output::SymbolKind;
+
+    let source = "let total = 0;\nfun add(n) {\n  let doubled = n * 2;\n  return doubled;\n}\nprint add(total);\n";
+    let output = compiler::Compiler::new(source).with_name("main").compile();
+    assert!(output.succeeded());
+
+    let symbols: Vec<(&str, SymbolKind, usize)> = output
+        .symbols
+        .iter()
+        .map(|symbol| (symbol.name.as_str(), symbol.kind, symbol.span.line))
+        .collect();
+    assert_eq!(
+        symbols,
+        [
+            ("total", SymbolKind::Global, 1),
+            ("add", SymbolKind::Function, 2),
+            ("n", SymbolKind::Parameter, 2),
+            ("doubled", SymbolKind::Local, 3),
+        ]
+    );
+
+    // lines 3 and 4 are only in the function, 6 only in the script
+    let blocks = |line| {
+        output
+            .offsets_of(line)
+            .map(|entry| entry.block.as_str())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(blocks(1), ["main"]);
+    assert_eq!(blocks(3), ["add"]);
+    assert_eq!(blocks(4), ["add"]);
+    assert_eq!(blocks(6), ["main"]);
+    assert_eq!(output.offsets_of(1).next().unwrap().offset, 0);
+
+    let failed = compiler::Compiler::new("let = 1;\n").compile();
+    assert!(!failed.succeeded());
+    assert!(!failed.diagnostics.is_empty());
+}
+
+fn optimized(source: &str) -> byte_block::ByteBlock {
+    let mut compiler = compiler::Compiler::new(source).with_optimizations(true);
+    assert!(compiler.compile().succeeded(), "{source}");
+    compiler.byte_code().clone()
+}
+
+fn listing(source: &str, optimize: bool) -> String {
+    let mut compiler = compiler::Compiler::new(source).with_optimizations(optimize);
+    assert!(compiler.compile().succeeded(), "{source}");
+    disassembler::Disassembler::listing(compiler.byte_code())
+}
+
+/// What running `source` prints, optimized or not.
+fn printed(source: &str, optimize: bool) -> String {
+    let captured = Captured::default();
+    let mut engine = engine::Engine::builder()
+        .with_output(captured.clone())
+        .with_optimizations(optimize)
+        .build();
+    engine.eval(source).unwrap();
+    captured.text()
+}
+
+#[test]
+fn optimizer_folds_constant_branches() {
+    // each pair compiles to the same code once optimized
+    let cases = [
+        ("if true { print 1; } else { print 2; }", "print 1;"),
+        // constants of removed code stay in the pool, so none are used here
+        (
+            "if false { print true; } else { print null; }",
+            "{ print null; }",
+        ),
+        ("if null { print 1; }", ""),
+        ("if \"yes\" { print true; }", "print true;"),
+        ("while false { print 1; }", ""),
+        ("let x = true && false;", "let x = false;"),
+        ("let x = false || true;", "let x = true;"),
+        ("let x = 1 || 2;", "let x = 1;"),
+    ];
+
+    for (source, expected) in cases {
+        assert_eq!(listing(source, true), listing(expected, false), "{source}");
+    }
+
+    // a condition that isn't constant is left alone
+    let source = "let x = 1; if x { print 1; }";
+    assert_eq!(listing(source, true), listing(source, false));
+}
+
+#[test]
+fn optimizer_folds_constants() {
+    // each folds into one constant, printed and returned from
+    let cases = [
+        ("print 1 + 2 * 3;", "7"),
+        ("print -(2 - 5) ** 2;", "9"),
+        ("print !true;", "false"),
+        ("print !(1 > 2);", "true"),
+        ("print \"a\" + \"b\" + \"c\";", "abc"),
+        ("print 10 / 4 < 2;", "false"),
+        ("print (1 + 1) == 2;", "true"),
+        ("print null == false;", "false"),
+    ];
+
+    for (source, expected) in cases {
+        assert_eq!(opcodes(&optimized(source)).len(), 3, "{source}");
+        assert_eq!(printed(source, true), format!("{expected}\n"), "{source}");
+        assert_eq!(printed(source, false), printed(source, true), "{source}");
+    }
+
+    // errors, and what the VM decides, are left to runtime
+    for source in [
+        "print 1 / 0;",
+        "print 1 + \"a\";",
+        "print -\"a\";",
+        "print 'a' + 'b';",
+        "print \"a\" - \"b\";",
+    ] {
+        assert_eq!(listing(source, true), listing(source, false), "{source}");
+    }
+
+    // a jump lands between the operands, '2' is added to x or to 1
+    let source = "let x = 5;\nprint (x || 1) + 2;\nx = null;\nprint (x || 1) + 2;\n";
+    assert_eq!(printed(source, true), "7\n3\n");
+}
+
+#[test]
+fn optimizer_peephole_rules() {
+    // each pair compiles to the same code once optimized
+    let cases = [
+        ("{ let x = 1; x = x; print x; }", "{ let x = 1; print x; }"),
+        (
+            "{ let x = 1; x = 2; print x; }",
+            "{ let x = 1; print x = 2; }",
+        ),
+        ("let y = 2; y = 3; print y;", "let y = 2; print y = 3;"),
+        ("let b = 1; print !!!b;", "let b = 1; print !b;"),
+        ("{ let x = 1; x; }", ""),
+        ("1; true; null; 'c';", ""),
+    ];
+
+    for (source, expected) in cases {
+        assert_eq!(listing(source, true), listing(expected, false), "{source}");
+        assert_eq!(printed(source, true), printed(source, false), "{source}");
+    }
+
+    // reading a global can fail, so it isn't dropped, and '!!x' is a bool
+    for source in ["let g = 1; g;", "let b = 1; print !!b;"] {
+        assert_eq!(listing(source, true), listing(source, false), "{source}");
+    }
+}
+
+#[test]
+fn optimizer_threads_jumps() {
+    use byte_block::OpCode;
+
+    let source = "fun pick(a, b) {\n  let picked = 0;\n  if a {\n    if b { picked = 1; } else { picked = 2; }\n  } else {\n    picked = 3;\n  }\n  return picked;\n}\nprint pick(true, true);\nprint pick(true, false);\nprint pick(false, true);\n";
+    let block = optimized(source);
+    let pick = block
+        .constants()
+        .find_map(|(_, constant)| match constant {
+            constant::Constant::Function(function) => Some(function.block.clone()),
+            _ => None,
+        })
+        .unwrap();
+
+    // no jump lands on another unconditional jump
+    let mut offset = 0;
+    let mut jumps = 0;
+    while offset < pick.bytes.len() {
+        let opcode = OpCode::from(pick.bytes[offset]).ok().unwrap();
+        if let Some(target) = pick.jump_target(offset) {
+            jumps += 1;
+            let landing = pick
+                .bytes
+                .get(target)
+                .map(|&byte| OpCode::from(byte).ok().unwrap());
+            assert!(
+                !matches!(landing, Some(OpCode::Jmp | OpCode::Loop)),
+                "jump at {offset} lands on {landing:?}"
+            );
+        }
+        offset += opcode.width();
+    }
+    assert_eq!(jumps, 4);
+
+    assert_eq!(printed(source, true), "1\n2\n3\n");
+    assert!(pick.bytes.len() < compiled_len_of_function(source));
+}
+
+fn compiled_len_of_function(source: &str) -> usize {
+    let mut compiler = compiler::Compiler::new(source);
+    assert!(compiler.compile().succeeded());
+    let len = compiler
+        .byte_code()
+        .constants()
+        .find_map(|(_, constant)| match constant {
+            constant::Constant::Function(function) => Some(function.block.bytes.len()),
+            _ => None,
+        });
+    len.unwrap()
+}
+
+#[test]
+fn optimized_scripts_print_the_same() {
+    for path in ["examples/script.dyn", "examples/tuples.dyn"] {
+        let source = std::fs::read_to_string(path).unwrap();
+        assert_eq!(printed(&source, true), printed(&source, false), "{path}");
+    }
+}
+
+#[cfg(feature = "stdlib")]
+#[test]
+fn calculator_profile() {
+    let captured = Captured::default();
+    let mut engine = engine::Engine::builder()
+        .calculator()
+        .with_angle_mode(virtual_machine::AngleMode::Degrees)
+        .with_output(captured.clone())
+        .build();
+
+    // assignments aren't echoed, expressions are, the last ';' is optional
+    engine
+        .eval("let r = 2;\nround_to(PI * r ** 2, 2);\nround_to(tan(45), 6)\n")
+        .unwrap();
+    engine.eval("r = r + 1;\nr * 2\n").unwrap();
+    assert_eq!(captured.text(), "12.57\n1\n6\n");
+
+    // only the math natives are there
+    assert!(engine.eval("sqrt(16)\n").is_ok());
+    assert!(engine.eval("clock()\n").is_err());
+    assert!(engine.eval("spawn(sqrt)\n").is_err());
+}
+
+#[test]
+fn lessons_run_without_errors() {
+    let answers = "\n".repeat(learn::LESSONS.len());
+    let mut out = Vec::new();
+    learn::run(0, &mut answers.as_bytes(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+
+    assert!(!out.contains("error:"), "{out}");
+    assert!(out.contains("hello, world\n"), "{out}");
+    // the last lesson uses a function from an earlier one
+    assert!(out.contains("average works\n9\n"), "{out}");
+    assert!(out.ends_with("keep going in the REPL\n"), "{out}");
+}
+
+#[test]
+fn lessons_skip_and_quit() {
+    let mut out = Vec::new();
+    learn::run(2, &mut "s\nq\n".as_bytes(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+
+    assert!(out.starts_with("-- Lesson 3/"), "{out}");
+    assert!(out.contains("-- Lesson 4/"), "{out}");
+    assert!(!out.contains("warm\n"), "{out}");
+    assert!(!out.contains("-- Lesson 5/"), "{out}");
+}
+
+#[test]
+fn number_display() {
+    use constant::Constant;
+
+    let cases = [
+        (4.0, "4"),
+        (-0.0, "0"),
+        (0.5, "0.5"),
+        (-2.25, "-2.25"),
+        (1e20, "100000000000000000000"),
+        (1e21, "1e21"),
+        (-1.5e22, "-1.5e22"),
+        (1e-7, "0.0000001"),
+        (2.5e-8, "2.5e-8"),
+        (f64::INFINITY, "inf"),
+    ];
+    for (x, expected) in cases {
+        assert_eq!(Constant::Number(x).to_string(), expected);
+    }
+
+    // a precision fixes the decimal places, of numbers in tuples too
+    assert_eq!(format!("{:.2}", Constant::Number(1.0 / 3.0)), "0.33");
+    assert_eq!(format!("{:.1}", Constant::Number(4.0)), "4.0");
+    assert_eq!(format!("{:.2}", Constant::Number(1e21)), "1.00e21");
+    let tuple = Constant::Tuple(vec![Constant::Number(0.125), Constant::Bool(true)]);
+    assert_eq!(format!("{tuple:.1}"), "(0.1, true)");
 }
@@ -0,0 +1,379 @@
+//! Language conformance tests.
+//!
+//! Every `.dyn` file under `tests/spec/` is run through the interpreter and
+//! checked against the directives written in its comments:
+//!
+//! - `// expect: <line>` the next line the script prints, in order
+//! - `// error: <message>` the script fails to compile or run, exits with
+//!   a non-zero code, and what it wrote to stderr contains `<message>`
+//!
+//! Scripts under `tests/spec/stdlib/` use natives from the `stdlib` feature
+//! and only run when it's enabled.
+//!
+//! With `debug-print` the compiler writes every block's disassembly to the
+//! stdout these tests compare, so they don't run with it.
+
+#![cfg(not(feature = "debug-print"))]
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const SPEC_DIR: &str = "tests/spec";
+
+const SUCCESS: &str = "program exited successfully...";
+
+// directories of scripts that need a cargo feature, skipped without it
+const FEATURE_DIRS: &[(&str, bool)] = &[("stdlib", cfg!(feature = "stdlib"))];
+
+struct Directives {
+    expected: Vec<String>,
+    error: Option<String>,
+}
+
+fn parse_directives(source: &str) -> Directives {
+    let mut directives = Directives {
+        expected: Vec::new(),
+        error: None,
+    };
+
+    for line in source.lines() {
+        if let Some(expect) = line.split("// expect: ").nth(1) {
+            directives.expected.push(expect.trim_end().to_string());
+        } else if let Some(error) = line.split("// error: ").nth(1) {
+            directives.error = Some(error.trim_end().to_string());
+        }
+    }
+
+    directives
+}
+
+fn collect_scripts(dir: &Path, scripts: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            let disabled = FEATURE_DIRS
+                .iter()
+                .any(|(dir, enabled)| !enabled && path.file_name() == Some(dir.as_ref()));
+            if !disabled {
+                collect_scripts(&path, scripts);
+            }
+        } else if path.extension().is_some_and(|ext| ext == "dyn") {
+            scripts.push(path);
+        }
+    }
+}
+
+/// Runs a single script, returning a description of the failure if the
+/// output doesn't match its directives.
+fn run_spec(path: &Path) -> Result<(), String> {
+    let source = fs::read_to_string(path).unwrap();
+    let directives = parse_directives(&source);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_testbed"))
+        .arg(path)
+        .output()
+        .map_err(|err| format!("failed to run interpreter: {err}"))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    let mut printed: Vec<&str> = stdout.lines().collect();
+    let succeeded = printed.last() == Some(&SUCCESS);
+    if succeeded {
+        printed.pop();
+    }
+
+    match &directives.error {
+        Some(error) => {
+            if succeeded {
+                return Err(format!("expected error '{error}' but the script succeeded"));
+            }
+            if output.status.success() {
+                return Err(format!("expected error '{error}' but the exit code was 0"));
+            }
+            if !stderr.contains(error.as_str()) {
+                return Err(format!("expected error '{error}', stderr was:\n{stderr}"));
+            }
+        }
+        None => {
+            if !succeeded {
+                return Err(format!("unexpected failure:\n{stderr}"));
+            }
+            if !output.status.success() {
+                return Err(format!(
+                    "script succeeded but exited with {}",
+                    output.status
+                ));
+            }
+        }
+    }
+
+    if printed != directives.expected {
+        return Err(format!(
+            "expected output:\n{}\nactual output:\n{}",
+            directives.expected.join("\n"),
+            printed.join("\n")
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn spec() {
+    let mut scripts = Vec::new();
+    collect_scripts(Path::new(SPEC_DIR), &mut scripts);
+    scripts.sort();
+
+    assert!(!scripts.is_empty(), "no spec scripts found in {SPEC_DIR}");
+
+    let failures: Vec<String> = scripts
+        .iter()
+        .filter_map(|path| {
+            run_spec(path)
+                .err()
+                .map(|err| format!("--- {} ---\n{err}", path.display()))
+        })
+        .collect();
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} spec scripts failed\n\n{}",
+        failures.len(),
+        scripts.len(),
+        failures.join("\n\n")
+    );
+}
+
+#[test]
+fn disassemble_without_running() {
+    let output = Command::new(env!("CARGO_BIN_EXE_testbed"))
+        .args(["--disassemble", "tests/spec/functions/recursion.dyn"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    assert!(stdout.starts_with("-- tests/spec/functions/recursion.dyn --"));
+    assert!(stdout.contains("-- fib --"), "{stdout}");
+    assert!(!stdout.contains(SUCCESS), "the script ran:\n{stdout}");
+}
+
+#[test]
+fn exit_codes() {
+    let code = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_testbed"))
+            .args(args)
+            .output()
+            .unwrap()
+            .status
+            .code()
+    };
+
+    assert_eq!(code(&["-e", "print 1;"]), Some(0));
+    assert_eq!(code(&["-e", "print 1 +;"]), Some(65));
+    assert_eq!(code(&["-e", "print -true;"]), Some(70));
+    assert_eq!(code(&["tests/spec/missing.dyn"]), Some(66));
+    assert_eq!(code(&["--no-such-flag"]), Some(64));
+    assert_eq!(
+        code(&["test", "-e", "test \"fails\" { assert_eq(1, 2); }"]),
+        Some(1)
+    );
+}
+
+/// Nesting past the parser's limit is a compile error rather than a stack
+/// overflow, nesting up to it compiles. Long chains of operators compile
+/// whatever their length.
+#[test]
+fn deep_nesting() {
+    use std::{io::Write, process::Stdio};
+
+    // the longest sources don't fit in an argument
+    let run = |source: String| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_testbed"))
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(source.as_bytes())
+            .unwrap();
+        child.wait_with_output().unwrap()
+    };
+
+    let chain = |terms: usize| format!("let a = 1;\nprint {};", vec!["a"; terms].join(" + "));
+    let blocks = |depth: usize| format!("{}print 1;{}", "{".repeat(depth), "}".repeat(depth));
+    let parens = |depth: usize| format!("print {}1{};", "(".repeat(depth), ")".repeat(depth));
+    let calls = |depth: usize| format!("fun f() {{ return f; }}\nf{};", "()".repeat(depth));
+
+    for (source, error) in [
+        (calls(3_000), "Expression nested too deeply"),
+        (blocks(3_000), "Blocks nested too deeply"),
+        (parens(10_000), "Expression nested too deeply"),
+    ] {
+        let output = run(source);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(65), "{stderr}");
+        assert!(stderr.contains(error), "{stderr}");
+    }
+
+    for source in [blocks(200), parens(200), calls(200)] {
+        let output = run(source);
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    // chains of operators don't nest however long they are
+    let output = run(chain(20_000));
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("20000\n"));
+    let output = run(format!(
+        "print {} || true;",
+        vec!["false"; 20_000].join(" || ")
+    ));
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("true\n"));
+}
+
+#[test]
+fn warnings_dont_fail_the_script() {
+    let output = Command::new(env!("CARGO_BIN_EXE_testbed"))
+        .args(["-e", "{ let unused = 1; }\nprint 2;"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(stdout.starts_with("2\n"), "{stdout}");
+    assert!(
+        stderr.contains("Compiler Warning: at 'unused': Unused variable 'unused'"),
+        "{stderr}"
+    );
+    assert!(output.status.success());
+}
+
+#[test]
+fn script_from_stdin() {
+    use std::{io::Write, process::Stdio};
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_testbed"))
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"print 1 + 2;\nprint -true;")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert_eq!(stdout, "3\n");
+    assert!(stderr.contains("    in <stdin>"), "{stderr}");
+    assert!(!output.status.success());
+}
+
+/// Types `lines` into the REPL, returns what it wrote to stdout and stderr.
+fn run_repl(lines: &[&str]) -> (String, String) {
+    run_interactive(&[], lines)
+}
+
+/// Runs the binary with `args`, typing `lines` into its stdin.
+fn run_interactive(args: &[&str], lines: &[&str]) -> (String, String) {
+    use std::{io::Write, process::Stdio};
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_testbed"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // a command that fails early exits before reading its input, so the
+    // pipe may already be closed
+    let _ = child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(format!("{}\n", lines.join("\n")).as_bytes());
+    let output = child.wait_with_output().unwrap();
+    (
+        String::from_utf8_lossy(&output.stdout).to_string(),
+        String::from_utf8_lossy(&output.stderr).to_string(),
+    )
+}
+
+#[test]
+fn repl_keeps_definitions() {
+    let (stdout, stderr) = run_repl(&[
+        "let x = 20;",
+        "macro double(v) {\n    print v * 2;\n}",
+        "let t = spawn(fun() { return x + 1; });",
+        "print join(t);",
+        "double(x);",
+        "x + 2",
+    ]);
+
+    assert!(stdout.contains(">> 21\n"), "{stdout}");
+    assert!(stdout.contains(">> 40\n"), "{stdout}");
+    assert!(stdout.contains(">> 22\n"), "{stdout}");
+    // the stack-trace feature traces every instruction to stderr
+    assert!(
+        cfg!(feature = "stack-trace") || stderr.is_empty(),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn repl_functions_see_later_definitions() {
+    let (stdout, stderr) = run_repl(&[
+        "fun helper() { return 1; }",
+        "fun main() { return helper() + 10; }",
+        "main()",
+        // redefining a function changes the functions that call it
+        "fun helper() { return 2; }",
+        "main()",
+        // so does assigning a global they read
+        "let base = 100;",
+        "fun add(x) { return x + base; }",
+        "base = 5;",
+        "add(1)",
+        // a global can be used before it's defined, if it's there by the call
+        "fun greet() { return greeting; }",
+        "greet()",
+        "let greeting = \"hi\";",
+        "greet()",
+    ]);
+
+    assert!(stdout.contains(">> 11\n"), "{stdout}");
+    assert!(stdout.contains(">> 12\n"), "{stdout}");
+    assert!(stdout.contains(">> 6\n"), "{stdout}");
+    assert!(stdout.contains(">> hi\n"), "{stdout}");
+    assert!(stderr.contains("Undefined variable 'greeting'"), "{stderr}");
+}
+
+#[test]
+fn learn_steps_through_lessons() {
+    let (stdout, stderr) = run_interactive(&["learn", "4"], &["", "q"]);
+    assert!(stdout.starts_with("-- Lesson 4/"), "{stdout}");
+    assert!(stdout.contains("16\n55\n25\n"), "{stdout}");
+    assert!(!stdout.contains("-- Lesson 6/"), "{stdout}");
+    // the stack-trace feature traces every instruction to stderr
+    assert!(
+        cfg!(feature = "stack-trace") || stderr.is_empty(),
+        "{stderr}"
+    );
+
+    let (_, stderr) = run_interactive(&["learn", "0"], &[]);
+    assert!(stderr.contains("There are lessons 1 to"), "{stderr}");
+}